@@ -0,0 +1,179 @@
+use super::advertisement;
+use super::error::BleError;
+use bluer::adv::{Advertisement, Feature, PlatformFeature, SecondaryChannel};
+use bluer::{Adapter, Address, AddressType, Session};
+use std::collections::BTreeSet;
+
+/// Description of a Bluetooth adapter available on the system.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AdapterInfo {
+    pub name: String,
+    pub address: Address,
+    pub powered: bool,
+}
+
+/// List all the Bluetooth adapters known to the Bluetooth daemon.
+pub(crate) async fn list_adapters(session: &Session) -> Result<Vec<AdapterInfo>, BleError> {
+    let mut adapters = Vec::new();
+    for name in session.adapter_names().await? {
+        let adapter = session.adapter(&name)?;
+        adapters.push(AdapterInfo {
+            address: adapter.address().await?,
+            powered: adapter.is_powered().await?,
+            name,
+        });
+    }
+    Ok(adapters)
+}
+
+/// Return the adapter with the given address among the listed ones.
+/// Return `BleError::AdapterNotFound` with the addresses of the listed adapters if none matches.
+pub(crate) fn find_by_address(
+    adapters: &[AdapterInfo],
+    address: Address,
+) -> Result<&AdapterInfo, BleError> {
+    adapters
+        .iter()
+        .find(|adapter| adapter.address == address)
+        .ok_or_else(|| BleError::AdapterNotFound {
+            address,
+            available: adapters.iter().map(|adapter| adapter.address).collect(),
+        })
+}
+
+/// Advertising features supported by a Bluetooth adapter and its controller.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AdapterCapabilities {
+    /// Number of advertisements the adapter can broadcast at the same time.
+    pub advertising_instances: u8,
+    /// Data the adapter can include in the advertisement on its own, such as the TX power.
+    pub system_includes: BTreeSet<Feature>,
+    /// PHYs the adapter can advertise on, only reported by some controllers.
+    pub secondary_channels: BTreeSet<SecondaryChannel>,
+    /// Maximum size of the advertising data, in bytes, if reported by the controller.
+    pub max_advertisement_length: Option<usize>,
+    /// Maximum size of the scan response data, in bytes, if reported by the controller.
+    pub max_scan_response_length: Option<usize>,
+    /// Minimum and maximum advertising TX power, in dBm, if reported by the controller.
+    pub tx_power_range: Option<(i16, i16)>,
+    /// Advertising features of the platform, such as hardware offloading.
+    pub platform_features: BTreeSet<PlatformFeature>,
+}
+
+impl AdapterCapabilities {
+    /// Check if the adapter supports extended advertising, with data beyond the legacy limit or on
+    /// the 2M and coded PHYs.
+    pub fn extended_advertising(&self) -> bool {
+        self.max_advertisement_length
+            .is_some_and(|length| length > advertisement::ADVERTISEMENT_LIMIT)
+            || self.secondary_channels.contains(&SecondaryChannel::TwoM)
+            || self.secondary_channels.contains(&SecondaryChannel::Coded)
+    }
+
+    /// Check that the adapter can broadcast the advertisement.
+    /// Return `BleError::Unsupported` if it cannot advertise at all, or
+    /// `BleError::AdvertisementTooLarge` if the advertisement exceeds what the controller accepts.
+    pub fn check(&self, adv: &Advertisement) -> Result<(), BleError> {
+        if self.advertising_instances == 0 {
+            return Err(BleError::Unsupported("advertising".to_string()));
+        }
+        let bytes = advertisement::advertisement_size(adv);
+        match self.max_advertisement_length {
+            Some(limit) if bytes > limit => Err(BleError::AdvertisementTooLarge { bytes, limit }),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Query the advertising features supported by the adapter.
+pub(crate) async fn query_capabilities(adapter: &Adapter) -> Result<AdapterCapabilities, BleError> {
+    let controller = adapter.supported_advertising_capabilities().await?;
+    Ok(AdapterCapabilities {
+        advertising_instances: adapter.supported_advertising_instances().await?,
+        system_includes: adapter.supported_advertising_system_includes().await?,
+        secondary_channels: adapter
+            .supported_advertising_secondary_channels()
+            .await?
+            .unwrap_or_default(),
+        max_advertisement_length: controller
+            .as_ref()
+            .map(|caps| caps.max_advertisement_length as usize),
+        max_scan_response_length: controller
+            .as_ref()
+            .map(|caps| caps.max_scan_response_length as usize),
+        tx_power_range: controller
+            .as_ref()
+            .map(|caps| (caps.min_tx_power, caps.max_tx_power)),
+        platform_features: adapter
+            .supported_advertising_features()
+            .await?
+            .unwrap_or_default(),
+    })
+}
+
+/// Check that the adapter advertises with the address and address type required by the builder.
+/// BlueZ picks the advertising address on its own, so a required one can only be checked, not set.
+/// Return `BleError::Unsupported` if the adapter uses another one.
+pub(crate) fn check_address(
+    required: (Option<Address>, Option<AddressType>),
+    address: Address,
+    address_type: AddressType,
+) -> Result<(), BleError> {
+    match required {
+        (Some(required), _) if required != address => Err(BleError::Unsupported(format!(
+            "address {}, the adapter uses {}",
+            required, address
+        ))),
+        (_, Some(required)) if required != address_type => Err(BleError::Unsupported(format!(
+            "{} address, the adapter uses a {} one",
+            required, address_type
+        ))),
+        _ => Ok(()),
+    }
+}
+
+/// Adapter-wide discoverable state, saved before changing it so it can be restored.
+pub(crate) struct DiscoverableState {
+    adapter: Adapter,
+    discoverable: bool,
+    timeout: u32,
+}
+
+impl DiscoverableState {
+    /// Save the discoverable state of the adapter.
+    pub async fn save(adapter: &Adapter) -> Result<Self, BleError> {
+        Ok(Self {
+            adapter: adapter.clone(),
+            discoverable: adapter.is_discoverable().await?,
+            timeout: adapter.discoverable_timeout().await?,
+        })
+    }
+
+    /// Restore the saved discoverable state of the adapter.
+    pub async fn restore(self) -> Result<(), BleError> {
+        self.adapter.set_discoverable(self.discoverable).await?;
+        self.adapter.set_discoverable_timeout(self.timeout).await?;
+        Ok(())
+    }
+}
+
+/// Adapter alias, saved before changing it so it can be restored.
+pub(crate) struct AdapterAliasState {
+    adapter: Adapter,
+    alias: String,
+}
+
+impl AdapterAliasState {
+    /// Save the alias of the adapter.
+    pub async fn save(adapter: &Adapter) -> Result<Self, BleError> {
+        Ok(Self {
+            adapter: adapter.clone(),
+            alias: adapter.alias().await?,
+        })
+    }
+
+    /// Restore the saved alias of the adapter.
+    pub async fn restore(self) -> Result<(), BleError> {
+        Ok(self.adapter.set_alias(self.alias).await?)
+    }
+}
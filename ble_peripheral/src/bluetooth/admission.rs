@@ -0,0 +1,70 @@
+use super::config::PeripheralConfig;
+use super::event::{emit, BleEngineEvent};
+use bluer::gatt::local::{ReqError, ReqResult};
+use bluer::Address;
+use std::sync::Arc;
+use tokio::sync::broadcast;
+
+/// Checks declining the sessions and writes of the centrals that are not allowed, or whose MTU is
+/// too small.
+/// Shared by the receive task and the write functions of the GATT application, so every write
+/// path applies the same rules.
+#[derive(Clone)]
+pub(crate) struct Admission {
+    allowed_centrals: Arc<Vec<Address>>,
+    min_mtu: Option<usize>,
+    events: broadcast::Sender<BleEngineEvent>,
+}
+
+impl Admission {
+    /// Create the checks configured by `config`, reporting the declined centrals on `events`.
+    pub fn new(config: &PeripheralConfig, events: broadcast::Sender<BleEngineEvent>) -> Self {
+        Self {
+            allowed_centrals: Arc::new(config.allowed_centrals.clone()),
+            min_mtu: config.min_mtu,
+            events,
+        }
+    }
+
+    /// Check whether the central with `address` may open sessions, as none is refused without
+    /// an allowlist.
+    pub fn is_allowed(&self, address: Option<Address>) -> bool {
+        self.allowed_centrals.is_empty()
+            || address.is_some_and(|address| self.allowed_centrals.contains(&address))
+    }
+
+    /// Report a session declined because its central is not allowed.
+    pub fn reject_central(&self, address: Option<Address>) {
+        match address {
+            Some(address) => log::warn!("Declining session of central {} not allowed", address),
+            None => log::warn!("Declining session of a central with an unknown address"),
+        }
+        emit(&self.events, BleEngineEvent::CentralRejected { address });
+    }
+
+    /// Check whether a session with `mtu` is too small to be accepted.
+    pub fn below_min_mtu(&self, mtu: usize) -> bool {
+        self.min_mtu.is_some_and(|min_mtu| mtu < min_mtu)
+    }
+
+    /// Report a session declined because its MTU is below the minimum.
+    pub fn reject_mtu(&self, mtu: usize) {
+        let min_mtu = self.min_mtu.unwrap_or_default();
+        log::warn!("Declining session with MTU {} below {}", mtu, min_mtu);
+        emit(&self.events, BleEngineEvent::MtuTooSmall { mtu, min_mtu });
+    }
+
+    /// Check a write handled through a write function, declining it as a session of the same
+    /// central and MTU would be.
+    pub fn check_write(&self, address: Option<Address>, mtu: usize) -> ReqResult<()> {
+        if !self.is_allowed(address) {
+            self.reject_central(address);
+            return Err(ReqError::NotAuthorized);
+        }
+        if self.below_min_mtu(mtu) {
+            self.reject_mtu(mtu);
+            return Err(ReqError::NotSupported);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,83 @@
+use super::error::BleError;
+use bluer::adv::Advertisement;
+use std::collections::BTreeSet;
+use uuid::Uuid;
+
+/// Maximum size of the legacy advertising data, in bytes.
+pub const ADVERTISEMENT_LIMIT: usize = 31;
+
+/// Size of the header of every AD structure: its length and its type.
+const AD_HEADER_SIZE: usize = 2;
+
+/// Size of the flags AD structure added by BlueZ to discoverable advertisements.
+const FLAGS_SIZE: usize = AD_HEADER_SIZE + 1;
+
+/// Bluetooth Base UUID, from which the 16 and 32-bit UUIDs are derived.
+const BASE_UUID: u128 = 0x0000000000001000800000805F9B34FB;
+
+/// Return the number of bytes the UUID takes in the advertising data.
+fn uuid_size(uuid: &Uuid) -> usize {
+    let value = uuid.as_u128();
+    if value & ((1 << 96) - 1) != BASE_UUID {
+        16
+    } else if value >> 96 <= 0xFFFF {
+        2
+    } else {
+        4
+    }
+}
+
+/// Return the number of bytes taken by a list of UUIDs, one AD structure per UUID size.
+fn uuid_list_size(uuids: &BTreeSet<Uuid>) -> usize {
+    [2, 4, 16]
+        .into_iter()
+        .map(|size| uuids.iter().filter(|uuid| uuid_size(uuid) == size).count() * size)
+        .filter(|&bytes| bytes > 0)
+        .map(|bytes| AD_HEADER_SIZE + bytes)
+        .sum()
+}
+
+/// Compute the size of the advertising data BlueZ builds for the advertisement.
+pub fn advertisement_size(adv: &Advertisement) -> usize {
+    let mut size = 0;
+    if adv.discoverable == Some(true) {
+        size += FLAGS_SIZE;
+    }
+    size += uuid_list_size(&adv.service_uuids);
+    size += uuid_list_size(&adv.solicit_uuids);
+    size += adv
+        .manufacturer_data
+        .values()
+        .map(|data| AD_HEADER_SIZE + 2 + data.len())
+        .sum::<usize>();
+    size += adv
+        .service_data
+        .iter()
+        .map(|(uuid, data)| AD_HEADER_SIZE + uuid_size(uuid) + data.len())
+        .sum::<usize>();
+    size += adv
+        .advertising_data
+        .values()
+        .map(|data| AD_HEADER_SIZE + data.len())
+        .sum::<usize>();
+    if let Some(local_name) = adv.local_name.as_ref() {
+        size += AD_HEADER_SIZE + local_name.len();
+    }
+    if adv.appearance.is_some() {
+        size += AD_HEADER_SIZE + 2;
+    }
+    size
+}
+
+/// Check that the advertisement fits in the advertising data.
+/// Return `BleError::AdvertisementTooLarge` with its size if it does not.
+pub fn validate_advertisement(adv: &Advertisement) -> Result<(), BleError> {
+    let bytes = advertisement_size(adv);
+    if bytes > ADVERTISEMENT_LIMIT {
+        return Err(BleError::AdvertisementTooLarge {
+            bytes,
+            limit: ADVERTISEMENT_LIMIT,
+        });
+    }
+    Ok(())
+}
@@ -0,0 +1,154 @@
+use super::error::BleError;
+use super::readvertise::Advertiser;
+use bluer::adv::Advertisement;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use tokio::time::{Duration, Instant};
+
+/// Maximum length of the device name in bytes, as defined by the Bluetooth specification.
+pub const MAX_ALIAS_LEN: usize = 248;
+
+/// Check that the alias can be used as the device name.
+/// Return an error if it is empty, too long, or contains control characters.
+pub fn validate_alias(alias: &str) -> Result<(), BleError> {
+    if alias.is_empty() {
+        return Err(BleError::InvalidAlias(
+            "Alias must not be empty".to_string(),
+        ));
+    }
+    if alias.len() > MAX_ALIAS_LEN {
+        return Err(BleError::InvalidAlias(format!(
+            "Alias is {} bytes long, the limit is {} bytes",
+            alias.len(),
+            MAX_ALIAS_LEN
+        )));
+    }
+    if alias.chars().any(char::is_control) {
+        return Err(BleError::InvalidAlias(
+            "Alias must not contain control characters".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+/// Remove the control characters of the alias and truncate it to the maximum length,
+/// without splitting a character.
+pub fn sanitize_alias(alias: &str) -> String {
+    let mut sanitized = String::with_capacity(alias.len().min(MAX_ALIAS_LEN));
+    for c in alias.chars().filter(|c| !c.is_control()) {
+        if sanitized.len() + c.len_utf8() > MAX_ALIAS_LEN {
+            break;
+        }
+        sanitized.push(c);
+    }
+    sanitized
+}
+
+/// Substitute the instance id into the `{id}` placeholder of an alias template.
+/// The placeholder can be zero-padded to a minimum width, so `SENSOR-{id:03}` with id 7 gives
+/// `SENSOR-007`. Return an error if the template has no valid placeholder.
+pub fn render_alias_template(template: &str, id: u32) -> Result<String, BleError> {
+    let invalid = || {
+        BleError::InvalidAlias(format!(
+            "Alias template {:?} must contain an {{id}} or {{id:0N}} placeholder",
+            template
+        ))
+    };
+    let start = template.find("{id").ok_or_else(invalid)?;
+    let end = start + template[start..].find('}').ok_or_else(invalid)?;
+    let width = match &template[start + 3..end] {
+        "" => 0,
+        spec => spec
+            .strip_prefix(":0")
+            .and_then(|width| width.parse().ok())
+            .ok_or_else(invalid)?,
+    };
+    Ok(format!(
+        "{}{:0width$}{}",
+        &template[..start],
+        id,
+        &template[end + 1..],
+        width = width
+    ))
+}
+
+/// Schedule replacing the alias with the name returned by a rotation function, for privacy.
+#[derive(Clone)]
+pub(crate) struct AliasRotation {
+    pub interval: Duration,
+    rotate: Arc<dyn Fn(u64) -> String + Send + Sync>,
+}
+
+impl AliasRotation {
+    /// Create a rotation calling `rotate` with the index of each rotation, counted from 0.
+    pub fn new<F>(interval: Duration, rotate: F) -> Self
+    where
+        F: Fn(u64) -> String + Send + Sync + 'static,
+    {
+        Self {
+            interval,
+            rotate: Arc::new(rotate),
+        }
+    }
+
+    /// Return the alias of the given rotation, sanitized if `sanitize` is set, and validated.
+    pub fn alias(&self, index: u64, sanitize: bool) -> Result<String, BleError> {
+        let alias = (self.rotate)(index);
+        let alias = match sanitize {
+            true => sanitize_alias(&alias),
+            false => alias,
+        };
+        validate_alias(&alias)?;
+        Ok(alias)
+    }
+}
+
+impl fmt::Debug for AliasRotation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("AliasRotation")
+            .field("interval", &self.interval)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Rotate the alias every interval, storing it in `current` and in the advertisement, which is
+/// registered again with the new local name if it is registered. An alias failing validation is
+/// skipped, keeping the previous one. Run until aborted by stopping the engine.
+pub(crate) async fn rotate_alias<A: Advertiser>(
+    advertiser: A,
+    rotation: AliasRotation,
+    sanitize: bool,
+    current: Arc<Mutex<Option<String>>>,
+    adv: Arc<Mutex<Advertisement>>,
+    handle: Arc<Mutex<Option<A::Handle>>>,
+) {
+    let start = Instant::now() + rotation.interval;
+    let mut ticks = tokio::time::interval_at(start, rotation.interval);
+    for index in 1.. {
+        ticks.tick().await;
+        let alias = match rotation.alias(index, sanitize) {
+            Ok(alias) => alias,
+            Err(err) => {
+                log::error!("Rotated alias rejected: {}", &err);
+                continue;
+            }
+        };
+        log::debug!("Rotating alias to {}", &alias);
+        *current.lock().unwrap() = Some(alias.clone());
+        adv.lock().unwrap().local_name = Some(alias);
+
+        // A withheld advertisement picks up the new alias once it is registered
+        if handle.lock().unwrap().is_none() {
+            continue;
+        }
+        match advertiser.advertise().await {
+            Ok(new_handle) => {
+                let mut handle = handle.lock().unwrap();
+                if handle.is_some() {
+                    *handle = Some(new_handle);
+                }
+            }
+            Err(err) => log::error!("Advertising the rotated alias failed: {}", &err),
+        }
+    }
+}
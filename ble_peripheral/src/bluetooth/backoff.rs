@@ -0,0 +1,47 @@
+use tokio::time::Duration;
+
+/// Action taken once a notification still cannot be written after the last retry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum BackoffExhausted {
+    /// Drop the message, keeping the central subscribed.
+    #[default]
+    DropMessage,
+    /// Consider the central gone and end its subscription.
+    Disconnect,
+}
+
+/// Backoff applied when the central's receive buffer is full, reported by the notifier as
+/// `WouldBlock`. The write is retried after a delay doubling with every attempt.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct WriteBackoff {
+    /// Delay before the first retry.
+    pub initial_delay: Duration,
+    /// Upper bound of the delay between two retries.
+    pub max_delay: Duration,
+    /// Number of retries before giving up on the notification.
+    pub max_retries: u32,
+    /// Action taken once the retries are exhausted.
+    pub on_exhausted: BackoffExhausted,
+}
+
+impl Default for WriteBackoff {
+    fn default() -> Self {
+        Self {
+            initial_delay: Duration::from_millis(10),
+            max_delay: Duration::from_secs(1),
+            max_retries: 5,
+            on_exhausted: BackoffExhausted::DropMessage,
+        }
+    }
+}
+
+impl WriteBackoff {
+    /// Return the delay before the given retry, counted from 0.
+    pub fn delay(&self, retry: u32) -> Duration {
+        self.initial_delay
+            .saturating_mul(2u32.saturating_pow(retry))
+            .min(self.max_delay)
+    }
+}
@@ -0,0 +1,62 @@
+use bluer::gatt::local::{
+    Characteristic, CharacteristicNotify, CharacteristicNotifyMethod, CharacteristicRead, Service,
+};
+use futures::FutureExt;
+use tokio::sync::watch;
+use uuid::Uuid;
+
+/// UUID of the standard Battery Service (0x180F).
+pub const BATTERY_SERVICE_UUID: Uuid = Uuid::from_u128(0x0000180F00001000800000805F9B34FB);
+
+/// UUID of the standard Battery Level characteristic (0x2A19).
+pub const BATTERY_LEVEL_UUID: Uuid = Uuid::from_u128(0x00002A1900001000800000805F9B34FB);
+
+/// Clamp a battery level to the 0–100 percent range.
+pub(crate) fn clamp_level(level: u8) -> u8 {
+    level.min(100)
+}
+
+/// Build the Battery Service exposing the level held by the watch channel.
+/// The level can be read, and subscribers are notified whenever it changes.
+pub(crate) fn battery_service(level: &watch::Sender<u8>) -> Service {
+    let read_level = level.subscribe();
+    let notify_level = level.subscribe();
+
+    Service {
+        uuid: BATTERY_SERVICE_UUID,
+        primary: true,
+        characteristics: vec![Characteristic {
+            uuid: BATTERY_LEVEL_UUID,
+            read: Some(CharacteristicRead {
+                read: true,
+                fun: Box::new(move |_req| {
+                    let level = *read_level.borrow();
+                    async move { Ok(vec![level]) }.boxed()
+                }),
+                ..Default::default()
+            }),
+            notify: Some(CharacteristicNotify {
+                notify: true,
+                method: CharacteristicNotifyMethod::Fun(Box::new(move |mut notifier| {
+                    let mut level = notify_level.clone();
+                    async move {
+                        tokio::spawn(async move {
+                            level.mark_unchanged();
+                            while level.changed().await.is_ok() {
+                                let value = *level.borrow_and_update();
+                                if let Err(err) = notifier.notify(vec![value]).await {
+                                    log::debug!("Battery level notification ended: {}", &err);
+                                    break;
+                                }
+                            }
+                        });
+                    }
+                    .boxed()
+                })),
+                ..Default::default()
+            }),
+            ..Default::default()
+        }],
+        ..Default::default()
+    }
+}
@@ -0,0 +1,55 @@
+use bluer::adv::Advertisement;
+use std::sync::Mutex;
+use tokio::time::{Duration, Instant};
+
+/// Minimum advertising interval while advertising is boosted.
+pub const BOOST_MIN_INTERVAL: Duration = Duration::from_millis(20);
+
+/// Maximum advertising interval while advertising is boosted.
+pub const BOOST_MAX_INTERVAL: Duration = Duration::from_millis(30);
+
+/// Apply the boosted advertising intervals to the advertisement.
+pub(crate) fn boosted(adv: Advertisement) -> Advertisement {
+    Advertisement {
+        min_interval: Some(BOOST_MIN_INTERVAL),
+        max_interval: Some(BOOST_MAX_INTERVAL),
+        ..adv
+    }
+}
+
+/// Window during which the advertising is boosted.
+#[derive(Default)]
+pub(crate) struct AdvertisingBoost {
+    until: Mutex<Option<Instant>>,
+}
+
+impl AdvertisingBoost {
+    /// Boost the advertising for at least `window`, extending the current boost if it ends later.
+    /// Return the time at which the boost ends.
+    pub fn extend(&self, window: Duration) -> Instant {
+        let mut until = self.until.lock().unwrap();
+        let deadline = (Instant::now() + window).max(until.unwrap_or_else(Instant::now));
+        *until = Some(deadline);
+        deadline
+    }
+
+    /// Check whether the advertising is currently boosted.
+    pub fn is_active(&self) -> bool {
+        self.until
+            .lock()
+            .unwrap()
+            .is_some_and(|until| Instant::now() < until)
+    }
+
+    /// End the boost if its window is over. Return `true` if it ended.
+    pub fn expire_if_due(&self) -> bool {
+        let mut until = self.until.lock().unwrap();
+        match *until {
+            Some(deadline) if Instant::now() >= deadline => {
+                *until = None;
+                true
+            }
+            _ => false,
+        }
+    }
+}
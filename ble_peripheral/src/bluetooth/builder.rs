@@ -0,0 +1,600 @@
+use super::alias::{render_alias_template, sanitize_alias, validate_alias, AliasRotation};
+use super::backoff::WriteBackoff;
+use super::battery::clamp_level;
+use super::boost::AdvertisingBoost;
+use super::capture::FrameCapture;
+use super::config::PeripheralConfig;
+use super::connection::ConnectionData;
+use super::endian::Endianness;
+use super::error::BleError;
+use super::fallback::WriteFallback;
+use super::message::BleMessage;
+use super::queue::{OverflowPolicy, SendLimit, SendOverflowPolicy};
+use super::read::ReadResponse;
+use super::security::Permissions;
+use super::splitter::{Framing, Reassembler, Splitter};
+use super::state::EngineState;
+use super::transfer::TransferState;
+use super::BlePeripheral;
+use bluer::{Address, AddressType};
+use std::ops::RangeInclusive;
+use std::sync::{Arc, Mutex};
+use tokio::sync::{broadcast, watch, Semaphore};
+use tokio::time::Duration;
+use uuid::Uuid;
+
+/// Number of engine events kept for subscribers that fall behind.
+const EVENT_CAPACITY: usize = 64;
+
+/// Number of received control messages kept for subscribers that fall behind.
+const CONTROL_CAPACITY: usize = 16;
+
+/// Number of raw received chunks kept for subscribers that fall behind.
+const RAW_CHUNK_CAPACITY: usize = 64;
+
+/// Builder for configuring a BLE peripheral before creating it.
+#[derive(Debug, Default)]
+pub struct BlePeripheralBuilder {
+    config: PeripheralConfig,
+}
+
+impl BlePeripheralBuilder {
+    /// Create a new builder with the default configuration.
+    pub fn new() -> BlePeripheralBuilder {
+        Self::default()
+    }
+
+    /// Create a new builder starting from a configuration, such as one loaded from a file.
+    pub fn from_config(config: PeripheralConfig) -> BlePeripheralBuilder {
+        Self { config }
+    }
+
+    /// Return the configuration set so far, such as for saving it to a file.
+    pub fn config(&self) -> &PeripheralConfig {
+        &self.config
+    }
+
+    /// Set the alias advertised as the local name of the peripheral.
+    pub fn alias<S: Into<String>>(mut self, alias: S) -> Self {
+        self.config.alias = Some(alias.into());
+        self
+    }
+
+    /// Derive the alias from a template and the instance id, for fleets of identical peripherals.
+    /// The `{id}` placeholder is replaced by the instance id, optionally zero-padded as in
+    /// `SENSOR-{id:03}`. Takes precedence over `alias`, and requires `instance_id` to be set.
+    pub fn alias_template<S: Into<String>>(mut self, template: S) -> Self {
+        self.config.alias_template = Some(template.into());
+        self
+    }
+
+    /// Set the instance id substituted into the alias template.
+    pub fn instance_id(mut self, id: u32) -> Self {
+        self.config.instance_id = Some(id);
+        self
+    }
+
+    /// Replace the alias every `interval` with the name returned by `rotation`, called with the
+    /// index of each rotation counted from 0, so a static name cannot be used to track the
+    /// peripheral. The advertisement is registered again with each new name. Takes precedence
+    /// over `alias` and `alias_template`, and rotated names failing validation are skipped.
+    /// Centrals finding or identifying the peripheral by its name lose track of it, and may show
+    /// a stale name cached by their OS, so they should scan for the service UUID instead. The
+    /// name is only rotated in the advertisement, not in the adapter alias. Building fails with
+    /// `BleError::InvalidConfig` if `interval` is zero.
+    pub fn rotate_alias<F>(mut self, interval: Duration, rotation: F) -> Self
+    where
+        F: Fn(u64) -> String + Send + Sync + 'static,
+    {
+        self.config.alias_rotation = Some(AliasRotation::new(interval, rotation));
+        self
+    }
+
+    /// Sanitize an invalid alias instead of rejecting it when building.
+    /// Control characters are removed and the alias is truncated to the maximum device name length.
+    pub fn sanitize_alias(mut self, enabled: bool) -> Self {
+        self.config.sanitize_alias = enabled;
+        self
+    }
+
+    /// Use a delimiter for text protocols.
+    /// Sent text messages get the delimiter appended, and received bytes are split on the
+    /// delimiter into separate text messages, even when a message spans several writes.
+    pub fn text_delimiter(mut self, delimiter: u8) -> Self {
+        self.config.text_delimiter = Some(delimiter);
+        self
+    }
+
+    /// Send a final goodbye message to the central when the engine is stopped,
+    /// giving it a chance to react before the connection goes away.
+    pub fn goodbye_message<M: Into<BleMessage>>(mut self, message: M) -> Self {
+        self.config.goodbye_message = Some(message.into());
+        self
+    }
+
+    /// Bound the number of received messages waiting to be consumed, at least one.
+    /// When full, messages are dropped following the overflow policy and a
+    /// `BleEngineEvent::ReceiveOverflow` is reported.
+    pub fn receive_capacity(mut self, capacity: usize) -> Self {
+        self.config.receive_capacity = Some(capacity);
+        self
+    }
+
+    /// Choose which message is dropped when the receive queue is full. Defaults to the oldest.
+    pub fn receive_overflow_policy(mut self, policy: OverflowPolicy) -> Self {
+        self.config.receive_overflow_policy = policy;
+        self
+    }
+
+    /// Bound the number of sent messages waiting to be notified by the BLE thread, at least one.
+    /// When full, sending follows the send overflow policy, and the handler set with
+    /// `on_send_overflow` is invoked. Applies to every sent message, including the file and image
+    /// chunks, pings, and transfer progress.
+    pub fn send_capacity(mut self, capacity: usize) -> Self {
+        self.config.send_capacity = Some(capacity);
+        self
+    }
+
+    /// Limit the sent messages not written to the notifier yet to `max`, so back-to-back sends do
+    /// not congest the notification socket. Sending waits for a prior write to complete once the
+    /// limit is reached, whatever the send overflow policy. Coalesced messages count until their
+    /// batch is written. At least one write is let through.
+    pub fn max_inflight_writes(mut self, max: usize) -> Self {
+        self.config.max_inflight_writes = Some(max.max(1));
+        self
+    }
+
+    /// Keep the messages sent before the engine starts instead of returning
+    /// `BleError::EngineNotStarted`, and send them to the first central subscribing once the
+    /// engine is started, after the initial messages. They bypass the send queue limits.
+    pub fn queue_before_start(mut self, enabled: bool) -> Self {
+        self.config.queue_before_start = enabled;
+        self
+    }
+
+    /// Choose whether a message sent while the send queue is full is dropped or waits for room.
+    /// Defaults to dropping it.
+    pub fn send_overflow_policy(mut self, policy: SendOverflowPolicy) -> Self {
+        self.config.send_overflow_policy = policy;
+        self
+    }
+
+    /// Let `send_file_windowed` have up to `size` file chunks in flight, sent ahead of the chunks
+    /// acknowledged by the central. Defaults to 1, waiting for each chunk to be acknowledged.
+    pub fn window_size(mut self, size: usize) -> Self {
+        self.config.window_size = Some(size);
+        self
+    }
+
+    /// Report the progress of the chunked transfers to the central, with a
+    /// `ControlMessage::TransferProgress` sent after every `every` chunks and after the last one,
+    /// at least one. This covers `send_file`, `send_file_windowed`, and `send_image`.
+    pub fn transfer_progress(mut self, every: usize) -> Self {
+        self.config.transfer_progress = Some(every.max(1));
+        self
+    }
+
+    /// Frame the messages with a custom splitter when sending and the matching reassembler when
+    /// receiving, instead of splitting them at the MTU and delivering each write as received.
+    /// `splitter::LengthPrefixed` frames each message with its length. Control messages are
+    /// never framed. The reassembler is cloned every time the engine is started.
+    pub fn framing<S, R>(mut self, splitter: S, reassembler: R) -> Self
+    where
+        S: Splitter + 'static,
+        R: Reassembler + Clone + Sync + 'static,
+    {
+        self.config.framing = Some(Framing::new(splitter, reassembler));
+        self
+    }
+
+    /// Decode the received bytes as TLV records, so a single write can carry several tagged
+    /// messages and a record can span several writes. Each record is received as a message whose
+    /// tag is available through `receive_envelope`. The records are decoded after the framing set
+    /// with `framing`, if any, and before the text delimiter.
+    pub fn tlv_records(mut self, enabled: bool) -> Self {
+        self.config.tlv_records = enabled;
+        self
+    }
+
+    /// Drop the duplicates of the messages received recently, such as the ones retransmitted by
+    /// a central delivering at least once. Each received message starts with a `u64` identifier,
+    /// encoded in the byte order set with `endianness`, available through `receive_envelope`, and only the identifiers of the last
+    /// `window` messages are remembered, at least one. The identifier follows the protocol
+    /// version, if any, and precedes the TLV records.
+    pub fn dedup_window(mut self, window: usize) -> Self {
+        self.config.dedup_window = Some(window.max(1));
+        self
+    }
+
+    /// Retry the notifications the central cannot take yet because its receive buffer is full,
+    /// waiting longer after every attempt instead of retrying at once. Once the retries are
+    /// exhausted, the message is dropped or the central disconnected according to the backoff.
+    /// Without a backoff, such a write ends the subscription like any other write failure.
+    pub fn write_backoff(mut self, backoff: WriteBackoff) -> Self {
+        self.config.write_backoff = Some(backoff);
+        self
+    }
+
+    /// Bound how long each notification waits for the central to confirm it, so a central that
+    /// never acknowledges cannot stall the sends. A notification still unconfirmed after the
+    /// timeout fails like any other write, ending the subscription of the central.
+    pub fn indication_timeout(mut self, timeout: Duration) -> Self {
+        self.config.indication_timeout = Some(timeout);
+        self
+    }
+
+    /// Run as a beacon, broadcasting data in non-connectable advertisements instead of serving
+    /// the GATT application. The data is set with `broadcast_data`. Beacons accept no
+    /// connection, so nothing is ever received and sent messages are dropped.
+    pub fn beacon_mode(mut self, enabled: bool) -> Self {
+        self.config.beacon_mode = enabled;
+        self
+    }
+
+    /// Flush the notifier after each notified message, so the OS does not hold it back in its
+    /// buffers. This minimizes the latency of each message at the cost of throughput.
+    pub fn flush_after_each(mut self, enabled: bool) -> Self {
+        self.config.flush_after_each = enabled;
+        self
+    }
+
+    /// Serve the standard Battery Service, which many phones display automatically.
+    /// The level is clamped to 0–100 and can be updated with `set_battery_level`.
+    pub fn battery_service(mut self, initial_level: u8) -> Self {
+        self.config.battery_level = Some(clamp_level(initial_level));
+        self
+    }
+
+    /// Let the central read the characteristic receiving the writes, returning the value staged
+    /// with `BlePeripheral::respond_to_read`. The characteristic is not readable otherwise.
+    pub fn read_responses(mut self, enabled: bool) -> Self {
+        self.config.read_responses = enabled;
+        self
+    }
+
+    /// Serve a write-only command characteristic and a notify-only response characteristic
+    /// instead of the single bidirectional one.
+    /// Messages are received from the command characteristic and sent on the response characteristic.
+    pub fn command_response_layout(mut self, command_uuid: Uuid, response_uuid: Uuid) -> Self {
+        self.config.command_response = Some((command_uuid, response_uuid));
+        self
+    }
+
+    /// Serve two notify-only characteristics, a priority one for control traffic and a bulk one
+    /// for the rest, next to a write-only characteristic receiving the messages.
+    /// `send_message` notifies on the bulk characteristic and `send_priority_message` on the
+    /// priority one, so the central can subscribe to and schedule them independently.
+    /// Cannot be combined with the command/response layout, which has its own response characteristic.
+    pub fn priority_channels(mut self, priority_uuid: Uuid, bulk_uuid: Uuid) -> Self {
+        self.config.priority_channels = Some((priority_uuid, bulk_uuid));
+        self
+    }
+
+    /// Coalesce rapid small sends into fewer notifications of up to `max_bytes`.
+    /// A batch is notified once full, or `max_delay` after its first message was queued.
+    /// Every message in a batch is framed by its length, and the central splits the notifications
+    /// back into messages as done by `coalesce::split_coalesced`. Sending a message that does not
+    /// fit in a batch with its frame header, or is longer than 65535 bytes, fails.
+    /// A batch larger than the notification MTU is notified in parts holding whole frames.
+    pub fn coalesce(mut self, max_bytes: usize, max_delay: Duration) -> Self {
+        self.config.coalesce = Some((max_bytes, max_delay));
+        self
+    }
+
+    /// Keep the last `n` raw frames sent and received, along with their direction and timestamp,
+    /// for debugging interoperability problems. The frames are read with `recent_frames`.
+    pub fn capture_frames(mut self, n: usize) -> Self {
+        self.config.capture_frames = Some(n);
+        self
+    }
+
+    /// Make the whole adapter discoverable while the engine runs, including to classic Bluetooth.
+    /// Off by default, since the advertisement alone makes the peripheral discoverable over BLE.
+    /// The previous discoverable state of the adapter is restored by `stop_engine`.
+    pub fn adapter_discoverable(mut self, enabled: bool) -> Self {
+        self.config.adapter_discoverable = enabled;
+        self
+    }
+
+    /// Require the adapter to advertise with a fixed address, so centrals can pin or allowlist it.
+    /// BlueZ does not let applications choose the advertising address, so it must be configured on
+    /// the system, such as with `btmgmt static-addr` as root. `start_engine` fails with
+    /// `BleError::Unsupported` if the adapter uses another address.
+    pub fn address(mut self, address: Address) -> Self {
+        self.config.address = Some(address);
+        self
+    }
+
+    /// Require the adapter to advertise with a public or a random static address.
+    /// Like the address itself, the address type can only be configured on the system, and many
+    /// controllers only have one. `start_engine` fails with `BleError::Unsupported` if the
+    /// adapter uses another address type.
+    pub fn address_type(mut self, address_type: AddressType) -> Self {
+        self.config.address_type = Some(address_type);
+        self
+    }
+
+    /// Require a secure link before the central can read or write the characteristic receiving the
+    /// writes, each operation with its own security level, such as open reads and authenticated
+    /// writes. Both operations are open by default.
+    pub fn permissions(mut self, permissions: Permissions) -> Self {
+        self.config.permissions = permissions;
+        self
+    }
+
+    /// Accept writes without response, trading reliability for throughput.
+    pub fn write_without_response(mut self, enabled: bool) -> Self {
+        self.config.write_without_response = enabled;
+        self
+    }
+
+    /// Fall back to acknowledged writes after `failures` consecutive write failures.
+    /// A write fails if its request cannot be accepted or its stream fails to be read, and
+    /// succeeds once its stream is read to its end.
+    /// The characteristic is reconfigured the next time the engine is started, and a
+    /// `BleEngineEvent::WriteFallback` is reported when the fallback is triggered.
+    pub fn write_fallback_after(mut self, failures: u32) -> Self {
+        self.config.write_fallback_threshold = Some(failures);
+        self
+    }
+
+    /// Deliver writes as `(offset, bytes)` pairs through `receive_offset_write`, instead of as
+    /// messages, for protocols where the central writes at specific offsets of a memory region.
+    pub fn offset_writes(mut self, enabled: bool) -> Self {
+        self.config.offset_writes = enabled;
+        self
+    }
+
+    /// Hand every write request of the central to the stream returned by `raw_write_requests`,
+    /// to be accepted or rejected by the caller. Writes are then no longer received as messages,
+    /// and this takes precedence over offset writes and the write validator.
+    pub fn raw_write_requests(mut self, enabled: bool) -> Self {
+        self.config.raw_write_requests = enabled;
+        self
+    }
+
+    /// Validate the framing of the received bytes, for catching protocol bugs during development.
+    /// Text that is not valid UTF-8 and incomplete messages discarded when the central
+    /// disconnects are reported as a `BleEngineEvent::ProtocolViolation` and logged as errors,
+    /// instead of being delivered corrupt or dropped silently. Off by default.
+    pub fn strict_validation(mut self, enabled: bool) -> Self {
+        self.config.strict_validation = enabled;
+        self
+    }
+
+    /// Deliver each received message that is valid UTF-8 as `BleMessage::Text`, and the others as
+    /// `BleMessage::Raw`, sparing text-oriented callers from converting every message.
+    /// Binary data that happens to be valid UTF-8, such as short runs of ASCII bytes, is delivered
+    /// as text too, so binary protocols should leave this off. Messages split by the text
+    /// delimiter are always text.
+    pub fn auto_text(mut self, enabled: bool) -> Self {
+        self.config.auto_text = enabled;
+        self
+    }
+
+    /// Start each message with a 1-byte protocol version, so the protocol can evolve without
+    /// breaking older centrals. Sent messages carry the version of the central, learned from the
+    /// messages it sent or else agreed during the handshake, and the newest supported one
+    /// until then. Received messages carrying a version outside `versions` are rejected with
+    /// `BleError::UnsupportedVersion`, reported as a `BleEngineEvent::ProtocolViolation`, and the
+    /// version of the others is set in their metadata to route them. With framing, the version
+    /// starts each frame, otherwise each write.
+    pub fn versioned_messages(mut self, versions: RangeInclusive<u8>) -> Self {
+        self.config.message_versions = Some(versions);
+        self
+    }
+
+    /// Decline the notification sessions opened once `max` centrals are subscribed, emitting a
+    /// `BleEngineEvent::SubscriberRejected` for each, instead of letting a new session replace the
+    /// current one. A slot frees when a subscribed central goes away. The engine notifies a single
+    /// central at a time, so any limit above one only lets new sessions replace the current one.
+    pub fn max_subscribers(mut self, max: usize) -> Self {
+        self.config.max_subscribers = Some(max);
+        self
+    }
+
+    /// Decline the write and notification sessions whose MTU is below `min_mtu`, emitting a
+    /// `BleEngineEvent::MtuTooSmall` for each, so the central renegotiates or disconnects instead
+    /// of running the protocol over a degenerate connection. Writes handled one request at a time,
+    /// such as validated or offset writes, are declined with `ReqError::NotSupported`.
+    pub fn min_mtu(mut self, min_mtu: usize) -> Self {
+        self.config.min_mtu = Some(min_mtu);
+        self
+    }
+
+    /// Only accept the write and notification sessions of the centrals with the given addresses,
+    /// declining the others and emitting a `BleEngineEvent::CentralRejected` for each. A session
+    /// whose central address is unknown is declined too. An empty allowlist accepts any central.
+    /// Writes handled one request at a time, such as validated or offset writes, are declined with
+    /// `ReqError::NotAuthorized`.
+    pub fn allowed_centrals(mut self, addresses: Vec<Address>) -> Self {
+        self.config.allowed_centrals = addresses;
+        self
+    }
+
+    /// Set the byte order of the numbers the peripheral encodes and decodes itself: the control
+    /// messages, the file chunk offsets, the image lengths and chunk headers, the coalesced frame
+    /// headers, the message identifiers and the TLV record lengths. A framing set with `framing`
+    /// encodes its own numbers, `splitter::LengthPrefixed` taking its byte order with
+    /// `LengthPrefixed::with_endianness`. Defaults to big-endian (network order).
+    pub fn endianness(mut self, endianness: Endianness) -> Self {
+        self.config.endianness = endianness;
+        self
+    }
+
+    /// Deliver empty writes as empty messages, for protocols using them as signals.
+    /// Empty writes are dropped by default, so received messages are never empty.
+    pub fn deliver_empty_writes(mut self, enabled: bool) -> Self {
+        self.config.deliver_empty_writes = enabled;
+        self
+    }
+
+    /// Exchange capabilities with the central when it subscribes, before any other notification.
+    /// The peripheral announces the protocol version, the MTU, and the given application-defined
+    /// feature bits in a `ControlMessage::Hello`, and the central answers with its own.
+    /// The agreed capabilities are read with `negotiated_capabilities`.
+    pub fn handshake(mut self, features: u32) -> Self {
+        self.config.handshake_features = Some(features);
+        self
+    }
+
+    /// Register the advertisement again if the system removes it while the engine runs, for
+    /// example because of resource limits or transient errors, emitting
+    /// `BleEngineEvent::AdvertisingRestarted` each time.
+    /// The advertisement is checked every `readvertise::READVERTISE_POLL_INTERVAL`, and is
+    /// considered removed once the adapter has no active advertising instance.
+    pub fn readvertise(mut self, enabled: bool) -> Self {
+        self.config.readvertise = enabled;
+        self
+    }
+
+    /// Withhold the advertisement until `BlePeripheral::set_ready` is called, so the peripheral is
+    /// not discoverable before the application has finished initializing. The GATT application is
+    /// still served when the engine starts.
+    pub fn defer_advertising(mut self, enabled: bool) -> Self {
+        self.config.defer_advertising = enabled;
+        self
+    }
+
+    /// Create the BLE peripheral with the configured options.
+    /// Return `BleError::InvalidAlias` if the alias cannot be used as the device name,
+    /// unless alias sanitization is enabled, or if the alias template cannot be rendered.
+    /// Return `BleError::InvalidConfig` if the receive or send capacity is zero, if a count the
+    /// setters clamp to at least one is zero, such as in a loaded configuration, if the alias
+    /// rotation interval is zero, or if both the priority channels and the command/response
+    /// layout are enabled.
+    pub fn build(self) -> Result<BlePeripheral, BleError> {
+        if self.config.receive_capacity == Some(0) || self.config.send_capacity == Some(0) {
+            return Err(BleError::InvalidConfig(
+                "Queue capacity must be greater than zero".to_string(),
+            ));
+        }
+        let zero_setting = [
+            ("max_inflight_writes", self.config.max_inflight_writes),
+            ("transfer_progress", self.config.transfer_progress),
+            ("dedup_window", self.config.dedup_window),
+            (
+                "coalesce max_bytes",
+                self.config.coalesce.map(|(max_bytes, _)| max_bytes),
+            ),
+        ]
+        .into_iter()
+        .find_map(|(name, value)| (value == Some(0)).then_some(name));
+        if let Some(name) = zero_setting {
+            return Err(BleError::InvalidConfig(format!(
+                "{} must be greater than zero",
+                name
+            )));
+        }
+        if self
+            .config
+            .alias_rotation
+            .as_ref()
+            .is_some_and(|rotation| rotation.interval.is_zero())
+        {
+            return Err(BleError::InvalidConfig(
+                "Alias rotation interval must be greater than zero".to_string(),
+            ));
+        }
+        if self.config.priority_channels.is_some() && self.config.command_response.is_some() {
+            return Err(BleError::InvalidConfig(
+                "Priority channels cannot be combined with the command/response layout".to_string(),
+            ));
+        }
+
+        // The configuration keeps the alias as set, so building from it again gives the same one
+        let mut alias = self.config.alias.clone();
+        if let Some(rotation) = self.config.alias_rotation.as_ref() {
+            alias = Some(rotation.alias(0, self.config.sanitize_alias)?);
+        } else if let Some(template) = self.config.alias_template.as_ref() {
+            let id = self.config.instance_id.ok_or_else(|| {
+                BleError::InvalidAlias("Alias template requires an instance id".to_string())
+            })?;
+            alias = Some(render_alias_template(template, id)?);
+        }
+        if let Some(unchecked) = alias.take() {
+            let checked = match self.config.sanitize_alias {
+                true => sanitize_alias(&unchecked),
+                false => unchecked,
+            };
+            validate_alias(&checked)?;
+            alias = Some(checked);
+        }
+
+        let battery_level = self
+            .config
+            .battery_level
+            .map(|level| watch::channel(level).0);
+
+        let frame_capture = self
+            .config
+            .capture_frames
+            .map(|n| Arc::new(FrameCapture::new(n)));
+
+        let write_fallback = self
+            .config
+            .write_fallback_threshold
+            .map(|threshold| Arc::new(WriteFallback::new(threshold)));
+
+        let send_limit = self
+            .config
+            .send_capacity
+            .map(|capacity| Arc::new(SendLimit::new(capacity, self.config.send_overflow_policy)));
+        let inflight_writes = self
+            .config
+            .max_inflight_writes
+            .map(|max| Arc::new(Semaphore::new(max)));
+
+        Ok(BlePeripheral {
+            alias,
+            config: self.config,
+            sender: None,
+            receiver: None,
+            app_handler: None,
+            adv_handler: Arc::new(Mutex::new(None)),
+            adv_boost: Arc::new(AdvertisingBoost::default()),
+            ble_thread: None,
+            ble_thread_exit: None,
+            state: EngineState::Stopped,
+            adapter: None,
+            subscribed_watcher: None,
+            last_notified_watcher: None,
+            metrics: None,
+            mtu_watcher: None,
+            capabilities_watcher: None,
+            peer_version_watcher: None,
+            write_validator: None,
+            events: broadcast::channel(EVENT_CAPACITY).0,
+            battery_level,
+            message_handler: Arc::new(Mutex::new(None)),
+            connection_data: Arc::new(ConnectionData::default()),
+            control: broadcast::channel(CONTROL_CAPACITY).0,
+            raw_chunks: broadcast::channel(RAW_CHUNK_CAPACITY).0,
+            next_ping_nonce: 0,
+            file_transfer: Arc::new(TransferState::default()),
+            frame_capture,
+            saved_discoverable: None,
+            saved_adapter_alias: Mutex::new(None),
+            write_fallback,
+            offset_sender: None,
+            offset_receiver: None,
+            raw_write_sender: None,
+            raw_write_receiver: None,
+            send_limit,
+            inflight_writes,
+            send_overflow_handler: Mutex::new(None),
+            read_response: Arc::new(ReadResponse::default()),
+            initial_messages: Arc::new(Mutex::new(Vec::new())),
+            queued_before_start: Mutex::new(Vec::new()),
+            gatt_relay: None,
+            readvertised: None,
+            readvertise_task: None,
+            rotated_alias: Arc::new(Mutex::new(None)),
+            alias_rotation_task: None,
+            ready: watch::channel(false).0,
+            connection_info: watch::channel(None).0,
+            deferred_advertising: None,
+            beacon_data: Vec::new(),
+        })
+    }
+}
@@ -0,0 +1,84 @@
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+/// Direction of a captured frame, as seen from the peripheral.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameDirection {
+    /// Notified by the peripheral to the central.
+    Sent,
+    /// Written by the central to the peripheral.
+    Received,
+}
+
+/// Raw bytes exchanged with the central, captured for debugging.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CapturedFrame {
+    pub direction: FrameDirection,
+    pub bytes: Vec<u8>,
+    pub timestamp: SystemTime,
+}
+
+/// Bytes of a single read from the central, before they are reassembled into messages.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RawChunk {
+    pub bytes: Vec<u8>,
+    pub timestamp: SystemTime,
+}
+
+impl RawChunk {
+    /// Create a new chunk read now.
+    pub(crate) fn new(bytes: &[u8]) -> Self {
+        Self {
+            bytes: bytes.to_vec(),
+            timestamp: SystemTime::now(),
+        }
+    }
+
+    /// Return the size of the chunk.
+    pub fn len(&self) -> usize {
+        self.bytes.len()
+    }
+
+    /// Check whether the chunk is empty.
+    pub fn is_empty(&self) -> bool {
+        self.bytes.is_empty()
+    }
+}
+
+/// Ring buffer holding the most recent frames exchanged with the central.
+pub(crate) struct FrameCapture {
+    capacity: usize,
+    frames: Mutex<VecDeque<CapturedFrame>>,
+}
+
+impl FrameCapture {
+    /// Create a new ring buffer keeping the last `capacity` frames.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            frames: Mutex::new(VecDeque::with_capacity(capacity)),
+        }
+    }
+
+    /// Record a frame, evicting the oldest one if the buffer is full.
+    pub fn record(&self, direction: FrameDirection, bytes: &[u8]) {
+        if self.capacity == 0 {
+            return;
+        }
+        let mut frames = self.frames.lock().unwrap();
+        if frames.len() == self.capacity {
+            frames.pop_front();
+        }
+        frames.push_back(CapturedFrame {
+            direction,
+            bytes: bytes.to_vec(),
+            timestamp: SystemTime::now(),
+        });
+    }
+
+    /// Return the captured frames, oldest first.
+    pub fn frames(&self) -> Vec<CapturedFrame> {
+        self.frames.lock().unwrap().iter().cloned().collect()
+    }
+}
@@ -0,0 +1,164 @@
+use super::endian::Endianness;
+use std::collections::BTreeMap;
+use std::error::Error;
+use tokio::time::{Duration, Instant};
+
+/// Size of the header prepended to every chunk: the chunk index followed by the chunk count,
+/// both encoded as u16 in the byte order of the chunks.
+pub const CHUNK_HEADER_SIZE: usize = 4;
+
+/// Default time the reassembler waits for a missing chunk before giving up.
+pub const DEFAULT_GAP_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Split a payload into sequenced chunks carrying at most `chunk_size` payload bytes each.
+/// Every chunk starts with a header holding its index and the total number of chunks, in the
+/// given byte order.
+pub fn split_into_chunks(
+    bytes: &[u8],
+    chunk_size: usize,
+    endianness: Endianness,
+) -> Result<Vec<Vec<u8>>, Box<dyn Error>> {
+    if chunk_size == 0 {
+        return Err("Chunk size must be greater than zero".into());
+    }
+
+    let count = bytes.len().div_ceil(chunk_size).max(1);
+    if count > u16::MAX as usize {
+        return Err("Payload requires more chunks than can be sequenced".into());
+    }
+
+    let chunks = (0..count)
+        .map(|index| {
+            let start = index * chunk_size;
+            let end = (start + chunk_size).min(bytes.len());
+            let mut chunk = Vec::with_capacity(CHUNK_HEADER_SIZE + end - start);
+            chunk.extend_from_slice(&endianness.u16_bytes(index as u16));
+            chunk.extend_from_slice(&endianness.u16_bytes(count as u16));
+            chunk.extend_from_slice(&bytes[start..end]);
+            chunk
+        })
+        .collect();
+    Ok(chunks)
+}
+
+/// Reassembler for sequenced chunks.
+/// Chunks are buffered by their index, so they may arrive in any order.
+/// The message is complete once every index up to the declared count has been received.
+/// The chunk headers are decoded as big-endian unless set otherwise with `with_endianness`.
+/// A gap is only detected by `push` or `check_timeout`, so a receive loop waiting for chunks
+/// should also wake up at the `deadline` and check the timeout, in case nothing follows the gap.
+pub struct ChunkReassembler {
+    gap_timeout: Duration,
+    endianness: Endianness,
+    expected_count: Option<u16>,
+    chunks: BTreeMap<u16, Vec<u8>>,
+    last_progress: Option<Instant>,
+}
+
+impl ChunkReassembler {
+    /// Create a new reassembler that errors when a gap is not filled within `gap_timeout`.
+    pub fn new(gap_timeout: Duration) -> ChunkReassembler {
+        ChunkReassembler {
+            gap_timeout,
+            endianness: Endianness::Big,
+            expected_count: None,
+            chunks: BTreeMap::new(),
+            last_progress: None,
+        }
+    }
+
+    /// Decode the chunk headers in the given byte order.
+    pub fn with_endianness(mut self, endianness: Endianness) -> ChunkReassembler {
+        self.endianness = endianness;
+        self
+    }
+
+    /// Feed a chunk into the reassembler.
+    /// Return the reassembled payload once all chunks are present, or `None` if some are still missing.
+    /// Return an error if the chunk is malformed or if the previous chunks have timed out.
+    pub fn push(&mut self, chunk: &[u8]) -> Result<Option<Vec<u8>>, Box<dyn Error>> {
+        self.check_timeout()?;
+
+        if chunk.len() < CHUNK_HEADER_SIZE {
+            return Err("Chunk is shorter than its header".into());
+        }
+        let index = self.endianness.read_u16([chunk[0], chunk[1]]);
+        let count = self.endianness.read_u16([chunk[2], chunk[3]]);
+
+        if count == 0 || index >= count {
+            return Err(
+                format!("Chunk index {} is out of range for count {}", index, count).into(),
+            );
+        }
+        match self.expected_count {
+            Some(expected) if expected != count => {
+                self.reset();
+                return Err(format!(
+                    "Chunk count mismatch: expected {}, received {}",
+                    expected, count
+                )
+                .into());
+            }
+            _ => self.expected_count = Some(count),
+        }
+
+        self.chunks
+            .insert(index, chunk[CHUNK_HEADER_SIZE..].to_vec());
+        self.last_progress = Some(Instant::now());
+
+        if self.chunks.len() < count as usize {
+            return Ok(None);
+        }
+
+        // The map is ordered by index, so concatenating its values restores the payload
+        let payload = std::mem::take(&mut self.chunks)
+            .into_values()
+            .flatten()
+            .collect();
+        self.reset();
+        Ok(Some(payload))
+    }
+
+    /// Check whether the pending chunks have been waiting for a missing chunk for too long.
+    /// The pending chunks are discarded when the timeout is exceeded.
+    pub fn check_timeout(&mut self) -> Result<(), Box<dyn Error>> {
+        if self
+            .deadline()
+            .is_some_and(|deadline| Instant::now() >= deadline)
+        {
+            let missing = self.missing_indices();
+            self.reset();
+            return Err(format!("Timed out waiting for chunks {:?}", missing).into());
+        }
+        Ok(())
+    }
+
+    /// Return the time at which the pending chunks time out, if a message is being reassembled.
+    pub fn deadline(&self) -> Option<Instant> {
+        self.last_progress
+            .map(|last_progress| last_progress + self.gap_timeout)
+    }
+
+    /// Return the indices that have not been received yet for the current message.
+    pub fn missing_indices(&self) -> Vec<u16> {
+        match self.expected_count {
+            Some(count) => (0..count)
+                .filter(|index| !self.chunks.contains_key(index))
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+
+    /// Discard any partially reassembled message.
+    pub fn reset(&mut self) {
+        self.expected_count = None;
+        self.chunks.clear();
+        self.last_progress = None;
+    }
+}
+
+impl Default for ChunkReassembler {
+    fn default() -> Self {
+        Self::new(DEFAULT_GAP_TIMEOUT)
+    }
+}
@@ -0,0 +1,140 @@
+use super::endian::Endianness;
+use super::error::BleError;
+use tokio::time::{Duration, Instant};
+
+/// Size of the length prefix framing every message in a coalesced notification.
+pub const FRAME_HEADER_SIZE: usize = 2;
+
+/// Split a coalesced notification back into the messages it batches.
+/// Each message is framed by its length, encoded as a u16 in the given byte order.
+pub fn split_coalesced(bytes: &[u8], endianness: Endianness) -> Result<Vec<Vec<u8>>, BleError> {
+    let mut messages = Vec::new();
+    let mut rest = bytes;
+    while !rest.is_empty() {
+        if rest.len() < FRAME_HEADER_SIZE {
+            return Err(BleError::InvalidMessage(
+                "Frame is shorter than its header".to_string(),
+            ));
+        }
+        let len = endianness.read_u16([rest[0], rest[1]]) as usize;
+        let frame = &rest[FRAME_HEADER_SIZE..];
+        if frame.len() < len {
+            return Err(BleError::InvalidMessage(format!(
+                "Frame declares {} bytes but only {} remain",
+                len,
+                frame.len()
+            )));
+        }
+        messages.push(frame[..len].to_vec());
+        rest = &frame[len..];
+    }
+    Ok(messages)
+}
+
+/// Split a batch at its frame boundaries into notifications of up to `mtu` bytes, so the central
+/// can split every notification on its own. A frame longer than `mtu` is kept whole in its own
+/// notification.
+pub(crate) fn split_batch(bytes: &[u8], mtu: usize, endianness: Endianness) -> Vec<&[u8]> {
+    let mut notifications = Vec::new();
+    let mut start = 0;
+    let mut pos = 0;
+    while pos + FRAME_HEADER_SIZE <= bytes.len() {
+        let frame_len =
+            FRAME_HEADER_SIZE + endianness.read_u16([bytes[pos], bytes[pos + 1]]) as usize;
+        if pos > start && pos + frame_len - start > mtu {
+            notifications.push(&bytes[start..pos]);
+            start = pos;
+        }
+        pos += frame_len;
+    }
+    if start < bytes.len() {
+        notifications.push(&bytes[start..]);
+    }
+    notifications
+}
+
+/// A batch of coalesced messages, along with the values held for them until it is notified.
+pub(crate) struct Batch<T> {
+    pub bytes: Vec<u8>,
+    pub held: Vec<T>,
+}
+
+/// Check whether a message of `len` bytes can be framed in a batch of up to `max_bytes`,
+/// as its length must also fit in the u16 frame header.
+pub fn fits_in_batch(len: usize, max_bytes: usize) -> bool {
+    len <= u16::MAX as usize && FRAME_HEADER_SIZE + len <= max_bytes
+}
+
+/// Batcher coalescing small queued messages into fewer notifications.
+/// A batch is flushed once it reaches `max_bytes`, or once `max_delay` has elapsed since its
+/// first message was queued. Each message comes with a value held until its batch is taken.
+/// The frame headers are encoded as big-endian unless set otherwise with `with_endianness`.
+pub(crate) struct Coalescer<T> {
+    max_bytes: usize,
+    max_delay: Duration,
+    endianness: Endianness,
+    buffer: Vec<u8>,
+    held: Vec<T>,
+    deadline: Option<Instant>,
+}
+
+impl<T> Coalescer<T> {
+    /// Create a new coalescer with the given limits.
+    pub fn new(max_bytes: usize, max_delay: Duration) -> Self {
+        Self {
+            max_bytes,
+            max_delay,
+            endianness: Endianness::default(),
+            buffer: Vec::new(),
+            held: Vec::new(),
+            deadline: None,
+        }
+    }
+
+    /// Encode the frame headers in the given byte order.
+    pub fn with_endianness(mut self, endianness: Endianness) -> Self {
+        self.endianness = endianness;
+        self
+    }
+
+    /// Frame a message and add it to the pending batch, holding `held` along with it.
+    /// The message must fit in a batch, as checked by `fits_in_batch`.
+    /// Return the batches that are ready to be notified.
+    pub fn push(&mut self, message: &[u8], held: T) -> Vec<Batch<T>> {
+        let mut ready = Vec::new();
+
+        // Flush the pending batch first if the message does not fit in it
+        let frame_len = FRAME_HEADER_SIZE + message.len();
+        if !self.buffer.is_empty() && self.buffer.len() + frame_len > self.max_bytes {
+            ready.extend(self.take());
+        }
+
+        self.buffer
+            .extend_from_slice(&self.endianness.u16_bytes(message.len() as u16));
+        self.buffer.extend_from_slice(message);
+        self.held.push(held);
+        if self.buffer.len() >= self.max_bytes {
+            ready.extend(self.take());
+        } else if self.deadline.is_none() {
+            self.deadline = Some(Instant::now() + self.max_delay);
+        }
+        ready
+    }
+
+    /// Return the time at which the pending batch must be flushed, if there is one.
+    pub fn deadline(&self) -> Option<Instant> {
+        self.deadline
+    }
+
+    /// Take the pending batch, if any.
+    pub fn take(&mut self) -> Option<Batch<T>> {
+        self.deadline = None;
+        match self.buffer.is_empty() {
+            true => None,
+            false => Some(Batch {
+                bytes: std::mem::take(&mut self.buffer),
+                held: std::mem::take(&mut self.held),
+            }),
+        }
+    }
+}
@@ -0,0 +1,42 @@
+use super::error::BleError;
+
+/// Wire format turning values into message bytes and back.
+/// Implement it to send and receive application types with `send_encoded` and `receive_decoded`.
+pub trait MessageCodec<T> {
+    /// Encode the value into the bytes of a message.
+    fn encode(value: &T) -> Result<Vec<u8>, BleError>;
+
+    /// Decode a value from the bytes of a message.
+    fn decode(bytes: &[u8]) -> Result<T, BleError>;
+}
+
+/// Codec passing the bytes through unchanged.
+pub struct RawCodec;
+
+impl MessageCodec<Vec<u8>> for RawCodec {
+    fn encode(value: &Vec<u8>) -> Result<Vec<u8>, BleError> {
+        Ok(value.clone())
+    }
+
+    fn decode(bytes: &[u8]) -> Result<Vec<u8>, BleError> {
+        Ok(bytes.to_vec())
+    }
+}
+
+/// Codec encoding values as JSON.
+#[cfg(feature = "json")]
+pub struct JsonCodec;
+
+#[cfg(feature = "json")]
+impl<T> MessageCodec<T> for JsonCodec
+where
+    T: serde::Serialize + serde::de::DeserializeOwned,
+{
+    fn encode(value: &T) -> Result<Vec<u8>, BleError> {
+        serde_json::to_vec(value).map_err(|err| BleError::InvalidMessage(err.to_string()))
+    }
+
+    fn decode(bytes: &[u8]) -> Result<T, BleError> {
+        serde_json::from_slice(bytes).map_err(|err| BleError::InvalidMessage(err.to_string()))
+    }
+}
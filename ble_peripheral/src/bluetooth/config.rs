@@ -0,0 +1,136 @@
+use super::alias::AliasRotation;
+use super::backoff::WriteBackoff;
+use super::endian::Endianness;
+#[cfg(feature = "serde")]
+use super::error::BleError;
+use super::message::BleMessage;
+use super::queue::{OverflowPolicy, SendOverflowPolicy};
+use super::security::Permissions;
+use super::splitter::Framing;
+use bluer::{Address, AddressType};
+use std::ops::RangeInclusive;
+use tokio::time::Duration;
+use uuid::Uuid;
+
+/// Configuration of a BLE peripheral, set through the builder.
+/// With the `serde` feature, it can be saved to and loaded from TOML for reproducible
+/// deployments. The framing is code rather than configuration, so it is never serialized.
+#[derive(Debug, Clone, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
+pub struct PeripheralConfig {
+    /// Alias advertised as the local name of the peripheral.
+    pub alias: Option<String>,
+    /// Template the alias is derived from along with the instance id, taking precedence over it.
+    pub alias_template: Option<String>,
+    /// Instance id substituted into the alias template.
+    pub instance_id: Option<u32>,
+    /// Rotation replacing the alias on a schedule, taking precedence over the alias and template.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub(crate) alias_rotation: Option<AliasRotation>,
+    /// Delimiter appended to sent text messages and used to split received bytes into text messages.
+    pub text_delimiter: Option<u8>,
+    /// Message sent to the central when the engine is stopped.
+    pub goodbye_message: Option<BleMessage>,
+    /// Maximum number of received messages waiting to be consumed, unbounded if `None`.
+    pub receive_capacity: Option<usize>,
+    /// Policy applied when a message is received while the receive queue is full.
+    pub receive_overflow_policy: OverflowPolicy,
+    /// Initial battery level of the Battery Service, which is only served if set.
+    pub battery_level: Option<u8>,
+    /// UUIDs of the command and response characteristics, replacing the bidirectional one if set.
+    pub command_response: Option<(Uuid, Uuid)>,
+    /// Whether the characteristic receiving the writes can be read, serving the staged response.
+    pub read_responses: bool,
+    /// Maximum size and delay of the batches coalescing sent messages, which are sent one by one if `None`.
+    pub coalesce: Option<(usize, Duration)>,
+    /// Number of recent raw frames kept for debugging, none are captured if `None`.
+    pub capture_frames: Option<usize>,
+    /// UUID of the service replacing the default one, set by `rotate_uuids`.
+    pub service_uuid: Option<Uuid>,
+    /// UUID of the characteristic receiving the writes replacing the default one, set by `rotate_uuids`.
+    pub characteristic_uuid: Option<Uuid>,
+    /// Whether the peripheral only broadcasts advertisements, without serving the GATT application.
+    pub beacon_mode: bool,
+    /// Whether to make the whole adapter discoverable while the engine runs.
+    pub adapter_discoverable: bool,
+    /// Address the adapter must advertise with, checked when starting.
+    pub address: Option<Address>,
+    /// Address type the adapter must advertise with, checked when starting.
+    pub address_type: Option<AddressType>,
+    /// Security levels required to read and write the characteristic receiving the writes.
+    pub permissions: Permissions,
+    /// Whether the characteristic accepts writes without response.
+    pub write_without_response: bool,
+    /// Number of consecutive write failures after which acknowledged writes are used instead.
+    pub write_fallback_threshold: Option<u32>,
+    /// Whether an invalid alias is sanitized instead of rejected.
+    pub sanitize_alias: bool,
+    /// Whether writes are delivered along with their offset instead of as messages.
+    pub offset_writes: bool,
+    /// Whether received bytes breaking the framing are rejected and reported instead of delivered.
+    pub strict_validation: bool,
+    /// Whether write requests are handed to the caller as is instead of received as messages.
+    pub raw_write_requests: bool,
+    /// Whether received messages that are valid UTF-8 are delivered as text instead of raw bytes.
+    pub auto_text: bool,
+    /// Whether empty writes are delivered as empty messages instead of dropped.
+    pub deliver_empty_writes: bool,
+    /// Features announced during the handshake, which is only performed if set.
+    pub handshake_features: Option<u32>,
+    /// UUIDs of the priority and bulk notify characteristics, served separately from the write one if set.
+    pub priority_channels: Option<(Uuid, Uuid)>,
+    /// Whether the advertisement is registered again if the system removes it.
+    pub readvertise: bool,
+    /// Whether the advertisement is withheld until the application is ready.
+    pub defer_advertising: bool,
+    /// Maximum number of file chunks sent ahead of the acknowledged ones by `send_file_windowed`.
+    pub window_size: Option<usize>,
+    /// Number of chunks between the progress reports of a chunked transfer, which has none if `None`.
+    pub transfer_progress: Option<usize>,
+    /// Splitter and reassembler framing the messages, which are split at the MTU if `None`.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub(crate) framing: Option<Framing>,
+    /// Protocol versions supported by the messages, which carry no version if `None`.
+    pub message_versions: Option<RangeInclusive<u8>>,
+    /// Whether received bytes are decoded as TLV records, each delivered as a tagged message.
+    pub tlv_records: bool,
+    /// Number of recently received message identifiers remembered to drop duplicates, which
+    /// carry no identifier if `None`.
+    pub dedup_window: Option<usize>,
+    /// Backoff retrying the notifications blocked by a full receive buffer, which fail at once if `None`.
+    pub write_backoff: Option<WriteBackoff>,
+    /// Time the central has to confirm each notification before the write fails, unbounded if `None`.
+    pub indication_timeout: Option<Duration>,
+    /// Whether the notifier is flushed after each notified message.
+    pub flush_after_each: bool,
+    /// Maximum number of sent messages waiting to be notified, unbounded if `None`.
+    pub send_capacity: Option<usize>,
+    /// Policy applied when a message is sent while the send queue is full.
+    pub send_overflow_policy: SendOverflowPolicy,
+    /// Maximum number of sent messages not written to the notifier yet, unlimited if `None`.
+    pub max_inflight_writes: Option<usize>,
+    /// Whether messages sent before the engine starts are kept for the first central subscribing.
+    pub queue_before_start: bool,
+    /// Maximum number of centrals subscribed at once, unlimited if `None`.
+    pub max_subscribers: Option<usize>,
+    /// Minimum MTU of the accepted write and notification sessions, any MTU if `None`.
+    pub min_mtu: Option<usize>,
+    /// Addresses of the centrals whose sessions are accepted, any central if empty.
+    pub allowed_centrals: Vec<Address>,
+    /// Byte order of the numbers in control messages, file chunks and image chunks.
+    pub endianness: Endianness,
+}
+
+#[cfg(feature = "serde")]
+impl PeripheralConfig {
+    /// Load a configuration from TOML, leaving the missing settings at their default.
+    pub fn from_toml(toml: &str) -> Result<Self, BleError> {
+        toml::from_str(toml).map_err(|err| BleError::InvalidConfig(err.to_string()))
+    }
+
+    /// Save the configuration as TOML.
+    pub fn to_toml(&self) -> Result<String, BleError> {
+        toml::to_string(self).map_err(|err| BleError::InvalidConfig(err.to_string()))
+    }
+}
@@ -0,0 +1,31 @@
+use std::any::{Any, TypeId};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+/// Application data attached to the current connection, one value per type.
+/// The data is cleared when the central device disconnects.
+#[derive(Default)]
+pub(crate) struct ConnectionData {
+    values: Mutex<HashMap<TypeId, Arc<dyn Any + Send + Sync>>>,
+}
+
+impl ConnectionData {
+    /// Attach a value to the connection, replacing any value of the same type.
+    pub fn set<T: Send + Sync + 'static>(&self, value: T) {
+        self.values
+            .lock()
+            .unwrap()
+            .insert(TypeId::of::<T>(), Arc::new(value));
+    }
+
+    /// Return the value of the given type attached to the connection.
+    pub fn get<T: Send + Sync + 'static>(&self) -> Option<Arc<T>> {
+        let value = self.values.lock().unwrap().get(&TypeId::of::<T>())?.clone();
+        value.downcast::<T>().ok()
+    }
+
+    /// Remove every value attached to the connection.
+    pub fn clear(&self) {
+        self.values.lock().unwrap().clear();
+    }
+}
@@ -0,0 +1,104 @@
+use super::endian::Endianness;
+use super::handshake::Capabilities;
+
+/// Marker starting every control message, telling it apart from application data.
+/// Received payloads starting with this marker are handled by the peripheral and never delivered.
+pub const CONTROL_MARKER: [u8; 2] = [0xFF, 0xBC];
+
+const PING: u8 = 0x01;
+const PONG: u8 = 0x02;
+const TRANSFER_ACK: u8 = 0x03;
+const HELLO: u8 = 0x04;
+const TRANSFER_PROGRESS: u8 = 0x05;
+
+/// Control messages exchanged with the central alongside the application messages.
+/// A control message is encoded as the control marker, followed by a kind byte and its payload.
+/// Numbers are encoded in the byte order given to `to_bytes` and `from_bytes`.
+///
+/// | Message          | Kind   | Payload                           |
+/// |------------------|--------|-----------------------------------|
+/// | Ping             | `0x01` | nonce u32                         |
+/// | Pong             | `0x02` | nonce u32                         |
+/// | TransferAck      | `0x03` | offset u64                        |
+/// | Hello            | `0x04` | version u8, mtu u16, features u32 |
+/// | TransferProgress | `0x05` | chunks u32, total u32             |
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ControlMessage {
+    /// Request the other side to answer with a pong carrying the same nonce.
+    Ping { nonce: u32 },
+    /// Answer to a ping.
+    Pong { nonce: u32 },
+    /// Sent by the central to acknowledge the bytes of the file transfer received so far.
+    TransferAck { offset: u64 },
+    /// Sent by both sides when the central subscribes, announcing their capabilities.
+    Hello(Capabilities),
+    /// Sent by the peripheral during a chunked transfer, once `chunks` of its `total` chunks are sent.
+    TransferProgress { chunks: u32, total: u32 },
+}
+
+impl ControlMessage {
+    /// Encode the control message into bytes, with its numbers in the given byte order
+    pub fn to_bytes(&self, endianness: Endianness) -> Vec<u8> {
+        let mut bytes = CONTROL_MARKER.to_vec();
+        match self {
+            ControlMessage::Ping { nonce } => {
+                bytes.push(PING);
+                bytes.extend_from_slice(&endianness.u32_bytes(*nonce));
+            }
+            ControlMessage::Pong { nonce } => {
+                bytes.push(PONG);
+                bytes.extend_from_slice(&endianness.u32_bytes(*nonce));
+            }
+            ControlMessage::TransferAck { offset } => {
+                bytes.push(TRANSFER_ACK);
+                bytes.extend_from_slice(&endianness.u64_bytes(*offset));
+            }
+            ControlMessage::Hello(capabilities) => {
+                bytes.push(HELLO);
+                bytes.push(capabilities.version);
+                bytes.extend_from_slice(&endianness.u16_bytes(capabilities.mtu));
+                bytes.extend_from_slice(&endianness.u32_bytes(capabilities.features));
+            }
+            ControlMessage::TransferProgress { chunks, total } => {
+                bytes.push(TRANSFER_PROGRESS);
+                bytes.extend_from_slice(&endianness.u32_bytes(*chunks));
+                bytes.extend_from_slice(&endianness.u32_bytes(*total));
+            }
+        }
+        bytes
+    }
+
+    /// Decode a control message from bytes, with its numbers in the given byte order.
+    /// Return `None` if the bytes are not a control message.
+    pub fn from_bytes(bytes: &[u8], endianness: Endianness) -> Option<ControlMessage> {
+        let body = bytes.strip_prefix(&CONTROL_MARKER)?;
+        let (&kind, payload) = body.split_first()?;
+        match kind {
+            PING => Some(ControlMessage::Ping {
+                nonce: endianness.read_u32(payload.try_into().ok()?),
+            }),
+            PONG => Some(ControlMessage::Pong {
+                nonce: endianness.read_u32(payload.try_into().ok()?),
+            }),
+            TRANSFER_ACK => Some(ControlMessage::TransferAck {
+                offset: endianness.read_u64(payload.try_into().ok()?),
+            }),
+            HELLO => match payload {
+                [version, m0, m1, f0, f1, f2, f3] => Some(ControlMessage::Hello(Capabilities {
+                    version: *version,
+                    mtu: endianness.read_u16([*m0, *m1]),
+                    features: endianness.read_u32([*f0, *f1, *f2, *f3]),
+                })),
+                _ => None,
+            },
+            TRANSFER_PROGRESS => match payload {
+                [c0, c1, c2, c3, t0, t1, t2, t3] => Some(ControlMessage::TransferProgress {
+                    chunks: endianness.read_u32([*c0, *c1, *c2, *c3]),
+                    total: endianness.read_u32([*t0, *t1, *t2, *t3]),
+                }),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+}
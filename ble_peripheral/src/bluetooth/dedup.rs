@@ -0,0 +1,80 @@
+use super::endian::Endianness;
+use super::error::BleError;
+use std::collections::{HashSet, VecDeque};
+
+/// Size of the identifier starting every received message when deduplication is enabled.
+pub const MESSAGE_ID_SIZE: usize = 8;
+
+/// Cache of the identifiers of the last messages seen, to drop the duplicates retransmitted by an
+/// at-least-once sender. Only the `capacity` most recently seen identifiers are remembered, so
+/// the memory stays bounded: a duplicate arriving after more messages than that is delivered again.
+#[derive(Debug, Clone)]
+pub struct DedupCache {
+    capacity: usize,
+    /// Identifiers from the least to the most recently seen.
+    order: VecDeque<u64>,
+    seen: HashSet<u64>,
+}
+
+impl DedupCache {
+    /// Create a new cache remembering up to `capacity` identifiers, at least one.
+    pub fn new(capacity: usize) -> Self {
+        let capacity = capacity.max(1);
+        Self {
+            capacity,
+            order: VecDeque::with_capacity(capacity),
+            seen: HashSet::with_capacity(capacity),
+        }
+    }
+
+    /// Record an identifier as the most recently seen.
+    /// Return `false` if it was already in the cache, meaning the message is a duplicate.
+    pub fn insert(&mut self, id: u64) -> bool {
+        if self.seen.contains(&id) {
+            // Seeing it again makes it the most recently seen
+            if let Some(position) = self.order.iter().position(|seen| *seen == id) {
+                self.order.remove(position);
+            }
+            self.order.push_back(id);
+            return false;
+        }
+        if self.order.len() == self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.seen.remove(&oldest);
+            }
+        }
+        self.order.push_back(id);
+        self.seen.insert(id);
+        true
+    }
+
+    /// Check whether an identifier is in the cache.
+    pub fn contains(&self, id: u64) -> bool {
+        self.seen.contains(&id)
+    }
+
+    /// Return the number of identifiers in the cache.
+    pub fn len(&self) -> usize {
+        self.order.len()
+    }
+
+    /// Check whether the cache is empty.
+    pub fn is_empty(&self) -> bool {
+        self.order.is_empty()
+    }
+}
+
+/// Split the identifier starting the bytes, encoded in the given byte order, from the rest of
+/// the message.
+pub(crate) fn strip_id(
+    mut bytes: Vec<u8>,
+    endianness: Endianness,
+) -> Result<(u64, Vec<u8>), BleError> {
+    if bytes.len() < MESSAGE_ID_SIZE {
+        return Err(BleError::InvalidMessage(
+            "Message is shorter than its identifier".to_string(),
+        ));
+    }
+    let payload = bytes.split_off(MESSAGE_ID_SIZE);
+    Ok((endianness.read_u64(bytes.try_into().unwrap()), payload))
+}
@@ -0,0 +1,66 @@
+use super::error::BleError;
+use super::message::BleMessage;
+
+/// Append the delimiter to a text message, leaving raw messages untouched.
+pub(crate) fn delimit_text(message: BleMessage, delimiter: u8) -> BleMessage {
+    match message {
+        BleMessage::Text(s) => {
+            let mut bytes = s.into_bytes();
+            bytes.push(delimiter);
+            BleMessage::Raw(bytes)
+        }
+        raw => raw,
+    }
+}
+
+/// Splitter turning a delimited byte stream into text messages.
+/// Bytes after the last delimiter are kept until the rest of the message arrives.
+pub(crate) struct TextSplitter {
+    delimiter: u8,
+    pending: Vec<u8>,
+}
+
+impl TextSplitter {
+    /// Create a new splitter for the given delimiter.
+    pub fn new(delimiter: u8) -> Self {
+        Self {
+            delimiter,
+            pending: Vec::new(),
+        }
+    }
+
+    /// Discard the bytes received after the last delimiter.
+    pub fn reset(&mut self) {
+        self.pending.clear();
+    }
+
+    /// Return the number of bytes received after the last delimiter.
+    pub fn pending_len(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// Feed received bytes and return the text messages completed by them.
+    /// Invalid UTF-8 is replaced, unless `strict` is set, in which case it is rejected.
+    pub fn push(&mut self, bytes: &[u8], strict: bool) -> Result<Vec<BleMessage>, BleError> {
+        let mut messages = Vec::new();
+        for &byte in bytes {
+            if byte == self.delimiter {
+                let pending = std::mem::take(&mut self.pending);
+                let text = match String::from_utf8(pending) {
+                    Ok(text) => text,
+                    Err(err) if strict => {
+                        return Err(BleError::InvalidMessage(format!(
+                            "Text is not valid UTF-8: {}",
+                            err.utf8_error()
+                        )))
+                    }
+                    Err(err) => String::from_utf8_lossy(err.as_bytes()).to_string(),
+                };
+                messages.push(BleMessage::Text(text));
+            } else {
+                self.pending.push(byte);
+            }
+        }
+        Ok(messages)
+    }
+}
@@ -0,0 +1,77 @@
+/// Byte order used to encode numbers in messages.
+/// Defaults to big-endian (network order).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Endianness {
+    /// Most significant byte first.
+    #[default]
+    Big,
+    /// Least significant byte first.
+    Little,
+}
+
+impl Endianness {
+    /// Encode a u16 in this byte order
+    pub fn u16_bytes(self, value: u16) -> [u8; 2] {
+        match self {
+            Endianness::Big => value.to_be_bytes(),
+            Endianness::Little => value.to_le_bytes(),
+        }
+    }
+
+    /// Encode a u32 in this byte order
+    pub fn u32_bytes(self, value: u32) -> [u8; 4] {
+        match self {
+            Endianness::Big => value.to_be_bytes(),
+            Endianness::Little => value.to_le_bytes(),
+        }
+    }
+
+    /// Encode a u64 in this byte order
+    pub fn u64_bytes(self, value: u64) -> [u8; 8] {
+        match self {
+            Endianness::Big => value.to_be_bytes(),
+            Endianness::Little => value.to_le_bytes(),
+        }
+    }
+
+    /// Encode a f64 in this byte order
+    pub fn f64_bytes(self, value: f64) -> [u8; 8] {
+        match self {
+            Endianness::Big => value.to_be_bytes(),
+            Endianness::Little => value.to_le_bytes(),
+        }
+    }
+
+    /// Decode a u16 encoded in this byte order
+    pub fn read_u16(self, bytes: [u8; 2]) -> u16 {
+        match self {
+            Endianness::Big => u16::from_be_bytes(bytes),
+            Endianness::Little => u16::from_le_bytes(bytes),
+        }
+    }
+
+    /// Decode a u32 encoded in this byte order
+    pub fn read_u32(self, bytes: [u8; 4]) -> u32 {
+        match self {
+            Endianness::Big => u32::from_be_bytes(bytes),
+            Endianness::Little => u32::from_le_bytes(bytes),
+        }
+    }
+
+    /// Decode a u64 encoded in this byte order
+    pub fn read_u64(self, bytes: [u8; 8]) -> u64 {
+        match self {
+            Endianness::Big => u64::from_be_bytes(bytes),
+            Endianness::Little => u64::from_le_bytes(bytes),
+        }
+    }
+
+    /// Decode a f64 encoded in this byte order
+    pub fn read_f64(self, bytes: [u8; 8]) -> f64 {
+        match self {
+            Endianness::Big => f64::from_be_bytes(bytes),
+            Endianness::Little => f64::from_le_bytes(bytes),
+        }
+    }
+}
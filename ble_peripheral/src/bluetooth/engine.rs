@@ -0,0 +1,871 @@
+use super::admission::Admission;
+use super::backoff::{BackoffExhausted, WriteBackoff};
+use super::capture::{FrameCapture, FrameDirection, RawChunk};
+use super::coalesce::{split_batch, Batch, Coalescer};
+use super::config::PeripheralConfig;
+use super::connection::ConnectionData;
+use super::control::ControlMessage;
+use super::endian::Endianness;
+use super::envelope::MessageSource;
+use super::error::BleError;
+use super::event::{emit, BleEngineEvent, ConnectionInfo};
+use super::fallback::WriteFallback;
+use super::handshake::{Capabilities, PROTOCOL_VERSION};
+use super::message::BleMessage;
+use super::metrics::MetricsRecorder;
+use super::outgoing::{write_notification, write_split, HeldPermits, OutgoingMessage};
+use super::queue::ReceiveQueue;
+use super::receive::ReceivePipeline;
+use super::splitter::Splitter;
+use super::transfer::TransferState;
+use super::MessageHandler;
+use bluer::gatt::{
+    local::{CharacteristicControlEvent, CharacteristicWriteIoRequest, ReqError},
+    CharacteristicReader, CharacteristicWriter,
+};
+use bluer::Address;
+use futures::{future, pin_mut, Stream, StreamExt};
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    sync::{broadcast, mpsc, watch},
+    time::{Duration, Instant},
+};
+
+/// A request from the central device to start writing to the characteristic.
+pub(crate) trait WriteRequest: Send + 'static {
+    type Reader: AsyncRead + Unpin + Send + 'static;
+
+    /// Maximum transmission unit of the write session.
+    fn mtu(&self) -> usize;
+
+    /// Accept the request and return the reader receiving the written bytes.
+    fn accept(self) -> std::io::Result<Self::Reader>;
+
+    /// Reject the request, failing the write session on the central.
+    fn reject(self);
+
+    /// Address of the central of the write session, if known.
+    fn device_address(&self) -> Option<Address> {
+        None
+    }
+}
+
+/// A notification session opened by the central device subscribing to the characteristic.
+pub(crate) trait Notifier: AsyncWrite + Unpin + Send + 'static {
+    /// Maximum transmission unit of the notification session.
+    fn mtu(&self) -> usize;
+
+    /// Address of the central of the notification session, if known.
+    fn device_address(&self) -> Option<Address> {
+        None
+    }
+}
+
+/// An event on the characteristic served by the peripheral.
+pub(crate) enum LinkEvent<Q, N> {
+    Write(Q),
+    Notify(N),
+    /// The central subscribed to the priority characteristic.
+    PriorityNotify(N),
+}
+
+impl WriteRequest for CharacteristicWriteIoRequest {
+    type Reader = CharacteristicReader;
+
+    fn mtu(&self) -> usize {
+        CharacteristicWriteIoRequest::mtu(self)
+    }
+
+    fn accept(self) -> std::io::Result<Self::Reader> {
+        CharacteristicWriteIoRequest::accept(self).map_err(std::io::Error::other)
+    }
+
+    fn reject(self) {
+        CharacteristicWriteIoRequest::reject(self, ReqError::Failed)
+    }
+
+    fn device_address(&self) -> Option<Address> {
+        Some(CharacteristicWriteIoRequest::device_address(self))
+    }
+}
+
+impl Notifier for CharacteristicWriter {
+    fn mtu(&self) -> usize {
+        CharacteristicWriter::mtu(self)
+    }
+
+    fn device_address(&self) -> Option<Address> {
+        Some(CharacteristicWriter::device_address(self))
+    }
+}
+
+impl From<CharacteristicControlEvent>
+    for LinkEvent<CharacteristicWriteIoRequest, CharacteristicWriter>
+{
+    /// Automatically convert a bluer characteristic event to a LinkEvent
+    fn from(evt: CharacteristicControlEvent) -> Self {
+        match evt {
+            CharacteristicControlEvent::Write(req) => LinkEvent::Write(req),
+            CharacteristicControlEvent::Notify(notifier) => LinkEvent::Notify(notifier),
+        }
+    }
+}
+
+/// Channels connecting the BLE thread to its BlePeripheral.
+pub(crate) struct EngineChannels {
+    pub send_rx: mpsc::UnboundedReceiver<OutgoingMessage>,
+    pub subscribed_tx: watch::Sender<bool>,
+    pub last_notified_tx: watch::Sender<Option<Vec<u8>>>,
+    pub mtu_tx: watch::Sender<Option<usize>>,
+    pub capabilities_tx: watch::Sender<Option<Capabilities>>,
+    pub peer_version_tx: watch::Sender<Option<u8>>,
+    pub events: broadcast::Sender<BleEngineEvent>,
+    pub connection_data: Arc<ConnectionData>,
+    pub frame_capture: Option<Arc<FrameCapture>>,
+    pub write_fallback: Option<Arc<WriteFallback>>,
+    pub metrics: Arc<MetricsRecorder>,
+    pub initial_messages: Arc<Mutex<Vec<BleMessage>>>,
+    pub queued_before_start: Vec<OutgoingMessage>,
+    pub connection_tx: watch::Sender<Option<ConnectionInfo>>,
+}
+
+/// Channels connecting the receive task of the BLE thread to its BlePeripheral.
+pub(crate) struct ReceiveChannels {
+    pub write_rx: mpsc::UnboundedReceiver<Vec<u8>>,
+    pub receive_queue: Arc<ReceiveQueue>,
+    pub message_handler: Arc<Mutex<Option<MessageHandler>>>,
+    pub control: broadcast::Sender<ControlMessage>,
+    pub raw_chunks: broadcast::Sender<RawChunk>,
+    pub file_transfer: Arc<TransferState>,
+}
+
+/// State of the BLE thread, handling the characteristic events and the queued messages.
+/// The GATT events and the received bytes are handled by a separate receive task, so a slow
+/// notification never stalls the receives.
+pub(crate) struct Engine<Q: WriteRequest, N: Notifier> {
+    channels: EngineChannels,
+    coalescer: Option<Coalescer<HeldPermits>>,
+    handshake_features: Option<u32>,
+    endianness: Endianness,
+    flush_after_each: bool,
+    splitter: Option<Arc<dyn Splitter>>,
+    write_backoff: Option<WriteBackoff>,
+    indication_timeout: Option<Duration>,
+    receive_task: Option<ReceiveTask<Q, N>>,
+    sessions_rx: mpsc::UnboundedReceiver<LinkEvent<Q, N>>,
+    notifier_opt: Option<N>,
+    priority_notifier_opt: Option<N>,
+}
+
+impl<Q: WriteRequest, N: Notifier> Engine<Q, N> {
+    /// Create a new engine communicating through the given channels, configured by the peripheral.
+    pub fn new(
+        channels: EngineChannels,
+        receive_channels: ReceiveChannels,
+        config: &PeripheralConfig,
+    ) -> Self {
+        let (sessions_tx, sessions_rx) = mpsc::unbounded_channel();
+        let receive_task = ReceiveTask {
+            events: channels.events.clone(),
+            frame_capture: channels.frame_capture.clone(),
+            metrics: channels.metrics.clone(),
+            write_fallback: channels.write_fallback.clone(),
+            mtu_tx: channels.mtu_tx.clone(),
+            capabilities_tx: channels.capabilities_tx.clone(),
+            peer_version_tx: channels.peer_version_tx.clone(),
+            channels: receive_channels,
+            sessions_tx,
+            subscribed_rx: channels.subscribed_tx.subscribe(),
+            receive_pipeline: ReceivePipeline::new(config),
+            max_subscribers: config.max_subscribers,
+            admission: Admission::new(config, channels.events.clone()),
+            subscribed: false,
+            handshake_features: config.handshake_features,
+            endianness: config.endianness,
+            receive_buffer: Vec::new(),
+            receivers: VecDeque::new(),
+            session_read: false,
+        };
+        Self {
+            channels,
+            coalescer: config.coalesce.map(|(max_bytes, max_delay)| {
+                Coalescer::new(max_bytes, max_delay).with_endianness(config.endianness)
+            }),
+            handshake_features: config.handshake_features,
+            endianness: config.endianness,
+            flush_after_each: config.flush_after_each,
+            splitter: config
+                .framing
+                .as_ref()
+                .map(|framing| framing.splitter.clone()),
+            write_backoff: config.write_backoff,
+            indication_timeout: config.indication_timeout,
+            receive_task: Some(receive_task),
+            sessions_rx,
+            notifier_opt: None,
+            priority_notifier_opt: None,
+        }
+    }
+
+    /// Run the engine until the send channel is closed.
+    /// The messages still queued when the channel closes are flushed before the notifier is shut down.
+    pub async fn run<E>(mut self, events: E)
+    where
+        E: Stream<Item = LinkEvent<Q, N>> + Send + 'static,
+    {
+        // Handle the GATT events and the received bytes in their own task
+        let receive_handle = self
+            .receive_task
+            .take()
+            .map(|task| tokio::spawn(task.run(events)));
+
+        loop {
+            let flush_at = self.coalescer.as_ref().and_then(Coalescer::deadline);
+
+            // Handle GATT, notify, and receive events concurrently
+            tokio::select! {
+                // Handle the notification sessions opened by the central
+                Some(evt) = self.sessions_rx.recv() => {
+                    match evt {
+                        // Write requests are handled by the receive task
+                        LinkEvent::Write(_) => {},
+                        // Handle the notify event
+                        LinkEvent::Notify(notifier) => {
+                            log::debug!("Accepting notify request event with MTU {}", notifier.mtu());
+                            // A new notification session starts a new connection
+                            self.channels.connection_data.clear();
+                            update_mtu(&self.channels.mtu_tx, &self.channels.events, notifier.mtu());
+                            self.channels.capabilities_tx.send_replace(None);
+                            self.channels.peer_version_tx.send_replace(None);
+                            self.channels.connection_tx.send_replace(Some(ConnectionInfo {
+                                mtu: notifier.mtu(),
+                                address: notifier.device_address(),
+                                connected_at: SystemTime::now(),
+                            }));
+                            self.notifier_opt = Some(notifier);
+                            self.channels.subscribed_tx.send_replace(true);
+                            self.send_hello().await;
+                            self.send_initial_messages().await;
+                            self.send_queued_before_start().await;
+                        },
+                        // Handle the notify event of the priority characteristic
+                        LinkEvent::PriorityNotify(notifier) => {
+                            log::debug!("Accepting priority notify request event with MTU {}", notifier.mtu());
+                            self.priority_notifier_opt = Some(notifier);
+                        },
+                    }
+                },
+
+                // Handle the notification event
+                notify_message = self.channels.send_rx.recv() => {
+                    let mut notify_message = match notify_message {
+                        Some(notify_message) => notify_message,
+                        // The peripheral is stopping and every queued message has been handled
+                        None => break,
+                    };
+                    if let Some(flushed) = notify_message.flushed {
+                        self.flush().await;
+                        // The flush may no longer be awaited
+                        let _ = flushed.send(());
+                        continue;
+                    }
+                    self.channels.metrics.record_message(self.channels.send_rx.len());
+                    if notify_message.priority {
+                        self.notify_priority(notify_message).await;
+                        continue;
+                    }
+                    match self.coalescer.as_mut() {
+                        Some(coalescer) if !notify_message.unframed && notify_message.report.is_none() => {
+                            if notify_message.is_expired() {
+                                log::debug!("Dropping expired message {:x?}", notify_message.message);
+                                continue;
+                            }
+                            // The permits are released once the batch holding the message is written
+                            let permits = notify_message.take_permits();
+                            let batches = coalescer.push(notify_message.message.as_bytes(), permits);
+                            for batch in batches {
+                                self.notify_batch(batch).await;
+                            }
+                        }
+                        _ => {
+                            // Unframed and reported messages must not overtake the coalesced ones
+                            if let Some(batch) = self.coalescer.as_mut().and_then(Coalescer::take) {
+                                self.notify_batch(batch).await;
+                            }
+                            self.notify(notify_message).await;
+                        }
+                    }
+                },
+
+                // Flush the coalesced messages once they waited long enough
+                _ = tokio::time::sleep_until(flush_at.unwrap_or_else(Instant::now)), if flush_at.is_some() => {
+                    if let Some(batch) = self.coalescer.as_mut().and_then(Coalescer::take) {
+                        self.notify_batch(batch).await;
+                    }
+                },
+            }
+        }
+
+        // Flush the messages still being coalesced
+        if let Some(batch) = self.coalescer.as_mut().and_then(Coalescer::take) {
+            self.notify_batch(batch).await;
+        }
+
+        // Close the notification sessions so the central learns the peripheral is going away
+        for mut notifier in [self.notifier_opt.take(), self.priority_notifier_opt.take()]
+            .into_iter()
+            .flatten()
+        {
+            if let Err(err) = notifier.shutdown().await {
+                log::error!("Notifier shutdown failed: {}", &err);
+            }
+        }
+
+        // Closing the sessions channel stops the receive task
+        drop(self.sessions_rx);
+        if let Some(receive_handle) = receive_handle {
+            if let Err(err) = receive_handle.await {
+                log::error!("Receive task failed: {}", &err);
+            }
+        }
+    }
+
+    /// Notify a message, dropping it if nobody is subscribed.
+    async fn notify(&mut self, outgoing: OutgoingMessage) {
+        let notifier = match self.notifier_opt.as_mut() {
+            Some(notifier) => notifier,
+            None => return,
+        };
+        let mtu = notification_mtu(&self.channels.mtu_tx, notifier);
+        let splitter = self.splitter.as_deref();
+        let backoff = self.write_backoff.as_ref();
+        match write_notification(
+            notifier,
+            outgoing,
+            mtu,
+            splitter,
+            backoff,
+            self.indication_timeout,
+            self.flush_after_each,
+        )
+        .await
+        {
+            Ok(Some(message_bytes)) => self.notified(message_bytes),
+            Ok(None) => {}
+            Err(err) if self.drops_on_full_buffer(&err) => {
+                log::warn!("Central receive buffer stayed full, dropped message");
+            }
+            Err(err) => {
+                log::error!("Write failed: {}", &err);
+                self.end_subscription();
+            }
+        }
+    }
+
+    /// Send the initial messages to the central that just subscribed, ahead of the queued ones.
+    async fn send_initial_messages(&mut self) {
+        let initial_messages = self.channels.initial_messages.lock().unwrap().clone();
+        for message in initial_messages {
+            if self.notifier_opt.is_none() {
+                break;
+            }
+            self.notify(OutgoingMessage::new(message)).await;
+        }
+    }
+
+    /// Send the messages queued before the engine started to the first central subscribing.
+    /// The messages are dropped if the central unsubscribes before they are all written.
+    async fn send_queued_before_start(&mut self) {
+        for outgoing in std::mem::take(&mut self.channels.queued_before_start) {
+            if self.notifier_opt.is_none() {
+                break;
+            }
+            if outgoing.priority {
+                self.notify_priority(outgoing).await;
+            } else {
+                self.notify(outgoing).await;
+            }
+        }
+    }
+
+    /// Notify a batch of coalesced messages, dropping it if nobody is subscribed.
+    async fn notify_batch(&mut self, batch: Batch<HeldPermits>) {
+        // The permits of the batched messages are released once this returns
+        let Batch {
+            bytes: batch,
+            held: _held,
+        } = batch;
+        let notifier = match self.notifier_opt.as_mut() {
+            Some(notifier) => notifier,
+            None => return,
+        };
+        log::debug!("Notifying coalesced messages {:x?}", batch);
+        let mtu = notification_mtu(&self.channels.mtu_tx, notifier);
+        let splitter = self.splitter.as_deref();
+        // A batch larger than the MTU is notified in parts that each hold whole frames
+        let parts = split_batch(&batch, mtu, self.endianness);
+        let mut written = Ok(());
+        for part in &parts {
+            written = write_split(
+                notifier,
+                part,
+                mtu,
+                splitter,
+                self.write_backoff.as_ref(),
+                self.indication_timeout,
+            )
+            .await
+            .map(|_| ());
+            if written.is_err() {
+                break;
+            }
+        }
+        if self.flush_after_each && written.is_ok() {
+            written = notifier.flush().await;
+        }
+        match written {
+            Ok(()) => {
+                for part in parts {
+                    self.notified(part.to_vec());
+                }
+            }
+            Err(err) if self.drops_on_full_buffer(&err) => {
+                log::warn!("Central receive buffer stayed full, dropped coalesced messages");
+            }
+            Err(err) => {
+                log::error!("Write failed: {}", &err);
+                self.end_subscription();
+            }
+        }
+    }
+
+    /// Notify the messages being coalesced and flush the notifiers, so nothing stays buffered.
+    async fn flush(&mut self) {
+        if let Some(batch) = self.coalescer.as_mut().and_then(Coalescer::take) {
+            self.notify_batch(batch).await;
+        }
+        if let Some(notifier) = self.notifier_opt.as_mut() {
+            if let Err(err) = notifier.flush().await {
+                log::error!("Flush failed: {}", &err);
+                self.end_subscription();
+            }
+        }
+        if let Some(notifier) = self.priority_notifier_opt.as_mut() {
+            if let Err(err) = notifier.flush().await {
+                log::error!("Priority flush failed: {}", &err);
+                self.priority_notifier_opt = None;
+            }
+        }
+    }
+
+    /// Notify a message on the priority characteristic, dropping it if nobody is subscribed to it.
+    async fn notify_priority(&mut self, outgoing: OutgoingMessage) {
+        let notifier = match self.priority_notifier_opt.as_mut() {
+            Some(notifier) => notifier,
+            None => return,
+        };
+        let mtu = notification_mtu(&self.channels.mtu_tx, notifier);
+        let splitter = self.splitter.as_deref();
+        let backoff = self.write_backoff.as_ref();
+        match write_notification(
+            notifier,
+            outgoing,
+            mtu,
+            splitter,
+            backoff,
+            self.indication_timeout,
+            self.flush_after_each,
+        )
+        .await
+        {
+            Ok(Some(message_bytes)) => self.notified(message_bytes),
+            Ok(None) => {}
+            Err(err) if self.drops_on_full_buffer(&err) => {
+                log::warn!("Central receive buffer stayed full, dropped priority message");
+            }
+            Err(err) => {
+                log::error!("Priority write failed: {}", &err);
+                self.priority_notifier_opt = None;
+            }
+        }
+    }
+
+    /// Record the bytes of a notification that was written to the central.
+    fn notified(&mut self, bytes: Vec<u8>) {
+        self.channels.metrics.record_notification(bytes.len());
+        if let Some(capture) = self.channels.frame_capture.as_ref() {
+            capture.record(FrameDirection::Sent, &bytes);
+        }
+        self.channels.last_notified_tx.send_replace(Some(bytes));
+    }
+
+    /// Announce the capabilities of the peripheral to the central, if the handshake is enabled.
+    async fn send_hello(&mut self) {
+        let (features, notifier) = match (self.handshake_features, self.notifier_opt.as_mut()) {
+            (Some(features), Some(notifier)) => (features, notifier),
+            _ => return,
+        };
+        let hello = ControlMessage::Hello(Capabilities {
+            version: PROTOCOL_VERSION,
+            mtu: notifier.mtu().min(u16::MAX as usize) as u16,
+            features,
+        });
+        let mtu = notification_mtu(&self.channels.mtu_tx, notifier);
+        if let Err(err) = write_split(
+            notifier,
+            &hello.to_bytes(self.endianness),
+            mtu,
+            None,
+            self.write_backoff.as_ref(),
+            self.indication_timeout,
+        )
+        .await
+        {
+            log::error!("Handshake failed: {}", &err);
+            self.end_subscription();
+        }
+    }
+
+    /// Check whether a failed write only drops its message, because the central's receive buffer
+    /// stayed full through every retry and the backoff keeps the central subscribed.
+    fn drops_on_full_buffer(&self, err: &std::io::Error) -> bool {
+        err.kind() == std::io::ErrorKind::WouldBlock
+            && self
+                .write_backoff
+                .is_some_and(|backoff| backoff.on_exhausted == BackoffExhausted::DropMessage)
+    }
+
+    /// Forget the notification session after the central device disconnected.
+    fn end_subscription(&mut self) {
+        self.notifier_opt = None;
+        self.channels.connection_data.clear();
+        self.channels.mtu_tx.send_replace(None);
+        self.channels.capabilities_tx.send_replace(None);
+        self.channels.peer_version_tx.send_replace(None);
+        self.channels.connection_tx.send_replace(None);
+        self.channels.subscribed_tx.send_replace(false);
+    }
+}
+
+/// Receiving half of the BLE thread, handling the GATT events and delivering the bytes written
+/// by the central device.
+struct ReceiveTask<Q: WriteRequest, N> {
+    channels: ReceiveChannels,
+    events: broadcast::Sender<BleEngineEvent>,
+    frame_capture: Option<Arc<FrameCapture>>,
+    metrics: Arc<MetricsRecorder>,
+    write_fallback: Option<Arc<WriteFallback>>,
+    mtu_tx: watch::Sender<Option<usize>>,
+    capabilities_tx: watch::Sender<Option<Capabilities>>,
+    peer_version_tx: watch::Sender<Option<u8>>,
+    sessions_tx: mpsc::UnboundedSender<LinkEvent<Q, N>>,
+    subscribed_rx: watch::Receiver<bool>,
+    receive_pipeline: ReceivePipeline,
+    max_subscribers: Option<usize>,
+    admission: Admission,
+    subscribed: bool,
+    handshake_features: Option<u32>,
+    endianness: Endianness,
+    receive_buffer: Vec<u8>,
+    /// Readers of the accepted write sessions, read one after the other in the accepted order.
+    receivers: VecDeque<Q::Reader>,
+    /// Whether bytes were read in the current write session.
+    session_read: bool,
+}
+
+impl<Q: WriteRequest, N: Notifier> ReceiveTask<Q, N> {
+    /// Run the receive task until the engine stops.
+    /// The notification sessions are handed to the engine, the rest is handled here.
+    async fn run<E>(mut self, events: E)
+    where
+        E: Stream<Item = LinkEvent<Q, N>>,
+    {
+        pin_mut!(events);
+
+        loop {
+            tokio::select! {
+                // Handle the GATT events
+                evt = events.next() => {
+                    match evt {
+                        // Handle the write event
+                        Some(LinkEvent::Write(req)) => self.accept_write(req),
+                        // Dropping the notifier of a declined session closes it
+                        Some(LinkEvent::Notify(notifier) | LinkEvent::PriorityNotify(notifier))
+                            if !self.admission.is_allowed(notifier.device_address()) =>
+                        {
+                            self.admission.reject_central(notifier.device_address());
+                        }
+                        Some(LinkEvent::Notify(notifier) | LinkEvent::PriorityNotify(notifier))
+                            if self.admission.below_min_mtu(notifier.mtu()) =>
+                        {
+                            self.admission.reject_mtu(notifier.mtu());
+                        }
+                        Some(LinkEvent::Notify(_)) if self.at_capacity() => self.reject_subscriber(),
+                        // Sending only fails when the engine is stopping
+                        Some(session) => {
+                            // A new notification session starts a new connection
+                            if let LinkEvent::Notify(_) = session {
+                                self.reset_connection();
+                                self.subscribed = true;
+                            }
+                            let _ = self.sessions_tx.send(session);
+                        }
+                        None => {}
+                    }
+                },
+
+                // The engine detected that the central went away
+                Ok(()) = self.subscribed_rx.changed() => {
+                    if !*self.subscribed_rx.borrow_and_update() {
+                        self.reset_connection();
+                        self.subscribed = false;
+                    }
+                },
+
+                // The engine is stopping
+                _ = self.sessions_tx.closed() => break,
+
+                // Handle the writes received through the write function
+                Some(received_message) = self.channels.write_rx.recv() => {
+                    self.deliver_received(received_message);
+                },
+
+                // Handle the receive event
+                received_length = read_next(self.receivers.front_mut(), &mut self.receive_buffer) => {
+                    match received_length {
+                        // Message received, more may follow in the same session
+                        Ok(n) if n > 0 => {
+                            // Read the message
+                            let received_message = self.receive_buffer[..n].to_vec();
+                            self.session_read = true;
+                            self.deliver_received(received_message);
+                        }
+
+                        // The session ended, as an empty write if nothing was read in it
+                        Ok(_) => {
+                            if !self.session_read {
+                                self.deliver_received(Vec::new());
+                            }
+                            if let Some(fallback) = self.write_fallback.as_ref() {
+                                fallback.record_success();
+                            }
+                            self.next_session();
+                        }
+
+                        Err(err) => {
+                            log::error!("Read stream error: {}", &err);
+                            self.record_write_failure();
+                            self.next_session();
+                        }
+                    }
+                }
+            }
+        }
+
+        // Nothing is received anymore, so the waiting receivers are released
+        self.channels.receive_queue.close();
+    }
+
+    /// Forget the write in progress and the partially received messages of the previous connection.
+    fn reset_connection(&mut self) {
+        log::debug!("Resetting the receive state of the previous connection");
+        self.receivers.clear();
+        self.session_read = false;
+        if let Err(err) = self.receive_pipeline.reset() {
+            self.protocol_violation(err);
+        }
+    }
+
+    /// Move on to the next accepted write session once the current one ended.
+    fn next_session(&mut self) {
+        self.receivers.pop_front();
+        self.session_read = false;
+    }
+
+    /// Check whether `max_subscribers` centrals are subscribed. The engine notifies a single
+    /// central at a time, so at most one is counted.
+    fn at_capacity(&self) -> bool {
+        self.max_subscribers
+            .is_some_and(|max| usize::from(self.subscribed) >= max)
+    }
+
+    /// Report a notification session declined because the subscribers are at capacity.
+    fn reject_subscriber(&self) {
+        let max_subscribers = self.max_subscribers.unwrap_or_default();
+        log::warn!(
+            "Declining notify session, at most {} subscribers",
+            max_subscribers
+        );
+        self.emit(BleEngineEvent::SubscriberRejected { max_subscribers });
+    }
+
+    /// Report a read breaking the protocol, found by strict validation.
+    fn protocol_violation(&self, err: BleError) {
+        log::error!("Protocol violation: {}", &err);
+        self.emit(BleEngineEvent::ProtocolViolation {
+            reason: err.to_string(),
+        });
+    }
+
+    /// Accept a write request from the central, reading the written bytes from its reader.
+    fn accept_write(&mut self, req: Q) {
+        let address = req.device_address();
+        if !self.admission.is_allowed(address) {
+            req.reject();
+            self.admission.reject_central(address);
+            return;
+        }
+        let mtu = req.mtu();
+        if self.admission.below_min_mtu(mtu) {
+            req.reject();
+            self.admission.reject_mtu(mtu);
+            return;
+        }
+        log::debug!("Accepting write request event with MTU {}", req.mtu());
+        update_mtu(&self.mtu_tx, &self.events, req.mtu());
+        // The buffer fits the largest packet of the queued sessions
+        if self.receive_buffer.len() < mtu {
+            self.receive_buffer = vec![0; mtu];
+        }
+        match req.accept() {
+            Ok(receiver) => {
+                // A session opened before the previous one was read to its end is read after it
+                self.receivers.push_back(receiver);
+            }
+            Err(err) => {
+                log::error!("Write request accept failed: {}", &err);
+                self.emit(BleEngineEvent::WriteAcceptFailed {
+                    error: err.to_string(),
+                });
+                self.record_write_failure();
+            }
+        }
+    }
+
+    /// Count a failed write towards the fallback to acknowledged writes.
+    fn record_write_failure(&self) {
+        let failures = self
+            .write_fallback
+            .as_ref()
+            .and_then(|fallback| fallback.record_failure());
+        if let Some(failures) = failures {
+            log::warn!(
+                "{} consecutive write failures, falling back to acknowledged writes",
+                failures
+            );
+            self.emit(BleEngineEvent::WriteFallback { failures });
+        }
+    }
+
+    /// Agree on the capabilities announced by the central with the ones of the peripheral.
+    fn negotiate(&self, central: Capabilities) {
+        let features = match self.handshake_features {
+            Some(features) => features,
+            None => return,
+        };
+        let mtu = self.mtu_tx.borrow().unwrap_or_default();
+        let local = Capabilities {
+            version: PROTOCOL_VERSION,
+            mtu: mtu.min(u16::MAX as usize) as u16,
+            features,
+        };
+        let negotiated = local.negotiate(&central);
+        log::debug!("Negotiated capabilities {:?}", negotiated);
+        self.capabilities_tx.send_replace(Some(negotiated));
+    }
+
+    /// Run the received bytes through the receive pipeline and queue the resulting messages.
+    /// Control messages are handed to the control subscribers instead.
+    fn deliver_received(&mut self, received_message: Vec<u8>) {
+        log::debug!("Received message: {:?}", received_message);
+        if let Some(capture) = self.frame_capture.as_ref() {
+            capture.record(FrameDirection::Received, &received_message);
+        }
+        if self.channels.raw_chunks.receiver_count() > 0 {
+            emit(&self.channels.raw_chunks, RawChunk::new(&received_message));
+        }
+        self.metrics.record_received(received_message.len());
+        if let Some(control) = ControlMessage::from_bytes(&received_message, self.endianness) {
+            match control {
+                ControlMessage::TransferAck { offset } => {
+                    self.channels.file_transfer.acknowledge(offset)
+                }
+                ControlMessage::Hello(capabilities) => self.negotiate(capabilities),
+                _ => {}
+            }
+            emit(&self.channels.control, control);
+            return;
+        }
+        let envelopes = match self.receive_pipeline.process(received_message) {
+            Ok(envelopes) => envelopes,
+            Err(err) => {
+                self.protocol_violation(err);
+                // The partially received messages can no longer be trusted
+                let _ = self.receive_pipeline.reset();
+                return;
+            }
+        };
+        for envelope in envelopes {
+            if let Some(version) = envelope.meta.version {
+                self.peer_version_tx.send_replace(Some(version));
+            }
+            // Hand the message to the handler if one is set, otherwise queue it.
+            // The handler is called unlocked, so it may replace itself.
+            let handler = self.channels.message_handler.lock().unwrap().clone();
+            let envelope = match handler {
+                Some(handler) => {
+                    handler(envelope.message);
+                    continue;
+                }
+                None => envelope.received_from(MessageSource::Central),
+            };
+            if let Some(dropped) = self.channels.receive_queue.push_envelope(envelope) {
+                log::warn!("Receive queue full, dropped message {:?}", dropped.message);
+                let capacity = self.channels.receive_queue.capacity().unwrap_or_default();
+                self.emit(BleEngineEvent::ReceiveOverflow { capacity });
+            }
+        }
+    }
+
+    /// Report an event to the event subscribers, if any.
+    fn emit(&self, event: BleEngineEvent) {
+        emit(&self.events, event);
+    }
+}
+
+/// Store the MTU of a new session, reporting a change from the MTU of the previous one.
+fn update_mtu(
+    mtu_tx: &watch::Sender<Option<usize>>,
+    events: &broadcast::Sender<BleEngineEvent>,
+    mtu: usize,
+) {
+    match mtu_tx.send_replace(Some(mtu)) {
+        Some(old) if old != mtu => {
+            log::debug!("MTU changed from {} to {}", old, mtu);
+            emit(events, BleEngineEvent::MtuChanged { old, new: mtu });
+        }
+        _ => {}
+    }
+}
+
+/// Return the size of the notifications: the MTU of the latest session, which can shrink
+/// mid-connection, capped by the MTU of the notifier.
+fn notification_mtu<N: Notifier>(mtu_tx: &watch::Sender<Option<usize>>, notifier: &N) -> usize {
+    mtu_tx
+        .borrow()
+        .map_or(notifier.mtu(), |mtu| mtu.min(notifier.mtu()))
+}
+
+/// Read the next write from the current reader, or wait forever if there is none.
+async fn read_next<R>(receiver_opt: Option<&mut R>, buffer: &mut [u8]) -> std::io::Result<usize>
+where
+    R: AsyncRead + Unpin,
+{
+    match receiver_opt {
+        Some(receiver) => receiver.read(buffer).await,
+        None => future::pending().await,
+    }
+}
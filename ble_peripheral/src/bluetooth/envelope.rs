@@ -0,0 +1,86 @@
+use super::message::BleMessage;
+use std::time::SystemTime;
+use tokio::time::Instant;
+
+/// Origin of a message in the receive queue.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MessageSource {
+    /// Written by the central device.
+    Central,
+    /// Queued locally with `BlePeripheral::loopback`.
+    Loopback,
+}
+
+/// Metadata carried alongside a message, which is never written to the central.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Metadata {
+    /// Identifier chosen by the application, such as a correlation ID.
+    pub id: Option<u64>,
+    /// Priority chosen by the application.
+    pub priority: Option<u8>,
+    /// Type tag of the TLV record the message was received in.
+    pub tag: Option<u8>,
+    /// Protocol version the message was received with.
+    pub version: Option<u8>,
+    /// Time at which the message was queued for receiving.
+    pub timestamp: Option<SystemTime>,
+    /// Origin of the message, set when it is queued for receiving.
+    pub source: Option<MessageSource>,
+    /// Instant at which the BLE thread queued the message, set when it is queued for receiving.
+    pub received_at: Option<Instant>,
+}
+
+/// Message along with its metadata.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BleEnvelope {
+    pub message: BleMessage,
+    pub meta: Metadata,
+}
+
+impl BleEnvelope {
+    /// Create a new envelope holding a message without metadata.
+    pub fn new(message: impl Into<BleMessage>) -> Self {
+        Self {
+            message: message.into(),
+            meta: Metadata::default(),
+        }
+    }
+
+    /// Set the identifier of the message.
+    pub fn id(mut self, id: u64) -> Self {
+        self.meta.id = Some(id);
+        self
+    }
+
+    /// Set the priority of the message.
+    pub fn priority(mut self, priority: u8) -> Self {
+        self.meta.priority = Some(priority);
+        self
+    }
+
+    /// Stamp the envelope as queued now from `source`, keeping the metadata already set.
+    pub(crate) fn received_from(mut self, source: MessageSource) -> Self {
+        self.meta.timestamp.get_or_insert_with(SystemTime::now);
+        self.meta.source = Some(source);
+        self.meta.received_at = Some(Instant::now());
+        self
+    }
+}
+
+/// Message along with the instant it was received by the BLE thread, to measure how long it
+/// waited before being consumed.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReceivedMessage {
+    pub message: BleMessage,
+    pub received_at: Instant,
+}
+
+impl From<BleEnvelope> for ReceivedMessage {
+    fn from(envelope: BleEnvelope) -> Self {
+        Self {
+            message: envelope.message,
+            // Only envelopes queued outside the BLE thread lack the instant
+            received_at: envelope.meta.received_at.unwrap_or_else(Instant::now),
+        }
+    }
+}
@@ -0,0 +1,110 @@
+use bluer::Address;
+use std::error::Error;
+use std::fmt;
+
+/// Errors returned by the BLE peripheral.
+#[derive(Debug)]
+pub enum BleError {
+    /// An error reported by the Bluetooth stack.
+    Bluetooth(bluer::Error),
+    /// A message could not be decoded.
+    InvalidMessage(String),
+    /// The engine must be started before this operation.
+    EngineNotStarted,
+    /// Writes are not received with their offset, as the `offset_writes` option is not enabled.
+    OffsetWritesNotEnabled,
+    /// The engine channel was closed, usually because the engine stopped.
+    ChannelClosed,
+    /// No central device is connected.
+    NotConnected,
+    /// The central did not answer in time.
+    Timeout,
+    /// There is no file transfer to resume.
+    NoFileTransfer,
+    /// The alias cannot be used as the device name.
+    InvalidAlias(String),
+    /// The advertisement does not fit in the advertising data.
+    AdvertisementTooLarge { bytes: usize, limit: usize },
+    /// A message was rejected because its counter was not newer than the last one accepted.
+    ReplayDetected { counter: u64, last_seen: u64 },
+    /// The message was dropped before it was written to the central.
+    NotDelivered,
+    /// The adapter does not support the requested feature.
+    Unsupported(String),
+    /// The engine did not start in time, usually because the Bluetooth stack is not responding.
+    StartupTimeout,
+    /// The operation is only available in beacon mode.
+    NotBeacon,
+    /// A configuration could not be loaded or saved, or holds an invalid setting.
+    InvalidConfig(String),
+    /// A received message carries a protocol version that is not supported.
+    UnsupportedVersion(u8),
+    /// The engine is already started, and must be stopped before starting it again.
+    AlreadyRunning,
+    /// No Bluetooth adapter has the requested address.
+    AdapterNotFound {
+        address: Address,
+        available: Vec<Address>,
+    },
+}
+
+impl fmt::Display for BleError {
+    /// Display the error as a string
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BleError::Bluetooth(err) => write!(f, "Bluetooth error: {}", err),
+            BleError::InvalidMessage(reason) => write!(f, "Invalid message: {}", reason),
+            BleError::EngineNotStarted => write!(f, "Engine not started"),
+            BleError::OffsetWritesNotEnabled => write!(f, "Offset writes not enabled"),
+            BleError::ChannelClosed => write!(f, "Engine channel closed"),
+            BleError::NotConnected => write!(f, "No central device connected"),
+            BleError::Timeout => write!(f, "Timed out waiting for the central"),
+            BleError::NoFileTransfer => write!(f, "No file transfer to resume"),
+            BleError::InvalidAlias(reason) => write!(f, "Invalid alias: {}", reason),
+            BleError::AdvertisementTooLarge { bytes, limit } => write!(
+                f,
+                "Advertisement too large: {} bytes exceed the {} bytes limit",
+                bytes, limit
+            ),
+            BleError::ReplayDetected { counter, last_seen } => write!(
+                f,
+                "Replay detected: counter {} is not newer than {}",
+                counter, last_seen
+            ),
+            BleError::NotDelivered => write!(f, "Message not delivered to the central"),
+            BleError::Unsupported(feature) => write!(f, "Unsupported by the adapter: {}", feature),
+            BleError::StartupTimeout => write!(f, "Timed out starting the engine"),
+            BleError::NotBeacon => write!(f, "Beacon mode is not enabled"),
+            BleError::InvalidConfig(reason) => write!(f, "Invalid configuration: {}", reason),
+            BleError::UnsupportedVersion(version) => {
+                write!(f, "Unsupported protocol version: {}", version)
+            }
+            BleError::AlreadyRunning => write!(f, "Engine already running"),
+            BleError::AdapterNotFound { address, available } => {
+                let available: Vec<String> = available.iter().map(Address::to_string).collect();
+                write!(
+                    f,
+                    "No adapter with address {}, available: [{}]",
+                    address,
+                    available.join(", ")
+                )
+            }
+        }
+    }
+}
+
+impl Error for BleError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            BleError::Bluetooth(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<bluer::Error> for BleError {
+    /// Automatically convert a bluer error to a BleError
+    fn from(err: bluer::Error) -> Self {
+        Self::Bluetooth(err)
+    }
+}
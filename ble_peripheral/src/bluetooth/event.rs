@@ -0,0 +1,54 @@
+use bluer::Address;
+use std::time::SystemTime;
+use tokio::sync::broadcast;
+
+/// Events reported by the BLE engine, for observing its behavior at runtime.
+#[derive(Debug, Clone, PartialEq)]
+pub enum BleEngineEvent {
+    /// A received message was dropped because the receive queue was full.
+    ReceiveOverflow { capacity: usize },
+    /// A write request from the central could not be accepted, so its bytes were not received.
+    WriteAcceptFailed { error: String },
+    /// Writes failed repeatedly, so acknowledged writes are used from the next engine start.
+    WriteFallback { failures: u32 },
+    /// The advertisement was removed by the system and has been registered again.
+    AdvertisingRestarted,
+    /// A new session was opened with a different MTU, which is now used to split the notifications.
+    MtuChanged { old: usize, new: usize },
+    /// Received bytes breaking the framing, found by strict validation, or carrying an
+    /// unsupported protocol version were rejected and not delivered.
+    ProtocolViolation { reason: String },
+    /// A notification session was declined because `max_subscribers` centrals were subscribed.
+    SubscriberRejected { max_subscribers: usize },
+    /// A write or notification session was declined because its MTU is below `min_mtu`.
+    MtuTooSmall { mtu: usize, min_mtu: usize },
+    /// A write, write session or notification session was declined because its central is not in
+    /// `allowed_centrals`, or its address is unknown.
+    CentralRejected { address: Option<Address> },
+    /// The connection was closed, for the given reason.
+    Closed { reason: DisconnectReason },
+}
+
+/// Reason why the connection with the central was closed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DisconnectReason {
+    /// The engine was stopped by `stop_engine`, with the reason given by the caller, if any.
+    EngineStopped(Option<String>),
+}
+
+/// Parameters of the connection established when a central subscribes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConnectionInfo {
+    /// MTU of the notification session.
+    pub mtu: usize,
+    /// Address of the central, if known to the transport.
+    pub address: Option<Address>,
+    /// Time at which the central subscribed.
+    pub connected_at: SystemTime,
+}
+
+/// Send an event, or any other broadcast value, to the subscribers of the channel, if any.
+pub(crate) fn emit<T>(sender: &broadcast::Sender<T>, value: T) {
+    // Sending only fails when nobody is subscribed, which is fine
+    let _ = sender.send(value);
+}
@@ -0,0 +1,41 @@
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+
+/// Tracker falling back to acknowledged writes after repeated write failures.
+/// Once triggered, the characteristic is served with acknowledged writes only from the next
+/// time the engine is started.
+pub(crate) struct WriteFallback {
+    threshold: u32,
+    failures: AtomicU32,
+    triggered: AtomicBool,
+}
+
+impl WriteFallback {
+    /// Create a new tracker triggering after `threshold` consecutive failures.
+    pub fn new(threshold: u32) -> Self {
+        Self {
+            threshold,
+            failures: AtomicU32::new(0),
+            triggered: AtomicBool::new(false),
+        }
+    }
+
+    /// Record a failed write.
+    /// Return the number of consecutive failures if this failure triggered the fallback.
+    pub fn record_failure(&self) -> Option<u32> {
+        let failures = self.failures.fetch_add(1, Ordering::SeqCst) + 1;
+        match failures >= self.threshold && !self.triggered.swap(true, Ordering::SeqCst) {
+            true => Some(failures),
+            false => None,
+        }
+    }
+
+    /// Record a successful write, resetting the consecutive failures.
+    pub fn record_success(&self) {
+        self.failures.store(0, Ordering::SeqCst);
+    }
+
+    /// Check whether the fallback to acknowledged writes was triggered.
+    pub fn is_triggered(&self) -> bool {
+        self.triggered.load(Ordering::SeqCst)
+    }
+}
@@ -0,0 +1,161 @@
+use super::engine::{LinkEvent, Notifier};
+use super::transport::{Transport, TransportLink};
+use super::BlePeripheral;
+use bluer::Address;
+use futures::StreamExt;
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+use tokio::io::AsyncWrite;
+use tokio::time::{Duration, Sleep};
+
+/// Faults injected into the notifications written through a `FaultyTransport`.
+/// The faulty notifications are chosen by a pseudo-random sequence started from `seed`, so a
+/// test sees the same faults on every run.
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct Faults {
+    /// Delay before each notification is written.
+    pub latency: Duration,
+    /// Share of the notifications silently dropped, from 0 to 1.
+    pub drop_rate: f64,
+    /// Share of the notifications held back and written right after the next one, from 0 to 1.
+    pub reorder_rate: f64,
+    /// Seed of the sequence choosing the faulty notifications.
+    pub seed: u64,
+}
+
+/// Transport wrapping another one, injecting faults into the notifications of its link to test
+/// how the messages recover from an unreliable central. Writes of the central are left as is.
+pub(crate) struct FaultyTransport<T> {
+    inner: T,
+    faults: Faults,
+}
+
+impl<T: Transport> FaultyTransport<T> {
+    /// Wrap a transport, injecting the given faults.
+    pub fn new(inner: T, faults: Faults) -> Self {
+        Self { inner, faults }
+    }
+}
+
+impl<T: Transport> Transport for FaultyTransport<T> {
+    type Request = T::Request;
+    type Notifier = FaultyNotifier<T::Notifier>;
+
+    async fn open(
+        self,
+        ble: &mut BlePeripheral,
+    ) -> Result<TransportLink<Self::Request, Self::Notifier>, Box<dyn std::error::Error>> {
+        let link = self.inner.open(ble).await?;
+        let faults = self.faults;
+        let events = link.events.map(move |evt| match evt {
+            LinkEvent::Write(req) => LinkEvent::Write(req),
+            LinkEvent::Notify(notifier) => LinkEvent::Notify(FaultyNotifier::new(notifier, faults)),
+            LinkEvent::PriorityNotify(notifier) => {
+                LinkEvent::PriorityNotify(FaultyNotifier::new(notifier, faults))
+            }
+        });
+        Ok(TransportLink {
+            events: events.boxed(),
+            write_rx: link.write_rx,
+        })
+    }
+}
+
+/// Notifier injecting faults into the notifications before writing them to the wrapped one.
+/// Each write is taken as a whole notification. A notification held back to be reordered stays
+/// held until the next one is written, and is lost if the wrapped notifier is not ready for it.
+pub(crate) struct FaultyNotifier<N> {
+    inner: N,
+    faults: Faults,
+    random: u64,
+    delay: Option<Pin<Box<Sleep>>>,
+    held: Option<Vec<u8>>,
+    /// Whether the notification being written was already delayed and chosen to be written.
+    forwarding: bool,
+}
+
+impl<N> FaultyNotifier<N> {
+    /// Wrap a notifier, injecting the given faults.
+    fn new(inner: N, faults: Faults) -> Self {
+        Self {
+            inner,
+            faults,
+            random: faults.seed,
+            delay: None,
+            held: None,
+            forwarding: false,
+        }
+    }
+
+    /// Return the next value of the pseudo-random sequence, from 0 to 1, with SplitMix64.
+    fn roll(&mut self) -> f64 {
+        self.random = self.random.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.random;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^= z >> 31;
+        (z >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+impl<N: Notifier> AsyncWrite for FaultyNotifier<N> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let this = &mut *self;
+        if !this.forwarding {
+            if !this.faults.latency.is_zero() {
+                let latency = this.faults.latency;
+                let delay = this
+                    .delay
+                    .get_or_insert_with(|| Box::pin(tokio::time::sleep(latency)));
+                ready!(delay.as_mut().poll(cx));
+                this.delay = None;
+            }
+            if this.roll() < this.faults.drop_rate {
+                log::debug!("Dropping notification {:x?}", buf);
+                return Poll::Ready(Ok(buf.len()));
+            }
+            if this.held.is_none() && this.roll() < this.faults.reorder_rate {
+                this.held = Some(buf.to_vec());
+                return Poll::Ready(Ok(buf.len()));
+            }
+            this.forwarding = true;
+        }
+
+        let written = ready!(Pin::new(&mut this.inner).poll_write(cx, buf));
+        this.forwarding = false;
+        if written.is_ok() {
+            if let Some(held) = this.held.take() {
+                if !matches!(
+                    Pin::new(&mut this.inner).poll_write(cx, &held),
+                    Poll::Ready(Ok(_))
+                ) {
+                    log::debug!("Lost reordered notification {:x?}", held);
+                }
+            }
+        }
+        Poll::Ready(written)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+impl<N: Notifier> Notifier for FaultyNotifier<N> {
+    fn mtu(&self) -> usize {
+        self.inner.mtu()
+    }
+
+    fn device_address(&self) -> Option<Address> {
+        self.inner.device_address()
+    }
+}
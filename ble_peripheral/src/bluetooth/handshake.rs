@@ -0,0 +1,25 @@
+/// Version of the protocol spoken by the peripheral, exchanged during the handshake.
+pub const PROTOCOL_VERSION: u8 = 1;
+
+/// Capabilities exchanged by the peripheral and the central when the central subscribes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Capabilities {
+    /// Version of the protocol.
+    pub version: u8,
+    /// Maximum transmission unit of the connection.
+    pub mtu: u16,
+    /// Application-defined bit set of the supported features.
+    pub features: u32,
+}
+
+impl Capabilities {
+    /// Agree on the capabilities supported by both sides: the lowest version and MTU,
+    /// and the features supported by both.
+    pub fn negotiate(&self, other: &Capabilities) -> Capabilities {
+        Capabilities {
+            version: self.version.min(other.version),
+            mtu: self.mtu.min(other.mtu),
+            features: self.features & other.features,
+        }
+    }
+}
@@ -0,0 +1,149 @@
+use super::endian::Endianness;
+use super::error::BleError;
+use super::handshake::PROTOCOL_VERSION;
+
+/// Size of an encoded message header.
+pub const MESSAGE_HEADER_SIZE: usize = 7;
+
+/// Flags of a message header, telling how its payload was transformed before being sent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct HeaderFlags(u8);
+
+impl HeaderFlags {
+    /// The payload is compressed.
+    pub const COMPRESSED: HeaderFlags = HeaderFlags(0b001);
+    /// The payload is encrypted.
+    pub const ENCRYPTED: HeaderFlags = HeaderFlags(0b010);
+    /// The payload ends with a checksum.
+    pub const CHECKSUMMED: HeaderFlags = HeaderFlags(0b100);
+    /// Every flag defined so far, the other bits being reserved.
+    pub const ALL: HeaderFlags = HeaderFlags(0b111);
+
+    /// Return flags with none set.
+    pub const fn empty() -> Self {
+        Self(0)
+    }
+
+    /// Return the flags from their bits.
+    /// Return an error if a reserved bit is set, as it may carry a meaning this version ignores.
+    pub fn from_bits(bits: u8) -> Result<Self, BleError> {
+        match bits & !Self::ALL.0 {
+            0 => Ok(Self(bits)),
+            reserved => Err(BleError::InvalidMessage(format!(
+                "Reserved header flags {:#010b} are set",
+                reserved
+            ))),
+        }
+    }
+
+    /// Return the bits of the flags.
+    pub const fn bits(&self) -> u8 {
+        self.0
+    }
+
+    /// Check whether every flag of `other` is set.
+    pub const fn contains(&self, other: HeaderFlags) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// Return the flags set in either `self` or `other`.
+    pub const fn union(self, other: HeaderFlags) -> Self {
+        Self(self.0 | other.0)
+    }
+}
+
+/// Fixed-size header describing the message following it: the version of the protocol, the
+/// application-defined type of the message, its flags, and the length of its payload.
+///
+/// | Byte | Field    | Encoding |
+/// |------|----------|----------|
+/// | 0    | version  | u8       |
+/// | 1    | msg_type | u8       |
+/// | 2    | flags    | u8       |
+/// | 3..7 | length   | u32      |
+///
+/// The length is encoded in the byte order given to each method, the one of the peripheral
+/// being set with `BlePeripheralBuilder::endianness`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MessageHeader {
+    pub version: u8,
+    pub msg_type: u8,
+    pub flags: HeaderFlags,
+    pub length: u32,
+}
+
+impl MessageHeader {
+    /// Create a new header for a payload of `length` bytes with the current protocol version
+    /// and no flags.
+    pub fn new(msg_type: u8, length: u32) -> Self {
+        Self {
+            version: PROTOCOL_VERSION,
+            msg_type,
+            flags: HeaderFlags::empty(),
+            length,
+        }
+    }
+
+    /// Set the flags of the header.
+    pub fn flags(mut self, flags: HeaderFlags) -> Self {
+        self.flags = flags;
+        self
+    }
+
+    /// Encode the header into bytes.
+    pub fn encode(&self, endianness: Endianness) -> [u8; MESSAGE_HEADER_SIZE] {
+        let mut bytes = [0; MESSAGE_HEADER_SIZE];
+        bytes[0] = self.version;
+        bytes[1] = self.msg_type;
+        bytes[2] = self.flags.bits();
+        bytes[3..].copy_from_slice(&endianness.u32_bytes(self.length));
+        bytes
+    }
+
+    /// Decode the header starting the bytes, ignoring the bytes following it.
+    /// Return an error if the bytes are shorter than a header or a reserved flag is set.
+    pub fn decode(bytes: &[u8], endianness: Endianness) -> Result<Self, BleError> {
+        let Some(header) = bytes.get(..MESSAGE_HEADER_SIZE) else {
+            return Err(BleError::InvalidMessage(format!(
+                "Message header needs {} bytes, got {}",
+                MESSAGE_HEADER_SIZE,
+                bytes.len()
+            )));
+        };
+        Ok(Self {
+            version: header[0],
+            msg_type: header[1],
+            flags: HeaderFlags::from_bits(header[2])?,
+            length: endianness.read_u32(header[3..].try_into().unwrap()),
+        })
+    }
+
+    /// Encode a message made of the header followed by its payload, with the length of the
+    /// header set to the one of the payload.
+    /// Return an error if the payload is longer than a header can describe.
+    pub fn frame(mut self, payload: &[u8], endianness: Endianness) -> Result<Vec<u8>, BleError> {
+        self.length = u32::try_from(payload.len()).map_err(|_| {
+            BleError::InvalidMessage(format!("Payload of {} bytes is too long", payload.len()))
+        })?;
+        let mut message = Vec::with_capacity(MESSAGE_HEADER_SIZE + payload.len());
+        message.extend_from_slice(&self.encode(endianness));
+        message.extend_from_slice(payload);
+        Ok(message)
+    }
+
+    /// Decode a message made of a header followed by its payload, returning both.
+    /// Return an error if the payload is shorter than the length of the header. Bytes following
+    /// the payload are not part of the message and are ignored.
+    pub fn split(message: &[u8], endianness: Endianness) -> Result<(Self, &[u8]), BleError> {
+        let header = Self::decode(message, endianness)?;
+        let payload = &message[MESSAGE_HEADER_SIZE..];
+        match payload.get(..header.length as usize) {
+            Some(payload) => Ok((header, payload)),
+            None => Err(BleError::InvalidMessage(format!(
+                "Message payload needs {} bytes, got {}",
+                header.length,
+                payload.len()
+            ))),
+        }
+    }
+}
@@ -0,0 +1,99 @@
+use super::chunk::{split_into_chunks, ChunkReassembler};
+use super::endian::Endianness;
+use super::error::BleError;
+use super::message::{BleMessage, LENGTH_PREFIX_SIZE};
+use image::{imageops::FilterType, DynamicImage, ImageFormat};
+use std::io::Cursor;
+use tokio::time::Instant;
+
+/// Resize the image to `width` x `height` and encode it as JPEG, ready to be framed and sent.
+/// The JPEG bytes are sent as they are: no further compression, such as deflate, is applied.
+pub fn encode_image(image: &DynamicImage, width: u32, height: u32) -> Result<Vec<u8>, BleError> {
+    let resized = image.resize_exact(width, height, FilterType::Nearest);
+    let mut bytes = Vec::new();
+    resized
+        .write_to(&mut Cursor::new(&mut bytes), ImageFormat::Jpeg)
+        .map_err(|err| BleError::InvalidMessage(format!("Image encoding failed: {}", err)))?;
+    Ok(bytes)
+}
+
+/// Frame an encoded image with its length, then split it into sequenced chunks carrying at most
+/// `chunk_size` bytes each, as reassembled by `ImageReceiver`. The length and the chunk headers
+/// are encoded in the given byte order.
+pub fn image_chunks(
+    encoded: Vec<u8>,
+    chunk_size: usize,
+    endianness: Endianness,
+) -> Result<Vec<Vec<u8>>, BleError> {
+    let framed = BleMessage::Raw(encoded).length_prefixed(endianness);
+    split_into_chunks(framed.as_bytes(), chunk_size, endianness)
+        .map_err(|err| BleError::InvalidMessage(err.to_string()))
+}
+
+/// Receiving side of the image pipeline, reversing `image_chunks` and `encode_image`.
+/// The frames are decoded as big-endian unless set otherwise with `with_endianness`.
+#[derive(Default)]
+pub struct ImageReceiver {
+    reassembler: ChunkReassembler,
+    endianness: Endianness,
+}
+
+impl ImageReceiver {
+    /// Create a new receiver, waiting for missing chunks for the default gap timeout.
+    pub fn new() -> ImageReceiver {
+        ImageReceiver::default()
+    }
+
+    /// Decode the image length and the chunk headers in the given byte order.
+    pub fn with_endianness(self, endianness: Endianness) -> ImageReceiver {
+        ImageReceiver {
+            reassembler: self.reassembler.with_endianness(endianness),
+            endianness,
+        }
+    }
+
+    /// Feed a chunk into the receiver.
+    /// Return the decoded image once all its chunks are received, or `None` if some are still missing.
+    /// Return an error if a chunk is malformed, or if the reassembled frame is not a valid image.
+    pub fn push(&mut self, chunk: &[u8]) -> Result<Option<DynamicImage>, BleError> {
+        let framed = match self
+            .reassembler
+            .push(chunk)
+            .map_err(|err| BleError::InvalidMessage(err.to_string()))?
+        {
+            Some(framed) => framed,
+            None => return Ok(None),
+        };
+
+        if framed.len() < LENGTH_PREFIX_SIZE {
+            return Err(BleError::InvalidMessage(
+                "Image frame is shorter than its length prefix".to_string(),
+            ));
+        }
+        let (prefix, encoded) = framed.split_at(LENGTH_PREFIX_SIZE);
+        let length = self.endianness.read_u32(prefix.try_into().unwrap()) as usize;
+        if length != encoded.len() {
+            return Err(BleError::InvalidMessage(format!(
+                "Image frame declares {} bytes, got {}",
+                length,
+                encoded.len()
+            )));
+        }
+        image::load_from_memory(encoded)
+            .map(Some)
+            .map_err(|err| BleError::InvalidMessage(format!("Image decoding failed: {}", err)))
+    }
+
+    /// Discard the pending chunks and return an error if a missing chunk has not arrived within
+    /// the gap timeout. Call it at the `deadline` when no further chunk is received.
+    pub fn check_timeout(&mut self) -> Result<(), BleError> {
+        self.reassembler
+            .check_timeout()
+            .map_err(|err| BleError::InvalidMessage(err.to_string()))
+    }
+
+    /// Return the time at which the pending chunks time out, if an image is being received.
+    pub fn deadline(&self) -> Option<Instant> {
+        self.reassembler.deadline()
+    }
+}
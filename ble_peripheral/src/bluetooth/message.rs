@@ -1,13 +1,32 @@
+use super::endian::Endianness;
+use super::error::BleError;
 use std::error::Error;
 use std::fmt;
 
+/// Size of the length prefix added by `BleMessage::length_prefixed`.
+pub const LENGTH_PREFIX_SIZE: usize = 4;
+
+/// Tag starting every event, telling events apart from data messages on the same characteristic.
+pub const EVENT_TAG: [u8; 2] = [0xFF, 0xBE];
+
+/// Reserved bytes of the ready message, which are not valid UTF-8, so no text can be mistaken for it.
+pub const READY_MARKER: [u8; 2] = [0xFF, 0xBD];
+
 // Enum representing the message that can be sent over Bluetooth Low Energy
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum BleMessage {
     Text(String),
     Raw(Vec<u8>),
 }
 
+/// Variant of a BleMessage, used as the target of `BleMessage::coerce`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MessageKind {
+    Text,
+    Raw,
+}
+
 impl BleMessage {
     /// Comsume the message and return the bytes representation of the message
     pub fn take_bytes(self) -> Vec<u8> {
@@ -17,6 +36,14 @@ impl BleMessage {
         }
     }
 
+    /// Return the bytes representation of the message without consuming it
+    pub fn as_bytes(&self) -> &[u8] {
+        match self {
+            BleMessage::Text(s) => s.as_bytes(),
+            BleMessage::Raw(v) => v,
+        }
+    }
+
     /// Convert from raw bytes message to a text message.
     /// Return an error if the message is not raw bytes.
     pub fn convert_to_text(self) -> Result<Self, Box<dyn Error>> {
@@ -29,6 +56,90 @@ impl BleMessage {
         }
     }
 
+    /// Return the variant of the message.
+    pub fn kind(&self) -> MessageKind {
+        match self {
+            BleMessage::Text(_) => MessageKind::Text,
+            BleMessage::Raw(_) => MessageKind::Raw,
+        }
+    }
+
+    /// Convert the message to the given variant, best-effort and without erroring.
+    /// Text is converted to raw bytes as UTF-8, and raw bytes to text with invalid UTF-8 replaced.
+    /// A message already of the given variant is returned unchanged.
+    pub fn coerce(self, kind: MessageKind) -> Self {
+        match (self, kind) {
+            (BleMessage::Text(s), MessageKind::Raw) => BleMessage::Raw(s.into_bytes()),
+            (BleMessage::Raw(v), MessageKind::Text) => {
+                BleMessage::Text(String::from_utf8_lossy(&v).to_string())
+            }
+            (message, _) => message,
+        }
+    }
+
+    /// Create a raw message holding a u32 encoded in the given byte order.
+    pub fn from_u32(value: u32, endianness: Endianness) -> Self {
+        Self::Raw(endianness.u32_bytes(value).to_vec())
+    }
+
+    /// Create a raw message holding a u64 encoded in the given byte order.
+    pub fn from_u64(value: u64, endianness: Endianness) -> Self {
+        Self::Raw(endianness.u64_bytes(value).to_vec())
+    }
+
+    /// Decode the message as a u32 encoded in the given byte order.
+    /// Return an error if the message is not exactly 4 bytes long.
+    pub fn to_u32(&self, endianness: Endianness) -> Result<u32, BleError> {
+        let bytes = self.as_bytes().try_into().map_err(|_| {
+            BleError::InvalidMessage(format!("Expected 4 bytes, got {}", self.as_bytes().len()))
+        })?;
+        Ok(endianness.read_u32(bytes))
+    }
+
+    /// Decode the message as a u64 encoded in the given byte order.
+    /// Return an error if the message is not exactly 8 bytes long.
+    pub fn to_u64(&self, endianness: Endianness) -> Result<u64, BleError> {
+        let bytes = self.as_bytes().try_into().map_err(|_| {
+            BleError::InvalidMessage(format!("Expected 8 bytes, got {}", self.as_bytes().len()))
+        })?;
+        Ok(endianness.read_u64(bytes))
+    }
+
+    /// Consume the message and return a raw message prefixed with its length as a u32
+    /// in the given byte order.
+    pub fn length_prefixed(self, endianness: Endianness) -> Self {
+        let bytes = self.take_bytes();
+        let mut prefixed = Vec::with_capacity(LENGTH_PREFIX_SIZE + bytes.len());
+        prefixed.extend_from_slice(&endianness.u32_bytes(bytes.len() as u32));
+        prefixed.extend(bytes);
+        Self::Raw(prefixed)
+    }
+
+    /// Consume the message and return it tagged as an event.
+    pub fn into_event(self) -> Self {
+        let mut tagged = EVENT_TAG.to_vec();
+        tagged.extend(self.take_bytes());
+        Self::Raw(tagged)
+    }
+
+    /// Return the payload of the message if it is tagged as an event, or `None` if it is data.
+    pub fn as_event(&self) -> Option<BleMessage> {
+        self.as_bytes()
+            .strip_prefix(&EVENT_TAG)
+            .map(|payload| Self::Raw(payload.to_vec()))
+    }
+
+    /// Create the message signaling that the sender is ready, such as for the next image.
+    pub fn ready() -> Self {
+        Self::Raw(READY_MARKER.to_vec())
+    }
+
+    /// Check whether the message is the ready message.
+    /// Text reading like "Ready" is not, and neither is a ready message converted to text.
+    pub fn is_ready(&self) -> bool {
+        self.as_bytes() == READY_MARKER
+    }
+
     /// Extend the raw bytes with another byte vector.
     /// Return an error if the message is not raw bytes
     pub fn extend_raw_bytes(&mut self, bytes: Vec<u8>) -> Result<(), Box<dyn Error>> {
@@ -0,0 +1,164 @@
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use tokio::time::{Duration, Instant};
+
+/// Duration of each bucket of the rolling windows measuring the throughput.
+const BUCKET_DURATION: Duration = Duration::from_millis(100);
+
+/// Number of buckets kept by the rolling windows, covering the longest throughput window.
+const BUCKET_COUNT: u64 = 100;
+
+/// Throughput over rolling windows, in bytes per second. Only the buckets of 100 ms that are
+/// over count, so the throughput lags behind by at most 100 ms.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Throughput {
+    /// Throughput over the last 100 ms.
+    pub inst_bps: f64,
+    /// Throughput over the last second.
+    pub avg_1s_bps: f64,
+    /// Throughput over the last 10 seconds.
+    pub avg_10s_bps: f64,
+}
+
+/// Snapshot of how the sent messages were batched into notifications since the engine started,
+/// for tuning the coalescing window.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct NotificationMetrics {
+    /// Number of messages taken from the send queue.
+    pub messages: u64,
+    /// Number of notifications written, each a message or a batch of coalesced messages.
+    pub notifications: u64,
+    /// Number of bytes notified.
+    pub notified_bytes: u64,
+    /// Average number of notifications written per second.
+    pub notifications_per_second: f64,
+    /// Average number of bytes per notification.
+    pub average_notification_bytes: f64,
+    /// Average number of messages still queued when a message is taken from the send queue.
+    pub average_queue_depth: f64,
+    /// Throughput of the notified bytes.
+    pub send_throughput: Throughput,
+    /// Throughput of the bytes written by the central.
+    pub receive_throughput: Throughput,
+}
+
+/// Ring buffer of the bytes counted in each bucket of the last `BUCKET_COUNT` buckets.
+#[derive(Default)]
+struct RateWindow {
+    /// Index of each bucket since the recorder started, along with its bytes.
+    buckets: VecDeque<(u64, u64)>,
+}
+
+impl RateWindow {
+    /// Count `bytes` in the bucket `index`, dropping the buckets that fell out of the window.
+    fn record(&mut self, index: u64, bytes: usize) {
+        match self.buckets.back_mut() {
+            Some((last, count)) if *last == index => *count += bytes as u64,
+            _ => self.buckets.push_back((index, bytes as u64)),
+        }
+        while let Some(&(first, _)) = self.buckets.front() {
+            if first + BUCKET_COUNT > index {
+                break;
+            }
+            self.buckets.pop_front();
+        }
+    }
+
+    /// Compute the throughput over the `buckets` buckets that are over before the bucket `index`.
+    fn rate(&self, index: u64, buckets: u64) -> f64 {
+        let bytes: u64 = self
+            .buckets
+            .iter()
+            .filter(|(bucket, _)| *bucket < index && *bucket + buckets >= index)
+            .map(|(_, bytes)| bytes)
+            .sum();
+        bytes as f64 / (BUCKET_DURATION.as_secs_f64() * buckets as f64)
+    }
+
+    /// Compute the throughput over every window before the bucket `index`.
+    fn throughput(&self, index: u64) -> Throughput {
+        Throughput {
+            inst_bps: self.rate(index, 1),
+            avg_1s_bps: self.rate(index, 10),
+            avg_10s_bps: self.rate(index, BUCKET_COUNT),
+        }
+    }
+}
+
+#[derive(Default)]
+struct Counters {
+    messages: u64,
+    notifications: u64,
+    notified_bytes: u64,
+    queue_depth_sum: u64,
+    sent: RateWindow,
+    received: RateWindow,
+}
+
+/// Counters of the notifications written and the bytes read by the BLE thread, shared with its
+/// BlePeripheral.
+pub(crate) struct MetricsRecorder {
+    started: Instant,
+    counters: Mutex<Counters>,
+}
+
+impl MetricsRecorder {
+    /// Create a new recorder measuring the rates from now.
+    pub fn new() -> Self {
+        Self {
+            started: Instant::now(),
+            counters: Mutex::new(Counters::default()),
+        }
+    }
+
+    /// Record a message taken from the send queue, with `depth` messages still queued behind it.
+    pub fn record_message(&self, depth: usize) {
+        let mut counters = self.counters.lock().unwrap();
+        counters.messages += 1;
+        counters.queue_depth_sum += depth as u64;
+    }
+
+    /// Record a notification of `bytes` bytes.
+    pub fn record_notification(&self, bytes: usize) {
+        let index = self.bucket_index();
+        let mut counters = self.counters.lock().unwrap();
+        counters.notifications += 1;
+        counters.notified_bytes += bytes as u64;
+        counters.sent.record(index, bytes);
+    }
+
+    /// Record `bytes` bytes written by the central.
+    pub fn record_received(&self, bytes: usize) {
+        let index = self.bucket_index();
+        self.counters.lock().unwrap().received.record(index, bytes);
+    }
+
+    /// Return the index of the current bucket since the recorder started.
+    fn bucket_index(&self) -> u64 {
+        (self.started.elapsed().as_millis() / BUCKET_DURATION.as_millis()) as u64
+    }
+
+    /// Take a snapshot of the metrics.
+    pub fn snapshot(&self) -> NotificationMetrics {
+        let index = self.bucket_index();
+        let counters = self.counters.lock().unwrap();
+        let average = |sum: u64, count: u64| match count {
+            0 => 0.0,
+            count => sum as f64 / count as f64,
+        };
+        let elapsed = self.started.elapsed().as_secs_f64();
+        NotificationMetrics {
+            messages: counters.messages,
+            notifications: counters.notifications,
+            notified_bytes: counters.notified_bytes,
+            notifications_per_second: match elapsed > 0.0 {
+                true => counters.notifications as f64 / elapsed,
+                false => 0.0,
+            },
+            average_notification_bytes: average(counters.notified_bytes, counters.notifications),
+            average_queue_depth: average(counters.queue_depth_sum, counters.messages),
+            send_throughput: counters.sent.throughput(index),
+            receive_throughput: counters.received.throughput(index),
+        }
+    }
+}
@@ -0,0 +1,507 @@
+use super::admission::Admission;
+use super::engine::{LinkEvent, Notifier, WriteRequest};
+use super::error::BleError;
+use super::readvertise::{keep_advertising, Advertiser, READVERTISE_POLL_INTERVAL};
+use super::transport::{open_offset_channel, Transport, TransportLink};
+use super::{handle_offset_write, BlePeripheral, WriteValidator};
+use bluer::gatt::local::{ReqError, ReqResult};
+use bluer::Address;
+use futures::channel::mpsc as stream_mpsc;
+use futures::StreamExt;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::sync::mpsc;
+
+/// Mock write request handing out a reader fed by the mock central.
+/// Accepting the request fails if it has no reader.
+pub(crate) struct MockWriteRequest {
+    mtu: usize,
+    address: Option<Address>,
+    reader: Option<MockReader>,
+}
+
+impl WriteRequest for MockWriteRequest {
+    type Reader = MockReader;
+
+    fn mtu(&self) -> usize {
+        self.mtu
+    }
+
+    fn accept(self) -> std::io::Result<Self::Reader> {
+        self.reader
+            .ok_or_else(|| std::io::Error::other("Write request rejected"))
+    }
+
+    /// Dropping the reader closes the channel of the mock central.
+    fn reject(self) {}
+
+    fn device_address(&self) -> Option<Address> {
+        self.address
+    }
+}
+
+/// Mock reader returning one written packet per read, or failing every read if it is broken.
+pub(crate) struct MockReader {
+    packets: mpsc::UnboundedReceiver<Vec<u8>>,
+    broken: bool,
+}
+
+impl AsyncRead for MockReader {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        if self.broken {
+            return Poll::Ready(Err(std::io::ErrorKind::ConnectionReset.into()));
+        }
+        match self.packets.poll_recv(cx) {
+            Poll::Ready(Some(packet)) => {
+                let len = packet.len().min(buf.remaining());
+                buf.put_slice(&packet[..len]);
+                Poll::Ready(Ok(()))
+            }
+            Poll::Ready(None) => Poll::Ready(Ok(())),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+/// Gate holding back the writes of a mock notifier until it is opened.
+#[derive(Clone, Default)]
+pub(crate) struct MockGate {
+    state: Arc<Mutex<(bool, Option<Waker>)>>,
+}
+
+impl MockGate {
+    /// Let the pending and future writes through.
+    pub fn open(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.0 = true;
+        if let Some(waker) = state.1.take() {
+            waker.wake();
+        }
+    }
+
+    /// Return whether the gate is open, registering the waker to be woken once it opens.
+    fn poll_open(&self, cx: &mut Context<'_>) -> bool {
+        let mut state = self.state.lock().unwrap();
+        if !state.0 {
+            state.1 = Some(cx.waker().clone());
+        }
+        state.0
+    }
+}
+
+/// Mock notifier recording each write as a separate notification.
+pub(crate) struct MockNotifier {
+    mtu: usize,
+    notifications: Option<mpsc::UnboundedSender<Vec<u8>>>,
+    gate: Option<MockGate>,
+    flushes: Arc<AtomicUsize>,
+    /// Number of writes still rejected as if the receive buffer of the central was full.
+    busy_writes: usize,
+    address: Option<Address>,
+}
+
+impl AsyncWrite for MockNotifier {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        if let Some(gate) = self.gate.as_ref() {
+            if !gate.poll_open(cx) {
+                return Poll::Pending;
+            }
+        }
+        if self.busy_writes > 0 {
+            self.busy_writes -= 1;
+            return Poll::Ready(Err(std::io::ErrorKind::WouldBlock.into()));
+        }
+        let sent = self
+            .notifications
+            .as_ref()
+            .map(|notifications| notifications.send(buf.to_vec()).is_ok())
+            .unwrap_or(false);
+        match sent {
+            true => Poll::Ready(Ok(buf.len())),
+            false => Poll::Ready(Err(std::io::ErrorKind::BrokenPipe.into())),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        self.flushes.fetch_add(1, Ordering::SeqCst);
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        self.notifications = None;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl Notifier for MockNotifier {
+    fn mtu(&self) -> usize {
+        self.mtu
+    }
+
+    fn device_address(&self) -> Option<Address> {
+        self.address
+    }
+}
+
+/// In-memory streams act as notifiers without an MTU limit.
+impl Notifier for tokio::io::DuplexStream {
+    fn mtu(&self) -> usize {
+        usize::MAX
+    }
+}
+
+/// Mock advertiser whose advertisement can be removed as if by the system.
+#[derive(Clone, Default)]
+pub(crate) struct MockAdvertiser {
+    advertising: Arc<AtomicBool>,
+    registrations: Arc<AtomicUsize>,
+}
+
+impl MockAdvertiser {
+    /// Remove the advertisement without clearing its handle.
+    pub fn remove(&self) {
+        self.advertising.store(false, Ordering::SeqCst);
+    }
+
+    /// Number of times the advertisement has been registered.
+    pub fn registrations(&self) -> usize {
+        self.registrations.load(Ordering::SeqCst)
+    }
+}
+
+impl Advertiser for MockAdvertiser {
+    type Handle = ();
+
+    async fn is_advertising(&self) -> Result<bool, BleError> {
+        Ok(self.advertising.load(Ordering::SeqCst))
+    }
+
+    async fn advertise(&self) -> Result<(), BleError> {
+        self.advertising.store(true, Ordering::SeqCst);
+        self.registrations.fetch_add(1, Ordering::SeqCst);
+        Ok(())
+    }
+}
+
+/// Write function of the offset writes, checking them as the GATT write function does.
+struct MockOffsetWrite {
+    admission: Admission,
+    validator: Option<WriteValidator>,
+    offset_tx: mpsc::UnboundedSender<(u16, Vec<u8>)>,
+}
+
+/// Write function of the offset writes, set once the mock transport is opened with offset writes
+/// enabled.
+type MockOffsetWrites = Arc<Mutex<Option<MockOffsetWrite>>>;
+
+/// Mock central device driving the BLE thread of a peripheral without Bluetooth hardware.
+pub(crate) struct MockCentral {
+    events_tx: stream_mpsc::UnboundedSender<LinkEvent<MockWriteRequest, MockNotifier>>,
+    offset_writes: MockOffsetWrites,
+}
+
+impl MockCentral {
+    /// Subscribe to notifications, returning the channel receiving each notification.
+    /// The channel closes when the peripheral ends the notification session.
+    pub fn subscribe(&self, mtu: usize) -> mpsc::UnboundedReceiver<Vec<u8>> {
+        self.subscribe_gated(mtu, None, Arc::default(), 0)
+    }
+
+    /// Subscribe to notifications and wait until `ble` sees the subscription.
+    pub async fn subscribe_and_wait(
+        &self,
+        ble: &BlePeripheral,
+        mtu: usize,
+    ) -> mpsc::UnboundedReceiver<Vec<u8>> {
+        let notifications = self.subscribe(mtu);
+        wait_subscribed(ble, true).await;
+        notifications
+    }
+
+    /// Subscribe to notifications that are held back until the returned gate is opened,
+    /// simulating a slow notification session.
+    pub fn subscribe_stalled(&self, mtu: usize) -> (mpsc::UnboundedReceiver<Vec<u8>>, MockGate) {
+        let gate = MockGate::default();
+        (
+            self.subscribe_gated(mtu, Some(gate.clone()), Arc::default(), 0),
+            gate,
+        )
+    }
+
+    /// Subscribe to notifications, also returning the number of times the notifier was flushed.
+    pub fn subscribe_counting_flushes(
+        &self,
+        mtu: usize,
+    ) -> (mpsc::UnboundedReceiver<Vec<u8>>, Arc<AtomicUsize>) {
+        let flushes = Arc::new(AtomicUsize::new(0));
+        (self.subscribe_gated(mtu, None, flushes.clone(), 0), flushes)
+    }
+
+    /// Subscribe to notifications whose first `busy_writes` writes fail with `WouldBlock`, as if
+    /// the receive buffer of the central was full until then.
+    pub fn subscribe_busy(
+        &self,
+        mtu: usize,
+        busy_writes: usize,
+    ) -> mpsc::UnboundedReceiver<Vec<u8>> {
+        self.subscribe_gated(mtu, None, Arc::default(), busy_writes)
+    }
+
+    /// Subscribe to notifications as the central with the given address.
+    pub fn subscribe_from(&self, mtu: usize, address: Address) -> mpsc::UnboundedReceiver<Vec<u8>> {
+        let (notifications_tx, notifications_rx) = mpsc::unbounded_channel();
+        let notifier = MockNotifier {
+            mtu,
+            notifications: Some(notifications_tx),
+            gate: None,
+            flushes: Arc::default(),
+            busy_writes: 0,
+            address: Some(address),
+        };
+        self.events_tx
+            .unbounded_send(LinkEvent::Notify(notifier))
+            .unwrap();
+        notifications_rx
+    }
+
+    fn subscribe_gated(
+        &self,
+        mtu: usize,
+        gate: Option<MockGate>,
+        flushes: Arc<AtomicUsize>,
+        busy_writes: usize,
+    ) -> mpsc::UnboundedReceiver<Vec<u8>> {
+        let (notifications_tx, notifications_rx) = mpsc::unbounded_channel();
+        let notifier = MockNotifier {
+            mtu,
+            notifications: Some(notifications_tx),
+            gate,
+            flushes,
+            busy_writes,
+            address: None,
+        };
+        self.events_tx
+            .unbounded_send(LinkEvent::Notify(notifier))
+            .unwrap();
+        notifications_rx
+    }
+
+    /// Subscribe to notifications of the priority characteristic, returning the channel receiving them.
+    pub fn subscribe_priority(&self, mtu: usize) -> mpsc::UnboundedReceiver<Vec<u8>> {
+        let (notifications_tx, notifications_rx) = mpsc::unbounded_channel();
+        let notifier = MockNotifier {
+            mtu,
+            notifications: Some(notifications_tx),
+            gate: None,
+            flushes: Arc::default(),
+            busy_writes: 0,
+            address: None,
+        };
+        self.events_tx
+            .unbounded_send(LinkEvent::PriorityNotify(notifier))
+            .unwrap();
+        notifications_rx
+    }
+
+    /// Start a write session, returning the channel used to write packets to the peripheral.
+    pub fn start_write(&self, mtu: usize) -> mpsc::UnboundedSender<Vec<u8>> {
+        self.start_write_from(mtu, None)
+    }
+
+    /// Start a write session as the central with the given address, if any.
+    pub fn start_write_from(
+        &self,
+        mtu: usize,
+        address: Option<Address>,
+    ) -> mpsc::UnboundedSender<Vec<u8>> {
+        let (packets_tx, packets_rx) = mpsc::unbounded_channel();
+        let request = MockWriteRequest {
+            mtu,
+            address,
+            reader: Some(MockReader {
+                packets: packets_rx,
+                broken: false,
+            }),
+        };
+        self.events_tx
+            .unbounded_send(LinkEvent::Write(request))
+            .unwrap();
+        packets_tx
+    }
+
+    /// Start a write session whose stream fails to be read by the peripheral.
+    pub fn fail_read(&self, mtu: usize) {
+        let (_, packets_rx) = mpsc::unbounded_channel();
+        let request = MockWriteRequest {
+            mtu,
+            address: None,
+            reader: Some(MockReader {
+                packets: packets_rx,
+                broken: true,
+            }),
+        };
+        self.events_tx
+            .unbounded_send(LinkEvent::Write(request))
+            .unwrap();
+    }
+
+    /// Start a write session whose request fails to be accepted by the peripheral.
+    pub fn fail_write(&self, mtu: usize) {
+        let request = MockWriteRequest {
+            mtu,
+            address: None,
+            reader: None,
+        };
+        self.events_tx
+            .unbounded_send(LinkEvent::Write(request))
+            .unwrap();
+    }
+
+    /// Write a single packet to the peripheral in its own write session.
+    pub fn write(&self, packet: &[u8]) {
+        self.start_write(512).send(packet.to_vec()).unwrap();
+    }
+
+    /// Write bytes at `offset` of the characteristic, answered like the GATT write function.
+    /// Fail if offset writes are not enabled.
+    pub fn write_at(&self, offset: u16, bytes: &[u8]) -> ReqResult<()> {
+        self.write_at_from(None, offset, bytes)
+    }
+
+    /// Write bytes at `offset` of the characteristic as the central with the given address, if any.
+    /// The write is made with an MTU of 512.
+    pub fn write_at_from(
+        &self,
+        address: Option<Address>,
+        offset: u16,
+        bytes: &[u8],
+    ) -> ReqResult<()> {
+        let offset_writes = self.offset_writes.lock().unwrap();
+        let write = offset_writes.as_ref().ok_or(ReqError::NotSupported)?;
+        write.admission.check_write(address, 512)?;
+        handle_offset_write(
+            write.validator.as_ref(),
+            offset,
+            bytes.to_vec(),
+            &write.offset_tx,
+        )
+    }
+}
+
+/// Mock transport whose link is driven by a mock central, without Bluetooth hardware.
+pub(crate) struct MockTransport {
+    events_rx: stream_mpsc::UnboundedReceiver<LinkEvent<MockWriteRequest, MockNotifier>>,
+    offset_writes: MockOffsetWrites,
+    stalled: bool,
+    failing_gatt: Option<MockAdvertiser>,
+}
+
+impl MockTransport {
+    /// Create a new mock transport along with the central driving its link.
+    pub fn new() -> (MockTransport, MockCentral) {
+        let (events_tx, events_rx) = stream_mpsc::unbounded();
+        let offset_writes = MockOffsetWrites::default();
+        let transport = MockTransport {
+            events_rx,
+            offset_writes: offset_writes.clone(),
+            stalled: false,
+            failing_gatt: None,
+        };
+        let central = MockCentral {
+            events_tx,
+            offset_writes,
+        };
+        (transport, central)
+    }
+
+    /// Create a mock transport whose link never finishes opening, as if the Bluetooth stack hung
+    /// after registering the channels of the offset writes.
+    pub fn stalled() -> MockTransport {
+        let (transport, _) = MockTransport::new();
+        MockTransport {
+            stalled: true,
+            ..transport
+        }
+    }
+
+    /// Create a mock transport registering the advertisement with `advertiser` and keeping it
+    /// registered, then failing to serve the GATT application.
+    pub fn failing_gatt(advertiser: MockAdvertiser) -> MockTransport {
+        let (transport, _) = MockTransport::new();
+        MockTransport {
+            failing_gatt: Some(advertiser),
+            ..transport
+        }
+    }
+
+    /// Return the link carrying the events sent by the mock central.
+    fn link(self) -> TransportLink<MockWriteRequest, MockNotifier> {
+        let (_, write_rx) = mpsc::unbounded_channel();
+        TransportLink {
+            events: self.events_rx.boxed(),
+            write_rx,
+        }
+    }
+}
+
+impl Transport for MockTransport {
+    type Request = MockWriteRequest;
+    type Notifier = MockNotifier;
+
+    async fn open(
+        self,
+        ble: &mut BlePeripheral,
+    ) -> Result<TransportLink<MockWriteRequest, MockNotifier>, Box<dyn std::error::Error>> {
+        if self.stalled {
+            let (offset_tx, offset_rx) = mpsc::unbounded_channel();
+            ble.offset_sender = Some(offset_tx);
+            ble.offset_receiver = Some(offset_rx);
+            std::future::pending::<()>().await;
+        }
+        if let Some(advertiser) = self.failing_gatt {
+            let handle = Arc::new(Mutex::new(Some(advertiser.advertise().await?)));
+            ble.readvertise_task = Some(tokio::spawn(keep_advertising(
+                advertiser,
+                handle,
+                ble.events.clone(),
+                READVERTISE_POLL_INTERVAL,
+            )));
+            return Err("GATT application registration failed".into());
+        }
+        open_offset_channel(ble);
+        *self.offset_writes.lock().unwrap() =
+            ble.offset_sender.clone().map(|offset_tx| MockOffsetWrite {
+                admission: Admission::new(&ble.config, ble.events.clone()),
+                validator: ble.write_validator.clone(),
+                offset_tx,
+            });
+        Ok(self.link())
+    }
+}
+
+/// Wait until the peripheral is subscribed to notifications, or no longer is if `subscribed` is
+/// false.
+pub(crate) async fn wait_subscribed(ble: &BlePeripheral, subscribed: bool) {
+    while ble.is_subscribed().await != subscribed {
+        tokio::task::yield_now().await;
+    }
+}
+
+/// Start the BLE thread of the peripheral on a mock link and return the central driving it.
+pub(crate) fn start_mock_engine(ble: &mut BlePeripheral) -> MockCentral {
+    let (transport, central) = MockTransport::new();
+    let link = transport.link();
+    ble.spawn_engine(link.events, link.write_rx);
+    central
+}
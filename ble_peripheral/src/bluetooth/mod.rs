@@ -1,250 +1,1597 @@
+pub mod adapter;
+mod admission;
+pub mod advertisement;
+pub mod alias;
+pub mod backoff;
+pub mod battery;
+pub mod boost;
+pub mod builder;
+pub mod capture;
+pub mod chunk;
+pub mod coalesce;
+pub mod codec;
+pub mod config;
+mod connection;
+pub mod control;
+pub mod dedup;
+mod delimiter;
+pub mod endian;
+mod engine;
+pub mod envelope;
+pub mod error;
+pub mod event;
+mod fallback;
+#[cfg(test)]
+mod faulty;
+pub mod handshake;
+pub mod header;
+pub mod image_transfer;
 pub mod message;
+pub mod metrics;
+#[cfg(test)]
+mod mock;
+mod outgoing;
+pub mod queue;
+pub mod raw_write;
+mod read;
+pub mod readvertise;
+mod receive;
+pub mod replay;
+pub mod report;
+pub mod security;
+pub mod sensor;
+pub mod splitter;
+pub mod state;
 mod test;
+pub mod tlv;
+pub mod transfer;
+mod transport;
 
+use adapter::{AdapterAliasState, AdapterCapabilities, AdapterInfo, DiscoverableState};
+use admission::Admission;
+use alias::validate_alias;
 use bluer::{
     adv::{Advertisement, AdvertisementHandle, Type as AdvertisementType},
-    gatt::{
-        local::{
-            characteristic_control, service_control, Application, ApplicationHandle,
-            Characteristic, CharacteristicControlEvent, CharacteristicNotify,
-            CharacteristicNotifyMethod, CharacteristicWrite, CharacteristicWriteMethod, Service,
-        },
-        CharacteristicReader, CharacteristicWriter,
+    gatt::local::{
+        Application, ApplicationHandle, Characteristic, CharacteristicControlHandle,
+        CharacteristicNotify, CharacteristicNotifyMethod, CharacteristicRead, CharacteristicWrite,
+        CharacteristicWriteMethod, ReqError, ReqResult, Service, ServiceControlHandle,
     },
-    Session,
+    Adapter, Address, Session,
 };
-use futures::{future, pin_mut, StreamExt};
+use boost::AdvertisingBoost;
+use builder::BlePeripheralBuilder;
+use capture::{CapturedFrame, FrameCapture, RawChunk};
+use codec::MessageCodec;
+use config::PeripheralConfig;
+use connection::ConnectionData;
+use control::ControlMessage;
+use delimiter::delimit_text;
+use engine::{Engine, EngineChannels, LinkEvent, Notifier, ReceiveChannels, WriteRequest};
+use envelope::{BleEnvelope, MessageSource, ReceivedMessage};
+use error::BleError;
+use event::{BleEngineEvent, ConnectionInfo, DisconnectReason};
+use fallback::WriteFallback;
+use futures::channel::mpsc as stream_mpsc;
+use futures::{future, Future, FutureExt, Stream};
+use handshake::Capabilities;
+use image::DynamicImage;
 use message::BleMessage;
+use metrics::{MetricsRecorder, NotificationMetrics, Throughput};
+use outgoing::OutgoingMessage;
+use queue::{ReceiveQueue, SendLimit, SendOverflowPolicy};
+use raw_write::{handle_raw_write, RawWriteRequest};
+use read::ReadResponse;
+use readvertise::AdapterAdvertiser;
+use report::{ReportSender, SendReport};
+use security::SecurityLevel;
+use state::EngineState;
 use std::error::Error;
+use std::sync::{Arc, Mutex};
+use tlv::{encode_records, TlvRecord};
 use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
-    sync::{mpsc, watch},
+    sync::{broadcast, mpsc, oneshot, watch, Semaphore},
     task::JoinHandle,
+    time::{Duration, Instant},
 };
+use transfer::{TransferState, FILE_CHUNK_HEADER_SIZE};
+use transport::{BluerTransport, GattRelay, Transport};
 use uuid::Uuid;
 
-static SERVICE_UUID: Uuid = Uuid::from_u128(0x0000181C00001000800000805F9B34FB);
-static CHARACTERISTIC_UUID: Uuid = Uuid::from_u128(0x00002AC400001000800000805F9B34FB);
+/// UUID of the GATT service served by the peripheral (User Data service, 0x181C).
+/// A matching central should discover this service to talk to the peripheral.
+pub const DEFAULT_SERVICE_UUID: Uuid = Uuid::from_u128(0x0000181C00001000800000805F9B34FB);
+
+/// UUID of the characteristic used for writing and notifying messages (Object Action Control Point, 0x2AC4).
+pub const DEFAULT_CHARACTERISTIC_UUID: Uuid = Uuid::from_u128(0x00002AC400001000800000805F9B34FB);
+
+/// Time given to the BLE thread to flush the queued messages when stopping.
+const STOP_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Interval between two checks of the adapter advertising state.
+const ADVERTISING_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Handler receiving messages in place of the receive queue.
+pub type MessageHandler = Arc<dyn Fn(BleMessage) + Send + Sync>;
+
+/// Validator deciding whether the payload of a write request is acceptable.
+pub type WriteValidator = Arc<dyn Fn(&[u8]) -> bool + Send + Sync>;
+
+/// Handler invoked with a message sent while the send queue is full.
+pub type SendOverflowHandler = Box<dyn Fn(&BleMessage) + Send>;
 
 /// BLE peripheral utility.
 /// For creating a BLE peripheral device that can be connected to a central device.
 pub struct BlePeripheral {
     pub alias: Option<String>,
-    sender: Option<mpsc::UnboundedSender<BleMessage>>,
-    receiver: Option<mpsc::UnboundedReceiver<BleMessage>>,
+    config: PeripheralConfig,
+    sender: Option<mpsc::UnboundedSender<OutgoingMessage>>,
+    receiver: Option<Arc<ReceiveQueue>>,
     app_handler: Option<ApplicationHandle>,
-    adv_handler: Option<AdvertisementHandle>,
+    adv_handler: Arc<Mutex<Option<AdvertisementHandle>>>,
+    adv_boost: Arc<AdvertisingBoost>,
     ble_thread: Option<JoinHandle<()>>,
+    /// Watcher whose sender is dropped once the BLE thread exits, whether it ended or was aborted.
+    ble_thread_exit: Option<watch::Receiver<()>>,
+    state: EngineState,
+    adapter: Option<Adapter>,
+    gatt_relay: Option<GattRelay>,
+    readvertised: Option<Arc<Mutex<Advertisement>>>,
+    readvertise_task: Option<JoinHandle<()>>,
+    rotated_alias: Arc<Mutex<Option<String>>>,
+    alias_rotation_task: Option<JoinHandle<()>>,
+    ready: watch::Sender<bool>,
+    connection_info: watch::Sender<Option<ConnectionInfo>>,
+    deferred_advertising: Option<JoinHandle<()>>,
+    beacon_data: Vec<u8>,
     subscribed_watcher: Option<watch::Receiver<bool>>,
+    last_notified_watcher: Option<watch::Receiver<Option<Vec<u8>>>>,
+    metrics: Option<Arc<MetricsRecorder>>,
+    mtu_watcher: Option<watch::Receiver<Option<usize>>>,
+    capabilities_watcher: Option<watch::Receiver<Option<Capabilities>>>,
+    peer_version_watcher: Option<watch::Receiver<Option<u8>>>,
+    write_validator: Option<WriteValidator>,
+    events: broadcast::Sender<BleEngineEvent>,
+    battery_level: Option<watch::Sender<u8>>,
+    message_handler: Arc<Mutex<Option<MessageHandler>>>,
+    connection_data: Arc<ConnectionData>,
+    control: broadcast::Sender<ControlMessage>,
+    raw_chunks: broadcast::Sender<RawChunk>,
+    next_ping_nonce: u32,
+    file_transfer: Arc<TransferState>,
+    frame_capture: Option<Arc<FrameCapture>>,
+    saved_discoverable: Option<DiscoverableState>,
+    saved_adapter_alias: Mutex<Option<AdapterAliasState>>,
+    write_fallback: Option<Arc<WriteFallback>>,
+    offset_sender: Option<mpsc::UnboundedSender<(u16, Vec<u8>)>>,
+    offset_receiver: Option<mpsc::UnboundedReceiver<(u16, Vec<u8>)>>,
+    raw_write_sender: Option<stream_mpsc::UnboundedSender<RawWriteRequest>>,
+    raw_write_receiver: Option<stream_mpsc::UnboundedReceiver<RawWriteRequest>>,
+    send_limit: Option<Arc<SendLimit>>,
+    inflight_writes: Option<Arc<Semaphore>>,
+    send_overflow_handler: Mutex<Option<SendOverflowHandler>>,
+    read_response: Arc<ReadResponse>,
+    initial_messages: Arc<Mutex<Vec<BleMessage>>>,
+    queued_before_start: Mutex<Vec<OutgoingMessage>>,
 }
 
 impl BlePeripheral {
     /// Create a new BLE peripheral with the given alias.
     pub async fn new(alias: Option<String>) -> Result<BlePeripheral, Box<dyn Error>> {
-        let sender = None;
-        let reader = None;
-        let app_handler = None;
-        let adv_handler = None;
-        let ble_thread = None;
-        let subscribed_watcher = None;
-
-        Ok(BlePeripheral {
-            sender,
-            receiver: reader,
-            alias,
-            app_handler,
-            adv_handler,
-            ble_thread,
-            subscribed_watcher,
-        })
+        let mut builder = Self::builder();
+        if let Some(alias) = alias {
+            builder = builder.alias(alias);
+        }
+        Ok(builder.build()?)
+    }
+
+    /// Create a new BLE peripheral from a configuration, such as one loaded with
+    /// `PeripheralConfig::from_toml`.
+    pub fn from_config(config: PeripheralConfig) -> Result<BlePeripheral, BleError> {
+        BlePeripheralBuilder::from_config(config).build()
+    }
+
+    /// Return the configuration of the peripheral, such as for saving it to a file.
+    /// UUIDs rotated by `rotate_uuids` are included.
+    pub fn config(&self) -> &PeripheralConfig {
+        &self.config
+    }
+
+    /// Create a builder for configuring a new BLE peripheral.
+    pub fn builder() -> BlePeripheralBuilder {
+        BlePeripheralBuilder::new()
+    }
+
+    /// List the Bluetooth adapters available on the system, with their names, addresses, and powered state.
+    pub async fn available_adapters() -> Result<Vec<AdapterInfo>, BleError> {
+        let session = Session::new().await?;
+        adapter::list_adapters(&session).await
+    }
+
+    /// Return the advertising features supported by the adapter, so unsupported ones can be
+    /// avoided before starting the engine. The adapter in use is queried once the engine is
+    /// started, the default one before.
+    pub async fn adapter_capabilities(&self) -> Result<AdapterCapabilities, BleError> {
+        match self.adapter.as_ref() {
+            Some(adapter) => adapter::query_capabilities(adapter).await,
+            None => {
+                let session = Session::new().await?;
+                adapter::query_capabilities(&session.default_adapter().await?).await
+            }
+        }
+    }
+
+    /// Mark the application as ready, so the advertisement withheld by `defer_advertising` is
+    /// registered. The peripheral stays ready from then on, also across engine restarts.
+    pub fn set_ready(&self) {
+        self.ready.send_replace(true);
+    }
+
+    /// Check whether the advertisement is withheld until `set_ready` is called.
+    fn advertising_withheld(&self) -> bool {
+        self.config.defer_advertising && !*self.ready.borrow()
     }
 
     /// Start the BLE peripheral advertising and GATT service
+    /// Return `BleError::AlreadyRunning` if the engine is already started, as starting it again
+    /// would leak the running BLE thread. Stop the engine first to restart it.
+    /// If the startup fails, such as when the GATT application cannot be registered, whatever was
+    /// registered so far is released, including the advertisement.
     pub async fn start_engine(&mut self) -> Result<(), Box<dyn Error>> {
-        // Initialize the BLE session and adapter
-        let session = Session::new().await?;
-        let adapter = session.default_adapter().await?;
-        adapter.set_powered(true).await?;
-        adapter.set_discoverable(true).await.unwrap();
-        adapter.set_discoverable_timeout(0).await.unwrap();
-
-        // Configure the advertisement
-        let adv = Advertisement {
-            service_uuids: vec![SERVICE_UUID].into_iter().collect(),
+        self.start_with(BluerTransport::default()).await
+    }
+
+    /// Start the BLE peripheral like `start_engine`, on the adapter with the given address instead
+    /// of the default one, such as to tell apart several identical adapters.
+    /// Return `BleError::AdapterNotFound` with the addresses of the available adapters if none
+    /// has the address.
+    pub async fn start_engine_on_address(
+        &mut self,
+        address: Address,
+    ) -> Result<(), Box<dyn Error>> {
+        let transport = BluerTransport {
+            adapter_address: Some(address),
+        };
+        self.start_with(transport).await
+    }
+
+    /// Start the BLE peripheral like `start_engine`, giving up once `timeout` has elapsed, since
+    /// the Bluetooth stack can hang instead of failing when it is wedged.
+    /// On failure, whatever was registered so far is released, and `BleError::StartupTimeout` is
+    /// returned if the startup timed out.
+    pub async fn start_engine_with_timeout(
+        &mut self,
+        timeout: Duration,
+    ) -> Result<(), Box<dyn Error>> {
+        self.start_with_timeout(BluerTransport::default(), timeout)
+            .await
+    }
+
+    /// Open the link to the central device through the given transport and start the BLE thread
+    /// handling its events.
+    pub(crate) async fn start_with<T: Transport>(
+        &mut self,
+        transport: T,
+    ) -> Result<(), Box<dyn Error>> {
+        self.prepare_start().await?;
+        self.state = EngineState::Starting;
+        let link = match transport.open(self).await {
+            Ok(link) => link,
+            Err(err) => {
+                // The advertisement may be registered even though the GATT application is not
+                self.abort_start().await;
+                return Err(err);
+            }
+        };
+        self.spawn_engine(link.events, link.write_rx);
+        Ok(())
+    }
+
+    /// Check that the engine can be started, releasing the link left behind by a BLE thread that
+    /// exited on its own or by a start that was cancelled.
+    async fn prepare_start(&mut self) -> Result<(), BleError> {
+        if self.is_running() {
+            return Err(BleError::AlreadyRunning);
+        }
+        if self.state != EngineState::Stopped {
+            log::warn!("Releasing the stale link of the previous start");
+            self.release_link().await;
+            self.state = EngineState::Stopped;
+        }
+        Ok(())
+    }
+
+    /// Start the BLE thread through the given transport, releasing the partially opened link if
+    /// it fails or does not open within `timeout`.
+    pub(crate) async fn start_with_timeout<T: Transport>(
+        &mut self,
+        transport: T,
+        timeout: Duration,
+    ) -> Result<(), Box<dyn Error>> {
+        // Checked before the timeout, so the running engine is not released on failure
+        self.prepare_start().await?;
+        let result = match tokio::time::timeout(timeout, self.start_with(transport)).await {
+            Ok(result) => result,
+            Err(_) => Err(BleError::StartupTimeout.into()),
+        };
+        if result.is_err() {
+            self.abort_start().await;
+        }
+        result
+    }
+
+    /// Release whatever a failed start registered so far, leaving the engine stopped.
+    async fn abort_start(&mut self) {
+        self.state = EngineState::Stopped;
+        self.release_link().await;
+        drop(self.offset_receiver.take());
+        drop(self.raw_write_receiver.take());
+    }
+
+    /// Wait until the advertisement is actually broadcasting at the controller level.
+    /// Registering the advertisement can succeed before the controller starts advertising, or even
+    /// if it rejects the advertisement, so this checks the active advertising instances of the adapter.
+    /// Return `BleError::Timeout` if the advertisement is still not active once `timeout` has elapsed.
+    pub async fn wait_until_advertising(&self, timeout: Duration) -> Result<(), BleError> {
+        let adapter = self.adapter.as_ref().ok_or(BleError::EngineNotStarted)?;
+        let poll = async {
+            while adapter.active_advertising_instances().await? == 0 {
+                tokio::time::sleep(ADVERTISING_POLL_INTERVAL).await;
+            }
+            Ok(())
+        };
+        tokio::time::timeout(timeout, poll)
+            .await
+            .map_err(|_| BleError::Timeout)?
+    }
+
+    /// Return the alias of the Bluetooth adapter, which some systems show for the device instead
+    /// of the local name of the advertisement.
+    pub async fn adapter_alias(&self) -> Result<String, BleError> {
+        let adapter = self.adapter.as_ref().ok_or(BleError::EngineNotStarted)?;
+        Ok(adapter.alias().await?)
+    }
+
+    /// Set the alias of the Bluetooth adapter, distinct from the local name of the advertisement.
+    /// This changes the alias of the whole adapter, for every application using it, so the alias
+    /// it had before the first change is restored when the engine stops.
+    pub async fn set_adapter_alias(&self, alias: &str) -> Result<(), BleError> {
+        let adapter = self.adapter.as_ref().ok_or(BleError::EngineNotStarted)?;
+        validate_alias(alias)?;
+        if self.saved_adapter_alias.lock().unwrap().is_none() {
+            let saved = AdapterAliasState::save(adapter).await?;
+            // Keep the alias saved by a concurrent change, which saw the original one too
+            self.saved_adapter_alias
+                .lock()
+                .unwrap()
+                .get_or_insert(saved);
+        }
+        Ok(adapter.set_alias(alias.to_string()).await?)
+    }
+
+    /// Return the security level of the link with the connected central device, queried from its
+    /// properties, so sensitive commands can be restricted to secure links.
+    /// Return `None` if the engine is not started, if no central is connected, or if the
+    /// properties cannot be read.
+    pub async fn security_level(&self) -> Option<SecurityLevel> {
+        let adapter = self.adapter.as_ref()?;
+        match security::connected_security_level(adapter).await {
+            Ok(level) => level,
+            Err(err) => {
+                log::error!("Security level query failed: {}", &err);
+                None
+            }
+        }
+    }
+
+    /// Initialize the channels and start the BLE thread handling the characteristic events.
+    fn spawn_engine<Q, N, E>(&mut self, events: E, write_rx: mpsc::UnboundedReceiver<Vec<u8>>)
+    where
+        Q: WriteRequest,
+        N: Notifier,
+        E: Stream<Item = LinkEvent<Q, N>> + Send + 'static,
+    {
+        // Initialize the send channel
+        let (send_tx, send_rx) = mpsc::unbounded_channel();
+        self.sender = Some(send_tx);
+
+        // Initialize the receive queue
+        let receive_queue = Arc::new(ReceiveQueue::new(
+            self.config.receive_capacity,
+            self.config.receive_overflow_policy,
+        ));
+        self.receiver = Some(receive_queue.clone());
+
+        // Initialize the subscribed watcher
+        let (subscribed_tx, subscribed_watch_rx) = watch::channel(false);
+        self.subscribed_watcher = Some(subscribed_watch_rx);
+
+        // Initialize the last notified value watcher
+        let (last_notified_tx, last_notified_rx) = watch::channel(None);
+        self.last_notified_watcher = Some(last_notified_rx);
+
+        // Initialize the notification metrics
+        let metrics = Arc::new(MetricsRecorder::new());
+        self.metrics = Some(metrics.clone());
+
+        // Initialize the MTU watcher
+        let (mtu_tx, mtu_rx) = watch::channel(None);
+        self.mtu_watcher = Some(mtu_rx);
+
+        // Initialize the negotiated capabilities watcher
+        let (capabilities_tx, capabilities_rx) = watch::channel(None);
+        self.capabilities_watcher = Some(capabilities_rx);
+
+        // Initialize the peer protocol version watcher
+        let (peer_version_tx, peer_version_rx) = watch::channel(None);
+        self.peer_version_watcher = Some(peer_version_rx);
+
+        let channels = EngineChannels {
+            send_rx,
+            subscribed_tx,
+            last_notified_tx,
+            mtu_tx,
+            capabilities_tx,
+            peer_version_tx,
+            events: self.events.clone(),
+            connection_data: self.connection_data.clone(),
+            frame_capture: self.frame_capture.clone(),
+            write_fallback: self.write_fallback.clone(),
+            metrics,
+            initial_messages: self.initial_messages.clone(),
+            queued_before_start: std::mem::take(self.queued_before_start.get_mut().unwrap()),
+            connection_tx: self.connection_info.clone(),
+        };
+        let receive_channels = ReceiveChannels {
+            write_rx,
+            receive_queue,
+            message_handler: self.message_handler.clone(),
+            control: self.control.clone(),
+            raw_chunks: self.raw_chunks.clone(),
+            file_transfer: self.file_transfer.clone(),
+        };
+        let engine: Engine<Q, N> = Engine::new(channels, receive_channels, &self.config);
+
+        // Store the BLE thread handle, along with the watcher closed once it exits
+        let (exit_tx, exit_rx) = watch::channel(());
+        self.ble_thread_exit = Some(exit_rx);
+        self.ble_thread = Some(tokio::spawn(async move {
+            engine.run(events).await;
+            drop(exit_tx);
+        }));
+        self.state = EngineState::Running;
+    }
+
+    /// Build the advertisement announcing the peripheral.
+    /// The advertising intervals are shortened while advertising is boosted.
+    fn advertisement(&self) -> Advertisement {
+        match self.adv_boost.is_active() {
+            true => boost::boosted(self.base_advertisement()),
+            false => self.base_advertisement(),
+        }
+    }
+
+    /// Return the alias advertised as the local name of the peripheral, as last rotated if the
+    /// alias rotates.
+    pub fn current_alias(&self) -> Option<String> {
+        let rotated = self.rotated_alias.lock().unwrap().clone();
+        rotated.or_else(|| self.alias.clone())
+    }
+
+    /// Build the advertisement announcing the peripheral with the default advertising intervals.
+    /// Beacons broadcast their data as the service data of their service, without accepting
+    /// connections.
+    fn base_advertisement(&self) -> Advertisement {
+        if self.config.beacon_mode {
+            return Advertisement {
+                service_data: [(self.service_uuid(), self.beacon_data.clone())].into(),
+                advertisement_type: AdvertisementType::Broadcast,
+                local_name: self.current_alias(),
+                ..Default::default()
+            };
+        }
+        Advertisement {
+            service_uuids: vec![self.service_uuid()].into_iter().collect(),
             advertisement_type: AdvertisementType::Peripheral,
             discoverable: Some(true),
-            local_name: self.alias.clone(),
+            local_name: self.current_alias(),
             ..Default::default()
+        }
+    }
+
+    /// Advertise with shorter intervals for `window`, to speed up discovery and reconnection,
+    /// then revert to the default intervals. Boosting again before the window is over extends it.
+    /// Has no effect while the advertisement is withheld until `set_ready` is called.
+    pub async fn boost_advertising(&mut self, window: Duration) -> Result<(), BleError> {
+        let adapter = self.adapter.clone().ok_or(BleError::EngineNotStarted)?;
+        let adv = self
+            .readvertised
+            .clone()
+            .ok_or(BleError::EngineNotStarted)?;
+        if self.advertising_withheld() {
+            return Ok(());
+        }
+        let deadline = self.adv_boost.extend(window);
+
+        // Registering the boosted advertisement unregisters the current one
+        let handle = adapter.advertise(self.advertisement()).await?;
+        *self.adv_handler.lock().unwrap() = Some(handle);
+
+        // Revert to the shared advertisement as it is once the window is over, with its changes
+        let advertiser = AdapterAdvertiser {
+            adapter,
+            adv,
+            boost: self.adv_boost.clone(),
         };
+        tokio::spawn(readvertise::revert_boost(
+            advertiser,
+            self.adv_boost.clone(),
+            deadline,
+            self.adv_handler.clone(),
+        ));
+        Ok(())
+    }
+
+    /// Return the UUID of the service, as rotated by `rotate_uuids`.
+    fn service_uuid(&self) -> Uuid {
+        self.config.service_uuid.unwrap_or(DEFAULT_SERVICE_UUID)
+    }
+
+    /// Return the UUID of the characteristic receiving the writes, as rotated by `rotate_uuids`.
+    /// Without rotation, it is the command characteristic of the command/response layout if set.
+    fn characteristic_uuid(&self) -> Uuid {
+        self.config
+            .characteristic_uuid
+            .or(self
+                .config
+                .command_response
+                .map(|(command_uuid, _)| command_uuid))
+            .unwrap_or(DEFAULT_CHARACTERISTIC_UUID)
+    }
+
+    /// Replace the UUIDs of the service and of the characteristic receiving the writes, such as
+    /// when deployments rotate them periodically for privacy.
+    /// While the engine runs, the GATT application and the advertisement are registered again
+    /// with the new UUIDs without stopping the engine: the queued messages are kept, and the new
+    /// application is served before the old one is removed. Connected centrals lose the old
+    /// characteristics, so they need to discover the services again and subscribe to the new
+    /// characteristic. Otherwise, the UUIDs are used from the next engine start.
+    pub async fn rotate_uuids(
+        &mut self,
+        service: Uuid,
+        characteristic: Uuid,
+    ) -> Result<(), BleError> {
+        self.config.service_uuid = Some(service);
+        self.config.characteristic_uuid = Some(characteristic);
+        let adapter = match self.adapter.clone() {
+            Some(adapter) => adapter,
+            None => return Ok(()),
+        };
+
+        // Serve the new application first, so the central never sees the peripheral without one
+        if let Some(relay) = self.gatt_relay.clone() {
+            let (app, events) = transport::gatt_link(self, &relay.write_tx);
+            let app_handle = adapter.serve_gatt_application(app).await?;
+            relay
+                .events_tx
+                .unbounded_send(events)
+                .map_err(|_| BleError::ChannelClosed)?;
+            self.app_handler = Some(app_handle);
+        }
+        self.refresh_advertisement(&adapter).await
+    }
+
+    /// Register the advertisement again, so it reflects the current UUIDs and broadcast data,
+    /// also when it is registered again after the system removed it.
+    async fn refresh_advertisement(&self, adapter: &Adapter) -> Result<(), BleError> {
+        if let Some(readvertised) = self.readvertised.as_ref() {
+            *readvertised.lock().unwrap() = self.base_advertisement();
+        }
+        if self.advertising_withheld() {
+            return Ok(());
+        }
+        let handle = adapter.advertise(self.advertisement()).await?;
+        *self.adv_handler.lock().unwrap() = Some(handle);
+        Ok(())
+    }
+
+    /// Replace the data broadcast by the beacon, advertised as the service data of its service.
+    /// Calling this periodically keeps the broadcast up to date, such as for sensor readings.
+    /// Return `BleError::NotBeacon` unless beacon mode is enabled, or
+    /// `BleError::AdvertisementTooLarge` if the data does not fit in the advertisement.
+    pub async fn broadcast_data(&mut self, data: Vec<u8>) -> Result<(), BleError> {
+        if !self.config.beacon_mode {
+            return Err(BleError::NotBeacon);
+        }
+        let previous = std::mem::replace(&mut self.beacon_data, data);
+        if let Err(err) = advertisement::validate_advertisement(&self.base_advertisement()) {
+            self.beacon_data = previous;
+            return Err(err);
+        }
+        match self.adapter.clone() {
+            Some(adapter) => self.refresh_advertisement(&adapter).await,
+            None => Ok(()),
+        }
+    }
 
-        // Initialize the GATT service and characteristic handles
-        let (_, service_handle) = service_control();
-        let (char_control, char_handle) = characteristic_control();
-
-        // Configure the GATT application
-        let app = Application {
-            services: vec![Service {
-                uuid: SERVICE_UUID,
-                primary: true,
-                characteristics: vec![Characteristic {
-                    uuid: CHARACTERISTIC_UUID,
-                    write: Some(CharacteristicWrite {
-                        write: true,
-                        write_without_response: false,
-                        method: CharacteristicWriteMethod::Io,
+    /// Build the GATT application exposing the message characteristic.
+    /// Writes are received over IO, unless a write validator is set, in which case each write
+    /// is validated and delivered through a write function so invalid ones can be rejected.
+    /// With offset writes, each write is delivered along with its offset through a write function.
+    /// With the command/response layout, writes and notifications are split over two characteristics,
+    /// the response one being controlled by `response_handle`.
+    /// With read responses, the characteristic receiving the writes can also be read, returning
+    /// the value staged with `respond_to_read`.
+    fn gatt_application(
+        &self,
+        service_handle: ServiceControlHandle,
+        char_handle: CharacteristicControlHandle,
+        response_handle: Option<CharacteristicControlHandle>,
+        priority_handle: Option<CharacteristicControlHandle>,
+        write_tx: &mpsc::UnboundedSender<Vec<u8>>,
+    ) -> Application {
+        // Writes handled one request at a time are declined like the sessions of the engine
+        let admission = Admission::new(&self.config, self.events.clone());
+        let write_method = match (
+            self.raw_write_sender.clone(),
+            self.offset_sender.clone(),
+            self.write_validator.clone(),
+        ) {
+            (Some(requests_tx), _, _) => {
+                CharacteristicWriteMethod::Fun(Box::new(move |value, req| {
+                    if let Err(err) =
+                        admission.check_write(Some(req.device_address), req.mtu as usize)
+                    {
+                        return async move { Err(err) }.boxed();
+                    }
+                    handle_raw_write(&requests_tx, req.mtu as usize, req.offset, value).boxed()
+                }))
+            }
+            (None, Some(offset_tx), validator) => {
+                CharacteristicWriteMethod::Fun(Box::new(move |value, req| {
+                    let result = admission
+                        .check_write(Some(req.device_address), req.mtu as usize)
+                        .and_then(|()| {
+                            handle_offset_write(validator.as_ref(), req.offset, value, &offset_tx)
+                        });
+                    async move { result }.boxed()
+                }))
+            }
+            (None, None, Some(validator)) => {
+                let write_tx = write_tx.clone();
+                CharacteristicWriteMethod::Fun(Box::new(move |value, req| {
+                    let result = admission
+                        .check_write(Some(req.device_address), req.mtu as usize)
+                        .and_then(|()| handle_validated_write(&validator, value, &write_tx));
+                    async move { result }.boxed()
+                }))
+            }
+            (None, None, None) => CharacteristicWriteMethod::Io,
+        };
+        let mut write = CharacteristicWrite {
+            write: true,
+            write_without_response: self.uses_write_without_response(),
+            method: write_method,
+            ..Default::default()
+        };
+        self.config.permissions.apply_to_write(&mut write);
+        let read = self.config.read_responses.then(|| {
+            let read_response = self.read_response.clone();
+            let mut read = CharacteristicRead {
+                read: true,
+                fun: Box::new(move |req| {
+                    let result = read_response.read(req.offset);
+                    async move { result }.boxed()
+                }),
+                ..Default::default()
+            };
+            self.config.permissions.apply_to_read(&mut read);
+            read
+        });
+        let notify = || CharacteristicNotify {
+            notify: true,
+            method: CharacteristicNotifyMethod::Io,
+            ..Default::default()
+        };
+
+        let characteristics = match (
+            self.config.command_response,
+            self.config.priority_channels,
+            response_handle,
+            priority_handle,
+        ) {
+            (_, Some((priority_uuid, bulk_uuid)), Some(bulk_handle), Some(priority_handle)) => {
+                vec![
+                    Characteristic {
+                        uuid: self.characteristic_uuid(),
+                        read,
+                        write: Some(write),
+                        control_handle: char_handle,
                         ..Default::default()
-                    }),
-                    notify: Some(CharacteristicNotify {
-                        notify: true,
-                        method: CharacteristicNotifyMethod::Io,
+                    },
+                    Characteristic {
+                        uuid: bulk_uuid,
+                        notify: Some(notify()),
+                        control_handle: bulk_handle,
+                        ..Default::default()
+                    },
+                    Characteristic {
+                        uuid: priority_uuid,
+                        notify: Some(notify()),
+                        control_handle: priority_handle,
                         ..Default::default()
-                    }),
+                    },
+                ]
+            }
+            (Some((_, response_uuid)), None, Some(response_handle), _) => vec![
+                Characteristic {
+                    uuid: self.characteristic_uuid(),
+                    read,
+                    write: Some(write),
                     control_handle: char_handle,
                     ..Default::default()
-                }],
-                control_handle: service_handle,
+                },
+                Characteristic {
+                    uuid: response_uuid,
+                    notify: Some(notify()),
+                    control_handle: response_handle,
+                    ..Default::default()
+                },
+            ],
+            _ => vec![Characteristic {
+                uuid: self.characteristic_uuid(),
+                read,
+                write: Some(write),
+                notify: Some(notify()),
+                control_handle: char_handle,
                 ..Default::default()
             }],
-            ..Default::default()
         };
 
-        // Start the BLE advertisement and GATT application
-        self.adv_handler = Some(adapter.advertise(adv).await?);
-        self.app_handler = Some(adapter.serve_gatt_application(app).await?);
+        let mut services = vec![Service {
+            uuid: self.service_uuid(),
+            primary: true,
+            characteristics,
+            control_handle: service_handle,
+            ..Default::default()
+        }];
+        if let Some(battery_level) = self.battery_level.as_ref() {
+            services.push(battery::battery_service(battery_level));
+        }
 
-        // Initialize the send channel
-        let (send_tx, mut send_rx) = mpsc::unbounded_channel();
-        self.sender = Some(send_tx);
+        Application {
+            services,
+            ..Default::default()
+        }
+    }
 
-        // Initialize the receive channel
-        let (receive_tx, receive_rx) = mpsc::unbounded_channel();
-        self.receiver = Some(receive_rx);
+    /// Check whether the characteristic is served with writes without response.
+    /// This turns false once the fallback to acknowledged writes is triggered.
+    pub fn uses_write_without_response(&self) -> bool {
+        let fallen_back = self
+            .write_fallback
+            .as_ref()
+            .map(|fallback| fallback.is_triggered())
+            .unwrap_or(false);
+        self.config.write_without_response && !fallen_back
+    }
 
-        // Initialize the subscribed watcher
-        let (subscribed_watch_tx, subscribed_watch_rx) = watch::channel(false);
-        self.subscribed_watcher = Some(subscribed_watch_rx);
+    /// Stop the BLE peripheral advertising and GATT service.
+    /// The goodbye message, if configured, and the messages still queued are flushed to the central
+    /// before the notification session is closed, unless this takes longer than the stop timeout.
+    /// The reason, if any, is logged and reported to the event subscribers in a
+    /// `BleEngineEvent::Closed` event, to correlate the stop with the rest of the application.
+    pub async fn stop_engine(&mut self, reason: Option<String>) {
+        log::info!(
+            "Stopping engine: {}",
+            reason.as_deref().unwrap_or("no reason given")
+        );
 
-        // Start the BLE thread
-        let ble_thread = tokio::spawn(async move {
-            pin_mut!(char_control);
+        // Tell the central that the peripheral is going away
+        if let Some(goodbye) = self.config.goodbye_message.clone() {
+            if let Err(err) = self.send_message(goodbye).await {
+                log::error!("Goodbye message error: {}", &err);
+            }
+        }
 
-            // Initialize the read buffer and notifier/reciever operators
-            let mut receive_buffer = Vec::new();
-            let mut receiver_opt: Option<CharacteristicReader> = None;
-            let mut notifier_opt: Option<CharacteristicWriter> = None;
+        // Close the send channel so the BLE thread flushes the queued messages and exits
+        self.state = EngineState::Stopping;
+        drop(self.sender.take());
+        if let Some(mut ble_thread) = self.ble_thread.take() {
+            if tokio::time::timeout(STOP_TIMEOUT, &mut ble_thread)
+                .await
+                .is_err()
+            {
+                ble_thread.abort();
+                ble_thread.await.unwrap_or(());
+            }
+        }
+        self.release_link().await;
+        self.state = EngineState::Stopped;
 
-            loop {
-                // Handle GATT, notify, and receive events concurrently
-                tokio::select! {
-                    // Handle the GATT events
-                    evt = char_control.next() => {
-                        match evt {
-                            // Handle the write event
-                            Some(CharacteristicControlEvent::Write(req)) => {
-                                log::debug!("Accepting write request event with MTU {}", req.mtu());
-                                receive_buffer = vec![0;req.mtu()];
-                                receiver_opt = Some(req.accept().unwrap());
-                            },
-                            // Handle the notify event
-                            Some(CharacteristicControlEvent::Notify(notifier)) => {
-                                log::debug!("Accepting notify request event with MTU {}", notifier.mtu());
-                                notifier_opt = Some(notifier);
-                                subscribed_watch_tx.send(true).unwrap();
-                            },
-                            _ => {},
-                        }
-                    },
+        self.connection_info.send_replace(None);
 
-                    // Handle the notification event
-                    notify_message = send_rx.recv() => {
-                        if notifier_opt.is_some() && notify_message.is_some() {
-                            // Convert the message to a byte array
-                            log::debug!("Notifying message {:x?}", notify_message);
-                            let message_bytes = notify_message.unwrap().take_bytes();
-
-                            // Write the message to the notify opterator
-                            if let Err(err) = notifier_opt.as_mut().unwrap().write_all(&message_bytes).await {
-                                log::error!("Write failed: {}", &err);
-                                notifier_opt = None;
-                                subscribed_watch_tx.send(false).unwrap();
-                            }
-                        }
-                    },
+        // Wake up the receivers, which would otherwise wait for messages that never come
+        if let Some(receiver) = self.receiver.as_ref() {
+            receiver.close();
+        }
 
-                    // Handle the receive event
-                    received_length = async {
-                        match &mut receiver_opt {
-                            Some(receiver) => receiver.read(&mut receive_buffer).await,
-                            None => future::pending().await,
-                        }
-                    } => {
-                        match received_length {
-                            // Message received
-                            Ok(n) => {
-                                // Read the message
-                                let received_message = receive_buffer[..n].to_vec();
-                                log::debug!("Received message: {:?}", received_message);
-
-                                // Send the message to the receiver
-                                if let Err(err) = receive_tx.send(received_message.into()) {
-                                    log::error!("Receive message error: {:?}", &err);
-                                }
-                            }
-
-                            Err(err) => {
-                                log::error!("Read stream error: {}", &err);
-                            }
-                        }
-                        receiver_opt = None;
-                    }
-                }
+        event::emit(
+            &self.events,
+            BleEngineEvent::Closed {
+                reason: DisconnectReason::EngineStopped(reason),
+            },
+        );
+    }
+
+    /// Wait until the BLE thread exits, whether it was stopped or ended on its own, such as to
+    /// supervise the peripheral along with other tasks in a `select!`.
+    /// Resolve at once if the engine is not started. The returned future does not borrow the
+    /// peripheral, so it can still be used, and stopped with `stop_engine`, while waiting.
+    /// Waiting can be cancelled without affecting the BLE thread.
+    pub fn wait(&self) -> impl Future<Output = ()> {
+        let exit = self.ble_thread_exit.clone();
+        async move {
+            if let Some(mut exit) = exit {
+                // Changes only fail once the sender is dropped by the exiting BLE thread
+                while exit.changed().await.is_ok() {}
             }
-        });
+        }
+    }
 
-        // Store the BLE thread handle
-        self.ble_thread = Some(ble_thread);
+    /// Check if the engine is started and its BLE thread is still alive.
+    pub fn is_running(&self) -> bool {
+        self.ble_thread
+            .as_ref()
+            .is_some_and(|ble_thread| !ble_thread.is_finished())
+    }
 
-        Ok(())
+    /// Return the lifecycle state of the engine, so operations can be guarded against a
+    /// double start or an engine that is not running.
+    /// A running engine whose BLE thread exited on its own is reported as stopped.
+    pub fn engine_state(&self) -> EngineState {
+        match self.state {
+            EngineState::Running if !self.is_running() => EngineState::Stopped,
+            state => state,
+        }
     }
 
-    /// Stop the BLE peripheral advertising and GATT service.
-    pub async fn stop_engine(&mut self) {
-        if let Some(ble_thread) = self.ble_thread.take() {
-            ble_thread.abort();
-            ble_thread.await.unwrap_or(());
+    /// Unregister the GATT application and the advertisement, and leave the adapter as it was found.
+    async fn release_link(&mut self) {
+        // Aborting first makes sure the deferred advertisement is never registered afterwards
+        if let Some(deferred_advertising) = self.deferred_advertising.take() {
+            deferred_advertising.abort();
+        }
+        if let Some(readvertise_task) = self.readvertise_task.take() {
+            readvertise_task.abort();
+        }
+        if let Some(alias_rotation_task) = self.alias_rotation_task.take() {
+            alias_rotation_task.abort();
         }
         drop(self.app_handler.take());
-        drop(self.adv_handler.take());
+        drop(self.gatt_relay.take());
+        drop(self.readvertised.take());
+        drop(self.adv_handler.lock().unwrap().take());
+        drop(self.adapter.take());
+        drop(self.offset_sender.take());
+        drop(self.raw_write_sender.take());
+
+        // Leave the adapter as it was found
+        if let Some(saved_discoverable) = self.saved_discoverable.take() {
+            if let Err(err) = saved_discoverable.restore().await {
+                log::error!("Restoring discoverable state failed: {}", &err);
+            }
+        }
+        let saved_adapter_alias = self.saved_adapter_alias.lock().unwrap().take();
+        if let Some(saved_adapter_alias) = saved_adapter_alias {
+            if let Err(err) = saved_adapter_alias.restore().await {
+                log::error!("Restoring adapter alias failed: {}", &err);
+            }
+        }
     }
 
     /// Send a message to the central device.
+    /// Return `BleError::EngineNotStarted` if the engine is not started, unless the message is
+    /// kept for later with `queue_before_start`.
     pub async fn send_message<M>(&self, message: M) -> Result<(), Box<dyn Error>>
     where
         M: Into<BleMessage>,
     {
+        Ok(self.enqueue(OutgoingMessage::new(message.into())).await?)
+    }
+
+    /// Send a message to the central device on the priority characteristic.
+    /// The message is dropped if the central is not subscribed to it, or if the priority
+    /// characteristics are not enabled with `priority_channels`.
+    pub async fn send_priority_message<M>(&self, message: M) -> Result<(), Box<dyn Error>>
+    where
+        M: Into<BleMessage>,
+    {
+        Ok(self
+            .enqueue(OutgoingMessage::priority(message.into()))
+            .await?)
+    }
+
+    /// Send an event to the central device, such as a state change or an alert.
+    /// Events are tagged so the central can tell them apart from data messages with `as_event`.
+    pub async fn send_event<M>(&self, event: M) -> Result<(), Box<dyn Error>>
+    where
+        M: Into<BleMessage>,
+    {
+        Ok(self
+            .enqueue(OutgoingMessage::new(event.into().into_event()))
+            .await?)
+    }
+
+    /// Encode a value with the codec `C` and send it to the central device.
+    pub async fn send_encoded<C, T>(&self, value: &T) -> Result<(), BleError>
+    where
+        C: MessageCodec<T>,
+    {
+        let message = BleMessage::Raw(C::encode(value)?);
+        self.enqueue(OutgoingMessage::new(message)).await
+    }
+
+    /// Send raw bytes to the central device exactly as given, in a single write.
+    /// The bytes bypass the text delimiter, coalescing, MTU splitting, and codecs, so none of the
+    /// framing or reliability features apply to them. Meant for wire-level interoperability testing.
+    pub async fn send_raw_unframed(&self, bytes: Vec<u8>) -> Result<(), BleError> {
+        self.enqueue(OutgoingMessage::unframed(BleMessage::Raw(bytes)))
+            .await
+    }
+
+    /// Send TLV records to the central device in a single message, so the central can tell the
+    /// messages they carry apart by their tags.
+    pub async fn send_records(&self, records: &[TlvRecord]) -> Result<(), Box<dyn Error>> {
+        self.send_message(encode_records(records, self.config.endianness))
+            .await
+    }
+
+    /// Send a message to the central device, dropping it if it is still queued once `ttl` has elapsed.
+    /// This prevents delivering stale data after a backlog builds up.
+    pub async fn send_message_with_ttl<M>(
+        &self,
+        message: M,
+        ttl: Duration,
+    ) -> Result<(), Box<dyn Error>>
+    where
+        M: Into<BleMessage>,
+    {
+        Ok(self
+            .enqueue(OutgoingMessage::with_ttl(message.into(), ttl))
+            .await?)
+    }
+
+    /// Send a message to the central device and wait until it is written, reporting the bytes it took.
+    /// The report tells the size of the message apart from the bytes written on the wire, which
+    /// include the framing such as the text delimiter. The message bypasses coalescing so it is
+    /// accounted for on its own. Return `NotDelivered` if the message is dropped instead, such as
+    /// when the central is not subscribed or the send queue is full.
+    pub async fn send_message_reported<M>(&self, message: M) -> Result<SendReport, BleError>
+    where
+        M: Into<BleMessage>,
+    {
+        let mut outgoing = OutgoingMessage::new(message.into());
+        let (report, report_rx) = ReportSender::new(outgoing.message.as_bytes().len());
+        outgoing.report = Some(report);
+        self.enqueue(outgoing).await?;
+        report_rx.await.map_err(|_| BleError::NotDelivered)
+    }
+
+    /// Flush the notifiers once the messages sent so far are written, including the ones being
+    /// coalesced, so none of them is held back in a buffer.
+    pub async fn flush_notifier(&self) -> Result<(), BleError> {
+        let sender = self.sender.as_ref().ok_or(BleError::EngineNotStarted)?;
+        let (flushed_tx, flushed_rx) = oneshot::channel();
+        sender
+            .send(OutgoingMessage::flush(flushed_tx))
+            .map_err(|_| BleError::ChannelClosed)?;
+        flushed_rx.await.map_err(|_| BleError::ChannelClosed)
+    }
+
+    /// Queue a message to be notified by the BLE thread. Every sent message goes through here.
+    /// The message is framed with the text delimiter and the protocol version first, unless it is
+    /// unframed or a control message, which the central recognizes by its marker alone.
+    /// When the send queue is bounded and full, the overflow handler is invoked and the message is
+    /// dropped or waits for room, following the send overflow policy.
+    async fn enqueue(&self, mut outgoing: OutgoingMessage) -> Result<(), BleError> {
+        if !outgoing.unframed && !outgoing.control {
+            if let Some(delimiter) = self.config.text_delimiter {
+                outgoing.message = delimit_text(outgoing.message, delimiter);
+            }
+            if let Some(version) = self.send_version() {
+                outgoing.message = receive::with_version(outgoing.message, version);
+            }
+        }
+        if let Some((max_bytes, _)) = self.config.coalesce {
+            let len = outgoing.message.as_bytes().len();
+            let coalesced = !outgoing.unframed && !outgoing.priority && outgoing.report.is_none();
+            if coalesced && !coalesce::fits_in_batch(len, max_bytes) {
+                return Err(BleError::InvalidMessage(format!(
+                    "Message of {} bytes does not fit in a coalesced batch of {} bytes",
+                    len, max_bytes
+                )));
+            }
+        }
+
         let sender = match self.sender.as_ref() {
             Some(sender) => sender,
-            None => {
-                return Err("Send channel not initialized".into());
+            None if self.config.queue_before_start => {
+                self.queued_before_start.lock().unwrap().push(outgoing);
+                return Ok(());
             }
+            None => return Err(BleError::EngineNotStarted),
         };
-        sender.send(message.into())?;
-        Ok(())
+        if let Some(limit) = self.send_limit.as_ref() {
+            let permit = match limit.try_acquire() {
+                Some(permit) => permit,
+                None => {
+                    if let Some(handler) = self.send_overflow_handler.lock().unwrap().as_ref() {
+                        handler(&outgoing.message);
+                    }
+                    match limit.policy() {
+                        SendOverflowPolicy::DropNewest => {
+                            log::warn!("Send queue full, dropped message {:?}", outgoing.message);
+                            return Ok(());
+                        }
+                        SendOverflowPolicy::Block => limit.acquire().await,
+                    }
+                }
+            };
+            outgoing.permit = Some(permit);
+        }
+        if let Some(inflight_writes) = self.inflight_writes.as_ref() {
+            // The semaphore is never closed
+            outgoing.write_permit = inflight_writes.clone().acquire_owned().await.ok();
+        }
+        sender.send(outgoing).map_err(|_| BleError::ChannelClosed)
+    }
+
+    /// Stage the value returned when the central reads the characteristic receiving the writes,
+    /// for request/read-response flows where the central reads the response to its request
+    /// instead of being notified. The value is returned by every read until it is replaced.
+    /// The characteristic is only readable if enabled with `read_responses`.
+    pub fn respond_to_read(&self, bytes: Vec<u8>) {
+        self.read_response.stage(bytes);
+    }
+
+    /// Set the messages sent automatically whenever a central subscribes, ahead of the queued
+    /// ones, such as a snapshot of the current state before the incremental updates.
+    /// The messages replace the previous ones and are framed like sent messages, carrying the
+    /// newest protocol version since the version of the central is not known yet.
+    pub fn set_initial_messages(&self, messages: Vec<BleMessage>) {
+        let versions = self.config.message_versions.as_ref();
+        let messages = messages
+            .into_iter()
+            .map(|mut message| {
+                if let Some(delimiter) = self.config.text_delimiter {
+                    message = delimit_text(message, delimiter);
+                }
+                if let Some(versions) = versions {
+                    message = receive::with_version(message, *versions.end());
+                }
+                message
+            })
+            .collect();
+        *self.initial_messages.lock().unwrap() = messages;
+    }
+
+    /// Set a handler invoked with each message sent while the bounded send queue is full,
+    /// whether the message is then dropped or waits for room.
+    pub fn on_send_overflow<F>(&self, handler: F)
+    where
+        F: Fn(&BleMessage) + Send + 'static,
+    {
+        *self.send_overflow_handler.lock().unwrap() = Some(Box::new(handler));
     }
 
     /// Receive a message from the central device.
     /// Receiving is blocking and will wait for the message if it is not ready.
     /// If there are multiple messages, the oldest one will be returned first.
-    pub async fn receive_message(&mut self) -> BleMessage {
+    /// Once the engine stops, the messages left are still returned, then `BleError::ChannelClosed`
+    /// is, so a caller waiting for a message does not hang.
+    pub async fn receive_message(&mut self) -> Result<BleMessage, BleError> {
+        let receiver = self.receiver.as_ref().ok_or(BleError::EngineNotStarted)?;
+        receiver.recv().await.ok_or(BleError::ChannelClosed)
+    }
+
+    /// Receive a message along with its metadata, which tells where and when it was queued.
+    /// Receiving is blocking and will wait for the message if it is not ready.
+    pub async fn receive_envelope(&mut self) -> Result<BleEnvelope, BleError> {
+        let receiver = self.receiver.as_ref().ok_or(BleError::EngineNotStarted)?;
+        receiver
+            .recv_envelope()
+            .await
+            .ok_or(BleError::ChannelClosed)
+    }
+
+    /// Receive a message along with the instant the BLE thread received it, which tells how long
+    /// the message waited in the receive queue.
+    /// Receiving is blocking and will wait for the message if it is not ready.
+    pub async fn receive_timestamped(&mut self) -> Result<ReceivedMessage, BleError> {
+        self.receive_envelope().await.map(ReceivedMessage::from)
+    }
+
+    /// Queue a message in the receive queue as if it was received, keeping its metadata.
+    /// Useful to feed locally generated messages through the same path as the ones of the central.
+    pub fn loopback(&self, envelope: BleEnvelope) -> Result<(), BleError> {
+        let receiver = self.receiver.as_ref().ok_or(BleError::EngineNotStarted)?;
+        if let Some(dropped) =
+            receiver.push_envelope(envelope.received_from(MessageSource::Loopback))
+        {
+            log::warn!("Receive queue full, dropped message {:?}", dropped.message);
+        }
+        Ok(())
+    }
+
+    /// Serve the requests of the central device: receive each message, hand it to the handler,
+    /// and send back the response it returns, if any. Messages are handled one at a time, in the
+    /// order they were received. Return once the BLE thread exits, or `BleError::ChannelClosed`
+    /// if a response cannot be sent.
+    pub async fn serve<F>(&mut self, mut handler: F) -> Result<(), BleError>
+    where
+        F: FnMut(BleMessage) -> Option<BleMessage>,
+    {
+        let receiver = self.receiver.clone().ok_or(BleError::EngineNotStarted)?;
+        let mut subscribed = self
+            .subscribed_watcher
+            .clone()
+            .ok_or(BleError::EngineNotStarted)?;
+        // The subscribed state is only dropped by the BLE thread when it exits
+        let stopped = async { while subscribed.changed().await.is_ok() {} };
+        tokio::pin!(stopped);
+
         loop {
-            let message = self.receiver.as_mut().unwrap().recv().await;
-            // Check if the message received is not empty, otherwise continue the loop
-            if let Some(message) = message {
-                return message;
+            let request = tokio::select! {
+                Some(request) = receiver.recv() => request,
+                _ = &mut stopped => return Ok(()),
+                else => return Ok(()),
+            };
+            if let Some(response) = handler(request) {
+                self.send_message(response)
+                    .await
+                    .map_err(|_| BleError::ChannelClosed)?;
             }
         }
     }
 
+    /// Receive a message from the central device, giving up once `timeout` has elapsed.
+    /// Return `BleError::Timeout` if no message arrived in time, so a missing response fails fast.
+    pub async fn receive_message_or_timeout(
+        &mut self,
+        timeout: Duration,
+    ) -> Result<BleMessage, BleError> {
+        let receiver = self.receiver.as_ref().ok_or(BleError::EngineNotStarted)?;
+        tokio::time::timeout(timeout, receiver.recv())
+            .await
+            .map_err(|_| BleError::Timeout)?
+            .ok_or(BleError::ChannelClosed)
+    }
+
+    /// Receive a message from the central device and decode it with the codec `C`.
+    /// Receiving is blocking and will wait for the message if it is not ready.
+    pub async fn receive_decoded<C, T>(&mut self) -> Result<T, BleError>
+    where
+        C: MessageCodec<T>,
+    {
+        let receiver = self.receiver.as_ref().ok_or(BleError::EngineNotStarted)?;
+        let message = receiver.recv().await.ok_or(BleError::ChannelClosed)?;
+        C::decode(message.as_bytes())
+    }
+
+    /// Receive the next write from the central device along with the offset it was written at,
+    /// for protocols treating the characteristic as a memory region.
+    /// Only available when offset writes are enabled with the `offset_writes` builder option,
+    /// otherwise return `BleError::OffsetWritesNotEnabled`.
+    pub async fn receive_offset_write(&mut self) -> Result<(u16, Vec<u8>), BleError> {
+        if !self.config.offset_writes {
+            return Err(BleError::OffsetWritesNotEnabled);
+        }
+        let receiver = self
+            .offset_receiver
+            .as_mut()
+            .ok_or(BleError::EngineNotStarted)?;
+        receiver.recv().await.ok_or(BleError::ChannelClosed)
+    }
+
+    /// Take the stream of the write requests of the central, each to be accepted or rejected by
+    /// the caller. This is an escape hatch below the message abstraction, for callers handling
+    /// the offsets, the MTU and the answers themselves.
+    /// Only available when raw write requests are enabled with the `raw_write_requests` builder
+    /// option. Return `BleError::EngineNotStarted` if the engine is not started, or if the stream
+    /// was already taken.
+    pub fn raw_write_requests(&mut self) -> Result<impl Stream<Item = RawWriteRequest>, BleError> {
+        self.raw_write_receiver
+            .take()
+            .ok_or(BleError::EngineNotStarted)
+    }
+
+    /// Receive the bytes of the next message from the central device, without any codec.
+    /// Unless a text delimiter is configured, each write of the central is received as written.
+    pub async fn receive_raw_unframed(&mut self) -> Result<Vec<u8>, BleError> {
+        let receiver = self.receiver.as_ref().ok_or(BleError::EngineNotStarted)?;
+        let message = receiver.recv().await.ok_or(BleError::ChannelClosed)?;
+        Ok(message.take_bytes())
+    }
+
+    /// Receive the next message from the central device into the caller's buffer.
+    /// The buffer is cleared and reused, so callers doing continuous receives can avoid allocating
+    /// per message. Return the length of the message.
+    pub async fn receive_into(&mut self, buf: &mut Vec<u8>) -> Result<usize, BleError> {
+        let receiver = self.receiver.as_ref().ok_or(BleError::EngineNotStarted)?;
+        let message = receiver.recv().await.ok_or(BleError::ChannelClosed)?;
+        buf.clear();
+        buf.extend_from_slice(message.as_bytes());
+        Ok(buf.len())
+    }
+
+    /// Receive a message from the central device without waiting.
+    /// Return the oldest buffered message, or `None` if no message is ready.
+    pub fn try_receive_message(&mut self) -> Option<BleMessage> {
+        self.receiver.as_ref()?.try_recv()
+    }
+
+    /// Set a handler invoked by the BLE thread on each received message.
+    /// While set, received messages go to the handler instead of the receive queue.
+    /// The handler can be replaced at any time without stopping the engine, even from within the
+    /// handler itself.
+    pub fn set_message_handler<F>(&self, handler: F)
+    where
+        F: Fn(BleMessage) + Send + Sync + 'static,
+    {
+        *self.message_handler.lock().unwrap() = Some(Arc::new(handler));
+    }
+
+    /// Remove the message handler, so received messages go to the receive queue again.
+    pub fn clear_message_handler(&self) {
+        *self.message_handler.lock().unwrap() = None;
+    }
+
+    /// Attach application data, such as an authentication status or a session id, to the current
+    /// connection. Each type has its own slot, and every slot is cleared when the central disconnects.
+    pub fn set_connection_data<T: Send + Sync + 'static>(&self, data: T) {
+        self.connection_data.set(data);
+    }
+
+    /// Return the data of the given type attached to the current connection.
+    pub fn connection_data<T: Send + Sync + 'static>(&self) -> Option<Arc<T>> {
+        self.connection_data.get::<T>()
+    }
+
+    /// Return the number of received messages waiting to be consumed.
+    pub fn pending_messages(&self) -> usize {
+        self.receiver
+            .as_ref()
+            .map(|receiver| receiver.len())
+            .unwrap_or(0)
+    }
+
+    /// Return the number of sent messages not written to the notifier yet, counted against
+    /// `max_inflight_writes`. Always 0 if the writes are not limited.
+    pub fn inflight_writes(&self) -> usize {
+        match (
+            self.inflight_writes.as_ref(),
+            self.config.max_inflight_writes,
+        ) {
+            (Some(inflight_writes), Some(max)) => max - inflight_writes.available_permits(),
+            _ => 0,
+        }
+    }
+
+    /// Set a validator for the payload of incoming write requests.
+    /// Writes for which the validator returns false are rejected with a GATT error response,
+    /// so the central learns its write was invalid. Takes effect on the next `start_engine`.
+    pub fn set_write_validator<F>(&mut self, validator: F)
+    where
+        F: Fn(&[u8]) -> bool + Send + Sync + 'static,
+    {
+        self.write_validator = Some(Arc::new(validator));
+    }
+
+    /// Return the bytes of the last message successfully notified to the central device.
+    pub fn last_notified(&self) -> Option<Vec<u8>> {
+        self.last_notified_watcher.as_ref()?.borrow().clone()
+    }
+
+    /// Return how the messages sent since the engine started were batched into notifications.
+    /// All the metrics are zero before the engine is started.
+    pub fn metrics(&self) -> NotificationMetrics {
+        self.metrics
+            .as_ref()
+            .map(|metrics| metrics.snapshot())
+            .unwrap_or_default()
+    }
+
+    /// Return the throughput of the notified bytes over rolling windows.
+    /// The throughput is zero before the engine is started.
+    pub fn throughput(&self) -> Throughput {
+        self.metrics().send_throughput
+    }
+
+    /// Return the throughput of the bytes written by the central over rolling windows.
+    /// The throughput is zero before the engine is started.
+    pub fn receive_throughput(&self) -> Throughput {
+        self.metrics().receive_throughput
+    }
+
+    /// Return the MTU exchanged with the connected central device, if any.
+    pub fn current_mtu(&self) -> Option<usize> {
+        *self.mtu_watcher.as_ref()?.borrow()
+    }
+
+    /// Return the capabilities negotiated with the central device during the handshake, if any.
+    /// The handshake is only performed when enabled with the `handshake` builder option.
+    pub fn negotiated_capabilities(&self) -> Option<Capabilities> {
+        *self.capabilities_watcher.as_ref()?.borrow()
+    }
+
+    /// Return the protocol version spoken by the central, learned from the version of the last
+    /// message it sent, or else agreed during the handshake, if any.
+    /// Always `None` unless the messages are versioned.
+    pub fn peer_version(&self) -> Option<u8> {
+        self.config.message_versions.as_ref()?;
+        let received = *self.peer_version_watcher.as_ref()?.borrow();
+        received.or(self
+            .negotiated_capabilities()
+            .map(|capabilities| capabilities.version))
+    }
+
+    /// Return the protocol version the sent messages carry: the version of the central if
+    /// supported, or else the newest supported one. `None` unless the messages are versioned.
+    fn send_version(&self) -> Option<u8> {
+        let versions = self.config.message_versions.as_ref()?;
+        match self.peer_version() {
+            Some(version) if versions.contains(&version) => Some(version),
+            _ => Some(*versions.end()),
+        }
+    }
+
+    /// Agree on an MTU with the central device before a bulk transfer, up to `desired`.
+    /// BlueZ exchanges the ATT MTU when the central connects, and bluer offers no way for the
+    /// peripheral to request another one, so this returns the exchanged MTU capped at `desired`.
+    pub async fn negotiate_mtu(&self, desired: usize) -> Result<usize, BleError> {
+        let mtu = self.current_mtu().ok_or(BleError::NotConnected)?;
+        Ok(mtu.min(desired))
+    }
+
+    /// Measure the round-trip latency to the central device.
+    /// A ping control message carrying a fresh nonce is sent, and the time until the central
+    /// echoes it back as a pong with the same nonce is returned.
+    /// The central must answer pings for this to succeed, see `ControlMessage` for the encoding.
+    pub async fn ping(&mut self, timeout: Duration) -> Result<Duration, BleError> {
+        if self.sender.is_none() {
+            return Err(BleError::EngineNotStarted);
+        }
+        let nonce = self.next_ping_nonce;
+        self.next_ping_nonce = self.next_ping_nonce.wrapping_add(1);
+
+        // Listen before sending so the pong cannot be missed
+        let mut control = self.control.subscribe();
+        let started = Instant::now();
+        let ping = ControlMessage::Ping { nonce };
+        self.enqueue(OutgoingMessage::control(ping, self.config.endianness))
+            .await?;
+
+        let pong = async {
+            loop {
+                match control.recv().await {
+                    Ok(ControlMessage::Pong { nonce: received }) if received == nonce => {
+                        return Ok(started.elapsed());
+                    }
+                    Ok(_) | Err(broadcast::error::RecvError::Lagged(_)) => {}
+                    Err(broadcast::error::RecvError::Closed) => {
+                        return Err(BleError::ChannelClosed)
+                    }
+                }
+            }
+        };
+        tokio::time::timeout(timeout, pong)
+            .await
+            .map_err(|_| BleError::Timeout)?
+    }
+
+    /// Send a file to the central device in chunks of at most `chunk_size` bytes.
+    /// Each chunk is prefixed with its offset in the file, and the central acknowledges the bytes
+    /// it received with `ControlMessage::TransferAck`, so an interrupted transfer can be continued
+    /// with `resume_file_transfer`. Starting a new transfer replaces the current one.
+    pub async fn send_file(&self, data: Vec<u8>, chunk_size: usize) -> Result<(), BleError> {
+        if chunk_size == 0 {
+            return Err(BleError::InvalidMessage(
+                "Chunk size must be greater than zero".to_string(),
+            ));
+        }
+        let length = data.len() as u64;
+        let chunks = self
+            .file_transfer
+            .start(data, chunk_size, self.config.endianness);
+        let total = chunks.len();
+        for (sent, chunk) in (1..).zip(chunks) {
+            self.enqueue(OutgoingMessage::new(BleMessage::Raw(chunk)))
+                .await?;
+            self.report_progress(sent, total).await?;
+        }
+        self.file_transfer.record_sent(length);
+        Ok(())
+    }
+
+    /// Send a file to the central device like `send_file`, keeping at most `window_size` chunks
+    /// in flight ahead of the bytes acknowledged with `ControlMessage::TransferAck`.
+    /// The window advances as acknowledgments arrive, so the central must acknowledge as it
+    /// receives. Return once the whole file is acknowledged, or `BleError::Timeout` if nothing
+    /// new is acknowledged within `ack_timeout`, in which case the transfer can be resumed.
+    pub async fn send_file_windowed(
+        &self,
+        data: Vec<u8>,
+        chunk_size: usize,
+        ack_timeout: Duration,
+    ) -> Result<(), BleError> {
+        if chunk_size == 0 {
+            return Err(BleError::InvalidMessage(
+                "Chunk size must be greater than zero".to_string(),
+            ));
+        }
+        let window_size = self.config.window_size.unwrap_or(1).max(1);
+        let length = data.len() as u64;
+        let mut chunks = self
+            .file_transfer
+            .start(data, chunk_size, self.config.endianness)
+            .into_iter();
+        let total = chunks.len();
+        let (mut sent, mut sent_bytes, mut acked) = (0, 0, 0);
+
+        loop {
+            let acked_chunks = match acked >= length {
+                true => total,
+                false => (acked / chunk_size as u64) as usize,
+            };
+            if acked_chunks == total {
+                return Ok(());
+            }
+
+            // Fill the window with the chunks following the acknowledged ones
+            while sent - acked_chunks < window_size {
+                let Some(chunk) = chunks.next() else { break };
+                // The last chunk may be shorter than the others
+                sent_bytes += (chunk.len() - FILE_CHUNK_HEADER_SIZE) as u64;
+                self.enqueue(OutgoingMessage::new(BleMessage::Raw(chunk)))
+                    .await?;
+                sent += 1;
+                self.report_progress(sent, total).await?;
+                self.file_transfer.record_sent(sent_bytes);
+            }
+
+            // Slide the window once the central acknowledges more bytes
+            acked = self.file_transfer.wait_for_ack(acked, ack_timeout).await?;
+        }
+    }
+
+    /// Send an image to the central device, resized to `width` x `height` and encoded as JPEG.
+    /// The encoded image is framed with its length and split into sequenced chunks of at most
+    /// `chunk_size` bytes, which the central turns back into the image with an `ImageReceiver`.
+    /// Apart from the JPEG encoding the image is sent uncompressed.
+    pub async fn send_image(
+        &self,
+        image: &DynamicImage,
+        width: u32,
+        height: u32,
+        chunk_size: usize,
+    ) -> Result<(), BleError> {
+        let encoded = image_transfer::encode_image(image, width, height)?;
+        let chunks = image_transfer::image_chunks(encoded, chunk_size, self.config.endianness)?;
+        let total = chunks.len();
+        for (sent, chunk) in (1..).zip(chunks) {
+            self.enqueue(OutgoingMessage::new(BleMessage::Raw(chunk)))
+                .await?;
+            self.report_progress(sent, total).await?;
+        }
+        Ok(())
+    }
+
+    /// Queue a progress report of a chunked transfer after the `sent`th of its `total` chunks, if
+    /// enabled with `transfer_progress` and it is due.
+    async fn report_progress(&self, sent: usize, total: usize) -> Result<(), BleError> {
+        let Some(every) = self.config.transfer_progress else {
+            return Ok(());
+        };
+        if !sent.is_multiple_of(every) && sent != total {
+            return Ok(());
+        }
+        let progress = ControlMessage::TransferProgress {
+            chunks: sent.try_into().unwrap_or(u32::MAX),
+            total: total.try_into().unwrap_or(u32::MAX),
+        };
+        self.enqueue(OutgoingMessage::control(progress, self.config.endianness))
+            .await
+    }
+
+    /// Continue the current file transfer from the last offset acknowledged by the central,
+    /// typically after it reconnected. Return the offset the transfer resumed from.
+    pub async fn resume_file_transfer(&self) -> Result<u64, BleError> {
+        if self.sender.is_none() {
+            return Err(BleError::EngineNotStarted);
+        }
+        let (offset, chunks) = self
+            .file_transfer
+            .resume()
+            .ok_or(BleError::NoFileTransfer)?;
+        for chunk in chunks {
+            self.enqueue(OutgoingMessage::new(BleMessage::Raw(chunk)))
+                .await?;
+        }
+        self.file_transfer.record_sent(u64::MAX);
+        Ok(offset)
+    }
+
+    /// Return the number of chunks of the current file transfer that were sent but not entirely
+    /// acknowledged by the central yet, such as for flow control or showing progress.
+    /// A chunk is only acknowledged once all of its bytes are. Zero without a transfer.
+    pub fn unacked_count(&self) -> usize {
+        self.file_transfer.unacked_chunks()
+    }
+
+    /// Return the offset of the current file transfer acknowledged by the central, if any.
+    pub fn file_transfer_offset(&self) -> Option<u64> {
+        self.file_transfer.acked_offset()
+    }
+
+    /// Return the most recent raw frames exchanged with the central, oldest first.
+    /// Frames are only captured when enabled with the `capture_frames` builder option.
+    pub fn recent_frames(&self) -> Vec<CapturedFrame> {
+        self.frame_capture
+            .as_ref()
+            .map(|capture| capture.frames())
+            .unwrap_or_default()
+    }
+
+    /// Subscribe to the events reported by the engine.
+    pub fn subscribe_events(&self) -> broadcast::Receiver<BleEngineEvent> {
+        self.events.subscribe()
+    }
+
+    /// Subscribe to the bytes of each read from the central as they arrive, before they are
+    /// reassembled into messages, such as for protocol analyzers. The messages are still
+    /// received as usual. A subscriber falling behind misses the oldest chunks.
+    pub fn subscribe_raw_chunks(&self) -> broadcast::Receiver<RawChunk> {
+        self.raw_chunks.subscribe()
+    }
+
+    /// Update the battery level exposed by the Battery Service and notify its subscribers.
+    /// The level is clamped to 0–100. Has no effect unless the Battery Service is enabled.
+    pub fn set_battery_level(&self, level: u8) {
+        match self.battery_level.as_ref() {
+            Some(battery_level) => {
+                battery_level.send_replace(battery::clamp_level(level));
+            }
+            None => log::warn!("Battery Service not enabled, ignoring battery level"),
+        }
+    }
+
+    /// Return the battery level exposed by the Battery Service, if enabled.
+    pub fn battery_level(&self) -> Option<u8> {
+        self.battery_level.as_ref().map(|level| *level.borrow())
+    }
+
+    /// Return the parameters of the connection with the subscribed central, if any.
+    pub fn connection_info(&self) -> Option<ConnectionInfo> {
+        self.connection_info.borrow().clone()
+    }
+
+    /// Wait for the next central to subscribe, returning the parameters of its connection.
+    /// Only a central subscribing after this is called is waited for, so a central already
+    /// subscribed is not returned; see `connection_info` for it.
+    pub fn next_connect(&self) -> impl Future<Output = ConnectionInfo> {
+        let mut connections = self.connection_info.subscribe();
+        async move {
+            // The sender only goes away with the peripheral, after which nobody connects
+            while connections.changed().await.is_ok() {
+                if let Some(info) = connections.borrow_and_update().clone() {
+                    return info;
+                }
+            }
+            future::pending().await
+        }
+    }
+
     /// Check if the BLE peripheral is subscribed to notifications.
     pub async fn is_subscribed(&self) -> bool {
         let subscribed_watcher = match self.subscribed_watcher.as_ref() {
@@ -254,3 +1601,40 @@ impl BlePeripheral {
         *subscribed_watcher.borrow()
     }
 }
+
+/// Validate a written payload, forwarding it to the BLE thread if it is accepted.
+fn handle_validated_write(
+    validator: &WriteValidator,
+    value: Vec<u8>,
+    write_tx: &mpsc::UnboundedSender<Vec<u8>>,
+) -> ReqResult<()> {
+    if !validator(&value) {
+        log::debug!("Rejecting invalid write {:?}", value);
+        return Err(ReqError::Failed);
+    }
+
+    if let Err(err) = write_tx.send(value) {
+        log::error!("Forward write error: {:?}", &err);
+        return Err(ReqError::Failed);
+    }
+    Ok(())
+}
+
+/// Validate a payload written at an offset, forwarding it with its offset if it is accepted.
+fn handle_offset_write(
+    validator: Option<&WriteValidator>,
+    offset: u16,
+    value: Vec<u8>,
+    offset_tx: &mpsc::UnboundedSender<(u16, Vec<u8>)>,
+) -> ReqResult<()> {
+    if validator.is_some_and(|validator| !validator(&value)) {
+        log::debug!("Rejecting invalid write {:?} at offset {}", value, offset);
+        return Err(ReqError::Failed);
+    }
+
+    if let Err(err) = offset_tx.send((offset, value)) {
+        log::error!("Forward write error: {:?}", &err);
+        return Err(ReqError::Failed);
+    }
+    Ok(())
+}
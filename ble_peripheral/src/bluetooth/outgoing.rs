@@ -0,0 +1,247 @@
+use super::backoff::WriteBackoff;
+use super::control::ControlMessage;
+use super::endian::Endianness;
+use super::engine::Notifier;
+use super::message::BleMessage;
+use super::queue::SendPermit;
+use super::report::ReportSender;
+use super::splitter::Splitter;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::{oneshot, OwnedSemaphorePermit};
+use tokio::time::{Duration, Instant};
+
+/// A message queued for notification, along with its delivery constraints.
+#[derive(Debug)]
+pub(crate) struct OutgoingMessage {
+    pub message: BleMessage,
+    pub expires_at: Option<Instant>,
+    /// Whether the message is written exactly as given, as a single write.
+    pub unframed: bool,
+    /// Whether the message is sent on the priority characteristic instead of the bulk one.
+    pub priority: bool,
+    /// Whether the message is a control message, never framed with the delimiter or the version.
+    pub control: bool,
+    /// Room reserved in the bounded send queue, released once the message is handled.
+    pub permit: Option<SendPermit>,
+    /// Write slot reserved by `max_inflight_writes`, released once the message is written.
+    pub write_permit: Option<OwnedSemaphorePermit>,
+    /// Channel reporting the written bytes once the message is notified, bypassing coalescing.
+    pub report: Option<ReportSender>,
+    /// Channel completed once the notifier is flushed, for a flush queued instead of a message.
+    pub flushed: Option<oneshot::Sender<()>>,
+}
+
+/// Permits of a message handed over to the coalescer, released once its batch is written.
+#[derive(Debug)]
+pub(crate) struct HeldPermits {
+    _permit: Option<SendPermit>,
+    _write_permit: Option<OwnedSemaphorePermit>,
+}
+
+impl OutgoingMessage {
+    /// Queue a message that never expires.
+    pub fn new(message: BleMessage) -> Self {
+        Self {
+            message,
+            expires_at: None,
+            unframed: false,
+            priority: false,
+            control: false,
+            permit: None,
+            write_permit: None,
+            report: None,
+            flushed: None,
+        }
+    }
+
+    /// Queue a message sent on the priority characteristic, bypassing coalescing.
+    pub fn priority(message: BleMessage) -> Self {
+        Self {
+            priority: true,
+            ..Self::new(message)
+        }
+    }
+
+    /// Queue a control message, exchanged alongside the application messages.
+    pub fn control(control: ControlMessage, endianness: Endianness) -> Self {
+        Self {
+            control: true,
+            ..Self::new(BleMessage::Raw(control.to_bytes(endianness)))
+        }
+    }
+
+    /// Queue a message written to the notifier exactly as given, bypassing coalescing and MTU splitting.
+    pub fn unframed(message: BleMessage) -> Self {
+        Self {
+            unframed: true,
+            ..Self::new(message)
+        }
+    }
+
+    /// Queue a flush of the notifier, once the messages queued before it are written.
+    pub fn flush(flushed: oneshot::Sender<()>) -> Self {
+        Self {
+            flushed: Some(flushed),
+            ..Self::new(BleMessage::Raw(Vec::new()))
+        }
+    }
+
+    /// Queue a message that is dropped if it is still waiting once `ttl` has elapsed.
+    pub fn with_ttl(message: BleMessage, ttl: Duration) -> Self {
+        Self {
+            expires_at: Some(Instant::now() + ttl),
+            ..Self::new(message)
+        }
+    }
+
+    /// Take the permits of the message, to keep them until the message is written.
+    pub fn take_permits(&mut self) -> HeldPermits {
+        HeldPermits {
+            _permit: self.permit.take(),
+            _write_permit: self.write_permit.take(),
+        }
+    }
+
+    /// Check if the message has waited in the queue for longer than its TTL.
+    pub fn is_expired(&self) -> bool {
+        self.expires_at
+            .map(|expires_at| Instant::now() >= expires_at)
+            .unwrap_or(false)
+    }
+}
+
+/// Write a queued message to the notifier, split into notifications of at most `mtu` bytes,
+/// by the splitter if one is given.
+/// Stale messages are dropped instead of being sent. Return the written bytes, or `None` if dropped.
+/// The notifier is flushed once the message is written if `flush` is set.
+/// Notifications blocked by a full receive buffer are retried with the backoff, if one is given.
+/// Each notification fails with `TimedOut` if the central does not confirm it within
+/// `confirm_timeout`, if one is given.
+pub(crate) async fn write_notification<N>(
+    notifier: &mut N,
+    outgoing: OutgoingMessage,
+    mtu: usize,
+    splitter: Option<&dyn Splitter>,
+    backoff: Option<&WriteBackoff>,
+    confirm_timeout: Option<Duration>,
+    flush: bool,
+) -> std::io::Result<Option<Vec<u8>>>
+where
+    N: Notifier,
+{
+    if outgoing.is_expired() {
+        log::debug!("Dropping expired message {:x?}", outgoing.message);
+        return Ok(None);
+    }
+
+    // Convert the message to a byte array
+    log::debug!("Notifying message {:x?}", outgoing.message);
+    let message_bytes = outgoing.message.take_bytes();
+
+    // Write the message to the notify opterator
+    let chunks = match outgoing.unframed {
+        true => write_chunks(notifier, [&message_bytes], backoff, confirm_timeout).await?,
+        false => {
+            write_split(
+                notifier,
+                &message_bytes,
+                mtu,
+                splitter,
+                backoff,
+                confirm_timeout,
+            )
+            .await?
+        }
+    };
+    if flush {
+        notifier.flush().await?;
+    }
+    if let Some(report) = outgoing.report {
+        report.complete(message_bytes.len(), chunks);
+    }
+    Ok(Some(message_bytes))
+}
+
+/// Write the bytes to the notifier as notifications of at most `mtu` bytes each, split by the
+/// splitter if one is given. Partial writes are continued until the whole notification is written.
+/// Return the number of notifications written.
+pub(crate) async fn write_split<N>(
+    notifier: &mut N,
+    bytes: &[u8],
+    mtu: usize,
+    splitter: Option<&dyn Splitter>,
+    backoff: Option<&WriteBackoff>,
+    confirm_timeout: Option<Duration>,
+) -> std::io::Result<usize>
+where
+    N: Notifier,
+{
+    match splitter {
+        Some(splitter) => {
+            write_chunks(
+                notifier,
+                splitter.split(bytes, mtu),
+                backoff,
+                confirm_timeout,
+            )
+            .await
+        }
+        None => write_chunks(notifier, bytes.chunks(mtu.max(1)), backoff, confirm_timeout).await,
+    }
+}
+
+/// Write each chunk to the notifier as a notification, continuing partial writes.
+/// A write blocked by a full receive buffer is retried after the delays of the backoff, if one is
+/// given, and fails with `WouldBlock` once the retries are exhausted, possibly after a part of the
+/// message was written. A write the central does not confirm within `confirm_timeout`, if one is
+/// given, fails with `TimedOut`. Return the number of notifications written.
+async fn write_chunks<N, C>(
+    notifier: &mut N,
+    chunks: impl IntoIterator<Item = C>,
+    backoff: Option<&WriteBackoff>,
+    confirm_timeout: Option<Duration>,
+) -> std::io::Result<usize>
+where
+    N: Notifier,
+    C: AsRef<[u8]>,
+{
+    let mut count = 0;
+    for notification in chunks {
+        let notification = notification.as_ref();
+        let mut written = 0;
+        let mut retry = 0;
+        while written < notification.len() {
+            let write = notifier.write(&notification[written..]);
+            let result = match confirm_timeout {
+                Some(confirm_timeout) => tokio::time::timeout(confirm_timeout, write)
+                    .await
+                    .unwrap_or_else(|_| {
+                        Err(std::io::Error::new(
+                            std::io::ErrorKind::TimedOut,
+                            "Central did not confirm the notification in time",
+                        ))
+                    }),
+                None => write.await,
+            };
+            match result {
+                Ok(0) => return Err(std::io::ErrorKind::WriteZero.into()),
+                Ok(n) => {
+                    written += n;
+                    retry = 0;
+                }
+                Err(err) if err.kind() == std::io::ErrorKind::WouldBlock => match backoff {
+                    Some(backoff) if retry < backoff.max_retries => {
+                        let delay = backoff.delay(retry);
+                        log::debug!("Central receive buffer full, retrying in {:?}", delay);
+                        tokio::time::sleep(delay).await;
+                        retry += 1;
+                    }
+                    _ => return Err(err),
+                },
+                Err(err) => return Err(err),
+            }
+        }
+        count += 1;
+    }
+    Ok(count)
+}
@@ -0,0 +1,182 @@
+use super::envelope::BleEnvelope;
+use super::message::BleMessage;
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::Notify;
+
+/// Policy applied when a message is received while the receive queue is full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum OverflowPolicy {
+    /// Drop the oldest queued message to make room for the new one.
+    #[default]
+    DropOldest,
+    /// Drop the new message, keeping the queued ones.
+    DropNewest,
+}
+
+/// Policy applied when a message is sent while the send queue is full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum SendOverflowPolicy {
+    /// Drop the new message.
+    #[default]
+    DropNewest,
+    /// Wait until the queue has room for the new message.
+    Block,
+}
+
+/// Queue of received messages waiting to be consumed along with their metadata, optionally bounded.
+/// Once closed, the queued messages can still be taken, then receiving returns `None`.
+pub(crate) struct ReceiveQueue {
+    messages: Mutex<VecDeque<BleEnvelope>>,
+    notify: Notify,
+    closed: AtomicBool,
+    capacity: Option<usize>,
+    policy: OverflowPolicy,
+}
+
+impl ReceiveQueue {
+    /// Create a new queue holding at most `capacity` messages, or unbounded if `None`.
+    pub fn new(capacity: Option<usize>, policy: OverflowPolicy) -> Self {
+        Self {
+            messages: Mutex::new(VecDeque::new()),
+            notify: Notify::new(),
+            closed: AtomicBool::new(false),
+            capacity,
+            policy,
+        }
+    }
+
+    /// Return the capacity of the queue, if bounded.
+    pub fn capacity(&self) -> Option<usize> {
+        self.capacity
+    }
+
+    /// Return the number of queued messages.
+    pub fn len(&self) -> usize {
+        self.messages.lock().unwrap().len()
+    }
+
+    /// Queue a received message along with its metadata.
+    /// Return the envelope dropped by the overflow policy if the queue was full.
+    pub fn push_envelope(&self, envelope: BleEnvelope) -> Option<BleEnvelope> {
+        let mut messages = self.messages.lock().unwrap();
+        let dropped = match self.capacity {
+            Some(capacity) if messages.len() >= capacity => match self.policy {
+                OverflowPolicy::DropOldest => {
+                    let oldest = messages.pop_front();
+                    messages.push_back(envelope);
+                    oldest
+                }
+                OverflowPolicy::DropNewest => Some(envelope),
+            },
+            _ => {
+                messages.push_back(envelope);
+                None
+            }
+        };
+        drop(messages);
+        self.notify.notify_one();
+        dropped
+    }
+
+    /// Take the oldest queued message without waiting.
+    pub fn try_recv(&self) -> Option<BleMessage> {
+        self.try_recv_envelope().map(|envelope| envelope.message)
+    }
+
+    /// Take the oldest queued message along with its metadata without waiting.
+    pub fn try_recv_envelope(&self) -> Option<BleEnvelope> {
+        self.messages.lock().unwrap().pop_front()
+    }
+
+    /// Take the oldest queued message, waiting for one if the queue is empty.
+    /// Return `None` once the queue is closed and empty.
+    pub async fn recv(&self) -> Option<BleMessage> {
+        self.recv_envelope().await.map(|envelope| envelope.message)
+    }
+
+    /// Take the oldest queued message along with its metadata, waiting for one if the queue is empty.
+    /// Return `None` once the queue is closed and empty.
+    pub async fn recv_envelope(&self) -> Option<BleEnvelope> {
+        loop {
+            // Registered before checking, so a close in between is not missed
+            let notified = self.notify.notified();
+            tokio::pin!(notified);
+            notified.as_mut().enable();
+            if let Some(envelope) = self.try_recv_envelope() {
+                return Some(envelope);
+            }
+            if self.closed.load(Ordering::SeqCst) {
+                return None;
+            }
+            notified.await;
+        }
+    }
+
+    /// Close the queue, waking up the receivers waiting for a message.
+    pub fn close(&self) {
+        self.closed.store(true, Ordering::SeqCst);
+        self.notify.notify_waiters();
+    }
+}
+
+/// Limit on the number of sent messages waiting to be notified by the BLE thread.
+#[derive(Debug)]
+pub(crate) struct SendLimit {
+    capacity: usize,
+    policy: SendOverflowPolicy,
+    pending: Mutex<usize>,
+    released: Notify,
+}
+
+impl SendLimit {
+    /// Create a new limit allowing at most `capacity` pending messages.
+    pub fn new(capacity: usize, policy: SendOverflowPolicy) -> Self {
+        Self {
+            capacity,
+            policy,
+            pending: Mutex::new(0),
+            released: Notify::new(),
+        }
+    }
+
+    /// Return the policy applied when the limit is reached.
+    pub fn policy(&self) -> SendOverflowPolicy {
+        self.policy
+    }
+
+    /// Reserve room for a message without waiting.
+    /// Return `None` if the limit is reached.
+    pub fn try_acquire(self: &Arc<Self>) -> Option<SendPermit> {
+        let mut pending = self.pending.lock().unwrap();
+        if *pending >= self.capacity {
+            return None;
+        }
+        *pending += 1;
+        Some(SendPermit(self.clone()))
+    }
+
+    /// Reserve room for a message, waiting for a pending message to be handled if the limit is reached.
+    pub async fn acquire(self: &Arc<Self>) -> SendPermit {
+        loop {
+            if let Some(permit) = self.try_acquire() {
+                return permit;
+            }
+            self.released.notified().await;
+        }
+    }
+}
+
+/// Room reserved for a pending message, released once the message is dropped.
+#[derive(Debug)]
+pub(crate) struct SendPermit(Arc<SendLimit>);
+
+impl Drop for SendPermit {
+    fn drop(&mut self) {
+        *self.0.pending.lock().unwrap() -= 1;
+        self.0.released.notify_one();
+    }
+}
@@ -0,0 +1,79 @@
+use bluer::gatt::local::{ReqError, ReqResult};
+use futures::channel::mpsc;
+use std::future::Future;
+use tokio::sync::oneshot;
+
+/// Write request of the central handed to the application as is, below the message abstraction.
+/// The central waits for the request to be answered with `accept` or `reject`. Dropping the
+/// request rejects it, and BlueZ fails it by itself if it is not answered in time.
+#[derive(Debug)]
+pub struct RawWriteRequest {
+    mtu: usize,
+    offset: u16,
+    bytes: Vec<u8>,
+    responder: oneshot::Sender<ReqResult<()>>,
+}
+
+impl RawWriteRequest {
+    /// Create a new request along with the channel receiving its answer.
+    pub(crate) fn new(
+        mtu: usize,
+        offset: u16,
+        bytes: Vec<u8>,
+    ) -> (Self, oneshot::Receiver<ReqResult<()>>) {
+        let (responder, response) = oneshot::channel();
+        let request = Self {
+            mtu,
+            offset,
+            bytes,
+            responder,
+        };
+        (request, response)
+    }
+
+    /// Return the MTU exchanged with the central.
+    pub fn mtu(&self) -> usize {
+        self.mtu
+    }
+
+    /// Return the offset the bytes are written at.
+    pub fn offset(&self) -> u16 {
+        self.offset
+    }
+
+    /// Return the written bytes.
+    pub fn bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    /// Accept the write, returning its bytes.
+    pub fn accept(self) -> Vec<u8> {
+        // The central may have stopped waiting for the answer
+        let _ = self.responder.send(Ok(()));
+        self.bytes
+    }
+
+    /// Reject the write, failing it on the central.
+    pub fn reject(self) {
+        let _ = self.responder.send(Err(ReqError::Failed));
+    }
+}
+
+/// Hand a write to the raw request stream and wait for the application to answer it.
+/// The write is rejected if nobody listens to the stream.
+pub(crate) fn handle_raw_write(
+    requests_tx: &mpsc::UnboundedSender<RawWriteRequest>,
+    mtu: usize,
+    offset: u16,
+    value: Vec<u8>,
+) -> impl Future<Output = ReqResult<()>> {
+    let (request, response) = RawWriteRequest::new(mtu, offset, value);
+    let sent = requests_tx.unbounded_send(request);
+    async move {
+        if let Err(err) = sent {
+            log::error!("Forward raw write error: {:?}", &err);
+            return Err(ReqError::Failed);
+        }
+        response.await.unwrap_or(Err(ReqError::Failed))
+    }
+}
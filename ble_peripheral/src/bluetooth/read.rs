@@ -0,0 +1,26 @@
+use bluer::gatt::local::{ReqError, ReqResult};
+use std::sync::Mutex;
+
+/// Value served to the GATT read requests of the characteristic receiving the writes.
+/// The value stays staged until it is replaced, so long reads can fetch it in several parts.
+#[derive(Default)]
+pub(crate) struct ReadResponse {
+    value: Mutex<Vec<u8>>,
+}
+
+impl ReadResponse {
+    /// Stage the value returned by the next read requests.
+    pub fn stage(&self, bytes: Vec<u8>) {
+        *self.value.lock().unwrap() = bytes;
+    }
+
+    /// Return the staged value starting at `offset`, as requested by the central.
+    /// Return `ReqError::InvalidOffset` if the offset is past the end of the value.
+    pub fn read(&self, offset: u16) -> ReqResult<Vec<u8>> {
+        let value = self.value.lock().unwrap();
+        value
+            .get(offset as usize..)
+            .map(<[u8]>::to_vec)
+            .ok_or(ReqError::InvalidOffset)
+    }
+}
@@ -0,0 +1,145 @@
+use super::boost::{self, AdvertisingBoost};
+use super::error::BleError;
+use super::event::{emit, BleEngineEvent};
+use bluer::adv::{Advertisement, AdvertisementHandle};
+use bluer::Adapter;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use tokio::sync::{broadcast, watch};
+use tokio::time::{Duration, Instant};
+
+/// Interval at which the advertisement is checked to still be registered.
+pub const READVERTISE_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Registrar of the advertisement announcing the peripheral.
+pub(crate) trait Advertiser: Send + Sync + 'static {
+    type Handle: Send + 'static;
+
+    /// Check whether the advertisement is still registered.
+    fn is_advertising(&self) -> impl Future<Output = Result<bool, BleError>> + Send;
+
+    /// Register the advertisement, returning the handle keeping it registered.
+    fn advertise(&self) -> impl Future<Output = Result<Self::Handle, BleError>> + Send;
+}
+
+/// Advertiser registering the advertisement with a Bluetooth adapter.
+/// The advertisement is still registered as long as the adapter has an active advertising instance.
+/// The advertisement is shared so it can be replaced while the engine runs.
+#[derive(Clone)]
+pub(crate) struct AdapterAdvertiser {
+    pub adapter: Adapter,
+    pub adv: Arc<Mutex<Advertisement>>,
+    pub boost: Arc<AdvertisingBoost>,
+}
+
+impl Advertiser for AdapterAdvertiser {
+    type Handle = AdvertisementHandle;
+
+    async fn is_advertising(&self) -> Result<bool, BleError> {
+        Ok(self.adapter.active_advertising_instances().await? > 0)
+    }
+
+    async fn advertise(&self) -> Result<AdvertisementHandle, BleError> {
+        let adv = current_advertisement(&self.adv, &self.boost);
+        Ok(self.adapter.advertise(adv).await?)
+    }
+}
+
+/// Return the shared advertisement as it is now, with the boosted intervals if boosted.
+pub(crate) fn current_advertisement(
+    adv: &Mutex<Advertisement>,
+    boost: &AdvertisingBoost,
+) -> Advertisement {
+    let adv = adv.lock().unwrap().clone();
+    match boost.is_active() {
+        true => boost::boosted(adv),
+        false => adv,
+    }
+}
+
+/// Check the advertisement every `interval` and register it again if the system removed it,
+/// emitting `BleEngineEvent::AdvertisingRestarted`.
+/// Return once the handle is cleared, which happens when the engine is stopped.
+pub(crate) async fn keep_advertising<A: Advertiser>(
+    advertiser: A,
+    handle: Arc<Mutex<Option<A::Handle>>>,
+    events: broadcast::Sender<BleEngineEvent>,
+    interval: Duration,
+) {
+    loop {
+        tokio::time::sleep(interval).await;
+        if handle.lock().unwrap().is_none() {
+            return;
+        }
+        match advertiser.is_advertising().await {
+            Ok(true) => continue,
+            Ok(false) => log::warn!("Advertisement was removed, registering it again"),
+            Err(err) => {
+                log::error!("Advertisement check failed: {}", &err);
+                continue;
+            }
+        }
+        match advertiser.advertise().await {
+            Ok(new_handle) => {
+                let mut handle = handle.lock().unwrap();
+                // The engine may have been stopped while registering
+                if handle.is_none() {
+                    return;
+                }
+                *handle = Some(new_handle);
+                emit(&events, BleEngineEvent::AdvertisingRestarted);
+            }
+            Err(err) => log::error!("Readvertising failed: {}", &err),
+        }
+    }
+}
+
+/// Register the advertisement again once the boost ending at `deadline` is over, so it reverts to
+/// the default intervals, unless the boost was extended in the meantime.
+/// The advertiser registers the advertisement as it is then, with any change made during the boost.
+pub(crate) async fn revert_boost<A: Advertiser>(
+    advertiser: A,
+    boost: Arc<AdvertisingBoost>,
+    deadline: Instant,
+    handle: Arc<Mutex<Option<A::Handle>>>,
+) {
+    tokio::time::sleep_until(deadline).await;
+    if !boost.expire_if_due() {
+        return;
+    }
+    match advertiser.advertise().await {
+        Ok(new_handle) => {
+            let mut handle = handle.lock().unwrap();
+            // The engine may have been stopped during the boost
+            if handle.is_some() {
+                *handle = Some(new_handle);
+            }
+        }
+        Err(err) => log::error!("Reverting boosted advertising failed: {}", &err),
+    }
+}
+
+/// Wait until the application is ready before registering the advertisement, then keep it
+/// registered every `readvertise_interval` if set.
+/// Return without advertising if the readiness gate is dropped first.
+pub(crate) async fn advertise_when_ready<A: Advertiser>(
+    advertiser: A,
+    mut ready: watch::Receiver<bool>,
+    handle: Arc<Mutex<Option<A::Handle>>>,
+    events: broadcast::Sender<BleEngineEvent>,
+    readvertise_interval: Option<Duration>,
+) {
+    if ready.wait_for(|ready| *ready).await.is_err() {
+        return;
+    }
+    match advertiser.advertise().await {
+        Ok(new_handle) => *handle.lock().unwrap() = Some(new_handle),
+        Err(err) => {
+            log::error!("Deferred advertising failed: {}", &err);
+            return;
+        }
+    }
+    if let Some(interval) = readvertise_interval {
+        keep_advertising(advertiser, handle, events, interval).await;
+    }
+}
@@ -0,0 +1,168 @@
+use super::config::PeripheralConfig;
+use super::dedup::{self, DedupCache};
+use super::delimiter::TextSplitter;
+use super::endian::Endianness;
+use super::envelope::BleEnvelope;
+use super::error::BleError;
+use super::message::BleMessage;
+use super::splitter::{Framing, Reassembler};
+use super::tlv::TlvDecoder;
+use std::ops::RangeInclusive;
+
+/// Pipeline turning the bytes read from the characteristic into received messages.
+pub(crate) struct ReceivePipeline {
+    framing: Option<Framing>,
+    reassembler: Option<Box<dyn Reassembler>>,
+    versions: Option<RangeInclusive<u8>>,
+    dedup: Option<DedupCache>,
+    tlv_decoder: Option<TlvDecoder>,
+    endianness: Endianness,
+    text_splitter: Option<TextSplitter>,
+    deliver_empty: bool,
+    strict: bool,
+    auto_text: bool,
+}
+
+impl ReceivePipeline {
+    /// Create a new pipeline for the given configuration.
+    pub fn new(config: &PeripheralConfig) -> Self {
+        Self {
+            framing: config.framing.clone(),
+            reassembler: config.framing.as_ref().map(|framing| framing.reassembler()),
+            versions: config.message_versions.clone(),
+            dedup: config.dedup_window.map(DedupCache::new),
+            tlv_decoder: config
+                .tlv_records
+                .then(|| TlvDecoder::default().with_endianness(config.endianness)),
+            endianness: config.endianness,
+            text_splitter: config.text_delimiter.map(TextSplitter::new),
+            deliver_empty: config.deliver_empty_writes,
+            strict: config.strict_validation,
+            auto_text: config.auto_text,
+        }
+    }
+
+    /// Discard the partially received messages, so they are not completed by the bytes of the
+    /// next connection. The messages completed so far were already delivered.
+    /// With strict validation, discarding any byte is reported as an error once reset.
+    pub fn reset(&mut self) -> Result<(), BleError> {
+        let discarded = self.reassembler.as_ref().map_or(0, |r| r.pending_len())
+            + self.tlv_decoder.as_ref().map_or(0, TlvDecoder::pending_len)
+            + self
+                .text_splitter
+                .as_ref()
+                .map_or(0, TextSplitter::pending_len);
+        self.reassembler = self.framing.as_ref().map(|framing| framing.reassembler());
+        if let Some(decoder) = self.tlv_decoder.as_mut() {
+            decoder.reset();
+        }
+        if let Some(splitter) = self.text_splitter.as_mut() {
+            splitter.reset();
+        }
+        match self.strict && discarded > 0 {
+            true => Err(BleError::InvalidMessage(format!(
+                "{} bytes of an incomplete message were discarded",
+                discarded
+            ))),
+            false => Ok(()),
+        }
+    }
+
+    /// Process the bytes of a single read and return the messages ready to be delivered, along
+    /// with their protocol version and the tag of the TLV record they were received in.
+    /// The bytes are reassembled into messages first if a reassembler is set, then stripped of
+    /// their protocol version if versioned, then of their identifier if deduplicated, dropping the
+    /// duplicates, then decoded as TLV records if enabled. Empty reads are dropped unless empty writes are delivered.
+    /// With strict validation, a read breaking the framing is rejected with an error instead
+    /// of delivering corrupt messages.
+    pub fn process(&mut self, bytes: Vec<u8>) -> Result<Vec<BleEnvelope>, BleError> {
+        if bytes.is_empty() && !self.deliver_empty {
+            log::debug!("Dropping empty write");
+            return Ok(Vec::new());
+        }
+        let reassembled = match self.reassembler.as_mut() {
+            Some(reassembler) => reassembler.push(&bytes),
+            None => vec![bytes],
+        };
+        let mut tagged = Vec::new();
+        for bytes in reassembled {
+            let (version, bytes) = match self.versions.as_ref() {
+                Some(versions) => strip_version(versions, bytes)?,
+                None => (None, bytes),
+            };
+            let (id, bytes) = match self.dedup.as_mut() {
+                Some(cache) => {
+                    let (id, bytes) = dedup::strip_id(bytes, self.endianness)?;
+                    if !cache.insert(id) {
+                        log::debug!("Dropping duplicate message {}", id);
+                        continue;
+                    }
+                    (Some(id), bytes)
+                }
+                None => (None, bytes),
+            };
+            match self.tlv_decoder.as_mut() {
+                Some(decoder) => tagged.extend(
+                    decoder
+                        .push(&bytes)
+                        .into_iter()
+                        .map(|record| (version, id, Some(record.tag), record.value)),
+                ),
+                None => tagged.push((version, id, None, bytes)),
+            }
+        }
+        let mut envelopes = Vec::new();
+        for (version, id, tag, bytes) in tagged {
+            let messages = match self.text_splitter.as_mut() {
+                Some(splitter) => splitter.push(&bytes, self.strict)?,
+                None if self.auto_text => vec![text_if_utf8(bytes)],
+                None => vec![bytes.into()],
+            };
+            envelopes.extend(messages.into_iter().map(|message| {
+                let mut envelope = BleEnvelope::new(message);
+                envelope.meta.tag = tag;
+                envelope.meta.version = version;
+                envelope.meta.id = id;
+                envelope
+            }));
+        }
+        Ok(envelopes)
+    }
+}
+
+/// Split the protocol version starting the bytes from the rest of the message, rejecting the
+/// versions that are not supported.
+fn strip_version(
+    versions: &RangeInclusive<u8>,
+    mut bytes: Vec<u8>,
+) -> Result<(Option<u8>, Vec<u8>), BleError> {
+    let version = match bytes.first() {
+        Some(version) => *version,
+        None => {
+            return Err(BleError::InvalidMessage(
+                "Missing protocol version".to_string(),
+            ))
+        }
+    };
+    if !versions.contains(&version) {
+        return Err(BleError::UnsupportedVersion(version));
+    }
+    bytes.remove(0);
+    Ok((Some(version), bytes))
+}
+
+/// Prefix the message with its protocol version.
+pub(crate) fn with_version(message: BleMessage, version: u8) -> BleMessage {
+    let mut bytes = Vec::with_capacity(message.as_bytes().len() + 1);
+    bytes.push(version);
+    bytes.extend_from_slice(message.as_bytes());
+    BleMessage::Raw(bytes)
+}
+
+/// Turn the bytes into a text message if they are valid UTF-8, or into a raw message otherwise.
+fn text_if_utf8(bytes: Vec<u8>) -> BleMessage {
+    match String::from_utf8(bytes) {
+        Ok(text) => BleMessage::Text(text),
+        Err(err) => BleMessage::Raw(err.into_bytes()),
+    }
+}
@@ -0,0 +1,77 @@
+use super::endian::Endianness;
+use super::error::BleError;
+
+/// Size of the counter prepended to every protected payload.
+pub const REPLAY_COUNTER_SIZE: usize = 8;
+
+/// Replay protection based on a monotonic message counter.
+/// The sending side embeds an increasing counter with each payload (for example in the nonce of
+/// an encrypted message), and the receiving side rejects any counter that is not greater than the
+/// last one it accepted, so a captured message cannot be sent again.
+/// The counter is encoded as big-endian unless set otherwise with `with_endianness`.
+#[derive(Debug, Default)]
+pub struct ReplayGuard {
+    next_counter: u64,
+    last_seen: Option<u64>,
+    endianness: Endianness,
+}
+
+impl ReplayGuard {
+    /// Create a new guard for a fresh session.
+    pub fn new() -> ReplayGuard {
+        Self::default()
+    }
+
+    /// Encode and decode the counter prepended to the payloads in the given byte order.
+    pub fn with_endianness(mut self, endianness: Endianness) -> ReplayGuard {
+        self.endianness = endianness;
+        self
+    }
+
+    /// Return the counter to embed in the next outgoing message.
+    pub fn next_counter(&mut self) -> u64 {
+        let counter = self.next_counter;
+        self.next_counter += 1;
+        counter
+    }
+
+    /// Check the counter of an incoming message, recording it if it is fresh.
+    /// Return `BleError::ReplayDetected` if the counter was already seen or is older.
+    pub fn check(&mut self, counter: u64) -> Result<(), BleError> {
+        if let Some(last_seen) = self.last_seen {
+            if counter <= last_seen {
+                return Err(BleError::ReplayDetected { counter, last_seen });
+            }
+        }
+        self.last_seen = Some(counter);
+        Ok(())
+    }
+
+    /// Prepend the next counter to an outgoing payload.
+    pub fn seal(&mut self, payload: &[u8]) -> Vec<u8> {
+        let mut sealed = Vec::with_capacity(REPLAY_COUNTER_SIZE + payload.len());
+        let counter = self.next_counter();
+        sealed.extend_from_slice(&self.endianness.u64_bytes(counter));
+        sealed.extend_from_slice(payload);
+        sealed
+    }
+
+    /// Check the counter prepended to an incoming payload and return the payload if it is fresh.
+    pub fn open(&mut self, sealed: &[u8]) -> Result<Vec<u8>, BleError> {
+        if sealed.len() < REPLAY_COUNTER_SIZE {
+            return Err(BleError::InvalidMessage(
+                "Message is shorter than its replay counter".to_string(),
+            ));
+        }
+        let (counter, payload) = sealed.split_at(REPLAY_COUNTER_SIZE);
+        self.check(self.endianness.read_u64(counter.try_into().unwrap()))?;
+        Ok(payload.to_vec())
+    }
+
+    /// Forget the counters of the previous session, both sent and seen.
+    /// Call this when a new session starts, as the peer restarts its counter too.
+    pub fn reset_replay_window(&mut self) {
+        self.next_counter = 0;
+        self.last_seen = None;
+    }
+}
@@ -0,0 +1,38 @@
+use tokio::sync::oneshot;
+
+/// Accounting of a message once it was written to the central.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SendReport {
+    /// Number of bytes of the message as given by the application.
+    pub app_bytes: usize,
+    /// Number of bytes written to the central, including the framing added by the peripheral.
+    pub wire_bytes: usize,
+    /// Number of notifications the message was split into.
+    pub chunks: usize,
+}
+
+/// Channel completing the report of a queued message once it is written.
+/// Dropping it without completing it tells the sender the message was not delivered.
+#[derive(Debug)]
+pub(crate) struct ReportSender {
+    app_bytes: usize,
+    tx: oneshot::Sender<SendReport>,
+}
+
+impl ReportSender {
+    /// Create a new report channel for a message of `app_bytes` bytes.
+    pub fn new(app_bytes: usize) -> (Self, oneshot::Receiver<SendReport>) {
+        let (tx, rx) = oneshot::channel();
+        (Self { app_bytes, tx }, rx)
+    }
+
+    /// Report the message as written in `chunks` notifications totalling `wire_bytes` bytes.
+    pub fn complete(self, wire_bytes: usize, chunks: usize) {
+        // The sender may have stopped waiting for the report
+        let _ = self.tx.send(SendReport {
+            app_bytes: self.app_bytes,
+            wire_bytes,
+            chunks,
+        });
+    }
+}
@@ -0,0 +1,63 @@
+use super::error::BleError;
+use bluer::gatt::local::{CharacteristicRead, CharacteristicWrite};
+use bluer::Adapter;
+
+/// Security level of the link with the central device.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum SecurityLevel {
+    /// The link is neither encrypted nor authenticated.
+    #[default]
+    None,
+    /// The link is encrypted with the keys of an unauthenticated pairing.
+    Encrypted,
+    /// The link is encrypted and the central device is trusted.
+    AuthenticatedEncrypted,
+}
+
+/// Security level a central must reach before each operation on the characteristic receiving the
+/// writes is allowed. BlueZ rejects the operation, or starts pairing, if the link is less secure.
+/// An authenticated level requires a pairing protected against man-in-the-middle attacks.
+/// Notifications cannot be restricted, since bluer does not expose their security flags.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Permissions {
+    pub read: SecurityLevel,
+    pub write: SecurityLevel,
+}
+
+impl Permissions {
+    /// Set the read flags of the characteristic to require the read security level.
+    pub(crate) fn apply_to_read(&self, read: &mut CharacteristicRead) {
+        read.encrypt_read = self.read == SecurityLevel::Encrypted;
+        read.encrypt_authenticated_read = self.read == SecurityLevel::AuthenticatedEncrypted;
+    }
+
+    /// Set the write flags of the characteristic to require the write security level.
+    pub(crate) fn apply_to_write(&self, write: &mut CharacteristicWrite) {
+        write.encrypt_write = self.write == SecurityLevel::Encrypted;
+        write.encrypt_authenticated_write = self.write == SecurityLevel::AuthenticatedEncrypted;
+    }
+}
+
+/// Query the security level of the first connected device from its properties.
+/// BlueZ encrypts the link with a paired device, but does not report whether the pairing was
+/// authenticated, so a paired device is only considered authenticated once it is trusted.
+/// Return `None` if no device is connected.
+pub(crate) async fn connected_security_level(
+    adapter: &Adapter,
+) -> Result<Option<SecurityLevel>, BleError> {
+    for address in adapter.device_addresses().await? {
+        let device = adapter.device(address)?;
+        if !device.is_connected().await? {
+            continue;
+        }
+        let level = match (device.is_paired().await?, device.is_trusted().await?) {
+            (false, _) => SecurityLevel::None,
+            (true, false) => SecurityLevel::Encrypted,
+            (true, true) => SecurityLevel::AuthenticatedEncrypted,
+        };
+        return Ok(Some(level));
+    }
+    Ok(None)
+}
@@ -0,0 +1,129 @@
+use super::endian::Endianness;
+use super::error::BleError;
+use super::message::BleMessage;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Size of an encoded sensor reading: unit, value, and timestamp.
+pub const SENSOR_READING_SIZE: usize = 17;
+
+/// Unit of a sensor reading.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SensorUnit {
+    Celsius,
+    Fahrenheit,
+    Percent,
+    Pascal,
+    Lux,
+    Volt,
+    Ampere,
+    Meter,
+}
+
+impl SensorUnit {
+    /// Return the byte identifying the unit on the wire.
+    pub fn code(self) -> u8 {
+        match self {
+            SensorUnit::Celsius => 0,
+            SensorUnit::Fahrenheit => 1,
+            SensorUnit::Percent => 2,
+            SensorUnit::Pascal => 3,
+            SensorUnit::Lux => 4,
+            SensorUnit::Volt => 5,
+            SensorUnit::Ampere => 6,
+            SensorUnit::Meter => 7,
+        }
+    }
+
+    /// Return the unit identified by the given byte.
+    pub fn from_code(code: u8) -> Result<Self, BleError> {
+        match code {
+            0 => Ok(SensorUnit::Celsius),
+            1 => Ok(SensorUnit::Fahrenheit),
+            2 => Ok(SensorUnit::Percent),
+            3 => Ok(SensorUnit::Pascal),
+            4 => Ok(SensorUnit::Lux),
+            5 => Ok(SensorUnit::Volt),
+            6 => Ok(SensorUnit::Ampere),
+            7 => Ok(SensorUnit::Meter),
+            _ => Err(BleError::InvalidMessage(format!(
+                "Unknown sensor unit {}",
+                code
+            ))),
+        }
+    }
+}
+
+/// A reading taken by a sensor, with its unit and the time it was taken.
+/// Encoded as the unit byte, the value as a f64, and the timestamp as u64 microseconds since the
+/// UNIX epoch, both in the byte order given to `to_ble_message` and `from_ble_message`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SensorReading {
+    pub value: f64,
+    pub unit: SensorUnit,
+    pub timestamp: SystemTime,
+}
+
+impl SensorReading {
+    /// Create a new reading taken now.
+    pub fn new(value: f64, unit: SensorUnit) -> SensorReading {
+        SensorReading {
+            value,
+            unit,
+            timestamp: SystemTime::now(),
+        }
+    }
+
+    /// Encode the reading into a raw BLE message, with its numbers in the given byte order.
+    pub fn to_ble_message(&self, endianness: Endianness) -> BleMessage {
+        let micros = self
+            .timestamp
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_micros() as u64;
+
+        let mut bytes = Vec::with_capacity(SENSOR_READING_SIZE);
+        bytes.push(self.unit.code());
+        bytes.extend_from_slice(&endianness.f64_bytes(self.value));
+        bytes.extend_from_slice(&endianness.u64_bytes(micros));
+        BleMessage::Raw(bytes)
+    }
+
+    /// Decode a reading from a raw BLE message, with its numbers in the given byte order.
+    /// Return an error if the message is not raw bytes or does not hold a reading.
+    pub fn from_ble_message(
+        message: &BleMessage,
+        endianness: Endianness,
+    ) -> Result<SensorReading, BleError> {
+        let bytes = match message {
+            BleMessage::Raw(bytes) => bytes,
+            _ => {
+                return Err(BleError::InvalidMessage(
+                    "Sensor reading must be raw bytes".to_string(),
+                ))
+            }
+        };
+        if bytes.len() != SENSOR_READING_SIZE {
+            return Err(BleError::InvalidMessage(format!(
+                "Sensor reading must be {} bytes, got {}",
+                SENSOR_READING_SIZE,
+                bytes.len()
+            )));
+        }
+
+        let unit = SensorUnit::from_code(bytes[0])?;
+        let value = endianness.read_f64(bytes[1..9].try_into().unwrap());
+        let micros = endianness.read_u64(bytes[9..17].try_into().unwrap());
+        Ok(SensorReading {
+            value,
+            unit,
+            timestamp: UNIX_EPOCH + Duration::from_micros(micros),
+        })
+    }
+}
+
+impl From<SensorReading> for BleMessage {
+    /// Automatically convert a sensor reading to a BleMessage, in network byte order
+    fn from(reading: SensorReading) -> Self {
+        reading.to_ble_message(Endianness::Big)
+    }
+}
@@ -0,0 +1,107 @@
+use super::endian::Endianness;
+use std::fmt;
+use std::sync::Arc;
+
+/// Size of the header prepended to every message by `LengthPrefixed`: the length of the
+/// message, encoded as a u32.
+pub const LENGTH_PREFIX_SIZE: usize = 4;
+
+/// Framing splitting the bytes of each sent message into the chunks written as notifications,
+/// replacing the plain split at the MTU.
+pub trait Splitter: Send + Sync {
+    /// Split the bytes of a message into chunks of at most `mtu` bytes.
+    fn split(&self, bytes: &[u8], mtu: usize) -> Vec<Vec<u8>>;
+}
+
+/// Framing turning the chunks written by the central back into messages, matching a `Splitter`.
+pub trait Reassembler: Send {
+    /// Feed the bytes of a received chunk and return the messages completed by it.
+    fn push(&mut self, chunk: &[u8]) -> Vec<Vec<u8>>;
+
+    /// Return the number of bytes received for the messages not completed yet.
+    /// Strict validation reports them as a violation when they are discarded.
+    fn pending_len(&self) -> usize {
+        0
+    }
+}
+
+/// Framing prefixing every message with its length, so messages can span several chunks and
+/// several messages can share one.
+/// The lengths are encoded as big-endian unless set otherwise with `with_endianness`, such as to
+/// the byte order of the peripheral.
+#[derive(Debug, Clone, Default)]
+pub struct LengthPrefixed {
+    endianness: Endianness,
+    pending: Vec<u8>,
+}
+
+impl LengthPrefixed {
+    /// Encode and decode the lengths in the given byte order.
+    pub fn with_endianness(mut self, endianness: Endianness) -> LengthPrefixed {
+        self.endianness = endianness;
+        self
+    }
+}
+
+impl Splitter for LengthPrefixed {
+    fn split(&self, bytes: &[u8], mtu: usize) -> Vec<Vec<u8>> {
+        let mut framed = Vec::with_capacity(LENGTH_PREFIX_SIZE + bytes.len());
+        framed.extend_from_slice(&self.endianness.u32_bytes(bytes.len() as u32));
+        framed.extend_from_slice(bytes);
+        framed.chunks(mtu.max(1)).map(<[u8]>::to_vec).collect()
+    }
+}
+
+impl Reassembler for LengthPrefixed {
+    fn push(&mut self, chunk: &[u8]) -> Vec<Vec<u8>> {
+        self.pending.extend_from_slice(chunk);
+        let mut messages = Vec::new();
+        while self.pending.len() >= LENGTH_PREFIX_SIZE {
+            let (header, body) = self.pending.split_at(LENGTH_PREFIX_SIZE);
+            let length = self.endianness.read_u32(header.try_into().unwrap()) as usize;
+            if body.len() < length {
+                break;
+            }
+            messages.push(body[..length].to_vec());
+            self.pending.drain(..LENGTH_PREFIX_SIZE + length);
+        }
+        messages
+    }
+
+    fn pending_len(&self) -> usize {
+        self.pending.len()
+    }
+}
+
+/// Splitter and reassembler set with the builder. Each engine start gets a fresh reassembler,
+/// so bytes left over from a previous connection are never prepended to new messages.
+#[derive(Clone)]
+pub(crate) struct Framing {
+    pub splitter: Arc<dyn Splitter>,
+    reassembler: Arc<dyn Fn() -> Box<dyn Reassembler> + Send + Sync>,
+}
+
+impl Framing {
+    /// Create a framing from a splitter and the reassembler cloned by every engine start.
+    pub fn new<S, R>(splitter: S, reassembler: R) -> Self
+    where
+        S: Splitter + 'static,
+        R: Reassembler + Clone + Sync + 'static,
+    {
+        Self {
+            splitter: Arc::new(splitter),
+            reassembler: Arc::new(move || Box::new(reassembler.clone())),
+        }
+    }
+
+    /// Create a new reassembler for an engine start.
+    pub fn reassembler(&self) -> Box<dyn Reassembler> {
+        (self.reassembler)()
+    }
+}
+
+impl fmt::Debug for Framing {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Framing").finish_non_exhaustive()
+    }
+}
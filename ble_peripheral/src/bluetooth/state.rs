@@ -0,0 +1,13 @@
+/// Lifecycle state of the engine of a BLE peripheral.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum EngineState {
+    /// The engine is not started, or its BLE thread has exited.
+    #[default]
+    Stopped,
+    /// The advertisement and the GATT application are being registered.
+    Starting,
+    /// The BLE thread is handling the link with the central.
+    Running,
+    /// The BLE thread is flushing the queued messages before exiting.
+    Stopping,
+}
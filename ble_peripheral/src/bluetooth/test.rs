@@ -1,6 +1,6 @@
 #[cfg(test)]
 mod bluetooth_test {
-    use super::super::BleMessage;
+    use super::super::message::BleMessage;
     use super::super::BlePeripheral;
 
     #[tokio::test]
@@ -24,8 +24,11 @@ mod bluetooth_test {
 
         // Asumming that the central device will send the same exact message back to the peripheral
 
-        // Wait for the same message to be received.
-        let message = ble.receive_message().await;
+        // Wait for the same message to be received, failing if the central does not answer.
+        let message = ble
+            .receive_message_or_timeout(tokio::time::Duration::from_secs(30))
+            .await
+            .unwrap();
 
         // Check if the message is text and if it is the same message that was sent.
         if let BleMessage::Text(message) = message.convert_to_text().unwrap() {
@@ -35,6 +38,4311 @@ mod bluetooth_test {
         }
 
         // Stop the BLE peripheral engine.
-        ble.stop_engine().await;
+        ble.stop_engine(None).await;
+    }
+
+    #[tokio::test]
+    async fn available_adapters_test() {
+        // Check if the user wants to run this test
+        let should_run = std::env::var("TEST_BLUETOOTH").unwrap_or("0".to_string());
+        if should_run != "1" {
+            return;
+        }
+
+        // List the adapters without creating a BLE peripheral.
+        let adapters = BlePeripheral::available_adapters().await.unwrap();
+        assert!(!adapters.is_empty());
+    }
+
+    #[tokio::test]
+    async fn discoverable_restored_test() {
+        // Check if the user wants to run this test
+        let should_run = std::env::var("TEST_BLUETOOTH").unwrap_or("0".to_string());
+        if should_run != "1" {
+            return;
+        }
+
+        // Make the adapter not discoverable before starting.
+        let session = bluer::Session::new().await.unwrap();
+        let adapter = session.default_adapter().await.unwrap();
+        adapter.set_powered(true).await.unwrap();
+        adapter.set_discoverable(false).await.unwrap();
+
+        // The adapter is discoverable while the engine runs.
+        let mut ble = BlePeripheral::builder()
+            .alias("TESTER")
+            .adapter_discoverable(true)
+            .build()
+            .unwrap();
+        ble.start_engine().await.unwrap();
+        assert!(adapter.is_discoverable().await.unwrap());
+
+        // The previous state is restored after stopping.
+        ble.stop_engine(None).await;
+        assert!(!adapter.is_discoverable().await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn wait_until_advertising_test() {
+        // Check if the user wants to run this test
+        let should_run = std::env::var("TEST_BLUETOOTH").unwrap_or("0".to_string());
+        if should_run != "1" {
+            return;
+        }
+
+        let mut ble = BlePeripheral::new(Some("TESTER".to_string()))
+            .await
+            .unwrap();
+        ble.start_engine().await.unwrap();
+
+        // The advertisement is live once the wait returns
+        ble.wait_until_advertising(tokio::time::Duration::from_secs(5))
+            .await
+            .unwrap();
+        let adapter = ble.adapter.as_ref().unwrap();
+        assert!(adapter.active_advertising_instances().await.unwrap() > 0);
+
+        ble.stop_engine(None).await;
+    }
+
+    #[tokio::test]
+    async fn negotiate_mtu_test() {
+        // Check if the user wants to run this test
+        let should_run = std::env::var("TEST_BLUETOOTH").unwrap_or("0".to_string());
+        if should_run != "1" {
+            return;
+        }
+
+        let mut ble = BlePeripheral::new(Some("TESTER".to_string()))
+            .await
+            .unwrap();
+        ble.start_engine().await.unwrap();
+
+        // Wait for the central device to subscribe to the peripheral.
+        while !ble.is_subscribed().await {
+            tokio::time::sleep(tokio::time::Duration::from_millis(200)).await;
+        }
+
+        // The ATT MTU is at least 23 and at most 517 bytes
+        let mtu = ble.negotiate_mtu(517).await.unwrap();
+        assert!((23..=517).contains(&mtu));
+
+        ble.stop_engine(None).await;
+    }
+
+    #[tokio::test]
+    async fn security_level_test() {
+        // Check if the user wants to run this test
+        let should_run = std::env::var("TEST_BLUETOOTH").unwrap_or("0".to_string());
+        if should_run != "1" {
+            return;
+        }
+
+        let mut ble = BlePeripheral::new(Some("TESTER".to_string()))
+            .await
+            .unwrap();
+        assert_eq!(ble.security_level().await, None);
+
+        // No central is connected right after starting
+        ble.start_engine().await.unwrap();
+        assert_eq!(ble.security_level().await, None);
+
+        ble.stop_engine(None).await;
+    }
+
+    #[tokio::test]
+    async fn adapter_capabilities_test() {
+        // Check if the user wants to run this test
+        let should_run = std::env::var("TEST_BLUETOOTH").unwrap_or("0".to_string());
+        if should_run != "1" {
+            return;
+        }
+
+        let ble = BlePeripheral::new(Some("TESTER".to_string()))
+            .await
+            .unwrap();
+        let capabilities = ble.adapter_capabilities().await.unwrap();
+        assert!(capabilities.advertising_instances > 0);
+        assert_eq!(ble.adapter_capabilities().await.unwrap(), capabilities);
+    }
+
+    #[tokio::test]
+    async fn address_type_test() {
+        // Check if the user wants to run this test
+        let should_run = std::env::var("TEST_BLUETOOTH").unwrap_or("0".to_string());
+        if should_run != "1" {
+            return;
+        }
+
+        // Requiring the address type the adapter already uses lets the engine start
+        let session = bluer::Session::new().await.unwrap();
+        let adapter = session.default_adapter().await.unwrap();
+        let address_type = adapter.address_type().await.unwrap();
+        let mut ble = BlePeripheral::builder()
+            .alias("TESTER")
+            .address(adapter.address().await.unwrap())
+            .address_type(address_type)
+            .build()
+            .unwrap();
+        ble.start_engine().await.unwrap();
+        ble.stop_engine(None).await;
+    }
+
+    #[tokio::test]
+    async fn rotate_uuids_test() {
+        // Check if the user wants to run this test
+        let should_run = std::env::var("TEST_BLUETOOTH").unwrap_or("0".to_string());
+        if should_run != "1" {
+            return;
+        }
+
+        let mut ble = BlePeripheral::new(Some("TESTER".to_string()))
+            .await
+            .unwrap();
+        ble.start_engine().await.unwrap();
+        let service = uuid::Uuid::from_u128(0x5E3A0001_0000_1000_8000_00805F9B34FB);
+        let characteristic = uuid::Uuid::from_u128(0x5E3A0002_0000_1000_8000_00805F9B34FB);
+        ble.rotate_uuids(service, characteristic).await.unwrap();
+
+        // The engine keeps running through the rotation
+        assert!(ble.is_running());
+        ble.send_message("rotated").await.unwrap();
+        ble.stop_engine(None).await;
+    }
+
+    #[tokio::test]
+    async fn beacon_mode_test() {
+        // Check if the user wants to run this test
+        let should_run = std::env::var("TEST_BLUETOOTH").unwrap_or("0".to_string());
+        if should_run != "1" {
+            return;
+        }
+
+        let mut ble = BlePeripheral::builder()
+            .alias("TESTER")
+            .beacon_mode(true)
+            .build()
+            .unwrap();
+        ble.start_engine().await.unwrap();
+
+        // Only the advertisement is registered
+        assert!(ble.app_handler.is_none());
+        assert!(ble.adv_handler.lock().unwrap().is_some());
+        for reading in 0..3u8 {
+            ble.broadcast_data(vec![reading]).await.unwrap();
+        }
+        ble.stop_engine(None).await;
+    }
+}
+
+#[cfg(test)]
+mod message_test {
+    use super::super::capture::FrameDirection;
+    use super::super::chunk::{split_into_chunks, ChunkReassembler};
+    use super::super::coalesce::split_coalesced;
+    use super::super::codec::MessageCodec;
+    use super::super::control::ControlMessage;
+    use super::super::endian::Endianness;
+    use super::super::envelope::{BleEnvelope, MessageSource};
+    use super::super::error::BleError;
+    use super::super::event::BleEngineEvent;
+    use super::super::handshake::PROTOCOL_VERSION;
+    use super::super::header::{HeaderFlags, MessageHeader, MESSAGE_HEADER_SIZE};
+    use super::super::message::{BleMessage, MessageKind, READY_MARKER};
+    use super::super::mock::start_mock_engine;
+    use super::super::receive::ReceivePipeline;
+    use super::super::replay::ReplayGuard;
+    use super::super::sensor::{SensorReading, SensorUnit};
+    use super::super::splitter::{LengthPrefixed, Reassembler, Splitter};
+    use super::super::tlv::{encode_records, TlvDecoder, TlvRecord};
+    use super::super::transfer::{decode_file_chunk, encode_file_chunk};
+    use super::super::BlePeripheral;
+    use std::time::UNIX_EPOCH;
+    use tokio::sync::mpsc;
+    use tokio::time::{Duration, Instant};
+
+    #[test]
+    fn shuffled_chunks_reassemble() {
+        let payload: Vec<u8> = (0..=255).collect();
+        let mut chunks = split_into_chunks(&payload, 20, Endianness::Big).unwrap();
+        assert_eq!(chunks.len(), 13);
+
+        // Deliver the chunks in a scrambled order
+        chunks.reverse();
+        chunks.swap(0, 6);
+        chunks.swap(3, 11);
+
+        let mut reassembler = ChunkReassembler::default();
+        let last = chunks.pop().unwrap();
+        for chunk in chunks {
+            assert!(reassembler.push(&chunk).unwrap().is_none());
+        }
+        assert_eq!(reassembler.push(&last).unwrap(), Some(payload));
+        assert!(reassembler.missing_indices().is_empty());
+    }
+
+    #[tokio::test]
+    async fn permanent_gap_errors() {
+        let payload: Vec<u8> = (0..100).collect();
+        let chunks = split_into_chunks(&payload, 30, Endianness::Big).unwrap();
+
+        let mut reassembler = ChunkReassembler::new(Duration::from_millis(20));
+        for chunk in chunks.iter().skip(1) {
+            assert!(reassembler.push(chunk).unwrap().is_none());
+        }
+        assert_eq!(reassembler.missing_indices(), vec![0]);
+
+        // The first chunk never arrives
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(reassembler.check_timeout().is_err());
+        assert!(reassembler.missing_indices().is_empty());
+    }
+
+    #[tokio::test]
+    async fn receive_loop_times_out_when_nothing_follows_the_gap() {
+        let payload: Vec<u8> = (0..100).collect();
+        let chunks = split_into_chunks(&payload, 30, Endianness::Big).unwrap();
+        let (sender, mut receiver) = mpsc::unbounded_channel();
+        for chunk in chunks.into_iter().skip(1) {
+            sender.send(chunk).unwrap();
+        }
+
+        // The sender stays open, so only the deadline can end the loop
+        let mut reassembler = ChunkReassembler::new(Duration::from_millis(20));
+        let started = Instant::now();
+        let error = loop {
+            let deadline = reassembler.deadline();
+            tokio::select! {
+                Some(chunk) = receiver.recv() => {
+                    assert!(reassembler.push(&chunk).unwrap().is_none());
+                }
+                _ = tokio::time::sleep_until(deadline.unwrap_or_else(Instant::now)), if deadline.is_some() => {
+                    if let Err(error) = reassembler.check_timeout() {
+                        break error;
+                    }
+                }
+            }
+        };
+        assert!(error.to_string().contains("[0]"));
+        assert!(started.elapsed() < Duration::from_secs(1));
+        assert!(reassembler.deadline().is_none());
+        drop(sender);
+    }
+
+    #[test]
+    fn stream_is_split_on_delimiter() {
+        let ble = BlePeripheral::builder()
+            .text_delimiter(b'\n')
+            .build()
+            .unwrap();
+        let mut pipeline = ReceivePipeline::new(&ble.config);
+
+        // The second message is split across reads, and a read ends on the delimiter
+        let mut texts = Vec::new();
+        for read in [&b"hello\nwor"[..], b"ld", b"\nlast\n", b"partial"] {
+            for envelope in pipeline.process(read.to_vec()).unwrap() {
+                match envelope.message {
+                    BleMessage::Text(text) => texts.push(text),
+                    message => panic!("Unexpected message {}", message),
+                }
+            }
+        }
+        assert_eq!(texts, vec!["hello", "world", "last"]);
+    }
+
+    #[tokio::test]
+    async fn sent_text_gets_delimiter() {
+        let mut ble = BlePeripheral::builder().text_delimiter(0).build().unwrap();
+        let (send_tx, mut send_rx) = mpsc::unbounded_channel();
+        ble.sender = Some(send_tx);
+
+        ble.send_message("ping").await.unwrap();
+        ble.send_message(vec![1, 2]).await.unwrap();
+        assert_eq!(
+            send_rx.recv().await.unwrap().message.take_bytes(),
+            b"ping\0"
+        );
+        assert_eq!(
+            send_rx.recv().await.unwrap().message.take_bytes(),
+            vec![1, 2]
+        );
+    }
+
+    #[test]
+    fn readings_round_trip() {
+        let units = [
+            SensorUnit::Celsius,
+            SensorUnit::Percent,
+            SensorUnit::Pascal,
+            SensorUnit::Volt,
+        ];
+        for (i, unit) in units.into_iter().enumerate() {
+            let reading = SensorReading {
+                value: -12.625 * i as f64,
+                unit,
+                timestamp: UNIX_EPOCH + Duration::from_micros(1_700_000_000_123_456 + i as u64),
+            };
+            let message = reading.to_ble_message(Endianness::Big);
+            assert_eq!(
+                SensorReading::from_ble_message(&message, Endianness::Big).unwrap(),
+                reading
+            );
+        }
+    }
+
+    #[test]
+    fn timestamp_keeps_microsecond_precision() {
+        let reading = SensorReading::new(21.5, SensorUnit::Celsius);
+        let decoded = SensorReading::from_ble_message(&reading.into(), Endianness::Big).unwrap();
+        let drift = reading.timestamp.duration_since(decoded.timestamp).unwrap();
+        assert!(drift < Duration::from_micros(1));
+    }
+
+    #[test]
+    fn malformed_readings_are_rejected() {
+        assert!(
+            SensorReading::from_ble_message(&BleMessage::from("21.5"), Endianness::Big).is_err()
+        );
+        assert!(
+            SensorReading::from_ble_message(&BleMessage::Raw(vec![0; 4]), Endianness::Big).is_err()
+        );
+        assert!(
+            SensorReading::from_ble_message(&BleMessage::Raw(vec![0xFF; 17]), Endianness::Big)
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn ready_message_matches_its_marker() {
+        assert!(BleMessage::ready().is_ready());
+        assert!(BleMessage::from(READY_MARKER.to_vec()).is_ready());
+        assert!(!BleMessage::from(b"\xFF\xBD\n".to_vec()).is_ready());
+    }
+
+    #[test]
+    fn lookalike_text_is_not_ready() {
+        for text in ["Ready", "ready", " Ready", "Ready\n", "READY", ""] {
+            assert!(!BleMessage::from(text).is_ready(), "{:?}", text);
+        }
+        // Converting the marker to text replaces its invalid UTF-8
+        let converted = BleMessage::ready().convert_to_text().unwrap();
+        assert!(!converted.is_ready());
+    }
+
+    #[tokio::test]
+    async fn events_are_distinguished_from_data() {
+        let mut ble = BlePeripheral::new(None).await.unwrap();
+        let central = start_mock_engine(&mut ble);
+        let mut notifications = central.subscribe_and_wait(&ble, 512).await;
+
+        ble.send_event("battery low").await.unwrap();
+        ble.send_message("data").await.unwrap();
+
+        let event = BleMessage::from(notifications.recv().await.unwrap());
+        assert_eq!(
+            event.as_event(),
+            Some(BleMessage::from(b"battery low".to_vec()))
+        );
+        let data = BleMessage::from(notifications.recv().await.unwrap());
+        assert_eq!(data.as_event(), None);
+        assert_eq!(data.as_bytes(), b"data");
+    }
+
+    #[derive(Debug, PartialEq)]
+    struct Point {
+        x: i16,
+        y: i16,
+    }
+
+    /// Codec encoding a point as its two coordinates in big-endian.
+    struct PointCodec;
+
+    impl MessageCodec<Point> for PointCodec {
+        fn encode(point: &Point) -> Result<Vec<u8>, BleError> {
+            Ok([point.x.to_be_bytes(), point.y.to_be_bytes()].concat())
+        }
+
+        fn decode(bytes: &[u8]) -> Result<Point, BleError> {
+            match bytes {
+                [x0, x1, y0, y1] => Ok(Point {
+                    x: i16::from_be_bytes([*x0, *x1]),
+                    y: i16::from_be_bytes([*y0, *y1]),
+                }),
+                _ => Err(BleError::InvalidMessage("Expected 4 bytes".to_string())),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn custom_codec_round_trip() {
+        let mut ble = BlePeripheral::new(None).await.unwrap();
+        let central = start_mock_engine(&mut ble);
+        let mut notifications = central.subscribe_and_wait(&ble, 512).await;
+
+        // The central decodes the sent point and writes it back
+        let point = Point { x: -3, y: 7 };
+        ble.send_encoded::<PointCodec, _>(&point).await.unwrap();
+        let notification = notifications.recv().await.unwrap();
+        assert_eq!(PointCodec::decode(&notification).unwrap(), point);
+        central.write(&notification);
+        assert_eq!(
+            ble.receive_decoded::<PointCodec, Point>().await.unwrap(),
+            point
+        );
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn json_codec_round_trip() {
+        use super::super::codec::JsonCodec;
+
+        let value = serde_json::json!({ "x": -3, "y": 7 });
+        let bytes = JsonCodec::encode(&value).unwrap();
+        let decoded: serde_json::Value = JsonCodec::decode(&bytes).unwrap();
+        assert_eq!(decoded, value);
+    }
+
+    #[test]
+    fn coerces_between_variants() {
+        let text = BleMessage::from("hi");
+        assert_eq!(text.kind(), MessageKind::Text);
+        assert_eq!(
+            text.clone().coerce(MessageKind::Raw),
+            BleMessage::Raw(b"hi".to_vec())
+        );
+        assert_eq!(text.clone().coerce(MessageKind::Text), text);
+
+        let raw = BleMessage::Raw(vec![b'o', b'k', 0xFF]);
+        assert_eq!(raw.kind(), MessageKind::Raw);
+        assert_eq!(
+            raw.clone().coerce(MessageKind::Text),
+            BleMessage::Text("ok\u{FFFD}".to_string())
+        );
+        assert_eq!(raw.clone().coerce(MessageKind::Raw), raw);
+
+        // The strict conversion still rejects text
+        assert!(text.convert_to_text().is_err());
+    }
+
+    /// Framing marking the last chunk of each message with a leading 0, and the others with a 1.
+    #[derive(Clone, Default)]
+    struct Continuation {
+        pending: Vec<u8>,
+    }
+
+    impl Splitter for Continuation {
+        fn split(&self, bytes: &[u8], mtu: usize) -> Vec<Vec<u8>> {
+            let parts: Vec<&[u8]> = bytes.chunks(mtu - 1).collect();
+            parts
+                .iter()
+                .enumerate()
+                .map(|(index, part)| {
+                    let mut chunk = vec![(index + 1 < parts.len()) as u8];
+                    chunk.extend_from_slice(part);
+                    chunk
+                })
+                .collect()
+        }
+    }
+
+    impl Reassembler for Continuation {
+        fn push(&mut self, chunk: &[u8]) -> Vec<Vec<u8>> {
+            self.pending.extend_from_slice(&chunk[1..]);
+            match chunk[0] {
+                0 => vec![std::mem::take(&mut self.pending)],
+                _ => Vec::new(),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn custom_framing_round_trips() {
+        let mut ble = BlePeripheral::builder()
+            .framing(Continuation::default(), Continuation::default())
+            .capture_frames(16)
+            .build()
+            .unwrap();
+        let central = start_mock_engine(&mut ble);
+        let mut notifications = central.subscribe_and_wait(&ble, 8).await;
+        let payload: Vec<u8> = (0..20).collect();
+
+        // Sent messages are split by the custom splitter
+        ble.send_message(payload.clone()).await.unwrap();
+        let mut reassembler = Continuation::default();
+        let mut received = Vec::new();
+        while received.is_empty() {
+            let chunk = notifications.recv().await.unwrap();
+            assert!(chunk.len() <= 8);
+            received = reassembler.push(&chunk);
+        }
+        assert_eq!(received, vec![payload.clone()]);
+
+        // Received chunks are reassembled by the custom reassembler, one write at a time
+        let received_frames = |ble: &BlePeripheral| {
+            ble.recent_frames()
+                .iter()
+                .filter(|frame| frame.direction == FrameDirection::Received)
+                .count()
+        };
+        for (index, chunk) in Continuation::default()
+            .split(&payload, 8)
+            .iter()
+            .enumerate()
+        {
+            central.write(chunk);
+            while received_frames(&ble) <= index {
+                tokio::task::yield_now().await;
+            }
+        }
+        assert_eq!(
+            ble.receive_message().await.unwrap(),
+            BleMessage::Raw(payload)
+        );
+    }
+
+    #[test]
+    fn length_prefixed_messages_share_chunks() {
+        let splitter = LengthPrefixed::default();
+        let mut stream = splitter.split(b"hello", 3).concat();
+        stream.extend(splitter.split(b"world", 3).concat());
+
+        let mut reassembler = LengthPrefixed::default();
+        let mut messages = Vec::new();
+        for chunk in stream.chunks(5) {
+            messages.extend(reassembler.push(chunk));
+        }
+        assert_eq!(messages, vec![b"hello".to_vec(), b"world".to_vec()]);
+    }
+
+    #[test]
+    fn single_record_is_decoded() {
+        let mut decoder = TlvDecoder::default();
+        let bytes = encode_records(&[TlvRecord::new(0x01, b"temp".to_vec())], Endianness::Big);
+        assert_eq!(bytes, [0x01, 0x00, 0x04, b't', b'e', b'm', b'p']);
+        assert_eq!(
+            decoder.push(&bytes),
+            vec![TlvRecord::new(0x01, b"temp".to_vec())]
+        );
+    }
+
+    #[test]
+    fn multiple_records_share_a_notification() {
+        let mut decoder = TlvDecoder::default();
+        let records = vec![
+            TlvRecord::new(0x01, vec![20]),
+            TlvRecord::new(0x02, Vec::new()),
+            TlvRecord::new(0x03, b"alert".to_vec()),
+        ];
+        assert_eq!(
+            decoder.push(&encode_records(&records, Endianness::Big)),
+            records
+        );
+    }
+
+    #[test]
+    fn record_spans_notification_boundary() {
+        let mut decoder = TlvDecoder::default();
+        let records = vec![
+            TlvRecord::new(0x01, b"first".to_vec()),
+            TlvRecord::new(0x02, b"second".to_vec()),
+        ];
+        let bytes = encode_records(&records, Endianness::Big);
+
+        // The second record is split inside its header, then inside its value
+        assert_eq!(decoder.push(&bytes[..9]), records[..1]);
+        assert!(decoder.push(&bytes[9..12]).is_empty());
+        assert_eq!(decoder.push(&bytes[12..]), records[1..]);
+    }
+
+    #[tokio::test]
+    async fn received_records_are_tagged() {
+        let mut ble = BlePeripheral::builder().tlv_records(true).build().unwrap();
+        let central = start_mock_engine(&mut ble);
+        let bytes = encode_records(
+            &[
+                TlvRecord::new(0x01, b"one".to_vec()),
+                TlvRecord::new(0x02, b"two".to_vec()),
+            ],
+            Endianness::Big,
+        );
+        // Each write is read on its own, the second record spanning both
+        central.write(&bytes[..8]);
+        let envelope = ble.receive_envelope().await.unwrap();
+        assert_eq!(envelope.meta.tag, Some(0x01));
+        assert_eq!(envelope.message, BleMessage::from(b"one".to_vec()));
+
+        central.write(&bytes[8..]);
+        let envelope = ble.receive_envelope().await.unwrap();
+        assert_eq!(envelope.meta.tag, Some(0x02));
+        assert_eq!(envelope.message, BleMessage::from(b"two".to_vec()));
+    }
+
+    #[test]
+    fn header_round_trips() {
+        let header = MessageHeader {
+            version: 3,
+            msg_type: 0x42,
+            flags: HeaderFlags::COMPRESSED,
+            length: 0x0102_0304,
+        };
+        let bytes = header.encode(Endianness::Big);
+        assert_eq!(bytes, [3, 0x42, 0b001, 1, 2, 3, 4]);
+        assert_eq!(
+            MessageHeader::decode(&bytes, Endianness::Big).unwrap(),
+            header
+        );
+
+        let header = MessageHeader::new(7, 0);
+        assert_eq!(header.version, PROTOCOL_VERSION);
+        assert_eq!(header.flags, HeaderFlags::empty());
+        assert_eq!(
+            MessageHeader::decode(&header.encode(Endianness::Big), Endianness::Big).unwrap(),
+            header
+        );
+    }
+
+    #[test]
+    fn flags_combine() {
+        let flags = HeaderFlags::COMPRESSED.union(HeaderFlags::CHECKSUMMED);
+        assert!(flags.contains(HeaderFlags::COMPRESSED));
+        assert!(flags.contains(HeaderFlags::CHECKSUMMED));
+        assert!(!flags.contains(HeaderFlags::ENCRYPTED));
+        assert!(!flags.contains(HeaderFlags::ALL));
+        assert!(HeaderFlags::ALL.contains(flags));
+        assert!(flags.contains(HeaderFlags::empty()));
+
+        for bits in 0..=HeaderFlags::ALL.bits() {
+            let header = MessageHeader::new(1, 10).flags(HeaderFlags::from_bits(bits).unwrap());
+            let decoded =
+                MessageHeader::decode(&header.encode(Endianness::Big), Endianness::Big).unwrap();
+            assert_eq!(decoded.flags.bits(), bits);
+        }
+    }
+
+    #[test]
+    fn reserved_flags_are_rejected() {
+        assert!(matches!(
+            HeaderFlags::from_bits(0b1000),
+            Err(BleError::InvalidMessage(_))
+        ));
+        let mut bytes = MessageHeader::new(1, 0).encode(Endianness::Big);
+        bytes[2] = 0x80 | HeaderFlags::ENCRYPTED.bits();
+        assert!(matches!(
+            MessageHeader::decode(&bytes, Endianness::Big),
+            Err(BleError::InvalidMessage(_))
+        ));
+    }
+
+    #[test]
+    fn truncated_headers_are_rejected() {
+        let bytes = MessageHeader::new(1, 4).encode(Endianness::Big);
+        for len in 0..MESSAGE_HEADER_SIZE {
+            assert!(matches!(
+                MessageHeader::decode(&bytes[..len], Endianness::Big),
+                Err(BleError::InvalidMessage(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn framed_message_splits_into_header_and_payload() {
+        let header = MessageHeader::new(9, 0).flags(HeaderFlags::ENCRYPTED);
+        let mut message = header.frame(b"payload", Endianness::Big).unwrap();
+        assert_eq!(message.len(), MESSAGE_HEADER_SIZE + 7);
+
+        let (decoded, payload) = MessageHeader::split(&message, Endianness::Big).unwrap();
+        assert_eq!(
+            decoded,
+            MessageHeader {
+                length: 7,
+                ..header
+            }
+        );
+        assert_eq!(payload, b"payload");
+
+        // Bytes following the payload are not part of the message
+        message.extend_from_slice(b"next");
+        assert_eq!(
+            MessageHeader::split(&message, Endianness::Big).unwrap().1,
+            b"payload"
+        );
+
+        // A payload shorter than announced is truncated
+        message.truncate(MESSAGE_HEADER_SIZE + 6);
+        assert!(matches!(
+            MessageHeader::split(&message, Endianness::Big),
+            Err(BleError::InvalidMessage(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn metadata_survives_loopback() {
+        let mut ble = BlePeripheral::new(None).await.unwrap();
+        assert!(matches!(
+            ble.loopback(BleEnvelope::new("early")),
+            Err(BleError::EngineNotStarted)
+        ));
+        let central = start_mock_engine(&mut ble);
+
+        ble.loopback(BleEnvelope::new("local").id(7).priority(2))
+            .unwrap();
+        let envelope = ble.receive_envelope().await.unwrap();
+        assert_eq!(envelope.message, BleMessage::from("local"));
+        assert_eq!(envelope.meta.id, Some(7));
+        assert_eq!(envelope.meta.priority, Some(2));
+        assert_eq!(envelope.meta.source, Some(MessageSource::Loopback));
+        assert!(envelope.meta.timestamp.is_some());
+
+        // Messages written by the central are stamped when received
+        central.write(b"remote");
+        let envelope = ble.receive_envelope().await.unwrap();
+        assert_eq!(envelope.message, BleMessage::from(b"remote".to_vec()));
+        assert_eq!(envelope.meta.id, None);
+        assert_eq!(envelope.meta.source, Some(MessageSource::Central));
+        assert!(envelope.meta.timestamp.is_some());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn received_at_is_set_before_consumption() {
+        let mut ble = BlePeripheral::new(None).await.unwrap();
+        let central = start_mock_engine(&mut ble);
+        central.write(b"late");
+        while ble.pending_messages() == 0 {
+            tokio::task::yield_now().await;
+        }
+        let queued = Instant::now();
+
+        // The message waits in the queue before being consumed
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let received = ble.receive_timestamped().await.unwrap();
+        assert_eq!(received.message, BleMessage::from(b"late".to_vec()));
+        assert!(received.received_at <= queued);
+        assert!(received.received_at.elapsed() >= Duration::from_millis(50));
+    }
+
+    #[tokio::test]
+    async fn valid_utf8_arrives_as_text() {
+        let mut ble = BlePeripheral::builder().auto_text(true).build().unwrap();
+        let central = start_mock_engine(&mut ble);
+
+        central.write("héllo".as_bytes());
+        assert_eq!(
+            ble.receive_message().await.unwrap(),
+            BleMessage::Text("héllo".into())
+        );
+        central.write(&[0xDE, 0xAD, 0xBE, 0xEF]);
+        assert_eq!(
+            ble.receive_message().await.unwrap(),
+            BleMessage::Raw(vec![0xDE, 0xAD, 0xBE, 0xEF])
+        );
+    }
+
+    #[tokio::test]
+    async fn text_stays_raw_by_default() {
+        let mut ble = BlePeripheral::new(None).await.unwrap();
+        let central = start_mock_engine(&mut ble);
+        central.write(b"hello");
+        assert_eq!(
+            ble.receive_message().await.unwrap(),
+            BleMessage::Raw(b"hello".to_vec())
+        );
+    }
+
+    #[test]
+    fn numbers_follow_endianness() {
+        let big = BleMessage::from_u32(0x01020304, Endianness::Big);
+        let little = BleMessage::from_u32(0x01020304, Endianness::Little);
+        assert_eq!(big.as_bytes(), &[0x01, 0x02, 0x03, 0x04]);
+        assert_eq!(little.as_bytes(), &[0x04, 0x03, 0x02, 0x01]);
+        assert_eq!(big.to_u32(Endianness::Big).unwrap(), 0x01020304);
+        assert_eq!(little.to_u32(Endianness::Little).unwrap(), 0x01020304);
+
+        let big = BleMessage::from_u64(1, Endianness::Big);
+        let little = BleMessage::from_u64(1, Endianness::Little);
+        assert_eq!(big.as_bytes(), &[0, 0, 0, 0, 0, 0, 0, 1]);
+        assert_eq!(little.as_bytes(), &[1, 0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(little.to_u64(Endianness::Little).unwrap(), 1);
+        assert!(big.to_u32(Endianness::Big).is_err());
+
+        // Length prefixes use the same byte order
+        let big = BleMessage::from("hi").length_prefixed(Endianness::Big);
+        let little = BleMessage::from("hi").length_prefixed(Endianness::Little);
+        assert_eq!(big.as_bytes(), &[0, 0, 0, 2, b'h', b'i']);
+        assert_eq!(little.as_bytes(), &[2, 0, 0, 0, b'h', b'i']);
+    }
+
+    #[test]
+    fn sensor_readings_round_trip_little_endian() {
+        let reading = SensorReading {
+            value: 21.5,
+            unit: SensorUnit::Celsius,
+            timestamp: UNIX_EPOCH + Duration::from_micros(1),
+        };
+        let message = reading.to_ble_message(Endianness::Little);
+        assert_eq!(&message.as_bytes()[1..9], &21.5f64.to_le_bytes());
+        assert_eq!(&message.as_bytes()[9..], &[1, 0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(
+            SensorReading::from_ble_message(&message, Endianness::Little).unwrap(),
+            reading
+        );
+    }
+
+    #[test]
+    fn chunk_headers_round_trip_little_endian() {
+        let chunk = encode_file_chunk(1, b"file", Endianness::Little);
+        assert_eq!(&chunk[..8], &[1, 0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(
+            decode_file_chunk(&chunk, Endianness::Little).unwrap(),
+            (1, &b"file"[..])
+        );
+
+        let chunks = split_into_chunks(&[7; 10], 5, Endianness::Little).unwrap();
+        assert_eq!(&chunks[1][..4], &[1, 0, 2, 0]);
+        let mut reassembler = ChunkReassembler::default().with_endianness(Endianness::Little);
+        assert_eq!(reassembler.push(&chunks[1]).unwrap(), None);
+        assert_eq!(reassembler.push(&chunks[0]).unwrap(), Some(vec![7; 10]));
+    }
+
+    #[test]
+    fn control_messages_round_trip_little_endian() {
+        let ack = ControlMessage::TransferAck { offset: 1 };
+        let bytes = ack.to_bytes(Endianness::Little);
+        assert_eq!(&bytes[3..], &[1, 0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(
+            ControlMessage::from_bytes(&bytes, Endianness::Little),
+            Some(ack)
+        );
+        assert_ne!(
+            ControlMessage::from_bytes(&bytes, Endianness::Big),
+            Some(ack)
+        );
+
+        let progress = ControlMessage::TransferProgress {
+            chunks: 1,
+            total: 2,
+        };
+        let bytes = progress.to_bytes(Endianness::Little);
+        assert_eq!(&bytes[3..], &[1, 0, 0, 0, 2, 0, 0, 0]);
+        assert_eq!(
+            ControlMessage::from_bytes(&bytes, Endianness::Little),
+            Some(progress)
+        );
+    }
+
+    #[test]
+    fn replay_counters_round_trip_little_endian() {
+        let mut sender = ReplayGuard::new().with_endianness(Endianness::Little);
+        let mut receiver = ReplayGuard::new().with_endianness(Endianness::Little);
+        sender.next_counter();
+        let sealed = sender.seal(b"payload");
+        assert_eq!(&sealed[..8], &[1, 0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(receiver.open(&sealed).unwrap(), b"payload".to_vec());
+        assert!(receiver.open(&sealed).is_err());
+    }
+
+    #[test]
+    fn tlv_lengths_round_trip_little_endian() {
+        let records = [TlvRecord::new(0x01, b"temp".to_vec())];
+        let bytes = encode_records(&records, Endianness::Little);
+        assert_eq!(bytes, [0x01, 0x04, 0x00, b't', b'e', b'm', b'p']);
+        let mut decoder = TlvDecoder::default().with_endianness(Endianness::Little);
+        assert_eq!(decoder.push(&bytes), records.to_vec());
+    }
+
+    #[test]
+    fn headers_round_trip_little_endian() {
+        let header = MessageHeader::new(1, 0x0102);
+        let bytes = header.encode(Endianness::Little);
+        assert_eq!(&bytes[3..], &[2, 1, 0, 0]);
+        assert_eq!(
+            MessageHeader::decode(&bytes, Endianness::Little).unwrap(),
+            header
+        );
+    }
+
+    #[test]
+    fn length_prefixes_round_trip_little_endian() {
+        let splitter = LengthPrefixed::default().with_endianness(Endianness::Little);
+        let chunks = splitter.split(b"abc", 512);
+        assert_eq!(chunks, vec![vec![3, 0, 0, 0, b'a', b'b', b'c']]);
+        let mut reassembler = LengthPrefixed::default().with_endianness(Endianness::Little);
+        assert_eq!(reassembler.push(&chunks[0]), vec![b"abc".to_vec()]);
+    }
+
+    #[tokio::test]
+    async fn coalesced_frames_use_the_configured_byte_order() {
+        let mut ble = BlePeripheral::builder()
+            .endianness(Endianness::Little)
+            .coalesce(64, Duration::from_millis(20))
+            .build()
+            .unwrap();
+        let central = start_mock_engine(&mut ble);
+        let mut notifications = central.subscribe_and_wait(&ble, 512).await;
+
+        let sent: Vec<Vec<u8>> = (0..3u8).map(|i| vec![i; 3]).collect();
+        for message in sent.iter() {
+            ble.send_message(message.clone()).await.unwrap();
+        }
+        let mut received = Vec::new();
+        while received.len() < sent.len() {
+            let notification = notifications.recv().await.unwrap();
+            assert_eq!(&notification[..2], &[3, 0]);
+            received.extend(split_coalesced(&notification, Endianness::Little).unwrap());
+        }
+        assert_eq!(received, sent);
+    }
+
+    #[tokio::test]
+    async fn message_ids_use_the_configured_byte_order() {
+        let mut ble = BlePeripheral::builder()
+            .endianness(Endianness::Little)
+            .dedup_window(4)
+            .build()
+            .unwrap();
+        let central = start_mock_engine(&mut ble);
+        let mut bytes = 0x0102u64.to_le_bytes().to_vec();
+        bytes.extend_from_slice(b"payload");
+        central.write(&bytes);
+        central.write(&bytes);
+        central.write(&[&3u64.to_le_bytes()[..], b"next"].concat());
+
+        let envelope = ble.receive_envelope().await.unwrap();
+        assert_eq!(envelope.meta.id, Some(0x0102));
+        assert_eq!(envelope.message, BleMessage::Raw(b"payload".to_vec()));
+        let envelope = ble.receive_envelope().await.unwrap();
+        assert_eq!(envelope.meta.id, Some(3));
+        ble.stop_engine(None).await;
+    }
+
+    #[tokio::test]
+    async fn peripheral_encodes_in_the_configured_byte_order() {
+        let mut ble = BlePeripheral::builder()
+            .endianness(Endianness::Little)
+            .transfer_progress(1)
+            .build()
+            .unwrap();
+        let central = start_mock_engine(&mut ble);
+        let mut notifications = central.subscribe_and_wait(&ble, 512).await;
+
+        ble.send_file((0..20).collect(), 10).await.unwrap();
+        let mut offsets = Vec::new();
+        while offsets.len() < 2 {
+            let notification = notifications.recv().await.unwrap();
+            if ControlMessage::from_bytes(&notification, Endianness::Little).is_none() {
+                offsets.push(
+                    decode_file_chunk(&notification, Endianness::Little)
+                        .unwrap()
+                        .0,
+                );
+            }
+        }
+        assert_eq!(offsets, vec![0, 10]);
+
+        // The acknowledgments of the central are decoded in the same byte order
+        central.write(&ControlMessage::TransferAck { offset: 10 }.to_bytes(Endianness::Little));
+        while ble.file_transfer_offset() != Some(10) {
+            tokio::task::yield_now().await;
+        }
+    }
+
+    #[test]
+    fn invalid_text_is_rejected() {
+        let malformed = b"ok\n\xFF\xFE\n".to_vec();
+        let lenient = BlePeripheral::builder()
+            .text_delimiter(b'\n')
+            .build()
+            .unwrap();
+        let mut pipeline = ReceivePipeline::new(&lenient.config);
+        assert_eq!(pipeline.process(malformed.clone()).unwrap().len(), 2);
+
+        let strict = BlePeripheral::builder()
+            .text_delimiter(b'\n')
+            .strict_validation(true)
+            .build()
+            .unwrap();
+        let mut pipeline = ReceivePipeline::new(&strict.config);
+        assert!(pipeline.process(malformed).is_err());
+    }
+
+    #[test]
+    fn unframed_bytes_are_rejected_on_reset() {
+        for strict in [false, true] {
+            let ble = BlePeripheral::builder()
+                .framing(LengthPrefixed::default(), LengthPrefixed::default())
+                .strict_validation(strict)
+                .build()
+                .unwrap();
+            let mut pipeline = ReceivePipeline::new(&ble.config);
+
+            // The bytes are read as the header of a huge message that never completes
+            assert!(pipeline.process(b"hello".to_vec()).unwrap().is_empty());
+            assert_eq!(pipeline.reset().is_err(), strict);
+            assert!(pipeline.reset().is_ok());
+        }
+    }
+
+    #[tokio::test]
+    async fn violation_is_reported_and_stream_recovers() {
+        let mut ble = BlePeripheral::builder()
+            .text_delimiter(b'\n')
+            .strict_validation(true)
+            .build()
+            .unwrap();
+        let mut events = ble.subscribe_events();
+        let central = start_mock_engine(&mut ble);
+
+        central.write(b"\xC3\x28\n");
+        match events.recv().await.unwrap() {
+            BleEngineEvent::ProtocolViolation { reason } => assert!(reason.contains("UTF-8")),
+            event => panic!("Unexpected event {:?}", event),
+        }
+        central.write(b"valid\n");
+        assert_eq!(
+            ble.receive_message().await.unwrap(),
+            BleMessage::from("valid")
+        );
+        assert_eq!(ble.pending_messages(), 0);
+    }
+}
+
+#[cfg(test)]
+mod gatt_test {
+    use super::super::battery::{BATTERY_LEVEL_UUID, BATTERY_SERVICE_UUID};
+    use super::super::error::BleError;
+    use super::super::message::BleMessage;
+    use super::super::mock::{start_mock_engine, MockTransport};
+    use super::super::security::{Permissions, SecurityLevel};
+    use super::super::transport::gatt_link;
+    use super::super::{
+        handle_validated_write, BlePeripheral, WriteValidator, DEFAULT_CHARACTERISTIC_UUID,
+        DEFAULT_SERVICE_UUID,
+    };
+    use bluer::gatt::local::{characteristic_control, service_control, ReqError};
+    use std::sync::Arc;
+    use tokio::sync::mpsc;
+    use uuid::Uuid;
+
+    #[tokio::test]
+    async fn public_uuids_match_internal_layout() {
+        assert_eq!(
+            crate::DEFAULT_SERVICE_UUID,
+            Uuid::from_u128(0x0000181C00001000800000805F9B34FB)
+        );
+        assert_eq!(
+            crate::DEFAULT_CHARACTERISTIC_UUID,
+            Uuid::from_u128(0x00002AC400001000800000805F9B34FB)
+        );
+
+        let ble = BlePeripheral::new(None).await.unwrap();
+
+        // The advertisement and GATT application use the public constants
+        let adv = ble.advertisement();
+        assert!(adv.service_uuids.contains(&crate::DEFAULT_SERVICE_UUID));
+
+        let (_, service_handle) = service_control();
+        let (_, char_handle) = characteristic_control();
+        let (write_tx, _write_rx) = tokio::sync::mpsc::unbounded_channel();
+        let app = ble.gatt_application(service_handle, char_handle, None, None, &write_tx);
+        assert_eq!(app.services[0].uuid, crate::DEFAULT_SERVICE_UUID);
+        assert_eq!(
+            app.services[0].characteristics[0].uuid,
+            crate::DEFAULT_CHARACTERISTIC_UUID
+        );
+        // The characteristic is not readable unless read responses are enabled
+        assert!(app.services[0].characteristics[0].read.is_none());
+    }
+
+    const SERVICE_UUID: Uuid = Uuid::from_u128(0x5E3A0001_0000_1000_8000_00805F9B34FB);
+    const CHARACTERISTIC_UUID: Uuid = Uuid::from_u128(0x5E3A0002_0000_1000_8000_00805F9B34FB);
+
+    #[tokio::test]
+    async fn gatt_layout_uses_rotated_uuids() {
+        let mut ble = BlePeripheral::new(None).await.unwrap();
+        ble.rotate_uuids(SERVICE_UUID, CHARACTERISTIC_UUID)
+            .await
+            .unwrap();
+
+        let adv = ble.advertisement();
+        assert!(adv.service_uuids.contains(&SERVICE_UUID));
+        assert!(!adv.service_uuids.contains(&DEFAULT_SERVICE_UUID));
+
+        let (write_tx, _write_rx) = mpsc::unbounded_channel();
+        let (app, _events) = gatt_link(&ble, &write_tx);
+        assert_eq!(app.services[0].uuid, SERVICE_UUID);
+        assert_eq!(app.services[0].characteristics[0].uuid, CHARACTERISTIC_UUID);
+    }
+
+    #[tokio::test]
+    async fn rotation_replaces_command_characteristic() {
+        let response_uuid = Uuid::from_u128(0x6E400003B5A3F393E0A9E50E24DCCA9E);
+        let mut ble = BlePeripheral::builder()
+            .command_response_layout(
+                Uuid::from_u128(0x6E400002B5A3F393E0A9E50E24DCCA9E),
+                response_uuid,
+            )
+            .build()
+            .unwrap();
+        ble.rotate_uuids(SERVICE_UUID, CHARACTERISTIC_UUID)
+            .await
+            .unwrap();
+
+        let (write_tx, _write_rx) = mpsc::unbounded_channel();
+        let (app, _events) = gatt_link(&ble, &write_tx);
+        let uuids: Vec<Uuid> = app.services[0]
+            .characteristics
+            .iter()
+            .map(|characteristic| characteristic.uuid)
+            .collect();
+        assert_eq!(uuids, vec![CHARACTERISTIC_UUID, response_uuid]);
+    }
+
+    #[test]
+    fn battery_service_is_served() {
+        let ble = BlePeripheral::builder()
+            .battery_service(120)
+            .build()
+            .unwrap();
+        assert_eq!(ble.battery_level(), Some(100));
+
+        let (_, service_handle) = service_control();
+        let (_, char_handle) = characteristic_control();
+        let (write_tx, _write_rx) = mpsc::unbounded_channel();
+        let app = ble.gatt_application(service_handle, char_handle, None, None, &write_tx);
+
+        let service = app
+            .services
+            .iter()
+            .find(|service| service.uuid == BATTERY_SERVICE_UUID)
+            .unwrap();
+        let characteristic = &service.characteristics[0];
+        assert_eq!(characteristic.uuid, BATTERY_LEVEL_UUID);
+        assert!(characteristic.read.as_ref().unwrap().read);
+        assert!(characteristic.notify.as_ref().unwrap().notify);
+    }
+
+    #[test]
+    fn set_battery_level_updates_value() {
+        let ble = BlePeripheral::builder()
+            .battery_service(80)
+            .build()
+            .unwrap();
+        ble.set_battery_level(42);
+        assert_eq!(ble.battery_level(), Some(42));
+        ble.set_battery_level(255);
+        assert_eq!(ble.battery_level(), Some(100));
+
+        // Without the service there is no level to update
+        let ble = BlePeripheral::builder().build().unwrap();
+        ble.set_battery_level(42);
+        assert_eq!(ble.battery_level(), None);
+    }
+
+    #[test]
+    fn command_response_layout_splits_characteristics() {
+        let command_uuid = Uuid::from_u128(0x6E400002B5A3F393E0A9E50E24DCCA9E);
+        let response_uuid = Uuid::from_u128(0x6E400003B5A3F393E0A9E50E24DCCA9E);
+        let ble = BlePeripheral::builder()
+            .command_response_layout(command_uuid, response_uuid)
+            .build()
+            .unwrap();
+
+        let (_, service_handle) = service_control();
+        let (_command_control, command_handle) = characteristic_control();
+        let (_response_control, response_handle) = characteristic_control();
+        let (write_tx, _write_rx) = mpsc::unbounded_channel();
+        let app = ble.gatt_application(
+            service_handle,
+            command_handle,
+            Some(response_handle),
+            None,
+            &write_tx,
+        );
+
+        let characteristics = &app.services[0].characteristics;
+        assert_eq!(characteristics.len(), 2);
+
+        // The command characteristic is write-only
+        let command = &characteristics[0];
+        assert_eq!(command.uuid, command_uuid);
+        assert!(command.write.as_ref().unwrap().write);
+        assert!(command.read.is_none());
+        assert!(command.notify.is_none());
+
+        // The response characteristic is notify-only
+        let response = &characteristics[1];
+        assert_eq!(response.uuid, response_uuid);
+        assert!(response.write.is_none());
+        assert!(response.notify.as_ref().unwrap().notify);
+    }
+
+    #[test]
+    fn priority_channels_create_both_notify_characteristics() {
+        let priority_uuid = Uuid::from_u128(0xC0DE0001);
+        let bulk_uuid = Uuid::from_u128(0xC0DE0002);
+        let ble = BlePeripheral::builder()
+            .priority_channels(priority_uuid, bulk_uuid)
+            .build()
+            .unwrap();
+
+        let (_, service_handle) = service_control();
+        let (_write_control, write_handle) = characteristic_control();
+        let (_bulk_control, bulk_handle) = characteristic_control();
+        let (_priority_control, priority_handle) = characteristic_control();
+        let (write_tx, _write_rx) = mpsc::unbounded_channel();
+        let app = ble.gatt_application(
+            service_handle,
+            write_handle,
+            Some(bulk_handle),
+            Some(priority_handle),
+            &write_tx,
+        );
+
+        let characteristics = &app.services[0].characteristics;
+        assert_eq!(characteristics.len(), 3);
+        assert_eq!(characteristics[0].uuid, DEFAULT_CHARACTERISTIC_UUID);
+        assert!(characteristics[0].notify.is_none());
+        for (characteristic, uuid) in characteristics[1..].iter().zip([bulk_uuid, priority_uuid]) {
+            assert_eq!(characteristic.uuid, uuid);
+            assert!(characteristic.write.is_none());
+            assert!(characteristic.notify.as_ref().unwrap().notify);
+        }
+    }
+
+    #[tokio::test]
+    async fn priority_messages_go_to_the_priority_characteristic() {
+        let mut ble = BlePeripheral::builder()
+            .priority_channels(Uuid::from_u128(1), Uuid::from_u128(2))
+            .build()
+            .unwrap();
+        let central = start_mock_engine(&mut ble);
+        let mut priority = central.subscribe_priority(512);
+        let mut bulk = central.subscribe_and_wait(&ble, 512).await;
+
+        ble.send_priority_message(BleMessage::Raw(vec![0x01]))
+            .await
+            .unwrap();
+        ble.send_message(BleMessage::Raw(vec![0x02])).await.unwrap();
+
+        assert_eq!(priority.recv().await.unwrap(), vec![0x01]);
+        assert_eq!(bulk.recv().await.unwrap(), vec![0x02]);
+        assert!(priority.try_recv().is_err());
+    }
+
+    #[test]
+    fn priority_channels_reject_the_command_response_layout() {
+        let result = BlePeripheral::builder()
+            .command_response_layout(Uuid::from_u128(1), Uuid::from_u128(2))
+            .priority_channels(Uuid::from_u128(3), Uuid::from_u128(4))
+            .build();
+        assert!(matches!(result, Err(BleError::InvalidConfig(_))));
+    }
+
+    #[test]
+    fn write_characteristic_is_readable() {
+        let ble = BlePeripheral::builder()
+            .read_responses(true)
+            .build()
+            .unwrap();
+        let (_, service_handle) = service_control();
+        let (_char_control, char_handle) = characteristic_control();
+        let (write_tx, _write_rx) = mpsc::unbounded_channel();
+        let app = ble.gatt_application(service_handle, char_handle, None, None, &write_tx);
+        assert!(
+            app.services[0].characteristics[0]
+                .read
+                .as_ref()
+                .unwrap()
+                .read
+        );
+    }
+
+    #[tokio::test]
+    async fn staged_response_is_read_after_request() {
+        let mut ble = BlePeripheral::builder()
+            .read_responses(true)
+            .build()
+            .unwrap();
+        let central = start_mock_engine(&mut ble);
+
+        // The central writes a request, and the peripheral stages the response
+        central.write(b"get");
+        assert_eq!(
+            ble.receive_message().await.unwrap(),
+            BleMessage::Raw(b"get".to_vec())
+        );
+        ble.respond_to_read(b"value".to_vec());
+
+        // The central reads the response, possibly in several parts
+        assert_eq!(ble.read_response.read(0).unwrap(), b"value".to_vec());
+        assert_eq!(ble.read_response.read(3).unwrap(), b"ue".to_vec());
+        assert!(matches!(
+            ble.read_response.read(6),
+            Err(ReqError::InvalidOffset)
+        ));
+    }
+
+    #[test]
+    fn operations_get_their_own_security_flags() {
+        let ble = BlePeripheral::builder()
+            .permissions(Permissions {
+                read: SecurityLevel::None,
+                write: SecurityLevel::AuthenticatedEncrypted,
+            })
+            .read_responses(true)
+            .build()
+            .unwrap();
+        let (_, service_handle) = service_control();
+        let (_char_control, char_handle) = characteristic_control();
+        let (write_tx, _write_rx) = mpsc::unbounded_channel();
+        let app = ble.gatt_application(service_handle, char_handle, None, None, &write_tx);
+        let characteristic = &app.services[0].characteristics[0];
+
+        let read = characteristic.read.as_ref().unwrap();
+        assert!(read.read);
+        assert!(!read.encrypt_read);
+        assert!(!read.encrypt_authenticated_read);
+        let write = characteristic.write.as_ref().unwrap();
+        assert!(write.write);
+        assert!(!write.encrypt_write);
+        assert!(write.encrypt_authenticated_write);
+
+        let ble = BlePeripheral::builder()
+            .permissions(Permissions {
+                read: SecurityLevel::Encrypted,
+                write: SecurityLevel::None,
+            })
+            .read_responses(true)
+            .build()
+            .unwrap();
+        let (_, service_handle) = service_control();
+        let (_char_control, char_handle) = characteristic_control();
+        let app = ble.gatt_application(service_handle, char_handle, None, None, &write_tx);
+        let characteristic = &app.services[0].characteristics[0];
+        assert!(characteristic.read.as_ref().unwrap().encrypt_read);
+        let write = characteristic.write.as_ref().unwrap();
+        assert!(!write.encrypt_write);
+        assert!(!write.encrypt_authenticated_write);
+    }
+
+    #[tokio::test]
+    async fn writes_keep_their_offset() {
+        let mut ble = BlePeripheral::builder()
+            .offset_writes(true)
+            .build()
+            .unwrap();
+        let (transport, central) = MockTransport::new();
+        ble.start_with(transport).await.unwrap();
+
+        // The central writes two regions of the characteristic
+        central.write_at(0, &[0x01, 0x02]).unwrap();
+        central.write_at(8, &[0xAA]).unwrap();
+
+        assert_eq!(
+            ble.receive_offset_write().await.unwrap(),
+            (0, vec![0x01, 0x02])
+        );
+        assert_eq!(ble.receive_offset_write().await.unwrap(), (8, vec![0xAA]));
+    }
+
+    #[tokio::test]
+    async fn offset_writes_must_be_enabled() {
+        let mut ble = BlePeripheral::builder().build().unwrap();
+        let (transport, central) = MockTransport::new();
+        ble.start_with(transport).await.unwrap();
+
+        assert!(central.write_at(0, &[0x01]).is_err());
+        assert!(matches!(
+            ble.receive_offset_write().await,
+            Err(BleError::OffsetWritesNotEnabled)
+        ));
+    }
+
+    #[test]
+    fn invalid_write_is_rejected() {
+        let validator: WriteValidator = Arc::new(|bytes: &[u8]| bytes.first() == Some(&0x01));
+        let (write_tx, mut write_rx) = mpsc::unbounded_channel();
+
+        // A write not starting with the expected marker is rejected and never delivered
+        let result = handle_validated_write(&validator, vec![0x02, 0xAA], &write_tx);
+        assert_eq!(result, Err(ReqError::Failed));
+        assert!(write_rx.try_recv().is_err());
+
+        // A valid write is accepted and delivered
+        let result = handle_validated_write(&validator, vec![0x01, 0xBB], &write_tx);
+        assert_eq!(result, Ok(()));
+        assert_eq!(write_rx.try_recv().unwrap(), vec![0x01, 0xBB]);
+    }
+}
+
+#[cfg(test)]
+mod advertising_test {
+    use super::super::adapter::{check_address, find_by_address, AdapterCapabilities, AdapterInfo};
+    use super::super::advertisement::{advertisement_size, validate_advertisement};
+    use super::super::alias::{rotate_alias, MAX_ALIAS_LEN};
+    use super::super::boost::{AdvertisingBoost, BOOST_MAX_INTERVAL, BOOST_MIN_INTERVAL};
+    use super::super::error::BleError;
+    use super::super::event::BleEngineEvent;
+    use super::super::mock::MockAdvertiser;
+    use super::super::readvertise::{
+        advertise_when_ready, current_advertisement, keep_advertising, revert_boost, Advertiser,
+    };
+    use super::super::{BlePeripheral, DEFAULT_SERVICE_UUID};
+    use bluer::adv::{Advertisement, Type as AdvertisementType};
+    use bluer::{Address, AddressType};
+    use std::sync::{Arc, Mutex};
+    use tokio::sync::broadcast;
+    use tokio::time::Duration;
+
+    #[test]
+    fn oversized_advertisement_is_rejected() {
+        // Flags, the 16-bit service UUID, and the local name
+        let ble = BlePeripheral::builder().alias("TESTER").build().unwrap();
+        let adv = ble.advertisement();
+        assert_eq!(advertisement_size(&adv), 3 + 4 + 8);
+        assert!(validate_advertisement(&adv).is_ok());
+
+        let ble = BlePeripheral::builder()
+            .alias("A peripheral with a very long name")
+            .build()
+            .unwrap();
+        let adv = ble.advertisement();
+        assert!(matches!(
+            validate_advertisement(&adv),
+            Err(BleError::AdvertisementTooLarge {
+                bytes: 43,
+                limit: 31
+            })
+        ));
+    }
+
+    #[test]
+    fn advertisement_is_checked_against_capabilities() {
+        let ble = BlePeripheral::builder().alias("TESTER").build().unwrap();
+        let adv = ble.advertisement();
+        let mut capabilities = AdapterCapabilities {
+            advertising_instances: 1,
+            ..Default::default()
+        };
+        assert!(capabilities.check(&adv).is_ok());
+        assert!(!capabilities.extended_advertising());
+
+        // The controller limit replaces the legacy one
+        capabilities.max_advertisement_length = Some(10);
+        assert!(matches!(
+            capabilities.check(&adv),
+            Err(BleError::AdvertisementTooLarge {
+                bytes: 15,
+                limit: 10
+            })
+        ));
+
+        capabilities.advertising_instances = 0;
+        assert!(matches!(
+            capabilities.check(&adv),
+            Err(BleError::Unsupported(_))
+        ));
+    }
+
+    #[test]
+    fn alias_is_validated() {
+        let ble = BlePeripheral::builder().alias("TESTER").build().unwrap();
+        assert_eq!(ble.alias.as_deref(), Some("TESTER"));
+
+        // An over-length alias is rejected by default
+        let long_alias = "A".repeat(MAX_ALIAS_LEN + 1);
+        assert!(matches!(
+            BlePeripheral::builder().alias(long_alias.clone()).build(),
+            Err(BleError::InvalidAlias(_))
+        ));
+        assert!(matches!(
+            BlePeripheral::builder().alias("TES\nTER").build(),
+            Err(BleError::InvalidAlias(_))
+        ));
+
+        // It is truncated when sanitization is enabled
+        let ble = BlePeripheral::builder()
+            .alias(long_alias)
+            .sanitize_alias(true)
+            .build()
+            .unwrap();
+        assert_eq!(ble.alias.unwrap().len(), MAX_ALIAS_LEN);
+        let ble = BlePeripheral::builder()
+            .alias("TES\nTER")
+            .sanitize_alias(true)
+            .build()
+            .unwrap();
+        assert_eq!(ble.alias.as_deref(), Some("TESTER"));
+    }
+
+    #[test]
+    fn alias_template_uses_instance_id() {
+        let ble = BlePeripheral::builder()
+            .alias_template("SENSOR-{id:03}")
+            .instance_id(1)
+            .build()
+            .unwrap();
+        assert_eq!(ble.alias.as_deref(), Some("SENSOR-001"));
+        let ble = BlePeripheral::builder()
+            .alias("IGNORED")
+            .alias_template("{id}-SENSOR")
+            .instance_id(42)
+            .build()
+            .unwrap();
+        assert_eq!(ble.alias.as_deref(), Some("42-SENSOR"));
+
+        // The template needs a placeholder and an instance id
+        assert!(matches!(
+            BlePeripheral::builder()
+                .alias_template("SENSOR")
+                .instance_id(1)
+                .build(),
+            Err(BleError::InvalidAlias(_))
+        ));
+        assert!(matches!(
+            BlePeripheral::builder()
+                .alias_template("SENSOR-{id}")
+                .build(),
+            Err(BleError::InvalidAlias(_))
+        ));
+
+        // The rendered alias is still checked against the length limit
+        assert!(matches!(
+            BlePeripheral::builder()
+                .alias_template(format!("{}{{id}}", "A".repeat(MAX_ALIAS_LEN)))
+                .instance_id(1)
+                .build(),
+            Err(BleError::InvalidAlias(_))
+        ));
+    }
+
+    #[test]
+    fn zero_rotation_interval_is_rejected() {
+        let result = BlePeripheral::builder()
+            .rotate_alias(Duration::ZERO, |index| format!("Anon-{}", index))
+            .build();
+        assert!(matches!(result, Err(BleError::InvalidConfig(_))));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn name_changes_after_the_interval() {
+        let ble = BlePeripheral::builder()
+            .alias("Static")
+            .rotate_alias(Duration::from_secs(600), |index| format!("Anon-{}", index))
+            .build()
+            .unwrap();
+        assert_eq!(ble.current_alias(), Some("Anon-0".to_string()));
+
+        let advertiser = MockAdvertiser::default();
+        let adv = Arc::new(Mutex::new(ble.base_advertisement()));
+        let rotation = tokio::spawn(rotate_alias(
+            advertiser.clone(),
+            ble.config.alias_rotation.clone().unwrap(),
+            false,
+            ble.rotated_alias.clone(),
+            adv.clone(),
+            Arc::new(Mutex::new(Some(()))),
+        ));
+
+        tokio::time::sleep(Duration::from_secs(599)).await;
+        assert_eq!(ble.current_alias(), Some("Anon-0".to_string()));
+        assert_eq!(advertiser.registrations(), 0);
+
+        tokio::time::sleep(Duration::from_secs(2)).await;
+        assert_eq!(ble.current_alias(), Some("Anon-1".to_string()));
+        assert_eq!(adv.lock().unwrap().local_name, Some("Anon-1".to_string()));
+        assert_eq!(
+            ble.base_advertisement().local_name,
+            Some("Anon-1".to_string())
+        );
+        assert_eq!(advertiser.registrations(), 1);
+
+        tokio::time::sleep(Duration::from_secs(600)).await;
+        assert_eq!(ble.current_alias(), Some("Anon-2".to_string()));
+        assert_eq!(advertiser.registrations(), 2);
+        rotation.abort();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn invalid_rotated_name_is_skipped() {
+        let ble = BlePeripheral::builder()
+            .rotate_alias(Duration::from_secs(60), |index| match index {
+                1 => String::new(),
+                _ => format!("Anon-{}", index),
+            })
+            .build()
+            .unwrap();
+        let rotation = tokio::spawn(rotate_alias(
+            MockAdvertiser::default(),
+            ble.config.alias_rotation.clone().unwrap(),
+            false,
+            ble.rotated_alias.clone(),
+            Arc::new(Mutex::new(ble.base_advertisement())),
+            Arc::new(Mutex::new(Some(()))),
+        ));
+
+        tokio::time::sleep(Duration::from_secs(61)).await;
+        assert_eq!(ble.current_alias(), Some("Anon-0".to_string()));
+        tokio::time::sleep(Duration::from_secs(60)).await;
+        assert_eq!(ble.current_alias(), Some("Anon-2".to_string()));
+        rotation.abort();
+    }
+
+    #[tokio::test]
+    async fn adapter_alias_requires_a_started_engine() {
+        let ble = BlePeripheral::builder().build().unwrap();
+        assert!(matches!(
+            ble.adapter_alias().await,
+            Err(BleError::EngineNotStarted)
+        ));
+        assert!(matches!(
+            ble.set_adapter_alias("Sensor").await,
+            Err(BleError::EngineNotStarted)
+        ));
+    }
+
+    #[tokio::test]
+    #[ignore = "requires a Bluetooth adapter"]
+    async fn adapter_alias_is_set_and_restored() {
+        let mut ble = BlePeripheral::builder().build().unwrap();
+        ble.start_engine().await.unwrap();
+        let original = ble.adapter_alias().await.unwrap();
+
+        ble.set_adapter_alias("BlePeripheralTest").await.unwrap();
+        assert_eq!(ble.adapter_alias().await.unwrap(), "BlePeripheralTest");
+        ble.set_adapter_alias("BlePeripheralTest2").await.unwrap();
+        assert_eq!(ble.adapter_alias().await.unwrap(), "BlePeripheralTest2");
+
+        // Stopping restores the alias the adapter had before the first change
+        ble.stop_engine(None).await;
+        ble.start_engine().await.unwrap();
+        assert_eq!(ble.adapter_alias().await.unwrap(), original);
+        ble.stop_engine(None).await;
+    }
+
+    #[test]
+    fn unknown_address_lists_the_available_ones() {
+        let adapters = vec![
+            AdapterInfo {
+                name: "hci0".to_string(),
+                address: Address::new([0x00, 0x1A, 0x7D, 0xDA, 0x71, 0x01]),
+                powered: true,
+            },
+            AdapterInfo {
+                name: "hci1".to_string(),
+                address: Address::new([0x00, 0x1A, 0x7D, 0xDA, 0x71, 0x02]),
+                powered: false,
+            },
+        ];
+        let found = find_by_address(&adapters, adapters[1].address).unwrap();
+        assert_eq!(found.name, "hci1");
+
+        let missing = Address::new([0x00, 0x1A, 0x7D, 0xDA, 0x71, 0x03]);
+        let err = find_by_address(&adapters, missing).unwrap_err();
+        assert!(matches!(
+            &err,
+            BleError::AdapterNotFound { address, available }
+                if *address == missing && available == &[adapters[0].address, adapters[1].address]
+        ));
+        assert_eq!(
+            err.to_string(),
+            "No adapter with address 00:1A:7D:DA:71:03, available: [00:1A:7D:DA:71:01, 00:1A:7D:DA:71:02]"
+        );
+    }
+
+    #[tokio::test]
+    #[ignore = "requires a Bluetooth adapter"]
+    async fn engine_binds_to_the_adapter_address() {
+        let adapters = BlePeripheral::available_adapters().await.unwrap();
+        let address = adapters[0].address;
+        let mut ble = BlePeripheral::builder().build().unwrap();
+        ble.start_engine_on_address(address).await.unwrap();
+        assert!(ble.is_running());
+        ble.stop_engine(None).await;
+    }
+
+    #[test]
+    fn required_address_type_is_configured() {
+        let address = Address::new([0xC0, 0x01, 0x02, 0x03, 0x04, 0x05]);
+        let ble = BlePeripheral::builder()
+            .address(address)
+            .address_type(AddressType::LeRandom)
+            .build()
+            .unwrap();
+        assert_eq!(ble.config.address, Some(address));
+        assert_eq!(ble.config.address_type, Some(AddressType::LeRandom));
+    }
+
+    #[test]
+    fn adapter_address_is_checked() {
+        let address = Address::new([0xC0, 0x01, 0x02, 0x03, 0x04, 0x05]);
+        let other = Address::new([0x00, 0x01, 0x02, 0x03, 0x04, 0x05]);
+        assert!(check_address((None, None), address, AddressType::LePublic).is_ok());
+        assert!(check_address(
+            (Some(address), Some(AddressType::LeRandom)),
+            address,
+            AddressType::LeRandom
+        )
+        .is_ok());
+        assert!(matches!(
+            check_address((Some(address), None), other, AddressType::LeRandom),
+            Err(BleError::Unsupported(_))
+        ));
+        assert!(matches!(
+            check_address(
+                (None, Some(AddressType::LeRandom)),
+                address,
+                AddressType::LePublic
+            ),
+            Err(BleError::Unsupported(_))
+        ));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn boost_is_applied_and_reverted() {
+        let ble = BlePeripheral::builder().alias("TESTER").build().unwrap();
+        assert_eq!(ble.advertisement().min_interval, None);
+
+        // The boosted intervals apply during the window
+        ble.adv_boost.extend(Duration::from_secs(2));
+        let adv = ble.advertisement();
+        assert_eq!(adv.min_interval, Some(BOOST_MIN_INTERVAL));
+        assert_eq!(adv.max_interval, Some(BOOST_MAX_INTERVAL));
+
+        // A shorter overlapping boost does not cut the window short
+        tokio::time::advance(Duration::from_secs(1)).await;
+        ble.adv_boost.extend(Duration::from_millis(500));
+        tokio::time::advance(Duration::from_millis(600)).await;
+        assert!(!ble.adv_boost.expire_if_due());
+        assert!(ble.advertisement().min_interval.is_some());
+
+        // The default intervals are restored after the window
+        tokio::time::advance(Duration::from_millis(400)).await;
+        assert!(ble.adv_boost.expire_if_due());
+        assert_eq!(ble.advertisement().min_interval, None);
+    }
+
+    /// Advertiser recording each registered advertisement.
+    struct RecordingAdvertiser {
+        adv: Arc<Mutex<Advertisement>>,
+        boost: Arc<AdvertisingBoost>,
+        registered: Arc<Mutex<Vec<Advertisement>>>,
+    }
+
+    impl Advertiser for RecordingAdvertiser {
+        type Handle = ();
+
+        async fn is_advertising(&self) -> Result<bool, BleError> {
+            Ok(true)
+        }
+
+        async fn advertise(&self) -> Result<(), BleError> {
+            let adv = current_advertisement(&self.adv, &self.boost);
+            self.registered.lock().unwrap().push(adv);
+            Ok(())
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn revert_keeps_changes_made_during_the_boost() {
+        let adv = Arc::new(Mutex::new(Advertisement {
+            local_name: Some("Before".to_string()),
+            ..Default::default()
+        }));
+        let boost = Arc::new(AdvertisingBoost::default());
+        let registered = Arc::new(Mutex::new(Vec::new()));
+        let advertiser = RecordingAdvertiser {
+            adv: adv.clone(),
+            boost: boost.clone(),
+            registered: registered.clone(),
+        };
+        let deadline = boost.extend(Duration::from_secs(1));
+        let revert = tokio::spawn(revert_boost(
+            advertiser,
+            boost,
+            deadline,
+            Arc::new(Mutex::new(Some(()))),
+        ));
+
+        // The alias rotates while boosted
+        adv.lock().unwrap().local_name = Some("After".to_string());
+        tokio::time::advance(Duration::from_secs(1)).await;
+        revert.await.unwrap();
+
+        let registered = registered.lock().unwrap();
+        assert_eq!(registered.len(), 1);
+        assert_eq!(registered[0].local_name.as_deref(), Some("After"));
+        assert_eq!(registered[0].min_interval, None);
+        assert_eq!(registered[0].max_interval, None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn removed_advertisement_is_registered_again() {
+        let advertiser = MockAdvertiser::default();
+        advertiser.advertise().await.unwrap();
+        let handle = Arc::new(Mutex::new(Some(())));
+        let (events_tx, mut events) = broadcast::channel(8);
+        let monitor = tokio::spawn(keep_advertising(
+            advertiser.clone(),
+            handle.clone(),
+            events_tx,
+            Duration::from_secs(1),
+        ));
+
+        // Nothing happens while the advertisement is registered
+        tokio::time::sleep(Duration::from_millis(2500)).await;
+        assert_eq!(advertiser.registrations(), 1);
+
+        advertiser.remove();
+        assert_eq!(
+            events.recv().await.unwrap(),
+            BleEngineEvent::AdvertisingRestarted
+        );
+        assert_eq!(advertiser.registrations(), 2);
+
+        // Clearing the handle, as stopping the engine does, ends the monitoring
+        *handle.lock().unwrap() = None;
+        monitor.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn broadcast_data_updates_advertisement() {
+        let mut ble = BlePeripheral::builder().beacon_mode(true).build().unwrap();
+        let adv = ble.advertisement();
+        assert_eq!(adv.advertisement_type, AdvertisementType::Broadcast);
+        assert!(adv.service_uuids.is_empty());
+        assert_eq!(adv.service_data[&DEFAULT_SERVICE_UUID], Vec::<u8>::new());
+
+        ble.broadcast_data(vec![0x01, 0x02]).await.unwrap();
+        let adv = ble.advertisement();
+        assert_eq!(adv.service_data[&DEFAULT_SERVICE_UUID], vec![0x01, 0x02]);
+
+        // Data not fitting in the advertisement is rejected, keeping the previous data
+        assert!(matches!(
+            ble.broadcast_data(vec![0; 32]).await,
+            Err(BleError::AdvertisementTooLarge { .. })
+        ));
+        let adv = ble.advertisement();
+        assert_eq!(adv.service_data[&DEFAULT_SERVICE_UUID], vec![0x01, 0x02]);
+    }
+
+    #[tokio::test]
+    async fn broadcast_requires_beacon_mode() {
+        let mut ble = BlePeripheral::new(None).await.unwrap();
+        assert!(matches!(
+            ble.broadcast_data(vec![0x01]).await,
+            Err(BleError::NotBeacon)
+        ));
+        let adv = ble.advertisement();
+        assert_eq!(adv.advertisement_type, AdvertisementType::Peripheral);
+        assert!(adv.service_data.is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn advertising_begins_after_set_ready() {
+        let ble = BlePeripheral::builder()
+            .defer_advertising(true)
+            .build()
+            .unwrap();
+        let advertiser = MockAdvertiser::default();
+        let handle = Arc::new(Mutex::new(None));
+        let deferred = tokio::spawn(advertise_when_ready(
+            advertiser.clone(),
+            ble.ready.subscribe(),
+            handle.clone(),
+            broadcast::channel(8).0,
+            None,
+        ));
+
+        tokio::time::sleep(Duration::from_secs(5)).await;
+        assert!(ble.advertising_withheld());
+        assert_eq!(advertiser.registrations(), 0);
+        assert!(handle.lock().unwrap().is_none());
+
+        ble.set_ready();
+        deferred.await.unwrap();
+        assert!(!ble.advertising_withheld());
+        assert_eq!(advertiser.registrations(), 1);
+        assert!(handle.lock().unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn dropped_gate_never_advertises() {
+        let advertiser = MockAdvertiser::default();
+        let (ready, ready_rx) = tokio::sync::watch::channel(false);
+        drop(ready);
+        advertise_when_ready(
+            advertiser.clone(),
+            ready_rx,
+            Arc::new(Mutex::new(None)),
+            broadcast::channel(8).0,
+            None,
+        )
+        .await;
+        assert_eq!(advertiser.registrations(), 0);
+    }
+}
+
+#[cfg(test)]
+mod receive_test {
+    use super::super::dedup::DedupCache;
+    use super::super::envelope::BleEnvelope;
+    use super::super::error::BleError;
+    use super::super::event::BleEngineEvent;
+    use super::super::message::BleMessage;
+    use super::super::mock::start_mock_engine;
+    use super::super::outgoing::write_notification;
+    use super::super::queue::{OverflowPolicy, ReceiveQueue};
+    use super::super::raw_write::handle_raw_write;
+    use super::super::replay::ReplayGuard;
+    use super::super::BlePeripheral;
+    use bluer::gatt::local::ReqError;
+    use futures::channel::mpsc as stream_mpsc;
+    use futures::StreamExt;
+    use std::sync::{Arc, Mutex};
+    use tokio::io::AsyncReadExt;
+    use tokio::sync::mpsc;
+    use tokio::time::Duration;
+
+    #[tokio::test]
+    async fn try_receive_does_not_block() {
+        let mut ble = BlePeripheral::new(None).await.unwrap();
+
+        // Nothing to receive before the engine is started
+        assert!(ble.try_receive_message().is_none());
+
+        let receive_queue = Arc::new(ReceiveQueue::new(None, OverflowPolicy::DropOldest));
+        ble.receiver = Some(receive_queue.clone());
+        assert!(ble.try_receive_message().is_none());
+
+        receive_queue.push_envelope(BleEnvelope::new("queued"));
+        match ble.try_receive_message() {
+            Some(BleMessage::Text(text)) => assert_eq!(text, "queued"),
+            message => panic!("Unexpected message {:?}", message),
+        }
+        assert!(ble.try_receive_message().is_none());
+    }
+
+    #[tokio::test]
+    async fn expired_message_is_dropped() {
+        let mut ble = BlePeripheral::new(None).await.unwrap();
+        let (send_tx, mut send_rx) = mpsc::unbounded_channel();
+        ble.sender = Some(send_tx);
+        let (mut notifier, mut central) = tokio::io::duplex(64);
+
+        // The short-lived message waits in the queue past its TTL
+        ble.send_message_with_ttl("stale", Duration::from_millis(10))
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(30)).await;
+        let outgoing = send_rx.recv().await.unwrap();
+        assert!(
+            write_notification(&mut notifier, outgoing, usize::MAX, None, None, None, false)
+                .await
+                .unwrap()
+                .is_none()
+        );
+
+        // A message without TTL still goes through
+        ble.send_message("fresh").await.unwrap();
+        let outgoing = send_rx.recv().await.unwrap();
+        assert!(
+            write_notification(&mut notifier, outgoing, usize::MAX, None, None, None, false)
+                .await
+                .unwrap()
+                .is_some()
+        );
+        drop(notifier);
+
+        let mut received = Vec::new();
+        central.read_to_end(&mut received).await.unwrap();
+        assert_eq!(received, b"fresh");
+    }
+
+    #[tokio::test]
+    async fn messages_reuse_caller_buffer() {
+        let mut ble = BlePeripheral::new(None).await.unwrap();
+        let mut buf = Vec::with_capacity(64);
+        assert!(matches!(
+            ble.receive_into(&mut buf).await,
+            Err(BleError::EngineNotStarted)
+        ));
+
+        let central = start_mock_engine(&mut ble);
+        let packets: [&[u8]; 3] = [b"first message", b"2nd", &[0, 1, 2, 3, 4]];
+        for packet in packets {
+            central.write(packet);
+            let len = ble.receive_into(&mut buf).await.unwrap();
+            assert_eq!(len, packet.len());
+            assert_eq!(buf, packet);
+        }
+        assert!(buf.capacity() >= 64);
+    }
+
+    async fn overfill(policy: OverflowPolicy) -> Vec<BleMessage> {
+        let mut ble = BlePeripheral::builder()
+            .receive_capacity(2)
+            .receive_overflow_policy(policy)
+            .build()
+            .unwrap();
+        let mut events = ble.subscribe_events();
+        let central = start_mock_engine(&mut ble);
+
+        // Fill the queue without consuming any message
+        for (i, packet) in [b"one", b"two"].into_iter().enumerate() {
+            central.write(packet);
+            while ble.pending_messages() <= i {
+                tokio::task::yield_now().await;
+            }
+        }
+
+        // One more message overflows the queue
+        central.write(b"333");
+        assert_eq!(
+            events.recv().await.unwrap(),
+            BleEngineEvent::ReceiveOverflow { capacity: 2 }
+        );
+
+        let mut received = Vec::new();
+        while let Some(message) = ble.try_receive_message() {
+            received.push(message);
+        }
+        received
+    }
+
+    #[test]
+    fn zero_capacity_is_rejected() {
+        let receive = BlePeripheral::builder().receive_capacity(0).build();
+        assert!(matches!(receive, Err(BleError::InvalidConfig(_))));
+        let send = BlePeripheral::builder().send_capacity(0).build();
+        assert!(matches!(send, Err(BleError::InvalidConfig(_))));
+    }
+
+    #[tokio::test]
+    async fn drop_oldest_when_full() {
+        let received = overfill(OverflowPolicy::DropOldest).await;
+        assert_eq!(
+            received,
+            vec![b"two".to_vec().into(), b"333".to_vec().into()]
+        );
+    }
+
+    #[tokio::test]
+    async fn drop_newest_when_full() {
+        let received = overfill(OverflowPolicy::DropNewest).await;
+        assert_eq!(
+            received,
+            vec![b"one".to_vec().into(), b"two".to_vec().into()]
+        );
+    }
+
+    fn collector() -> (
+        Arc<Mutex<Vec<BleMessage>>>,
+        impl Fn(BleMessage) + Send + Sync,
+    ) {
+        let collected = Arc::new(Mutex::new(Vec::new()));
+        let sink = collected.clone();
+        (collected, move |message| sink.lock().unwrap().push(message))
+    }
+
+    async fn wait_for(collected: &Arc<Mutex<Vec<BleMessage>>>, len: usize) {
+        while collected.lock().unwrap().len() < len {
+            tokio::task::yield_now().await;
+        }
+    }
+
+    #[tokio::test]
+    async fn handlers_swap_mid_stream() {
+        let mut ble = BlePeripheral::new(None).await.unwrap();
+        let central = start_mock_engine(&mut ble);
+
+        let (first, handler) = collector();
+        ble.set_message_handler(handler);
+        central.write(b"one");
+        wait_for(&first, 1).await;
+
+        // Swap the handler while the engine is running
+        let (second, handler) = collector();
+        ble.set_message_handler(handler);
+        central.write(b"two");
+        wait_for(&second, 1).await;
+
+        assert_eq!(*first.lock().unwrap(), vec![b"one".to_vec().into()]);
+        assert_eq!(*second.lock().unwrap(), vec![b"two".to_vec().into()]);
+
+        // Without a handler, messages go back to the receive queue
+        ble.clear_message_handler();
+        central.write(b"three");
+        assert_eq!(
+            ble.receive_message().await.unwrap(),
+            b"three".to_vec().into()
+        );
+        assert_eq!(second.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn handler_replaces_itself() {
+        let mut ble = BlePeripheral::new(None).await.unwrap();
+        let central = start_mock_engine(&mut ble);
+        let ble = Arc::new(ble);
+
+        // The first message swaps the handler from within it
+        let (second, next_handler) = collector();
+        let next_handler = Mutex::new(Some(next_handler));
+        let peripheral = Arc::downgrade(&ble);
+        ble.set_message_handler(move |_| {
+            let (Some(ble), Some(handler)) =
+                (peripheral.upgrade(), next_handler.lock().unwrap().take())
+            else {
+                return;
+            };
+            ble.set_message_handler(handler);
+        });
+        central.write(b"one");
+        central.write(b"two");
+        wait_for(&second, 1).await;
+        assert_eq!(*second.lock().unwrap(), vec![b"two".to_vec().into()]);
+    }
+
+    #[tokio::test]
+    async fn receive_times_out() {
+        let mut ble = BlePeripheral::new(None).await.unwrap();
+        assert!(matches!(
+            ble.receive_message_or_timeout(Duration::from_millis(10))
+                .await,
+            Err(BleError::EngineNotStarted)
+        ));
+
+        // Nothing is written, so the receive gives up instead of blocking
+        let _central = start_mock_engine(&mut ble);
+        assert!(matches!(
+            ble.receive_message_or_timeout(Duration::from_millis(10))
+                .await,
+            Err(BleError::Timeout)
+        ));
+    }
+
+    #[test]
+    fn replayed_messages_are_rejected() {
+        let mut sender = ReplayGuard::new();
+        let mut receiver = ReplayGuard::new();
+
+        let first = sender.seal(b"first");
+        let second = sender.seal(b"second");
+        assert_eq!(receiver.open(&first).unwrap(), b"first");
+        assert_eq!(receiver.open(&second).unwrap(), b"second");
+
+        // Sending a captured message again, or an older one, is rejected
+        assert!(matches!(
+            receiver.open(&second),
+            Err(BleError::ReplayDetected {
+                counter: 1,
+                last_seen: 1
+            })
+        ));
+        assert!(matches!(
+            receiver.open(&first),
+            Err(BleError::ReplayDetected { .. })
+        ));
+
+        // Fresh messages are still accepted
+        let third = sender.seal(b"third");
+        assert_eq!(receiver.open(&third).unwrap(), b"third");
+    }
+
+    #[test]
+    fn reset_starts_a_new_session() {
+        let mut sender = ReplayGuard::new();
+        let mut receiver = ReplayGuard::new();
+        for _ in 0..3 {
+            receiver.open(&sender.seal(b"old session")).unwrap();
+        }
+
+        sender.reset_replay_window();
+        let restarted = sender.seal(b"new session");
+        assert!(receiver.open(&restarted).is_err());
+
+        receiver.reset_replay_window();
+        assert_eq!(receiver.open(&restarted).unwrap(), b"new session");
+        assert!(receiver.open(&[0; 3]).is_err());
+    }
+
+    #[tokio::test]
+    async fn raw_request_is_accepted_and_read() {
+        let mut ble = BlePeripheral::builder()
+            .raw_write_requests(true)
+            .build()
+            .unwrap();
+        assert!(matches!(
+            ble.raw_write_requests().err(),
+            Some(BleError::EngineNotStarted)
+        ));
+        let (requests_tx, requests_rx) = stream_mpsc::unbounded();
+        ble.raw_write_receiver = Some(requests_rx);
+        let mut requests = ble.raw_write_requests().unwrap();
+
+        // The central waits until the caller answers its request
+        let accepted = tokio::spawn(handle_raw_write(&requests_tx, 247, 4, vec![0x01, 0x02]));
+        let request = requests.next().await.unwrap();
+        assert_eq!(request.mtu(), 247);
+        assert_eq!(request.offset(), 4);
+        assert_eq!(request.bytes(), [0x01, 0x02]);
+        assert_eq!(request.accept(), vec![0x01, 0x02]);
+        assert!(accepted.await.unwrap().is_ok());
+
+        // Rejected and dropped requests fail on the central
+        let rejected = tokio::spawn(handle_raw_write(&requests_tx, 247, 0, vec![0xFF]));
+        requests.next().await.unwrap().reject();
+        assert!(matches!(rejected.await.unwrap(), Err(ReqError::Failed)));
+        let dropped = tokio::spawn(handle_raw_write(&requests_tx, 247, 0, vec![0xFF]));
+        drop(requests.next().await.unwrap());
+        assert!(matches!(dropped.await.unwrap(), Err(ReqError::Failed)));
+    }
+
+    #[tokio::test]
+    async fn empty_write_is_not_delivered() {
+        let mut ble = BlePeripheral::builder().build().unwrap();
+        let central = start_mock_engine(&mut ble);
+
+        central.write(b"");
+        for _ in 0..10 {
+            tokio::task::yield_now().await;
+        }
+        assert_eq!(ble.try_receive_message(), None);
+
+        central.write(b"next");
+        assert_eq!(
+            ble.receive_message().await.unwrap(),
+            BleMessage::Raw(b"next".to_vec())
+        );
+    }
+
+    #[tokio::test]
+    async fn empty_write_is_delivered_if_enabled() {
+        let mut ble = BlePeripheral::builder()
+            .deliver_empty_writes(true)
+            .build()
+            .unwrap();
+        let central = start_mock_engine(&mut ble);
+
+        central.write(b"");
+        assert_eq!(
+            ble.receive_message().await.unwrap(),
+            BleMessage::Raw(Vec::new())
+        );
+    }
+
+    #[tokio::test]
+    async fn session_is_read_until_it_ends() {
+        let mut ble = BlePeripheral::builder()
+            .text_delimiter(b'\n')
+            .build()
+            .unwrap();
+        let central = start_mock_engine(&mut ble);
+
+        // A message longer than the MTU is written in several packets of the same session
+        let packets = central.start_write(8);
+        for packet in [&b"multi-pa"[..], b"cket mes", b"sage\n"] {
+            packets.send(packet.to_vec()).unwrap();
+        }
+        assert_eq!(
+            ble.receive_message().await.unwrap(),
+            BleMessage::from("multi-packet message")
+        );
+
+        // The session stays open for the following messages
+        packets.send(b"next\n".to_vec()).unwrap();
+        assert_eq!(
+            ble.receive_message().await.unwrap(),
+            BleMessage::from("next")
+        );
+    }
+
+    #[tokio::test]
+    async fn back_to_back_sessions_are_read_in_order() {
+        let mut ble = BlePeripheral::new(None).await.unwrap();
+        let central = start_mock_engine(&mut ble);
+
+        // Each write opens a session before the previous one was read
+        for message in [&b"first"[..], b"second", b"third"] {
+            central.write(message);
+        }
+        for expected in [&b"first"[..], b"second", b"third"] {
+            assert_eq!(
+                ble.receive_message().await.unwrap(),
+                BleMessage::Raw(expected.to_vec())
+            );
+        }
+    }
+
+    fn with_id(id: u64, payload: &[u8]) -> Vec<u8> {
+        let mut bytes = id.to_be_bytes().to_vec();
+        bytes.extend_from_slice(payload);
+        bytes
+    }
+
+    #[test]
+    fn cache_remembers_the_last_ids() {
+        let mut cache = DedupCache::new(2);
+        assert!(cache.insert(1));
+        assert!(cache.insert(2));
+        assert!(!cache.insert(1));
+        // 2 is the least recently seen, so it is evicted
+        assert!(cache.insert(3));
+        assert!(!cache.contains(2));
+        assert!(cache.contains(1));
+        assert_eq!(cache.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn duplicates_within_the_window_are_dropped() {
+        let mut ble = BlePeripheral::builder().dedup_window(2).build().unwrap();
+        let central = start_mock_engine(&mut ble);
+        central.write(&with_id(1, b"first"));
+        central.write(&with_id(1, b"first"));
+        central.write(&with_id(2, b"second"));
+        central.write(&with_id(3, b"third"));
+        // 1 fell out of the window, so its retransmit is delivered again
+        central.write(&with_id(1, b"first"));
+        central.write(&with_id(3, b"third"));
+        central.write(&with_id(4, b"fourth"));
+
+        let expected: [(u64, &[u8]); 5] = [
+            (1, b"first"),
+            (2, b"second"),
+            (3, b"third"),
+            (1, b"first"),
+            (4, b"fourth"),
+        ];
+        for (id, payload) in expected {
+            let envelope = ble.receive_envelope().await.unwrap();
+            assert_eq!(envelope.meta.id, Some(id));
+            assert_eq!(envelope.message, BleMessage::Raw(payload.to_vec()));
+        }
+        ble.stop_engine(None).await;
+    }
+
+    #[tokio::test]
+    async fn engine_survives_accept_failure() {
+        let mut ble = BlePeripheral::new(None).await.unwrap();
+        let mut events = ble.subscribe_events();
+        let central = start_mock_engine(&mut ble);
+
+        // The failed write is reported instead of crashing the engine
+        central.fail_write(512);
+        assert!(matches!(
+            events.recv().await.unwrap(),
+            BleEngineEvent::WriteAcceptFailed { .. }
+        ));
+
+        // The engine keeps receiving writes
+        central.write(b"still running");
+        assert_eq!(
+            ble.receive_message().await.unwrap(),
+            BleMessage::from(b"still running".to_vec())
+        );
+        assert!(!ble.ble_thread.as_ref().unwrap().is_finished());
+    }
+
+    #[tokio::test]
+    async fn repeated_failures_fall_back_to_acknowledged_writes() {
+        let mut ble = BlePeripheral::builder()
+            .write_without_response(true)
+            .write_fallback_after(3)
+            .build()
+            .unwrap();
+        let mut events = ble.subscribe_events();
+        let central = start_mock_engine(&mut ble);
+        assert!(ble.uses_write_without_response());
+
+        for _ in 0..3 {
+            central.fail_write(512);
+        }
+        let mut fallback = None;
+        while fallback.is_none() {
+            if let BleEngineEvent::WriteFallback { failures } = events.recv().await.unwrap() {
+                fallback = Some(failures);
+            }
+        }
+        assert_eq!(fallback, Some(3));
+
+        // The characteristic is reconfigured for acknowledged writes
+        assert!(!ble.uses_write_without_response());
+    }
+
+    #[tokio::test]
+    async fn read_errors_count_as_failures() {
+        let mut ble = BlePeripheral::builder()
+            .write_without_response(true)
+            .write_fallback_after(2)
+            .build()
+            .unwrap();
+        let mut events = ble.subscribe_events();
+        let central = start_mock_engine(&mut ble);
+
+        // A write read to its end resets the consecutive failures
+        central.fail_read(512);
+        central.write(&[0x01]);
+        central.fail_read(512);
+        central.write(&[0x02]);
+        assert_eq!(ble.receive_message().await.unwrap(), vec![0x01].into());
+        assert_eq!(ble.receive_message().await.unwrap(), vec![0x02].into());
+        assert!(ble.uses_write_without_response());
+
+        central.fail_read(512);
+        central.fail_read(512);
+
+        let mut fallback = None;
+        while fallback.is_none() {
+            if let BleEngineEvent::WriteFallback { failures } = events.recv().await.unwrap() {
+                fallback = Some(failures);
+            }
+        }
+        assert_eq!(fallback, Some(2));
+        assert!(!ble.uses_write_without_response());
+    }
+
+    #[tokio::test]
+    async fn chunks_are_emitted_before_reassembly() {
+        let mut ble = BlePeripheral::builder()
+            .text_delimiter(b'\n')
+            .build()
+            .unwrap();
+        let mut chunks = ble.subscribe_raw_chunks();
+        let central = start_mock_engine(&mut ble);
+
+        // The first chunk is emitted while its message is still incomplete
+        central.write(b"hel");
+        let chunk = chunks.recv().await.unwrap();
+        assert_eq!(chunk.len(), 3);
+        assert_eq!(chunk.bytes, b"hel");
+        assert_eq!(ble.pending_messages(), 0);
+
+        central.write(b"lo\nworld\n");
+        assert_eq!(
+            ble.receive_message().await.unwrap(),
+            BleMessage::from("hello")
+        );
+        let second = chunks.try_recv().unwrap();
+        assert_eq!(second.len(), 9);
+        assert!(second.timestamp >= chunk.timestamp);
+        assert_eq!(
+            ble.receive_message().await.unwrap(),
+            BleMessage::from("world")
+        );
+        assert!(chunks.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn echo_handler_answers_requests() {
+        let mut ble = BlePeripheral::builder()
+            .text_delimiter(b'\n')
+            .build()
+            .unwrap();
+        let central = start_mock_engine(&mut ble);
+        let mut notifications = central.subscribe_and_wait(&ble, 512).await;
+
+        let client = async {
+            // Requests answered with `None` get no response
+            central.write(b"quiet\nping\n");
+            assert_eq!(notifications.recv().await.unwrap(), b"ping\n");
+            central.write(b"pong\n");
+            assert_eq!(notifications.recv().await.unwrap(), b"pong\n");
+        };
+        let server = ble.serve(|request| match request {
+            BleMessage::Text(text) if text == "quiet" => None,
+            request => Some(request),
+        });
+        tokio::select! {
+            _ = client => {}
+            result = server => panic!("Server stopped early: {:?}", result),
+        }
+    }
+}
+
+#[cfg(test)]
+mod send_test {
+    use super::super::backoff::{BackoffExhausted, WriteBackoff};
+    use super::super::capture::FrameDirection;
+    use super::super::coalesce::{fits_in_batch, split_coalesced};
+    use super::super::codec::RawCodec;
+    use super::super::endian::Endianness;
+    use super::super::error::BleError;
+    use super::super::message::BleMessage;
+    use super::super::mock::{start_mock_engine, wait_subscribed, MockTransport};
+    use super::super::queue::SendOverflowPolicy;
+    use super::super::report::SendReport;
+    use super::super::transfer::{decode_file_chunk, encode_file_chunk};
+    use super::super::BlePeripheral;
+    use std::sync::atomic::Ordering;
+    use std::sync::{Arc, Mutex};
+    use tokio::time::{Duration, Instant};
+
+    #[tokio::test]
+    async fn last_notified_tracks_sent_bytes() {
+        let mut ble = BlePeripheral::new(None).await.unwrap();
+        assert!(ble.last_notified().is_none());
+
+        let central = start_mock_engine(&mut ble);
+        let mut notifications = central.subscribe_and_wait(&ble, 64).await;
+        assert!(ble.last_notified().is_none());
+
+        ble.send_message(vec![1, 2, 3]).await.unwrap();
+        assert_eq!(notifications.recv().await.unwrap(), vec![1, 2, 3]);
+        while ble.last_notified().is_none() {
+            tokio::task::yield_now().await;
+        }
+        assert_eq!(ble.last_notified(), Some(vec![1, 2, 3]));
+    }
+
+    #[tokio::test]
+    async fn tiny_sends_are_coalesced() {
+        let mut ble = BlePeripheral::builder()
+            .coalesce(64, Duration::from_millis(20))
+            .build()
+            .unwrap();
+        let central = start_mock_engine(&mut ble);
+        let mut notifications = central.subscribe_and_wait(&ble, 512).await;
+
+        let sent: Vec<Vec<u8>> = (0..5u8).map(|i| vec![i; 3]).collect();
+        for message in sent.iter() {
+            ble.send_message(message.clone()).await.unwrap();
+        }
+
+        // Split the notifications back into messages until every message arrived
+        let mut writes = 0;
+        let mut received = Vec::new();
+        while received.len() < sent.len() {
+            let notification = notifications.recv().await.unwrap();
+            received.extend(split_coalesced(&notification, Endianness::Big).unwrap());
+            writes += 1;
+        }
+        assert!(writes < sent.len());
+        assert_eq!(received, sent);
+    }
+
+    #[tokio::test]
+    async fn batches_larger_than_the_mtu_keep_whole_frames() {
+        let mut ble = BlePeripheral::builder()
+            .coalesce(200, Duration::from_millis(20))
+            .build()
+            .unwrap();
+        let central = start_mock_engine(&mut ble);
+        let mut notifications = central.subscribe_and_wait(&ble, 23).await;
+
+        let sent: Vec<Vec<u8>> = (0..6u8).map(|i| vec![i; 8]).collect();
+        for message in sent.iter() {
+            ble.send_message(message.clone()).await.unwrap();
+        }
+
+        // Every notification fits the MTU and splits on its own
+        let mut received = Vec::new();
+        while received.len() < sent.len() {
+            let notification = notifications.recv().await.unwrap();
+            assert!(notification.len() <= 23);
+            received.extend(split_coalesced(&notification, Endianness::Big).unwrap());
+        }
+        assert_eq!(received, sent);
+    }
+
+    #[tokio::test]
+    async fn messages_longer_than_a_batch_are_rejected() {
+        let mut ble = BlePeripheral::builder()
+            .coalesce(64, Duration::from_millis(20))
+            .build()
+            .unwrap();
+        let central = start_mock_engine(&mut ble);
+        let mut notifications = central.subscribe_and_wait(&ble, 512).await;
+
+        let err = ble.send_message(vec![0; 63]).await.unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<BleError>(),
+            Some(BleError::InvalidMessage(_))
+        ));
+
+        // A message filling the batch with its header still goes through
+        ble.send_message(vec![1; 62]).await.unwrap();
+        let notification = notifications.recv().await.unwrap();
+        assert_eq!(
+            split_coalesced(&notification, Endianness::Big).unwrap(),
+            vec![vec![1; 62]]
+        );
+    }
+
+    #[tokio::test]
+    async fn messages_overflowing_the_frame_header_are_rejected() {
+        let mut ble = BlePeripheral::builder()
+            .coalesce(100_000, Duration::from_millis(20))
+            .build()
+            .unwrap();
+        let _central = start_mock_engine(&mut ble);
+
+        let err = ble.send_message(vec![0; 70_000]).await.unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<BleError>(),
+            Some(BleError::InvalidMessage(_))
+        ));
+        assert!(fits_in_batch(u16::MAX as usize, 100_000));
+    }
+
+    #[tokio::test]
+    async fn recent_frames_are_captured() {
+        let mut ble = BlePeripheral::builder().capture_frames(2).build().unwrap();
+        let central = start_mock_engine(&mut ble);
+        let mut notifications = central.subscribe_and_wait(&ble, 512).await;
+
+        central.write(b"first");
+        ble.receive_message().await.unwrap();
+        ble.send_message(b"second".to_vec()).await.unwrap();
+        notifications.recv().await.unwrap();
+        central.write(b"third");
+        ble.receive_message().await.unwrap();
+
+        // Only the last two frames are kept, oldest first
+        let frames = ble.recent_frames();
+        assert_eq!(frames.len(), 2);
+        assert_eq!(frames[0].direction, FrameDirection::Sent);
+        assert_eq!(frames[0].bytes, b"second");
+        assert_eq!(frames[1].direction, FrameDirection::Received);
+        assert_eq!(frames[1].bytes, b"third");
+        assert!(frames[0].timestamp <= frames[1].timestamp);
+    }
+
+    #[tokio::test]
+    async fn large_payload_is_split_at_mtu() {
+        let mut ble = BlePeripheral::new(None).await.unwrap();
+        let central = start_mock_engine(&mut ble);
+        let mut notifications = central.subscribe_and_wait(&ble, 20).await;
+
+        let payload: Vec<u8> = (0..50).collect();
+        ble.send_message(payload.clone()).await.unwrap();
+
+        // Every notification fits in the MTU
+        let mut received = Vec::new();
+        for expected_len in [20, 20, 10] {
+            let notification = notifications.recv().await.unwrap();
+            assert_eq!(notification.len(), expected_len);
+            received.extend(notification);
+        }
+        assert_eq!(received, payload);
+    }
+
+    #[tokio::test]
+    async fn raw_bytes_are_sent_verbatim() {
+        let mut ble = BlePeripheral::builder()
+            .coalesce(64, Duration::from_millis(20))
+            .build()
+            .unwrap();
+        let central = start_mock_engine(&mut ble);
+        let mut notifications = central.subscribe_and_wait(&ble, 4).await;
+
+        // No length prefix or MTU split is applied
+        let bytes = b"verbatim".to_vec();
+        ble.send_raw_unframed(bytes.clone()).await.unwrap();
+        assert_eq!(notifications.recv().await.unwrap(), bytes);
+
+        central.write(&[0x00, 0xFF]);
+        assert_eq!(ble.receive_raw_unframed().await.unwrap(), vec![0x00, 0xFF]);
+    }
+
+    #[tokio::test]
+    async fn slow_send_does_not_stall_receives() {
+        let mut ble = BlePeripheral::builder().build().unwrap();
+        let central = start_mock_engine(&mut ble);
+        let (mut notifications, gate) = central.subscribe_stalled(20);
+        wait_subscribed(&ble, true).await;
+
+        // The large payload is stuck writing its first notification
+        let payload = vec![0xAB; 2000];
+        ble.send_message(BleMessage::Raw(payload.clone()))
+            .await
+            .unwrap();
+
+        // Receives still go through while the send is stalled
+        central.write(b"hello");
+        let received = tokio::time::timeout(Duration::from_secs(1), ble.receive_message())
+            .await
+            .expect("receive stalled by the pending send")
+            .unwrap();
+        assert_eq!(received, BleMessage::Raw(b"hello".to_vec()));
+        assert!(notifications.try_recv().is_err());
+
+        // The payload is sent once the notification session catches up
+        gate.open();
+        let mut sent = Vec::new();
+        while sent.len() < payload.len() {
+            sent.extend(notifications.recv().await.unwrap());
+        }
+        assert_eq!(sent, payload);
+    }
+
+    #[tokio::test]
+    async fn overflow_handler_gets_dropped_message() {
+        let mut ble = BlePeripheral::builder()
+            .send_capacity(1)
+            .send_overflow_policy(SendOverflowPolicy::DropNewest)
+            .build()
+            .unwrap();
+        let overflowed = Arc::new(Mutex::new(Vec::new()));
+        let handler_overflowed = overflowed.clone();
+        ble.on_send_overflow(move |message| {
+            handler_overflowed.lock().unwrap().push(message.clone());
+        });
+
+        let central = start_mock_engine(&mut ble);
+        let (mut notifications, gate) = central.subscribe_stalled(512);
+        wait_subscribed(&ble, true).await;
+
+        // The first message fills the queue while its notification is stalled
+        ble.send_message(BleMessage::Raw(vec![0x01])).await.unwrap();
+        ble.send_message(BleMessage::Raw(vec![0x02])).await.unwrap();
+        assert_eq!(
+            *overflowed.lock().unwrap(),
+            vec![BleMessage::Raw(vec![0x02])]
+        );
+
+        // Room is made once the notification goes through
+        gate.open();
+        assert_eq!(notifications.recv().await.unwrap(), vec![0x01]);
+        ble.send_message(BleMessage::Raw(vec![0x03])).await.unwrap();
+        assert_eq!(notifications.recv().await.unwrap(), vec![0x03]);
+        assert_eq!(overflowed.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn file_chunks_are_limited_by_the_send_queue() {
+        let mut ble = BlePeripheral::builder()
+            .send_capacity(1)
+            .send_overflow_policy(SendOverflowPolicy::DropNewest)
+            .build()
+            .unwrap();
+        let overflowed = Arc::new(Mutex::new(Vec::new()));
+        let handler_overflowed = overflowed.clone();
+        ble.on_send_overflow(move |message| {
+            handler_overflowed.lock().unwrap().push(message.clone());
+        });
+
+        let central = start_mock_engine(&mut ble);
+        let (mut notifications, gate) = central.subscribe_stalled(512);
+        wait_subscribed(&ble, true).await;
+
+        // Only the first chunk fits in the queue while its notification is stalled
+        ble.send_file((0..20).collect(), 10).await.unwrap();
+        assert_eq!(
+            *overflowed.lock().unwrap(),
+            vec![BleMessage::Raw(encode_file_chunk(
+                10,
+                &(10..20).collect::<Vec<u8>>(),
+                Endianness::Big
+            ))]
+        );
+        gate.open();
+        assert_eq!(
+            notifications.recv().await.unwrap(),
+            encode_file_chunk(0, &(0..10).collect::<Vec<u8>>(), Endianness::Big)
+        );
+    }
+
+    #[tokio::test]
+    async fn report_counts_framing_and_chunks() {
+        let mut ble = BlePeripheral::builder()
+            .text_delimiter(b'\n')
+            .build()
+            .unwrap();
+        let central = start_mock_engine(&mut ble);
+        let mut notifications = central.subscribe_and_wait(&ble, 4).await;
+
+        let report = ble.send_message_reported("hello world").await.unwrap();
+        assert_eq!(
+            report,
+            SendReport {
+                app_bytes: 11,
+                wire_bytes: 12,
+                chunks: 3,
+            }
+        );
+
+        // The wire bytes add up to the notifications the central received
+        let mut received = Vec::new();
+        for _ in 0..report.chunks {
+            received.extend(notifications.recv().await.unwrap());
+        }
+        assert_eq!(received, b"hello world\n".to_vec());
+    }
+
+    #[tokio::test]
+    async fn unsubscribed_message_is_not_delivered() {
+        let mut ble = BlePeripheral::builder().build().unwrap();
+        let _central = start_mock_engine(&mut ble);
+        assert!(matches!(
+            ble.send_message_reported("hello").await,
+            Err(BleError::NotDelivered)
+        ));
+    }
+
+    #[tokio::test]
+    async fn notifier_is_flushed_after_each_message() {
+        let mut ble = BlePeripheral::builder()
+            .flush_after_each(true)
+            .build()
+            .unwrap();
+        let central = start_mock_engine(&mut ble);
+        let (mut notifications, flushes) = central.subscribe_counting_flushes(512);
+        wait_subscribed(&ble, true).await;
+
+        for (i, message) in ["one", "two", "three"].into_iter().enumerate() {
+            ble.send_message(message).await.unwrap();
+            assert_eq!(notifications.recv().await.unwrap(), message.as_bytes());
+            assert_eq!(flushes.load(Ordering::SeqCst), i + 1);
+        }
+    }
+
+    #[tokio::test]
+    async fn notifier_is_flushed_on_demand() {
+        let mut ble = BlePeripheral::builder().build().unwrap();
+        let central = start_mock_engine(&mut ble);
+        let (mut notifications, flushes) = central.subscribe_counting_flushes(512);
+        wait_subscribed(&ble, true).await;
+
+        ble.send_message("one").await.unwrap();
+        ble.send_message("two").await.unwrap();
+        ble.flush_notifier().await.unwrap();
+        assert_eq!(flushes.load(Ordering::SeqCst), 1);
+        assert_eq!(notifications.recv().await.unwrap(), b"one");
+        assert_eq!(notifications.recv().await.unwrap(), b"two");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn burst_is_batched_into_one_notification() {
+        let mut ble = BlePeripheral::builder()
+            .coalesce(1000, Duration::from_millis(50))
+            .build()
+            .unwrap();
+        let central = start_mock_engine(&mut ble);
+        let mut notifications = central.subscribe_and_wait(&ble, 512).await;
+
+        // The whole burst is queued before the BLE thread takes the first message
+        for _ in 0..4 {
+            ble.send_message(BleMessage::Raw(vec![0; 10]))
+                .await
+                .unwrap();
+        }
+        // Each coalesced message is framed by its 2-byte length
+        assert_eq!(notifications.recv().await.unwrap().len(), 48);
+        tokio::time::sleep(Duration::from_millis(1950)).await;
+
+        let metrics = ble.metrics();
+        assert_eq!(metrics.messages, 4);
+        assert_eq!(metrics.notifications, 1);
+        assert_eq!(metrics.notified_bytes, 48);
+        assert_eq!(metrics.average_notification_bytes, 48.0);
+        assert_eq!(metrics.average_queue_depth, 1.5);
+        assert_eq!(metrics.notifications_per_second, 0.5);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn throughput_follows_the_send_and_receive_rates() {
+        let mut ble = BlePeripheral::builder().build().unwrap();
+        assert_eq!(ble.throughput(), Default::default());
+        let central = start_mock_engine(&mut ble);
+        let mut notifications = central.subscribe_and_wait(&ble, 512).await;
+
+        // 100 bytes sent and 50 bytes received every 100 ms, for 2 seconds
+        for _ in 0..20 {
+            ble.send_message(BleMessage::Raw(vec![0; 100]))
+                .await
+                .unwrap();
+            assert_eq!(notifications.recv().await.unwrap().len(), 100);
+            central.write(&[1; 50]);
+            ble.receive_message().await.unwrap();
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+
+        let sent = ble.throughput();
+        assert!((900.0..=1100.0).contains(&sent.inst_bps));
+        assert!((900.0..=1100.0).contains(&sent.avg_1s_bps));
+        assert!((180.0..=220.0).contains(&sent.avg_10s_bps));
+        let received = ble.receive_throughput();
+        assert!((450.0..=550.0).contains(&received.inst_bps));
+        assert!((450.0..=550.0).contains(&received.avg_1s_bps));
+        assert!((90.0..=110.0).contains(&received.avg_10s_bps));
+
+        // Nothing is counted once the transfer stopped for longer than the windows
+        tokio::time::sleep(Duration::from_secs(10)).await;
+        assert_eq!(ble.throughput(), Default::default());
+    }
+
+    #[test]
+    fn delay_doubles_up_to_max() {
+        let backoff = WriteBackoff {
+            initial_delay: Duration::from_millis(10),
+            max_delay: Duration::from_millis(50),
+            ..WriteBackoff::default()
+        };
+        let delays: Vec<_> = (0..4).map(|retry| backoff.delay(retry)).collect();
+        assert_eq!(delays, [10, 20, 40, 50].map(Duration::from_millis).to_vec());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn full_buffer_is_retried_until_it_recovers() {
+        let mut ble = BlePeripheral::builder()
+            .write_backoff(WriteBackoff::default())
+            .build()
+            .unwrap();
+        let central = start_mock_engine(&mut ble);
+        let mut notifications = central.subscribe_busy(512, 3);
+        wait_subscribed(&ble, true).await;
+
+        // The retries wait 10, 20, then 40ms before the buffer frees up
+        let started = Instant::now();
+        ble.send_message(b"hello".to_vec()).await.unwrap();
+        assert_eq!(notifications.recv().await.unwrap(), b"hello");
+        assert_eq!(started.elapsed(), Duration::from_millis(70));
+        assert!(ble.is_subscribed().await);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn exhausted_retries_apply_policy() {
+        for (on_exhausted, subscribed) in [
+            (BackoffExhausted::DropMessage, true),
+            (BackoffExhausted::Disconnect, false),
+        ] {
+            let mut ble = BlePeripheral::builder()
+                .write_backoff(WriteBackoff {
+                    max_retries: 2,
+                    on_exhausted,
+                    ..WriteBackoff::default()
+                })
+                .build()
+                .unwrap();
+            let central = start_mock_engine(&mut ble);
+            let mut notifications = central.subscribe_busy(512, 3);
+            wait_subscribed(&ble, true).await;
+
+            ble.send_message(b"lost".to_vec()).await.unwrap();
+            ble.send_message(b"next".to_vec()).await.unwrap();
+            match subscribed {
+                // The buffer frees up after the first message is dropped
+                true => assert_eq!(notifications.recv().await.unwrap(), b"next"),
+                false => assert!(notifications.recv().await.is_none()),
+            }
+            assert_eq!(ble.is_subscribed().await, subscribed);
+        }
+    }
+
+    #[tokio::test]
+    async fn initial_messages_are_sent_on_subscription() {
+        let mut ble = BlePeripheral::builder()
+            .text_delimiter(b'\n')
+            .build()
+            .unwrap();
+        ble.set_initial_messages(vec![
+            BleMessage::from("temp=21"),
+            BleMessage::from("humidity=40"),
+        ]);
+        let (transport, central) = MockTransport::new();
+        ble.start_with(transport).await.unwrap();
+
+        let mut notifications = central.subscribe(512);
+        assert_eq!(notifications.recv().await.unwrap(), b"temp=21\n".to_vec());
+        assert_eq!(
+            notifications.recv().await.unwrap(),
+            b"humidity=40\n".to_vec()
+        );
+
+        // The incremental updates follow the snapshot
+        ble.send_message("temp=22").await.unwrap();
+        assert_eq!(notifications.recv().await.unwrap(), b"temp=22\n".to_vec());
+        ble.stop_engine(None).await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn sends_wait_for_the_inflight_writes() {
+        let mut ble = BlePeripheral::builder()
+            .max_inflight_writes(2)
+            .build()
+            .unwrap();
+        let (transport, central) = MockTransport::new();
+        ble.start_with(transport).await.unwrap();
+        let (mut notifications, gate) = central.subscribe_stalled(512);
+        wait_subscribed(&ble, true).await;
+
+        // The writes stall, so the third send waits for a slot
+        ble.send_message("first").await.unwrap();
+        ble.send_message("second").await.unwrap();
+        assert_eq!(ble.inflight_writes(), 2);
+        let third = tokio::time::timeout(Duration::from_secs(1), ble.send_message("third")).await;
+        assert!(third.is_err());
+        assert_eq!(ble.inflight_writes(), 2);
+
+        gate.open();
+        assert_eq!(notifications.recv().await.unwrap(), b"first".to_vec());
+        assert_eq!(notifications.recv().await.unwrap(), b"second".to_vec());
+        ble.send_message("third").await.unwrap();
+        assert_eq!(notifications.recv().await.unwrap(), b"third".to_vec());
+        assert_eq!(ble.inflight_writes(), 0);
+        ble.stop_engine(None).await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn coalesced_messages_count_until_their_batch_is_written() {
+        let mut ble = BlePeripheral::builder()
+            .max_inflight_writes(2)
+            .coalesce(64, Duration::from_millis(20))
+            .build()
+            .unwrap();
+        let (transport, central) = MockTransport::new();
+        ble.start_with(transport).await.unwrap();
+        let mut notifications = central.subscribe_and_wait(&ble, 512).await;
+
+        // Both messages wait in the pending batch, so the third send waits for a slot
+        ble.send_message("first").await.unwrap();
+        ble.send_message("second").await.unwrap();
+        let third =
+            tokio::time::timeout(Duration::from_millis(10), ble.send_message("third")).await;
+        assert!(third.is_err());
+        assert_eq!(ble.inflight_writes(), 2);
+
+        let batch = notifications.recv().await.unwrap();
+        assert_eq!(
+            split_coalesced(&batch, Endianness::Big).unwrap(),
+            vec![b"first".to_vec(), b"second".to_vec()]
+        );
+        assert_eq!(ble.inflight_writes(), 0);
+        ble.stop_engine(None).await;
+    }
+
+    #[tokio::test]
+    async fn send_before_start_is_rejected() {
+        let ble = BlePeripheral::new(None).await.unwrap();
+        let err = ble.send_message("too early").await.unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<BleError>(),
+            Some(BleError::EngineNotStarted)
+        ));
+    }
+
+    #[tokio::test]
+    async fn sends_before_start_are_flushed_on_subscription() {
+        let mut ble = BlePeripheral::builder()
+            .queue_before_start(true)
+            .build()
+            .unwrap();
+        ble.send_message("first").await.unwrap();
+        ble.send_message("second").await.unwrap();
+        let (transport, central) = MockTransport::new();
+        ble.start_with(transport).await.unwrap();
+
+        let mut notifications = central.subscribe(512);
+        assert_eq!(notifications.recv().await.unwrap(), b"first".to_vec());
+        assert_eq!(notifications.recv().await.unwrap(), b"second".to_vec());
+        ble.send_message("third").await.unwrap();
+        assert_eq!(notifications.recv().await.unwrap(), b"third".to_vec());
+        ble.stop_engine(None).await;
+    }
+
+    #[tokio::test]
+    async fn encoded_values_and_file_chunks_are_queued_before_start() {
+        let mut ble = BlePeripheral::builder()
+            .queue_before_start(true)
+            .build()
+            .unwrap();
+        ble.send_encoded::<RawCodec, _>(&b"early".to_vec())
+            .await
+            .unwrap();
+        ble.send_file(b"file".to_vec(), 10).await.unwrap();
+        let (transport, central) = MockTransport::new();
+        ble.start_with(transport).await.unwrap();
+
+        let mut notifications = central.subscribe(512);
+        assert_eq!(notifications.recv().await.unwrap(), b"early".to_vec());
+        let notification = notifications.recv().await.unwrap();
+        assert_eq!(
+            decode_file_chunk(&notification, Endianness::Big).unwrap(),
+            (0, &b"file"[..])
+        );
+        ble.stop_engine(None).await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn unconfirmed_notification_fails_after_the_timeout() {
+        let mut ble = BlePeripheral::builder()
+            .indication_timeout(Duration::from_millis(500))
+            .build()
+            .unwrap();
+        let (transport, central) = MockTransport::new();
+        ble.start_with(transport).await.unwrap();
+        // The central never confirms the notifications
+        let (_notifications, _gate) = central.subscribe_stalled(512);
+        wait_subscribed(&ble, true).await;
+
+        let start = Instant::now();
+        assert!(matches!(
+            ble.send_message_reported("unconfirmed").await,
+            Err(BleError::NotDelivered)
+        ));
+        assert!(start.elapsed() >= Duration::from_millis(500));
+        assert!(!ble.is_subscribed().await);
+        ble.stop_engine(None).await;
+    }
+}
+
+#[cfg(test)]
+mod transfer_test {
+    use super::super::control::ControlMessage;
+    use super::super::endian::Endianness;
+    use super::super::error::BleError;
+    use super::super::image_transfer::{encode_image, ImageReceiver};
+    use super::super::mock::start_mock_engine;
+    use super::super::transfer::decode_file_chunk;
+    use super::super::BlePeripheral;
+    use image::GenericImageView;
+    use std::sync::Arc;
+    use tokio::time::{Duration, Instant};
+
+    #[tokio::test]
+    async fn interrupted_transfer_resumes_from_ack() {
+        let mut ble = BlePeripheral::new(None).await.unwrap();
+        let central = start_mock_engine(&mut ble);
+        let mut notifications = central.subscribe_and_wait(&ble, 512).await;
+
+        let file: Vec<u8> = (0..40).collect();
+        ble.send_file(file.clone(), 10).await.unwrap();
+
+        // The central only gets the first two chunks before the link drops
+        for expected_offset in [0, 10] {
+            let notification = notifications.recv().await.unwrap();
+            let (offset, bytes) = decode_file_chunk(&notification, Endianness::Big).unwrap();
+            assert_eq!(offset, expected_offset);
+            assert_eq!(bytes, &file[offset as usize..offset as usize + 10]);
+        }
+        central.write(&ControlMessage::TransferAck { offset: 20 }.to_bytes(Endianness::Big));
+        while ble.file_transfer_offset() != Some(20) {
+            tokio::task::yield_now().await;
+        }
+        drop(notifications);
+
+        // The central reconnects and the transfer continues after the acknowledged bytes
+        let mut notifications = central.subscribe(256);
+        while ble.current_mtu() != Some(256) {
+            tokio::task::yield_now().await;
+        }
+        assert_eq!(ble.resume_file_transfer().await.unwrap(), 20);
+        let mut received = file[..20].to_vec();
+        for expected_offset in [20, 30] {
+            let notification = notifications.recv().await.unwrap();
+            let (offset, bytes) = decode_file_chunk(&notification, Endianness::Big).unwrap();
+            assert_eq!(offset, expected_offset);
+            received.extend_from_slice(bytes);
+        }
+        assert_eq!(received, file);
+    }
+
+    /// Transfer a file of eight chunks to a central acknowledging each one after a round trip,
+    /// and return how long the transfer took.
+    async fn windowed_transfer_duration(window_size: usize) -> Duration {
+        let mut ble = BlePeripheral::builder()
+            .window_size(window_size)
+            .build()
+            .unwrap();
+        let central = Arc::new(start_mock_engine(&mut ble));
+        let mut notifications = central.subscribe_and_wait(&ble, 512).await;
+
+        let acking_central = central.clone();
+        tokio::spawn(async move {
+            while let Some(notification) = notifications.recv().await {
+                let (offset, bytes) = decode_file_chunk(&notification, Endianness::Big).unwrap();
+                let ack = ControlMessage::TransferAck {
+                    offset: offset + bytes.len() as u64,
+                };
+                let central = acking_central.clone();
+                tokio::spawn(async move {
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                    central.write(&ack.to_bytes(Endianness::Big));
+                });
+            }
+        });
+
+        let file: Vec<u8> = (0..80).collect();
+        let started = Instant::now();
+        ble.send_file_windowed(file, 10, Duration::from_secs(5))
+            .await
+            .unwrap();
+        assert_eq!(ble.file_transfer_offset(), Some(80));
+        started.elapsed()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn wider_window_transfers_faster() {
+        let stop_and_wait = windowed_transfer_duration(1).await;
+        let windowed = windowed_transfer_duration(4).await;
+        assert!(stop_and_wait >= Duration::from_millis(800));
+        assert!(windowed < stop_and_wait / 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn unacknowledged_transfer_times_out() {
+        let mut ble = BlePeripheral::builder().window_size(2).build().unwrap();
+        let central = start_mock_engine(&mut ble);
+        let mut notifications = central.subscribe_and_wait(&ble, 512).await;
+
+        let transfer = ble.send_file_windowed((0..40).collect(), 10, Duration::from_secs(1));
+        assert!(matches!(transfer.await, Err(BleError::Timeout)));
+
+        // Only the first window was sent
+        for expected_offset in [0, 10] {
+            let (offset, _) =
+                decode_file_chunk(&notifications.recv().await.unwrap(), Endianness::Big).unwrap();
+            assert_eq!(offset, expected_offset);
+        }
+        assert!(notifications.try_recv().is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn short_last_chunk_is_resumed_by_its_length() {
+        let mut ble = BlePeripheral::builder().window_size(4).build().unwrap();
+        let central = start_mock_engine(&mut ble);
+        let mut notifications = central.subscribe_and_wait(&ble, 512).await;
+
+        // The central acknowledges the first two chunks, then stops answering
+        let file: Vec<u8> = (0..25).collect();
+        let transfer = ble.send_file_windowed(file.clone(), 10, Duration::from_secs(1));
+        let acks = async {
+            for _ in 0..3 {
+                notifications.recv().await.unwrap();
+            }
+            assert_eq!(ble.unacked_count(), 3);
+            central.write(&ControlMessage::TransferAck { offset: 20 }.to_bytes(Endianness::Big));
+        };
+        let (transfer, _) = tokio::join!(transfer, acks);
+        assert!(matches!(transfer, Err(BleError::Timeout)));
+        assert_eq!(ble.unacked_count(), 1);
+
+        // Only the short last chunk is sent again
+        assert_eq!(ble.resume_file_transfer().await.unwrap(), 20);
+        let notification = notifications.recv().await.unwrap();
+        assert_eq!(
+            decode_file_chunk(&notification, Endianness::Big).unwrap(),
+            (20, &file[20..])
+        );
+        assert!(notifications.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn unacked_count_follows_acknowledgments() {
+        let mut ble = BlePeripheral::new(None).await.unwrap();
+        let central = start_mock_engine(&mut ble);
+        let _notifications = central.subscribe_and_wait(&ble, 512).await;
+        assert_eq!(ble.unacked_count(), 0);
+
+        ble.send_file((0..35).collect(), 10).await.unwrap();
+        assert_eq!(ble.unacked_count(), 4);
+
+        // A partially acknowledged chunk is still in flight
+        for (offset, unacked) in [(10, 3), (15, 3), (30, 1), (35, 0)] {
+            central.write(&ControlMessage::TransferAck { offset }.to_bytes(Endianness::Big));
+            while ble.file_transfer_offset() != Some(offset) {
+                tokio::task::yield_now().await;
+            }
+            assert_eq!(ble.unacked_count(), unacked);
+        }
+    }
+
+    #[tokio::test]
+    async fn image_round_trips_through_the_pipeline() {
+        let mut ble = BlePeripheral::new(None).await.unwrap();
+        let central = start_mock_engine(&mut ble);
+        let mut notifications = central.subscribe_and_wait(&ble, 512).await;
+
+        let image = image::open("test_assets/test_image1.jpg").unwrap();
+        ble.send_image(&image, 75, 100, 200).await.unwrap();
+
+        // Each chunk fits in a single notification
+        let mut receiver = ImageReceiver::new();
+        let received = loop {
+            let notification = notifications.recv().await.unwrap();
+            if let Some(received) = receiver.push(&notification).unwrap() {
+                break received;
+            }
+        };
+        assert_eq!(received.dimensions(), (75, 100));
+        let expected = image::load_from_memory(&encode_image(&image, 75, 100).unwrap()).unwrap();
+        assert_eq!(received, expected);
+    }
+
+    #[tokio::test]
+    async fn progress_is_interleaved_with_the_chunks() {
+        let mut ble = BlePeripheral::builder()
+            .transfer_progress(2)
+            .build()
+            .unwrap();
+        let central = start_mock_engine(&mut ble);
+        let mut notifications = central.subscribe_and_wait(&ble, 512).await;
+
+        // 5 chunks, reported after the 2nd, the 4th, and the last one
+        ble.send_file((0..45).collect(), 10).await.unwrap();
+        let mut received = Vec::new();
+        for _ in 0..8 {
+            let notification = notifications.recv().await.unwrap();
+            match ControlMessage::from_bytes(&notification, Endianness::Big) {
+                Some(ControlMessage::TransferProgress { chunks, total }) => {
+                    received.push(format!("progress {}/{}", chunks, total))
+                }
+                Some(control) => panic!("Unexpected control message {:?}", control),
+                None => {
+                    let (offset, _) = decode_file_chunk(&notification, Endianness::Big).unwrap();
+                    received.push(format!("chunk {}", offset));
+                }
+            }
+        }
+        assert_eq!(
+            received,
+            [
+                "chunk 0",
+                "chunk 10",
+                "progress 2/5",
+                "chunk 20",
+                "chunk 30",
+                "progress 4/5",
+                "chunk 40",
+                "progress 5/5",
+            ]
+        );
+        ble.stop_engine(None).await;
+    }
+}
+
+#[cfg(test)]
+mod connection_test {
+    use super::super::capture::FrameDirection;
+    use super::super::codec::RawCodec;
+    use super::super::control::ControlMessage;
+    use super::super::endian::Endianness;
+    use super::super::error::BleError;
+    use super::super::event::BleEngineEvent;
+    use super::super::handshake::{Capabilities, PROTOCOL_VERSION};
+    use super::super::message::BleMessage;
+    use super::super::mock::{start_mock_engine, wait_subscribed, MockCentral, MockTransport};
+    use super::super::transfer::decode_file_chunk;
+    use super::super::BlePeripheral;
+    use bluer::gatt::local::ReqError;
+    use bluer::Address;
+    use std::time::SystemTime;
+    use tokio::sync::mpsc;
+    use tokio::time::Duration;
+    use uuid::Uuid;
+
+    #[derive(Debug, PartialEq)]
+    struct Session {
+        id: u32,
+    }
+
+    #[tokio::test]
+    async fn data_is_cleared_on_disconnect() {
+        let mut ble = BlePeripheral::new(None).await.unwrap();
+        let central = start_mock_engine(&mut ble);
+        let notifications = central.subscribe_and_wait(&ble, 64).await;
+
+        ble.set_connection_data(Session { id: 7 });
+        ble.set_connection_data(true);
+        assert_eq!(
+            *ble.connection_data::<Session>().unwrap(),
+            Session { id: 7 }
+        );
+        assert!(*ble.connection_data::<bool>().unwrap());
+        assert!(ble.connection_data::<String>().is_none());
+
+        // The central goes away, which the engine notices on the next notification
+        drop(notifications);
+        ble.send_message("unheard").await.unwrap();
+        wait_subscribed(&ble, false).await;
+        assert!(ble.connection_data::<Session>().is_none());
+        assert!(ble.connection_data::<bool>().is_none());
+    }
+
+    #[tokio::test]
+    async fn negotiated_mtu_is_capped() {
+        let mut ble = BlePeripheral::new(None).await.unwrap();
+        let central = start_mock_engine(&mut ble);
+        assert!(matches!(
+            ble.negotiate_mtu(247).await,
+            Err(BleError::NotConnected)
+        ));
+
+        let _notifications = central.subscribe_and_wait(&ble, 185).await;
+        assert_eq!(ble.current_mtu(), Some(185));
+        assert_eq!(ble.negotiate_mtu(247).await.unwrap(), 185);
+        assert_eq!(ble.negotiate_mtu(100).await.unwrap(), 100);
+    }
+
+    #[tokio::test]
+    async fn mtu_change_is_reported_and_used() {
+        let mut ble = BlePeripheral::new(None).await.unwrap();
+        let mut events = ble.subscribe_events();
+        let central = start_mock_engine(&mut ble);
+        let mut notifications = central.subscribe_and_wait(&ble, 100).await;
+
+        // A write session with a smaller MTU shrinks the notifications
+        let _packets = central.start_write(40);
+        assert_eq!(
+            events.recv().await.unwrap(),
+            BleEngineEvent::MtuChanged { old: 100, new: 40 }
+        );
+        assert_eq!(ble.current_mtu(), Some(40));
+
+        ble.send_message(BleMessage::Raw(vec![0xAA; 90]))
+            .await
+            .unwrap();
+        for expected_len in [40, 40, 10] {
+            assert_eq!(notifications.recv().await.unwrap().len(), expected_len);
+        }
+    }
+
+    #[tokio::test]
+    async fn sessions_below_min_mtu_are_rejected() {
+        let mut ble = BlePeripheral::builder()
+            .min_mtu(100)
+            .priority_channels(Uuid::from_u128(1), Uuid::from_u128(2))
+            .build()
+            .unwrap();
+        let mut events = ble.subscribe_events();
+        let central = start_mock_engine(&mut ble);
+
+        let mut notifications = central.subscribe(23);
+        assert_eq!(
+            events.recv().await.unwrap(),
+            BleEngineEvent::MtuTooSmall {
+                mtu: 23,
+                min_mtu: 100
+            }
+        );
+        assert_eq!(notifications.recv().await, None);
+        assert!(!ble.is_subscribed().await);
+
+        let packets = central.start_write(23);
+        assert_eq!(
+            events.recv().await.unwrap(),
+            BleEngineEvent::MtuTooSmall {
+                mtu: 23,
+                min_mtu: 100
+            }
+        );
+        assert!(packets.send(b"dropped".to_vec()).is_err());
+        assert_eq!(ble.current_mtu(), None);
+
+        let mut priority = central.subscribe_priority(23);
+        assert_eq!(
+            events.recv().await.unwrap(),
+            BleEngineEvent::MtuTooSmall {
+                mtu: 23,
+                min_mtu: 100
+            }
+        );
+        assert_eq!(priority.recv().await, None);
+
+        // Sessions with a large enough MTU are accepted
+        let _notifications = central.subscribe_and_wait(&ble, 100).await;
+        assert_eq!(ble.current_mtu(), Some(100));
+    }
+
+    #[test]
+    fn control_message_round_trip() {
+        let ping = ControlMessage::Ping { nonce: 0xDEADBEEF };
+        assert_eq!(
+            ControlMessage::from_bytes(&ping.to_bytes(Endianness::Big), Endianness::Big),
+            Some(ping)
+        );
+        assert_eq!(ControlMessage::from_bytes(b"Ping", Endianness::Big), None);
+    }
+
+    #[tokio::test]
+    async fn ping_measures_round_trip() {
+        let mut ble = BlePeripheral::new(None).await.unwrap();
+        let central = start_mock_engine(&mut ble);
+        let mut notifications = central.subscribe_and_wait(&ble, 512).await;
+
+        // The central echoes every ping back as a pong
+        tokio::spawn(async move {
+            while let Some(notification) = notifications.recv().await {
+                if let Some(ControlMessage::Ping { nonce }) =
+                    ControlMessage::from_bytes(&notification, Endianness::Big)
+                {
+                    central.write(&ControlMessage::Pong { nonce }.to_bytes(Endianness::Big));
+                }
+            }
+        });
+
+        let latency = ble.ping(Duration::from_secs(1)).await.unwrap();
+        assert!(latency < Duration::from_secs(1));
+
+        // The pong is consumed by the ping and never delivered as a message
+        assert!(ble.try_receive_message().is_none());
+    }
+
+    #[tokio::test]
+    async fn capabilities_are_negotiated_on_subscribe() {
+        let mut ble = BlePeripheral::builder().handshake(0b11).build().unwrap();
+        let central = start_mock_engine(&mut ble);
+        let mut notifications = central.subscribe(185);
+
+        // The peripheral announces its capabilities first
+        let hello =
+            ControlMessage::from_bytes(&notifications.recv().await.unwrap(), Endianness::Big);
+        assert_eq!(
+            hello,
+            Some(ControlMessage::Hello(Capabilities {
+                version: PROTOCOL_VERSION,
+                mtu: 185,
+                features: 0b11,
+            }))
+        );
+        assert_eq!(ble.negotiated_capabilities(), None);
+
+        // The central answers with its own capabilities
+        let central_hello = ControlMessage::Hello(Capabilities {
+            version: PROTOCOL_VERSION,
+            mtu: 100,
+            features: 0b01,
+        });
+        central.write(&central_hello.to_bytes(Endianness::Big));
+        while ble.negotiated_capabilities().is_none() {
+            tokio::task::yield_now().await;
+        }
+        assert_eq!(
+            ble.negotiated_capabilities(),
+            Some(Capabilities {
+                version: PROTOCOL_VERSION,
+                mtu: 100,
+                features: 0b01,
+            })
+        );
+    }
+
+    /// Start a peripheral supporting the versions 1 and 2, with a subscribed central.
+    async fn start_versioned() -> (BlePeripheral, MockCentral, mpsc::UnboundedReceiver<Vec<u8>>) {
+        let mut ble = BlePeripheral::builder()
+            .versioned_messages(1..=2)
+            .build()
+            .unwrap();
+        let central = start_mock_engine(&mut ble);
+        let notifications = central.subscribe_and_wait(&ble, 512).await;
+        (ble, central, notifications)
+    }
+
+    #[tokio::test]
+    async fn matching_version_is_delivered() {
+        let (mut ble, central, mut notifications) = start_versioned().await;
+
+        // The newest version is used until the central sends a message
+        assert_eq!(ble.peer_version(), None);
+        ble.send_message("hello").await.unwrap();
+        assert_eq!(notifications.recv().await.unwrap(), b"\x02hello");
+
+        central.write(b"\x02hi");
+        let envelope = ble.receive_envelope().await.unwrap();
+        assert_eq!(envelope.message, BleMessage::Raw(b"hi".to_vec()));
+        assert_eq!(envelope.meta.version, Some(2));
+        assert_eq!(ble.peer_version(), Some(2));
+    }
+
+    #[tokio::test]
+    async fn older_supported_version_is_spoken_back() {
+        let (mut ble, central, mut notifications) = start_versioned().await;
+
+        central.write(b"\x01hi");
+        let envelope = ble.receive_envelope().await.unwrap();
+        assert_eq!(envelope.message, BleMessage::Raw(b"hi".to_vec()));
+        assert_eq!(envelope.meta.version, Some(1));
+        assert_eq!(ble.peer_version(), Some(1));
+
+        ble.send_message("hello").await.unwrap();
+        assert_eq!(notifications.recv().await.unwrap(), b"\x01hello");
+    }
+
+    #[tokio::test]
+    async fn unsupported_version_is_rejected() {
+        let (mut ble, central, _notifications) = start_versioned().await;
+        let mut events = ble.subscribe_events();
+
+        central.write(b"\x03hi");
+        assert_eq!(
+            events.recv().await.unwrap(),
+            BleEngineEvent::ProtocolViolation {
+                reason: BleError::UnsupportedVersion(3).to_string()
+            }
+        );
+        assert_eq!(ble.pending_messages(), 0);
+        assert_eq!(ble.peer_version(), None);
+
+        // The stream recovers with the next supported message
+        central.write(b"\x01ok");
+        assert_eq!(
+            ble.receive_message().await.unwrap(),
+            BleMessage::Raw(b"ok".to_vec())
+        );
+    }
+
+    #[tokio::test]
+    async fn encoded_values_and_file_chunks_carry_the_version() {
+        let (ble, _central, mut notifications) = start_versioned().await;
+
+        ble.send_encoded::<RawCodec, _>(&b"point".to_vec())
+            .await
+            .unwrap();
+        assert_eq!(notifications.recv().await.unwrap(), b"\x02point");
+
+        ble.send_file(b"file".to_vec(), 10).await.unwrap();
+        let notification = notifications.recv().await.unwrap();
+        assert_eq!(notification[0], 2);
+        assert_eq!(
+            decode_file_chunk(&notification[1..], Endianness::Big).unwrap(),
+            (0, &b"file"[..])
+        );
+    }
+
+    /// Write a partial text message and wait until the peripheral read it.
+    async fn write_partial(ble: &BlePeripheral, central: &MockCentral) {
+        central.write(b"hel");
+        while !ble
+            .recent_frames()
+            .iter()
+            .any(|frame| frame.direction == FrameDirection::Received)
+        {
+            tokio::task::yield_now().await;
+        }
+    }
+
+    #[tokio::test]
+    async fn new_connection_starts_clean() {
+        let mut ble = BlePeripheral::builder()
+            .text_delimiter(b'\n')
+            .capture_frames(8)
+            .build()
+            .unwrap();
+        let central = start_mock_engine(&mut ble);
+        let _notifications = central.subscribe(512);
+        write_partial(&ble, &central).await;
+
+        // The central reconnects and the partial message is discarded
+        let _notifications = central.subscribe(512);
+        central.write(b"lo\n");
+        assert_eq!(ble.receive_message().await.unwrap(), BleMessage::from("lo"));
+    }
+
+    #[tokio::test]
+    async fn disconnect_discards_partial_message() {
+        let mut ble = BlePeripheral::builder()
+            .text_delimiter(b'\n')
+            .capture_frames(8)
+            .build()
+            .unwrap();
+        let central = start_mock_engine(&mut ble);
+        let notifications = central.subscribe_and_wait(&ble, 512).await;
+        central.write(b"complete\nhel");
+        assert_eq!(
+            ble.receive_message().await.unwrap(),
+            BleMessage::from("complete")
+        );
+
+        // The engine detects the disconnect when notifying fails
+        drop(notifications);
+        ble.send_message("ping").await.unwrap();
+        wait_subscribed(&ble, false).await;
+        for _ in 0..10 {
+            tokio::task::yield_now().await;
+        }
+
+        central.write(b"lo\n");
+        assert_eq!(ble.receive_message().await.unwrap(), BleMessage::from("lo"));
+    }
+
+    #[tokio::test]
+    async fn dropped_watchers_do_not_crash_the_thread() {
+        let mut ble = BlePeripheral::builder().build().unwrap();
+        let central = start_mock_engine(&mut ble);
+        let notifications = central.subscribe_and_wait(&ble, 512).await;
+
+        // The subscribed state is updated on disconnect even though nobody watches it anymore
+        ble.subscribed_watcher = None;
+        ble.mtu_watcher = None;
+        ble.capabilities_watcher = None;
+        drop(notifications);
+        ble.send_message("ping").await.unwrap();
+        for _ in 0..10 {
+            tokio::task::yield_now().await;
+        }
+
+        // The thread exits cleanly once the peripheral is dropped
+        let ble_thread = ble.ble_thread.take().unwrap();
+        drop(ble);
+        ble_thread.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn second_subscriber_is_rejected_until_a_slot_frees() {
+        let mut ble = BlePeripheral::builder().max_subscribers(1).build().unwrap();
+        let mut events = ble.subscribe_events();
+        let central = start_mock_engine(&mut ble);
+        let mut first = central.subscribe_and_wait(&ble, 512).await;
+
+        // The declined session is closed, and the first central keeps receiving
+        let mut second = central.subscribe(512);
+        assert_eq!(
+            events.recv().await.unwrap(),
+            BleEngineEvent::SubscriberRejected { max_subscribers: 1 }
+        );
+        assert_eq!(second.recv().await, None);
+        ble.send_message(BleMessage::Raw(vec![1])).await.unwrap();
+        assert_eq!(first.recv().await.unwrap(), vec![1]);
+
+        // The slot frees once the first central goes away
+        drop(first);
+        ble.send_message(BleMessage::Raw(vec![2])).await.unwrap();
+        wait_subscribed(&ble, false).await;
+        let mut third = central.subscribe_and_wait(&ble, 512).await;
+        ble.send_message(BleMessage::Raw(vec![3])).await.unwrap();
+        assert_eq!(third.recv().await.unwrap(), vec![3]);
+    }
+
+    #[tokio::test]
+    async fn only_listed_centrals_are_accepted() {
+        let listed = Address::new([0x12, 0x34, 0x56, 0x78, 0x9A, 0x01]);
+        let unlisted = Address::new([0x12, 0x34, 0x56, 0x78, 0x9A, 0x02]);
+        let mut ble = BlePeripheral::builder()
+            .allowed_centrals(vec![listed])
+            .build()
+            .unwrap();
+        let mut events = ble.subscribe_events();
+        let central = start_mock_engine(&mut ble);
+
+        // The unlisted central can neither subscribe nor write
+        let mut rejected_notifications = central.subscribe_from(512, unlisted);
+        assert_eq!(
+            events.recv().await.unwrap(),
+            BleEngineEvent::CentralRejected {
+                address: Some(unlisted)
+            }
+        );
+        assert!(rejected_notifications.recv().await.is_none());
+        assert!(!ble.is_subscribed().await);
+        let rejected_write = central.start_write_from(512, Some(unlisted));
+        assert_eq!(
+            events.recv().await.unwrap(),
+            BleEngineEvent::CentralRejected {
+                address: Some(unlisted)
+            }
+        );
+        rejected_write.closed().await;
+
+        // The listed one is served as usual
+        let mut notifications = central.subscribe_from(512, listed);
+        wait_subscribed(&ble, true).await;
+        central
+            .start_write_from(512, Some(listed))
+            .send(b"hello".to_vec())
+            .unwrap();
+        assert_eq!(
+            ble.receive_message().await.unwrap(),
+            BleMessage::Raw(b"hello".to_vec())
+        );
+        ble.send_message("welcome").await.unwrap();
+        assert_eq!(notifications.recv().await.unwrap(), b"welcome".to_vec());
+        ble.stop_engine(None).await;
+    }
+
+    #[tokio::test]
+    async fn unlisted_centrals_cannot_subscribe_to_the_priority_characteristic() {
+        let listed = Address::new([0x12, 0x34, 0x56, 0x78, 0x9A, 0x01]);
+        let mut ble = BlePeripheral::builder()
+            .allowed_centrals(vec![listed])
+            .priority_channels(Uuid::from_u128(1), Uuid::from_u128(2))
+            .build()
+            .unwrap();
+        let mut events = ble.subscribe_events();
+        let central = start_mock_engine(&mut ble);
+
+        // The mock priority sessions come from a central with an unknown address
+        let mut priority = central.subscribe_priority(512);
+        assert_eq!(
+            events.recv().await.unwrap(),
+            BleEngineEvent::CentralRejected { address: None }
+        );
+        assert!(priority.recv().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn unlisted_centrals_cannot_write_at_an_offset() {
+        let listed = Address::new([0x12, 0x34, 0x56, 0x78, 0x9A, 0x01]);
+        let unlisted = Address::new([0x12, 0x34, 0x56, 0x78, 0x9A, 0x02]);
+        let mut ble = BlePeripheral::builder()
+            .allowed_centrals(vec![listed])
+            .offset_writes(true)
+            .build()
+            .unwrap();
+        let mut events = ble.subscribe_events();
+        let (transport, central) = MockTransport::new();
+        ble.start_with(transport).await.unwrap();
+
+        assert_eq!(
+            central.write_at_from(Some(unlisted), 0, &[0x01]),
+            Err(ReqError::NotAuthorized)
+        );
+        assert_eq!(
+            events.recv().await.unwrap(),
+            BleEngineEvent::CentralRejected {
+                address: Some(unlisted)
+            }
+        );
+
+        central.write_at_from(Some(listed), 4, &[0x02]).unwrap();
+        assert_eq!(ble.receive_offset_write().await.unwrap(), (4, vec![0x02]));
+    }
+
+    #[tokio::test]
+    async fn connect_carries_the_negotiated_parameters() {
+        let mut ble = BlePeripheral::builder().build().unwrap();
+        let central = start_mock_engine(&mut ble);
+        assert_eq!(ble.connection_info(), None);
+
+        let started = SystemTime::now();
+        let connected = ble.next_connect();
+        let address = Address::new([0x12, 0x34, 0x56, 0x78, 0x9A, 0xBC]);
+        let _notifications = central.subscribe_from(247, address);
+        let info = connected.await;
+        assert_eq!(info.mtu, 247);
+        assert_eq!(info.address, Some(address));
+        assert!(info.connected_at >= started);
+        assert_eq!(ble.connection_info(), Some(info.clone()));
+
+        // Only the centrals subscribing afterwards are waited for
+        let next = ble.next_connect();
+        tokio::pin!(next);
+        assert!(futures::poll!(next.as_mut()).is_pending());
+        let _notifications = central.subscribe(512);
+        let info = next.await;
+        assert_eq!(info.mtu, 512);
+        assert_eq!(info.address, None);
+
+        ble.stop_engine(None).await;
+        assert_eq!(ble.connection_info(), None);
+    }
+}
+
+#[cfg(test)]
+mod lifecycle_test {
+    use super::super::control::ControlMessage;
+    use super::super::endian::Endianness;
+    use super::super::error::BleError;
+    use super::super::event::{BleEngineEvent, DisconnectReason};
+    use super::super::faulty::{Faults, FaultyTransport};
+    use super::super::message::BleMessage;
+    use super::super::mock::{start_mock_engine, MockAdvertiser, MockTransport};
+    use super::super::readvertise::READVERTISE_POLL_INTERVAL;
+    use super::super::state::EngineState;
+    use super::super::transfer::decode_file_chunk;
+    use super::super::BlePeripheral;
+    use std::sync::{Arc, Mutex};
+    use tokio::time::{Duration, Instant};
+
+    #[test]
+    fn peripheral_is_built_from_its_config() {
+        let ble = BlePeripheral::builder()
+            .alias("Sensor")
+            .text_delimiter(b'\n')
+            .build()
+            .unwrap();
+        let rebuilt = BlePeripheral::from_config(ble.config().clone()).unwrap();
+        assert_eq!(rebuilt.alias, Some("Sensor".to_string()));
+        assert_eq!(rebuilt.config().text_delimiter, Some(b'\n'));
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn config_round_trips_through_toml() {
+        use super::super::config::PeripheralConfig;
+        use super::super::message::BleMessage;
+        use super::super::security::{Permissions, SecurityLevel};
+        use tokio::time::Duration;
+        use uuid::Uuid;
+
+        let service = Uuid::from_u128(0x1234);
+        let builder = BlePeripheral::builder()
+            .alias_template("SENSOR-{id:03}")
+            .instance_id(7)
+            .goodbye_message("Goodbye")
+            .coalesce(200, Duration::from_millis(20))
+            .permissions(Permissions {
+                read: SecurityLevel::Encrypted,
+                write: SecurityLevel::AuthenticatedEncrypted,
+            })
+            .readvertise(true);
+        let mut config = builder.config().clone();
+        config.service_uuid = Some(service);
+
+        let toml = config.to_toml().unwrap();
+        let loaded = PeripheralConfig::from_toml(&toml).unwrap();
+        assert_eq!(loaded.to_toml().unwrap(), toml);
+        assert_eq!(loaded.goodbye_message, Some(BleMessage::from("Goodbye")));
+        assert_eq!(loaded.coalesce, Some((200, Duration::from_millis(20))));
+
+        let ble = BlePeripheral::from_config(loaded).unwrap();
+        assert_eq!(ble.alias, Some("SENSOR-007".to_string()));
+        assert_eq!(ble.service_uuid(), service);
+        assert_eq!(
+            ble.config().permissions.write,
+            SecurityLevel::AuthenticatedEncrypted
+        );
+        assert!(ble.config().readvertise);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn loaded_zero_counts_are_rejected() {
+        use super::super::config::PeripheralConfig;
+        use tokio::time::Duration;
+
+        let zeroed: [fn(&mut PeripheralConfig); 4] = [
+            |config| config.max_inflight_writes = Some(0),
+            |config| config.transfer_progress = Some(0),
+            |config| config.dedup_window = Some(0),
+            |config| config.coalesce = Some((0, Duration::from_millis(20))),
+        ];
+        for zero in zeroed {
+            let mut config = BlePeripheral::builder().config().clone();
+            zero(&mut config);
+            let loaded = PeripheralConfig::from_toml(&config.to_toml().unwrap()).unwrap();
+            assert!(matches!(
+                BlePeripheral::from_config(loaded),
+                Err(BleError::InvalidConfig(_))
+            ));
+        }
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn missing_settings_are_left_at_their_default() {
+        use super::super::config::PeripheralConfig;
+
+        let config = PeripheralConfig::from_toml("alias = \"Sensor\"").unwrap();
+        assert_eq!(config.alias, Some("Sensor".to_string()));
+        assert!(!config.readvertise);
+        assert!(PeripheralConfig::from_toml("alias = 3").is_err());
+    }
+
+    #[tokio::test]
+    async fn goodbye_sent_before_close() {
+        let mut ble = BlePeripheral::builder()
+            .goodbye_message("Goodbye")
+            .build()
+            .unwrap();
+        let central = start_mock_engine(&mut ble);
+        let mut notifications = central.subscribe_and_wait(&ble, 64).await;
+
+        ble.send_message("data").await.unwrap();
+        ble.stop_engine(None).await;
+
+        // The goodbye message arrives after the queued data, right before the session closes
+        assert_eq!(notifications.recv().await.unwrap(), b"data");
+        assert_eq!(notifications.recv().await.unwrap(), b"Goodbye");
+        assert!(notifications.recv().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn reason_surfaces_in_close_event() {
+        let mut ble = BlePeripheral::builder().build().unwrap();
+        let mut events = ble.subscribe_events();
+        let central = start_mock_engine(&mut ble);
+        let mut notifications = central.subscribe_and_wait(&ble, 512).await;
+
+        ble.stop_engine(Some("Firmware update".into())).await;
+        assert_eq!(notifications.recv().await, None);
+        assert_eq!(
+            events.recv().await.unwrap(),
+            BleEngineEvent::Closed {
+                reason: DisconnectReason::EngineStopped(Some("Firmware update".into()))
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn stopping_releases_waiting_receivers() {
+        let mut ble = BlePeripheral::builder().build().unwrap();
+        let _central = start_mock_engine(&mut ble);
+        let receiver = ble.receiver.clone().unwrap();
+        let waiting = tokio::spawn(async move { receiver.recv().await });
+        for _ in 0..10 {
+            tokio::task::yield_now().await;
+        }
+        assert!(!waiting.is_finished());
+
+        ble.stop_engine(None).await;
+        let received = tokio::time::timeout(Duration::from_secs(1), waiting)
+            .await
+            .expect("receiver still waiting after the engine stopped")
+            .unwrap();
+        assert_eq!(received, None);
+    }
+
+    #[tokio::test]
+    async fn messages_left_are_received_before_the_channel_closes() {
+        let mut ble = BlePeripheral::builder().build().unwrap();
+        let central = start_mock_engine(&mut ble);
+        central.write(b"left");
+        while ble.pending_messages() == 0 {
+            tokio::task::yield_now().await;
+        }
+        ble.stop_engine(None).await;
+
+        assert_eq!(
+            ble.receive_message().await.unwrap(),
+            BleMessage::Raw(b"left".to_vec())
+        );
+        assert!(matches!(
+            ble.receive_message().await,
+            Err(BleError::ChannelClosed)
+        ));
+        assert!(matches!(
+            ble.receive_envelope().await,
+            Err(BleError::ChannelClosed)
+        ));
+    }
+
+    #[tokio::test]
+    async fn mock_transport_sends_and_receives() {
+        let mut ble = BlePeripheral::builder()
+            .text_delimiter(b'\n')
+            .build()
+            .unwrap();
+        let (transport, central) = MockTransport::new();
+        ble.start_with(transport).await.unwrap();
+
+        let mut notifications = central.subscribe_and_wait(&ble, 512).await;
+        ble.send_message("ping").await.unwrap();
+        assert_eq!(notifications.recv().await.unwrap(), b"ping\n".to_vec());
+
+        central.write(b"pong\n");
+        assert_eq!(
+            ble.receive_message().await.unwrap(),
+            BleMessage::from("pong")
+        );
+
+        // Stopping the engine closes the notification session
+        ble.stop_engine(None).await;
+        assert_eq!(notifications.recv().await, None);
+    }
+
+    #[tokio::test]
+    async fn engine_state_follows_start_and_stop() {
+        let mut ble = BlePeripheral::builder().build().unwrap();
+        assert_eq!(ble.engine_state(), EngineState::Stopped);
+        assert!(!ble.is_running());
+
+        let (transport, _central) = MockTransport::new();
+        ble.start_with(transport).await.unwrap();
+        assert_eq!(ble.engine_state(), EngineState::Running);
+        assert!(ble.is_running());
+
+        ble.stop_engine(None).await;
+        assert_eq!(ble.engine_state(), EngineState::Stopped);
+        assert!(!ble.is_running());
+
+        // The engine can be started again
+        let (transport, _central) = MockTransport::new();
+        ble.start_with(transport).await.unwrap();
+        assert_eq!(ble.engine_state(), EngineState::Running);
+        ble.stop_engine(None).await;
+    }
+
+    #[tokio::test]
+    async fn second_start_is_rejected() {
+        let mut ble = BlePeripheral::builder().build().unwrap();
+        let (transport, central) = MockTransport::new();
+        ble.start_with(transport).await.unwrap();
+
+        let (transport, _second_central) = MockTransport::new();
+        let err = ble.start_with(transport).await.unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<BleError>(),
+            Some(BleError::AlreadyRunning)
+        ));
+        let (transport, _second_central) = MockTransport::new();
+        let err = ble
+            .start_with_timeout(transport, Duration::from_secs(5))
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<BleError>(),
+            Some(BleError::AlreadyRunning)
+        ));
+
+        // The first engine keeps its channels
+        assert_eq!(ble.engine_state(), EngineState::Running);
+        let mut notifications = central.subscribe_and_wait(&ble, 512).await;
+        ble.send_message("still here").await.unwrap();
+        assert_eq!(notifications.recv().await.unwrap(), b"still here".to_vec());
+        ble.stop_engine(None).await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn startup_timeout_releases_partial_link() {
+        let mut ble = BlePeripheral::builder()
+            .offset_writes(true)
+            .build()
+            .unwrap();
+        let err = ble
+            .start_with_timeout(MockTransport::stalled(), Duration::from_secs(5))
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<BleError>(),
+            Some(BleError::StartupTimeout)
+        ));
+        assert!(ble.offset_sender.is_none());
+        assert!(ble.offset_receiver.is_none());
+        assert!(ble.sender.is_none());
+        assert_eq!(ble.engine_state(), EngineState::Stopped);
+
+        // The engine can be started again once the stack answers
+        let (transport, central) = MockTransport::new();
+        ble.start_with_timeout(transport, Duration::from_secs(5))
+            .await
+            .unwrap();
+        let _notifications = central.subscribe_and_wait(&ble, 512).await;
+        ble.stop_engine(None).await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn gatt_failure_tears_down_the_advertisement() {
+        let mut ble = BlePeripheral::builder().build().unwrap();
+        let advertiser = MockAdvertiser::default();
+        assert!(ble
+            .start_with(MockTransport::failing_gatt(advertiser.clone()))
+            .await
+            .is_err());
+        assert_eq!(advertiser.registrations(), 1);
+        assert!(ble.readvertise_task.is_none());
+        assert_eq!(ble.engine_state(), EngineState::Stopped);
+
+        // The advertisement is no longer kept registered once removed
+        advertiser.remove();
+        tokio::time::sleep(READVERTISE_POLL_INTERVAL * 3).await;
+        assert_eq!(advertiser.registrations(), 1);
+
+        // The engine can be started again
+        let (transport, _central) = MockTransport::new();
+        ble.start_with(transport).await.unwrap();
+        ble.stop_engine(None).await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn resumed_transfer_recovers_from_dropped_chunks() {
+        let mut ble = BlePeripheral::builder().build().unwrap();
+        let (transport, central) = MockTransport::new();
+        let faults = Faults {
+            drop_rate: 0.3,
+            seed: 7,
+            ..Faults::default()
+        };
+        ble.start_with(FaultyTransport::new(transport, faults))
+            .await
+            .unwrap();
+        let central = Arc::new(central);
+        let mut notifications = central.subscribe_and_wait(&ble, 512).await;
+
+        // The central only keeps the chunks following the bytes it already has
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let (acking_central, central_received) = (central.clone(), received.clone());
+        tokio::spawn(async move {
+            while let Some(notification) = notifications.recv().await {
+                let (offset, bytes) = decode_file_chunk(&notification, Endianness::Big).unwrap();
+                let mut received = central_received.lock().unwrap();
+                if offset == received.len() as u64 {
+                    received.extend_from_slice(bytes);
+                    let ack = ControlMessage::TransferAck {
+                        offset: received.len() as u64,
+                    };
+                    acking_central.write(&ack.to_bytes(Endianness::Big));
+                }
+            }
+        });
+
+        // Resume the transfer until every byte is acknowledged
+        let file: Vec<u8> = (0..200).map(|i| i as u8).collect();
+        ble.send_file(file.clone(), 10).await.unwrap();
+        let mut attempts = 1;
+        loop {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            if ble.file_transfer_offset() == Some(file.len() as u64) {
+                break;
+            }
+            assert!(attempts < 100, "transfer did not recover");
+            ble.resume_file_transfer().await.unwrap();
+            attempts += 1;
+        }
+        assert!(attempts > 1);
+        assert_eq!(*received.lock().unwrap(), file);
+        ble.stop_engine(None).await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn notifications_are_delayed_and_reordered() {
+        let mut ble = BlePeripheral::builder().build().unwrap();
+        let (transport, central) = MockTransport::new();
+        let faults = Faults {
+            latency: Duration::from_millis(50),
+            reorder_rate: 1.0,
+            ..Faults::default()
+        };
+        ble.start_with(FaultyTransport::new(transport, faults))
+            .await
+            .unwrap();
+        let mut notifications = central.subscribe_and_wait(&ble, 512).await;
+
+        // Every notification is held back until the next one is written
+        let started = Instant::now();
+        for message in ["a", "b", "c", "d"] {
+            ble.send_message(message).await.unwrap();
+        }
+        let mut order = Vec::new();
+        for _ in 0..4 {
+            order.push(notifications.recv().await.unwrap());
+        }
+        assert!(started.elapsed() >= Duration::from_millis(100));
+        assert_eq!(order, [b"b", b"a", b"d", b"c"].map(|bytes| bytes.to_vec()));
+        ble.stop_engine(None).await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn wait_resolves_once_the_engine_stops() {
+        let mut ble = BlePeripheral::builder().build().unwrap();
+        // Nothing to wait for before the engine is started
+        ble.wait().await;
+
+        let _central = start_mock_engine(&mut ble);
+        assert!(tokio::time::timeout(Duration::from_secs(1), ble.wait())
+            .await
+            .is_err());
+        assert!(ble.is_running());
+
+        ble.stop_engine(None).await;
+        tokio::time::timeout(Duration::from_secs(1), ble.wait())
+            .await
+            .unwrap();
+        assert!(!ble.is_running());
+    }
+
+    #[tokio::test]
+    async fn peripheral_is_usable_while_waiting() {
+        let mut ble = BlePeripheral::builder().build().unwrap();
+        let _central = start_mock_engine(&mut ble);
+        let waiting = tokio::spawn(ble.wait());
+
+        ble.send_message(vec![0x01]).await.unwrap();
+        assert!(!waiting.is_finished());
+
+        ble.stop_engine(None).await;
+        tokio::time::timeout(Duration::from_secs(1), waiting)
+            .await
+            .unwrap()
+            .unwrap();
     }
 }
@@ -0,0 +1,90 @@
+use super::endian::Endianness;
+
+/// Size of the header starting every TLV record: its type tag, then the length of its value
+/// encoded as a u16.
+pub const TLV_HEADER_SIZE: usize = 3;
+
+/// Type-length-value record, letting several tagged messages share one notification.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TlvRecord {
+    pub tag: u8,
+    pub value: Vec<u8>,
+}
+
+impl TlvRecord {
+    /// Create a new record. The value is truncated to the maximum length of a record.
+    pub fn new(tag: u8, value: impl Into<Vec<u8>>) -> Self {
+        let mut value = value.into();
+        value.truncate(u16::MAX as usize);
+        Self { tag, value }
+    }
+
+    /// Append the encoded record to `buf`, with its length in the given byte order.
+    pub fn encode_into(&self, buf: &mut Vec<u8>, endianness: Endianness) {
+        buf.push(self.tag);
+        buf.extend_from_slice(&endianness.u16_bytes(self.value.len() as u16));
+        buf.extend_from_slice(&self.value);
+    }
+}
+
+/// Encode records one after the other, as written in a single notification.
+pub fn encode_records(records: &[TlvRecord], endianness: Endianness) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(
+        records
+            .iter()
+            .map(|record| TLV_HEADER_SIZE + record.value.len())
+            .sum(),
+    );
+    for record in records {
+        record.encode_into(&mut buf, endianness);
+    }
+    buf
+}
+
+/// Decoder turning the received bytes back into records.
+/// A record spanning several notifications is kept until its last byte is received.
+/// The lengths are decoded as big-endian unless set otherwise with `with_endianness`.
+#[derive(Debug, Clone, Default)]
+pub struct TlvDecoder {
+    endianness: Endianness,
+    pending: Vec<u8>,
+}
+
+impl TlvDecoder {
+    /// Decode the lengths of the records in the given byte order.
+    pub fn with_endianness(mut self, endianness: Endianness) -> TlvDecoder {
+        self.endianness = endianness;
+        self
+    }
+
+    /// Feed the bytes of a received notification and return the records completed by it.
+    pub fn push(&mut self, bytes: &[u8]) -> Vec<TlvRecord> {
+        self.pending.extend_from_slice(bytes);
+        let mut records = Vec::new();
+        let mut consumed = 0;
+        while let Some(header) = self.pending.get(consumed..consumed + TLV_HEADER_SIZE) {
+            let len = self.endianness.read_u16([header[1], header[2]]) as usize;
+            let start = consumed + TLV_HEADER_SIZE;
+            let Some(value) = self.pending.get(start..start + len) else {
+                break;
+            };
+            records.push(TlvRecord {
+                tag: header[0],
+                value: value.to_vec(),
+            });
+            consumed = start + len;
+        }
+        self.pending.drain(..consumed);
+        records
+    }
+
+    /// Return the number of bytes received for the partially received record.
+    pub fn pending_len(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// Discard the bytes of the partially received record.
+    pub fn reset(&mut self) {
+        self.pending.clear();
+    }
+}
@@ -0,0 +1,136 @@
+use super::endian::Endianness;
+use super::error::BleError;
+use std::sync::Mutex;
+use tokio::sync::watch;
+use tokio::time::Duration;
+
+/// Size of the header prepended to every file chunk: the offset of the chunk in the file,
+/// encoded as a u64 in the byte order of the transfer.
+pub const FILE_CHUNK_HEADER_SIZE: usize = 8;
+
+/// Encode a file chunk starting at `offset` in the file, with the offset in the given byte order.
+pub fn encode_file_chunk(offset: u64, bytes: &[u8], endianness: Endianness) -> Vec<u8> {
+    let mut chunk = Vec::with_capacity(FILE_CHUNK_HEADER_SIZE + bytes.len());
+    chunk.extend_from_slice(&endianness.u64_bytes(offset));
+    chunk.extend_from_slice(bytes);
+    chunk
+}
+
+/// Decode a file chunk into its offset in the file and its bytes, with the offset in the given
+/// byte order.
+pub fn decode_file_chunk(chunk: &[u8], endianness: Endianness) -> Result<(u64, &[u8]), BleError> {
+    if chunk.len() < FILE_CHUNK_HEADER_SIZE {
+        return Err(BleError::InvalidMessage(
+            "File chunk is shorter than its header".to_string(),
+        ));
+    }
+    let (header, bytes) = chunk.split_at(FILE_CHUNK_HEADER_SIZE);
+    Ok((endianness.read_u64(header.try_into().unwrap()), bytes))
+}
+
+/// A file being sent to the central, along with how much of it was sent and acknowledged.
+struct FileTransfer {
+    data: Vec<u8>,
+    chunk_size: usize,
+    endianness: Endianness,
+    sent: u64,
+    acked: u64,
+}
+
+/// State of the current file transfer, shared between the peripheral and its BLE thread.
+/// It outlives the connection so the transfer can be resumed after the central reconnects.
+#[derive(Default)]
+pub(crate) struct TransferState {
+    transfer: Mutex<Option<FileTransfer>>,
+    acked_tx: watch::Sender<u64>,
+}
+
+impl TransferState {
+    /// Start a new transfer, replacing the current one, and return its encoded chunks.
+    /// The chunk offsets are encoded in the given byte order, also used when resuming.
+    pub fn start(&self, data: Vec<u8>, chunk_size: usize, endianness: Endianness) -> Vec<Vec<u8>> {
+        let transfer = FileTransfer {
+            data,
+            chunk_size,
+            endianness,
+            sent: 0,
+            acked: 0,
+        };
+        let chunks = chunks_from(&transfer, 0);
+        *self.transfer.lock().unwrap() = Some(transfer);
+        self.acked_tx.send_replace(0);
+        chunks
+    }
+
+    /// Record the offset acknowledged by the central. Acknowledgments never move backwards.
+    pub fn acknowledge(&self, offset: u64) {
+        if let Some(transfer) = self.transfer.lock().unwrap().as_mut() {
+            let offset = offset.min(transfer.data.len() as u64);
+            transfer.acked = transfer.acked.max(offset);
+            self.acked_tx.send_replace(transfer.acked);
+        }
+    }
+
+    /// Wait until the central acknowledges more than `offset` bytes of the current transfer.
+    /// Return the acknowledged offset, or `BleError::Timeout` if nothing new is acknowledged in time.
+    pub async fn wait_for_ack(&self, offset: u64, timeout: Duration) -> Result<u64, BleError> {
+        let mut acked_rx = self.acked_tx.subscribe();
+        let wait = async {
+            // The sender lives as long as the transfer state
+            let acked = acked_rx.wait_for(|&acked| acked > offset).await.unwrap();
+            *acked
+        };
+        tokio::time::timeout(timeout, wait)
+            .await
+            .map_err(|_| BleError::Timeout)
+    }
+
+    /// Record that the chunks up to `offset` were queued for sending.
+    pub fn record_sent(&self, offset: u64) {
+        if let Some(transfer) = self.transfer.lock().unwrap().as_mut() {
+            transfer.sent = transfer.sent.max(offset.min(transfer.data.len() as u64));
+        }
+    }
+
+    /// Return the number of chunks of the current transfer that were sent but not entirely
+    /// acknowledged yet.
+    pub fn unacked_chunks(&self) -> usize {
+        let guard = self.transfer.lock().unwrap();
+        let Some(transfer) = guard.as_ref() else {
+            return 0;
+        };
+        if transfer.acked >= transfer.sent {
+            return 0;
+        }
+        let chunk_size = transfer.chunk_size as u64;
+        (transfer.sent.div_ceil(chunk_size) - transfer.acked / chunk_size) as usize
+    }
+
+    /// Return the acknowledged offset of the current transfer, if any.
+    pub fn acked_offset(&self) -> Option<u64> {
+        self.transfer
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map(|transfer| transfer.acked)
+    }
+
+    /// Return the acknowledged offset and the encoded chunks that follow it, if there is a transfer.
+    pub fn resume(&self) -> Option<(u64, Vec<Vec<u8>>)> {
+        let guard = self.transfer.lock().unwrap();
+        let transfer = guard.as_ref()?;
+        Some((transfer.acked, chunks_from(transfer, transfer.acked)))
+    }
+}
+
+/// Encode the chunks of a transfer starting at `offset`.
+fn chunks_from(transfer: &FileTransfer, offset: u64) -> Vec<Vec<u8>> {
+    transfer.data[offset as usize..]
+        .chunks(transfer.chunk_size)
+        .enumerate()
+        .map(|(index, bytes)| {
+            let chunk_offset = offset + (index * transfer.chunk_size) as u64;
+            encode_file_chunk(chunk_offset, bytes, transfer.endianness)
+        })
+        .collect()
+}
@@ -0,0 +1,241 @@
+use super::adapter::{self, DiscoverableState};
+use super::advertisement;
+use super::alias;
+use super::engine::{LinkEvent, Notifier, WriteRequest};
+use super::readvertise::{self, AdapterAdvertiser, READVERTISE_POLL_INTERVAL};
+use super::BlePeripheral;
+use bluer::gatt::{
+    local::{
+        characteristic_control, service_control, Application, CharacteristicControlEvent,
+        CharacteristicWriteIoRequest,
+    },
+    CharacteristicWriter,
+};
+use bluer::{Address, Session};
+use futures::channel::mpsc as stream_mpsc;
+use futures::stream::BoxStream;
+use futures::StreamExt;
+use std::error::Error;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc;
+
+/// Link opened by a transport, carrying the events of the characteristics served to the central.
+pub(crate) struct TransportLink<Q, N> {
+    /// Write requests and notification sessions of the characteristics.
+    pub events: BoxStream<'static, LinkEvent<Q, N>>,
+    /// Writes delivered outside of the write requests, such as the validated ones.
+    pub write_rx: mpsc::UnboundedReceiver<Vec<u8>>,
+}
+
+/// Backend advertising the peripheral and serving its characteristics to the central device.
+/// The BLE thread only sees the opened link: the bytes written by the central are read from the
+/// readers of the write requests, and the notifications are written to the notifiers, so the
+/// message logic runs the same on any transport.
+pub(crate) trait Transport {
+    type Request: WriteRequest;
+    type Notifier: Notifier;
+
+    /// Advertise the peripheral and serve its GATT application, returning the opened link.
+    fn open(
+        self,
+        ble: &mut BlePeripheral,
+    ) -> impl Future<Output = Result<TransportLink<Self::Request, Self::Notifier>, Box<dyn Error>>>;
+}
+
+/// Event of the characteristics served through bluer.
+pub(crate) type BluerEvent = LinkEvent<CharacteristicWriteIoRequest, CharacteristicWriter>;
+
+/// Handover of a GATT application served again while the BLE thread runs, so its
+/// characteristic events reach the running thread along with the ones of the first application.
+#[derive(Clone)]
+pub(crate) struct GattRelay {
+    /// Channel handing the characteristic events of each new application to the BLE thread.
+    pub events_tx: stream_mpsc::UnboundedSender<BoxStream<'static, BluerEvent>>,
+    /// Channel of the writes handled outside of IO, shared by every application.
+    pub write_tx: mpsc::UnboundedSender<Vec<u8>>,
+}
+
+/// Transport backed by the BlueZ Bluetooth stack through bluer.
+#[derive(Default)]
+pub(crate) struct BluerTransport {
+    /// Address of the adapter to bind to, the default adapter if `None`.
+    pub adapter_address: Option<Address>,
+}
+
+impl Transport for BluerTransport {
+    type Request = CharacteristicWriteIoRequest;
+    type Notifier = CharacteristicWriter;
+
+    async fn open(
+        self,
+        ble: &mut BlePeripheral,
+    ) -> Result<TransportLink<Self::Request, Self::Notifier>, Box<dyn Error>> {
+        // Initialize the BLE session and adapter
+        let session = Session::new().await?;
+        let adapter = match self.adapter_address {
+            Some(address) => {
+                let adapters = adapter::list_adapters(&session).await?;
+                session.adapter(&adapter::find_by_address(&adapters, address)?.name)?
+            }
+            None => session.default_adapter().await?,
+        };
+        adapter.set_powered(true).await?;
+        adapter::check_address(
+            (ble.config.address, ble.config.address_type),
+            adapter.address().await?,
+            adapter.address_type().await?,
+        )?;
+        if ble.config.adapter_discoverable {
+            // The discoverable state is adapter-wide, so it is restored when stopping
+            ble.saved_discoverable = Some(DiscoverableState::save(&adapter).await?);
+            adapter.set_discoverable(true).await?;
+            adapter.set_discoverable_timeout(0).await?;
+        }
+
+        // Configure the advertisement, making sure it fits before handing it to bluer
+        let adv = ble.advertisement();
+        advertisement::validate_advertisement(&adv)?;
+        adapter::query_capabilities(&adapter).await?.check(&adv)?;
+
+        // Register the advertisement again when the system removes it or it changes
+        let shared_adv = Arc::new(Mutex::new(ble.base_advertisement()));
+        ble.readvertised = Some(shared_adv.clone());
+        let advertiser = AdapterAdvertiser {
+            adapter: adapter.clone(),
+            adv: shared_adv.clone(),
+            boost: ble.adv_boost.clone(),
+        };
+        if let Some(rotation) = ble.config.alias_rotation.clone() {
+            ble.alias_rotation_task = Some(tokio::spawn(alias::rotate_alias(
+                advertiser.clone(),
+                rotation,
+                ble.config.sanitize_alias,
+                ble.rotated_alias.clone(),
+                shared_adv,
+                ble.adv_handler.clone(),
+            )));
+        }
+        if ble.config.defer_advertising {
+            // Withhold the advertisement until the application is ready
+            let readvertise_interval = ble.config.readvertise.then_some(READVERTISE_POLL_INTERVAL);
+            ble.deferred_advertising = Some(tokio::spawn(readvertise::advertise_when_ready(
+                advertiser,
+                ble.ready.subscribe(),
+                ble.adv_handler.clone(),
+                ble.events.clone(),
+                readvertise_interval,
+            )));
+        } else {
+            // Start the BLE advertisement
+            *ble.adv_handler.lock().unwrap() = Some(adapter.advertise(adv).await?);
+            if ble.config.readvertise {
+                ble.readvertise_task = Some(tokio::spawn(readvertise::keep_advertising(
+                    advertiser,
+                    ble.adv_handler.clone(),
+                    ble.events.clone(),
+                    READVERTISE_POLL_INTERVAL,
+                )));
+            }
+        }
+
+        // Beacons only broadcast, so no GATT application is served and nothing is ever received
+        if ble.config.beacon_mode {
+            ble.adapter = Some(adapter);
+            let (_, write_rx) = mpsc::unbounded_channel();
+            return Ok(TransportLink {
+                events: futures::stream::pending().boxed(),
+                write_rx,
+            });
+        }
+
+        // Initialize the channel for writes handled outside of IO
+        let (write_tx, write_rx) = mpsc::unbounded_channel();
+
+        open_offset_channel(ble);
+
+        // Initialize the channel for write requests handed to the caller as is
+        if ble.config.raw_write_requests {
+            let (requests_tx, requests_rx) = stream_mpsc::unbounded();
+            ble.raw_write_sender = Some(requests_tx);
+            ble.raw_write_receiver = Some(requests_rx);
+        }
+
+        // Start the GATT application
+        let (app, char_events) = gatt_link(ble, &write_tx);
+        ble.app_handler = Some(adapter.serve_gatt_application(app).await?);
+
+        ble.adapter = Some(adapter);
+
+        // The events of the applications served again are relayed to the same link
+        let (events_tx, events_rx) = stream_mpsc::unbounded();
+        ble.gatt_relay = Some(GattRelay {
+            events_tx,
+            write_tx,
+        });
+        let events = futures::stream::select(char_events, events_rx.flatten_unordered(None));
+
+        Ok(TransportLink {
+            events: events.boxed(),
+            write_rx,
+        })
+    }
+}
+
+/// Initialize the channel for writes received with their offset, if offset writes are enabled.
+pub(crate) fn open_offset_channel(ble: &mut BlePeripheral) {
+    if ble.config.offset_writes {
+        let (offset_tx, offset_rx) = mpsc::unbounded_channel();
+        ble.offset_sender = Some(offset_tx);
+        ble.offset_receiver = Some(offset_rx);
+    }
+}
+
+/// Build the GATT application of the peripheral along with the events of its characteristics.
+pub(crate) fn gatt_link(
+    ble: &BlePeripheral,
+    write_tx: &mpsc::UnboundedSender<Vec<u8>>,
+) -> (Application, BoxStream<'static, BluerEvent>) {
+    // Initialize the GATT service and characteristic handles
+    let (_, service_handle) = service_control();
+    let (char_control, char_handle) = characteristic_control();
+
+    // The response characteristic gets its own events, merged with the command ones
+    let split_layout =
+        ble.config.command_response.is_some() || ble.config.priority_channels.is_some();
+    let (char_events, response_handle) = match split_layout {
+        true => {
+            let (response_control, response_handle) = characteristic_control();
+            let events = futures::stream::select(char_control, response_control).boxed();
+            (events.map(LinkEvent::from).boxed(), Some(response_handle))
+        }
+        false => (char_control.map(LinkEvent::from).boxed(), None),
+    };
+
+    // The priority characteristic only has notify events, told apart from the bulk ones
+    let (char_events, priority_handle) = match ble.config.priority_channels {
+        Some(_) => {
+            let (priority_control, priority_handle) = characteristic_control();
+            let priority_events = priority_control.filter_map(|evt| async move {
+                match evt {
+                    CharacteristicControlEvent::Notify(notifier) => {
+                        Some(LinkEvent::PriorityNotify(notifier))
+                    }
+                    CharacteristicControlEvent::Write(_) => None,
+                }
+            });
+            let events = futures::stream::select(char_events, priority_events).boxed();
+            (events, Some(priority_handle))
+        }
+        None => (char_events, None),
+    };
+
+    let app = ble.gatt_application(
+        service_handle,
+        char_handle,
+        response_handle,
+        priority_handle,
+        write_tx,
+    );
+    (app, char_events)
+}
@@ -0,0 +1,9 @@
+pub mod bluetooth;
+
+pub use bluetooth::builder::BlePeripheralBuilder;
+pub use bluetooth::config::PeripheralConfig;
+pub use bluetooth::control::ControlMessage;
+pub use bluetooth::error::BleError;
+pub use bluetooth::event::BleEngineEvent;
+pub use bluetooth::message::BleMessage;
+pub use bluetooth::{BlePeripheral, DEFAULT_CHARACTERISTIC_UUID, DEFAULT_SERVICE_UUID};
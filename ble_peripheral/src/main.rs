@@ -1,8 +1,4 @@
-mod bluetooth;
-
-use bluetooth::message::BleMessage;
-use bluetooth::BlePeripheral;
-use std::io::Cursor;
+use ble_peripheral::bluetooth::BlePeripheral;
 use std::vec::Vec;
 
 #[tokio::main]
@@ -25,15 +21,8 @@ async fn main() {
         tokio::time::sleep(tokio::time::Duration::from_millis(200)).await;
     }
 
-    // Wait for the central device to send the Ready message.
-    loop {
-        let message = ble.receive_message().await;
-        if let BleMessage::Text(message) = message.convert_to_text().unwrap() {
-            if message == "Ready" {
-                break;
-            }
-        }
-    }
+    // Wait for the central device to send the ready message.
+    while !ble.receive_message().await.unwrap().is_ready() {}
 
     let mut time_records: Vec<tokio::time::Duration> = Vec::new();
 
@@ -48,35 +37,16 @@ async fn main() {
         // Save the current time.
         let start_time = tokio::time::Instant::now();
 
-        // Resize the image
-        let img = img.resize_exact(75, 100, image::imageops::FilterType::Nearest);
-
-        // Convert the image to a byte array.
-        let mut bytes: Vec<u8> = Vec::new();
-        img.write_to(&mut Cursor::new(&mut bytes), image::ImageFormat::Jpeg)
-            .unwrap();
-
-        let duration = tokio::time::Instant::now() - start_time;
-        println!("Image preprocessed {}: {:?}", i, duration);
-
-        // Send the image file size to the central device.
-        ble.send_message(bytes.len()).await.unwrap();
-
-        // Send the image file to the central device.
-        ble.send_message(bytes).await.unwrap();
+        // Resize and encode the image, then frame and chunk it and send the chunks to the central
+        // device, which reassembles and decodes them with an `ImageReceiver`. The JPEG bytes are
+        // sent without further compression.
+        ble.send_image(&img, 75, 100, 180).await.unwrap();
 
         let duration = tokio::time::Instant::now() - start_time;
         println!("Image sent {}: {:?}", i, duration);
 
-        // Wait for a confirmation to be received.
-        loop {
-            let message = ble.receive_message().await;
-            if let BleMessage::Text(message) = message.convert_to_text().unwrap() {
-                if message == "Ready" {
-                    break;
-                }
-            }
-        }
+        // Wait for the ready message confirming the image was received.
+        while !ble.receive_message().await.unwrap().is_ready() {}
 
         // Save the duration taken
         let duration = tokio::time::Instant::now() - start_time;
@@ -90,5 +60,5 @@ async fn main() {
     println!("Average total delay: {:?}", average);
 
     // Stop the BLE peripheral engine.
-    ble.stop_engine().await;
+    ble.stop_engine(None).await;
 }